@@ -0,0 +1,6 @@
+//! Optional `IndexBackend` implementations
+//! Each backend pulls in its own heavy dependencies, so they're behind feature flags:
+//! - `rocksdb-backend`: `rocksdb::RocksdbBackend`, an embedded key-value store
+
+#[cfg(feature = "rocksdb-backend")]
+pub mod rocksdb;
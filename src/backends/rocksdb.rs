@@ -0,0 +1,241 @@
+//! RocksDB backend
+//! An embedded alternative to `SurrealBackend`, for small and offline deployments.
+//! Entries are stored with bincode in two column families:
+//! - `files`: path -> `IndexedFile`
+//! - `tags`: `tag \0 path` -> nothing, to look up files by tag without a full scan
+//!
+//! The root of the index, if set, is kept under `root` in the default column family
+
+use std::path::{Path, PathBuf};
+
+use ::rocksdb::{ColumnFamily, IteratorMode, Options, WriteBatch, DB};
+use async_trait::async_trait;
+use color_eyre::{eyre::eyre, Result};
+use futures_util::stream::BoxStream;
+
+use crate::db::IndexBackend;
+use crate::files::{Index, IndexedFile};
+use crate::query::{eval_score, MatchContext, Query, ScoringConfig};
+
+const FILES_CF: &str = "files";
+const TAGS_CF: &str = "tags";
+const ROOT_KEY: &[u8] = b"root";
+
+pub struct RocksdbBackend {
+    db: DB,
+    /// Root path of the stored index, see `set_root`
+    root: Option<PathBuf>,
+}
+
+impl RocksdbBackend {
+    /// Open (or create) the database at `path`
+    pub fn new(path: &Path) -> Result<Self> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+        Self::with_options(path, opts)
+    }
+
+    /// Open the database at `path` with custom RocksDB options.
+    /// `opts` needs `create_missing_column_families` for a new database
+    pub fn with_options(path: &Path, opts: Options) -> Result<Self> {
+        let db = DB::open_cf(&opts, path, [FILES_CF, TAGS_CF])?;
+        let root = match db.get(ROOT_KEY)? {
+            Some(root) => Some(PathBuf::from(String::from_utf8(root)?)),
+            None => None,
+        };
+        Ok(Self { db, root })
+    }
+
+    /// Root path of the stored index, if one was set
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    /// Store the directory the entries were indexed from. Queries resolve terms like
+    /// `depth:` relative to it, the same as `Index::root` for an in-memory index
+    pub fn set_root(&mut self, root: &Path) -> Result<()> {
+        self.db.put(ROOT_KEY, file_key(root)?)?;
+        self.root = Some(root.to_path_buf());
+        Ok(())
+    }
+
+    fn cf(&self, name: &str) -> Result<&ColumnFamily> {
+        self.db
+            .cf_handle(name)
+            .ok_or_else(|| eyre!("Missing column family {}", name))
+    }
+
+    /// Every stored entry, in path order
+    fn scan(&self) -> Result<Vec<IndexedFile>> {
+        let mut files = Vec::new();
+        for item in self.db.iterator_cf(self.cf(FILES_CF)?, IteratorMode::Start) {
+            let (_, value) = item?;
+            files.push(bincode::deserialize(&value)?);
+        }
+        Ok(files)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<IndexedFile>> {
+        match self.db.get_cf(self.cf(FILES_CF)?, key)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Queue removing the tag entries of `file`
+    fn delete_tags(&self, batch: &mut WriteBatch, key: &str, file: &IndexedFile) -> Result<()> {
+        let tags = self.cf(TAGS_CF)?;
+        for tag in &file.tags {
+            batch.delete_cf(tags, tag_key(tag, key));
+        }
+        Ok(())
+    }
+
+    /// Paths of every file with `tag`, in path order
+    pub fn files_with_tag(&self, tag: &str) -> Result<Vec<PathBuf>> {
+        let prefix = tag_key(tag, "");
+        let mut paths = Vec::new();
+        for item in self.db.prefix_iterator_cf(self.cf(TAGS_CF)?, &prefix) {
+            let (key, _) = item?;
+            // without a prefix extractor, the iterator runs past the prefix
+            let Some(path) = key.strip_prefix(prefix.as_slice()) else {
+                break;
+            };
+            paths.push(PathBuf::from(String::from_utf8(path.to_vec())?));
+        }
+        Ok(paths)
+    }
+}
+
+/// Key of a file in the `files` column family
+fn file_key(path: &Path) -> Result<&str> {
+    path.to_str()
+        .ok_or_else(|| eyre!("{} is not valid UTF-8", path.display()))
+}
+
+/// Key of a tag entry in the `tags` column family.
+/// Tags can't contain a null byte, so this sorts every path of a tag together
+fn tag_key(tag: &str, path: &str) -> Vec<u8> {
+    [tag.as_bytes(), b"\0", path.as_bytes()].concat()
+}
+
+#[async_trait]
+impl IndexBackend for RocksdbBackend {
+    async fn push_file(&mut self, entry: IndexedFile) -> Result<IndexedFile> {
+        let key = file_key(&entry.path)?;
+        let mut batch = WriteBatch::default();
+        if let Some(old) = self.get(key)? {
+            self.delete_tags(&mut batch, key, &old)?;
+        }
+        let tags = self.cf(TAGS_CF)?;
+        for tag in &entry.tags {
+            batch.put_cf(tags, tag_key(tag, key), []);
+        }
+        batch.put_cf(self.cf(FILES_CF)?, key, bincode::serialize(&entry)?);
+        self.db.write(batch)?;
+        Ok(entry)
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        let key = file_key(path)?;
+        let mut batch = WriteBatch::default();
+        if let Some(old) = self.get(key)? {
+            self.delete_tags(&mut batch, key, &old)?;
+        }
+        batch.delete_cf(self.cf(FILES_CF)?, key);
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    async fn query(&mut self, q: &Query) -> Result<Vec<(i64, IndexedFile)>> {
+        let ctx = MatchContext::new(self.root(), ScoringConfig::default()).with_query(q);
+        let mut results = Vec::new();
+        for item in self.db.iterator_cf(self.cf(FILES_CF)?, IteratorMode::Start) {
+            let (_, value) = item?;
+            if let Some(result) = eval_score(q, bincode::deserialize(&value)?, &ctx)? {
+                results.push(result);
+            }
+        }
+
+        Index::sort_by_score_desc(&mut results);
+        if let Some(limit) = q.result_limit() {
+            results.truncate(limit);
+        }
+        Ok(results)
+    }
+
+    /// Reads every entry up front, since RocksDB iterators can't be held across awaits
+    fn all_files(&mut self) -> BoxStream<'_, Result<IndexedFile>> {
+        let files = match self.scan() {
+            Ok(files) => files.into_iter().map(Ok).collect(),
+            Err(e) => vec![Err(e)],
+        };
+        Box::pin(futures_util::stream::iter(files))
+    }
+}
+
+#[tokio::test]
+async fn test_rocksdb_backend() -> Result<()> {
+    use crate::query::parse_query;
+
+    let dir = tempfile::tempdir()?;
+    let mut backend = RocksdbBackend::new(&dir.path().join("db"))?;
+    for (path, tags) in [
+        ("src/main.rs", vec!["owo"]),
+        ("README.md", vec!["owo", "uwu"]),
+    ] {
+        backend
+            .push_file(IndexedFile {
+                path: PathBuf::from(path),
+                tags: tags.into_iter().map(String::from).collect(),
+                ..Default::default()
+            })
+            .await?;
+    }
+
+    let results = backend.query(&parse_query("ext:rs")?).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].1.path, PathBuf::from("src/main.rs"));
+    assert_eq!(backend.files_with_tag("owo")?.len(), 2);
+
+    // replacing an entry drops its old tags
+    backend
+        .push_file(IndexedFile {
+            path: PathBuf::from("README.md"),
+            ..Default::default()
+        })
+        .await?;
+    assert_eq!(backend.files_with_tag("uwu")?, Vec::<PathBuf>::new());
+    assert_eq!(
+        backend.files_with_tag("owo")?,
+        [PathBuf::from("src/main.rs")]
+    );
+
+    backend.remove_file(Path::new("src/main.rs")).await?;
+    assert!(backend.files_with_tag("owo")?.is_empty());
+    assert_eq!(backend.query_count(&parse_query("*")?).await?, 1);
+
+    // `depth:` counts from the root, like it does for an in-memory index
+    backend
+        .push_file(IndexedFile {
+            path: PathBuf::from("/home/user/notes.txt"),
+            ..Default::default()
+        })
+        .await?;
+    let results = backend.query(&parse_query("depth:1")?).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].1.path, PathBuf::from("README.md"));
+    backend.set_root(Path::new("/home/user"))?;
+    let results = backend.query(&parse_query("depth:1")?).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].1.path, PathBuf::from("/home/user/notes.txt"));
+
+    // everything is still there after reopening
+    drop(backend);
+    let mut backend = RocksdbBackend::new(&dir.path().join("db"))?;
+    assert_eq!(backend.query_count(&parse_query("*")?).await?, 2);
+    assert_eq!(backend.root(), Some(Path::new("/home/user")));
+
+    Ok(())
+}
@@ -0,0 +1,308 @@
+//! Command line interface
+//! Argument parsing for the `datchani` binary, using clap's derive API.
+
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+
+use crate::grpc::DEFAULT_GRPC_PORT;
+use crate::output::{GroupBy, OutputFieldSet, OutputFormat};
+
+#[derive(Debug, Parser)]
+#[command(name = "datchani", version, about = "A Certain Magical Indexer")]
+pub struct Cli {
+    /// Path to the index file
+    #[arg(long, global = true, default_value = "index.json")]
+    pub index: PathBuf,
+    /// Skip files larger than SIZE when indexing, e.g. `100MB` or `1GiB`
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+    /// Don't descend into other filesystems when indexing (Unix only)
+    #[arg(short = 'x', long, global = true)]
+    pub one_filesystem: bool,
+    /// Check terms like `can:write` against the filesystem instead of the indexed permissions.
+    /// More accurate, but does I/O for every file they're matched against
+    #[arg(long, global = true)]
+    pub live_checks: bool,
+    /// Only log messages at LEVEL or above, overrides `RUST_LOG`
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LogLevel>,
+    /// How log messages are written to stderr
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Pretty)]
+    pub log_format: LogFormat,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Search the current directory
+    Search(SearchArgs),
+    /// Interactively refine a query, the selected path is printed to stdout
+    Interactive,
+    /// Re-walk the indexed directory, dropping entries that no longer exist
+    Gc,
+    /// Index the current directory from scratch, replacing the index file.
+    /// Tags and hashes in the old index are lost, unlike with `gc`
+    Rebuild {
+        /// Copy every entry from the database instead of walking the directory,
+        /// see `DATCHANI_DB_URL`
+        #[arg(long)]
+        from_db: bool,
+    },
+    /// Export or import the whole index
+    Index {
+        #[command(subcommand)]
+        command: IndexCommand,
+    },
+    /// Manage file tags
+    Tag {
+        #[command(subcommand)]
+        command: TagCommand,
+    },
+    /// Manage saved queries
+    Query {
+        #[command(subcommand)]
+        command: QueryCommand,
+    },
+    /// Show the most common extensions, MIME types and tags in the index,
+    /// or in the current directory if there's no index file
+    Stats {
+        /// How many of each to show
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        top: usize,
+    },
+    /// Serve the index over gRPC on localhost until stopped, see `proto/datchani.proto`.
+    /// Indexes the current directory first if there's no index file
+    Serve {
+        #[arg(long, value_name = "PORT", default_value_t = DEFAULT_GRPC_PORT)]
+        grpc_port: u16,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IndexCommand {
+    /// Write the index to a file in another format, e.g. to share it
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Only export the files matching this query
+        #[arg(long, value_name = "QUERY")]
+        filter: Option<String>,
+    },
+    /// Read an exported index and add every entry to the database, see `DATCHANI_DB_URL`
+    Import {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Json)]
+        format: ExportFormat,
+        #[arg(short, long)]
+        input: PathBuf,
+    },
+}
+
+/// File formats for `index export` and `index import`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Json,
+    /// One row per file, with the metadata in a leading `#` comment line
+    Csv,
+    Cbor,
+    Msgpack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    /// The `RUST_LOG` style directive for this level
+    pub fn directive(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Multi-line and colored, for reading in a terminal
+    Pretty,
+    /// One JSON object per line, for log aggregation
+    Json,
+    /// One line per message
+    Compact,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TagCommand {
+    /// Copy the tags stored in extended attributes into the index,
+    /// for every indexed file below PATH (the current directory by default)
+    Sync {
+        path: Option<PathBuf>,
+        /// Only print the changes, without writing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Update the database instead of the index file, see `DATCHANI_DB_URL`
+        #[arg(long)]
+        db: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum QueryCommand {
+    /// Save a query under a name
+    Save {
+        name: String,
+        query: Vec<String>,
+        /// A short description of what the query is for
+        #[arg(short, long)]
+        description: Option<String>,
+    },
+    /// Run a saved query
+    Run {
+        name: String,
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// List all saved queries
+    List,
+}
+
+#[derive(Debug, Args)]
+pub struct SearchArgs {
+    /// The search query, see the query syntax documentation
+    pub query: Vec<String>,
+    /// Keep running, and print the results again whenever a file in the directory changes
+    #[arg(long, conflicts_with_all = ["gt", "lt", "eq"])]
+    pub watch: bool,
+    /// With --watch, wait until nothing has changed for this many milliseconds before refreshing
+    #[arg(long, value_name = "MS", default_value_t = 300, requires = "watch")]
+    pub watch_delay_ms: u64,
+    /// Search the paths read from stdin, one per line, instead of the current directory.
+    /// Paths that can't be indexed are skipped
+    #[arg(long, conflicts_with = "watch")]
+    pub stdin: bool,
+    /// Like --stdin, but the paths are separated by null bytes, as printed by `find -print0`
+    #[arg(long, conflicts_with_all = ["watch", "stdin"])]
+    pub stdin_null: bool,
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+/// Options controlling how query results are printed
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    /// Only print the number of matching results
+    #[arg(long)]
+    pub count: bool,
+    /// With --count, exit with 1 unless the count is greater than N
+    #[arg(long, value_name = "N", requires = "count")]
+    pub gt: Option<usize>,
+    /// With --count, exit with 1 unless the count is less than N
+    #[arg(long, value_name = "N", requires = "count")]
+    pub lt: Option<usize>,
+    /// With --count, exit with 1 unless the count is exactly N
+    #[arg(long, value_name = "N", requires = "count")]
+    pub eq: Option<usize>,
+    /// Stop after N results
+    #[arg(long, value_name = "N")]
+    pub limit: Option<usize>,
+    /// Separate results with a null byte instead of a newline, for use with `xargs -0`
+    #[arg(short = '0', long)]
+    pub null: bool,
+    /// Print each result as a JSON object, one per line.
+    /// --null has no effect on JSON output
+    #[arg(long)]
+    pub json: bool,
+    /// How to print the results
+    #[arg(long, value_enum, default_value_t = OutputFormat::Plain)]
+    pub format: OutputFormat,
+    /// Comma separated columns to print with --format=table:
+    /// score, path, size, mime, tags, modified and preview,
+    /// or `all` for every column but preview
+    #[arg(long, value_name = "FIELDS", default_value = "all")]
+    pub fields: OutputFieldSet,
+    /// Print the results in sections, one per extension, directory, MIME type or tag
+    #[arg(long, value_enum, conflicts_with_all = ["count", "json", "null"])]
+    pub group_by: Option<GroupBy>,
+    /// Don't color --format=pretty output. Setting `NO_COLOR` does the same
+    #[arg(long)]
+    pub no_color: bool,
+    /// List the terms each result matched and their scores below it.
+    /// With --json they're in the `explanation` field instead
+    #[arg(long, conflicts_with_all = ["count", "group_by", "format", "null"])]
+    pub explain: bool,
+}
+
+impl OutputArgs {
+    /// Whether `count` satisfies all of `--gt`, `--lt` and `--eq`
+    pub fn count_matches(&self, count: usize) -> bool {
+        self.gt.map_or(true, |n| count > n)
+            && self.lt.map_or(true, |n| count < n)
+            && self.eq.map_or(true, |n| count == n)
+    }
+}
+
+/// Parse a human readable size like `100MB` into bytes.
+/// SI units (`KB`, `MB`, ...) are powers of 1000, IEC units (`KiB`, `MiB`, ...) powers of 1024,
+/// and a plain number (optionally followed by `B`) is taken as bytes. Units are case insensitive
+pub fn parse_size(input: &str) -> Result<u64, String> {
+    let input = input.trim();
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size `{}`", input))?;
+
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1000,
+        "mb" => 1000u64.pow(2),
+        "gb" => 1000u64.pow(3),
+        "tb" => 1000u64.pow(4),
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        "tib" => 1 << 40,
+        _ => {
+            return Err(format!(
+                "unknown size unit `{}`, expected one of B, KB, MB, GB, TB, KiB, MiB, GiB or TiB",
+                unit.trim()
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size `{}` is too large", input))
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(parse_size("512"), Ok(512));
+    assert_eq!(parse_size("512B"), Ok(512));
+    assert_eq!(parse_size("10KB"), Ok(10_000));
+    assert_eq!(parse_size("10KiB"), Ok(10_240));
+    assert_eq!(parse_size("100MB"), Ok(100_000_000));
+    assert_eq!(parse_size("1 gib"), Ok(1 << 30));
+    assert!(parse_size("MB").is_err());
+    assert!(parse_size("10XB").unwrap_err().contains("`XB`"));
+    assert!(parse_size("99999999999TB").is_err());
+}
@@ -0,0 +1,92 @@
+//! User configuration
+//! Read from `~/.config/datchani/config.toml`, a missing file means the defaults.
+//! Custom MIME types are given as one `[[custom_mime]]` table per rule:
+//!
+//! ```toml
+//! [[custom_mime]]
+//! pattern = "*.pbxproj"
+//! mime = "text/x-xcode-project"
+//! ```
+//!
+//! Patterns are globs matched against the full path, the first matching rule wins.
+
+use std::{fs, path::Path};
+
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::files::MimeRules;
+
+/// A single `[[custom_mime]]` rule
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CustomMime {
+    pub pattern: String,
+    pub mime: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub custom_mime: Vec<CustomMime>,
+}
+
+impl Config {
+    /// Read the config at `path`, a missing file gives the default config
+    pub fn open(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(s) => Ok(toml::from_str(&s)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Read the config in the user's config directory.
+    /// Without a config directory, e.g. when `HOME` isn't set, that's the default config too
+    pub fn open_default() -> Result<Self> {
+        match dirs::config_dir() {
+            Some(dir) => Self::open(&dir.join("datchani").join("config.toml")),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Compile the `[[custom_mime]]` rules, see `IndexConfig::custom_mime_rules`
+    pub fn mime_rules(&self) -> Result<MimeRules> {
+        MimeRules::new(
+            self.custom_mime
+                .iter()
+                .map(|rule| (rule.pattern.clone(), rule.mime.clone()))
+                .collect(),
+        )
+    }
+}
+
+#[test]
+fn test_config() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("config.toml");
+    assert_eq!(Config::open(&path)?, Config::default());
+
+    fs::write(
+        &path,
+        r#"
+        [[custom_mime]]
+        pattern = "*.pbxproj"
+        mime = "text/x-xcode-project"
+
+        [[custom_mime]]
+        pattern = "*.owo"
+        mime = "text/x-owo"
+        "#,
+    )?;
+    let config = Config::open(&path)?;
+    assert_eq!(config.custom_mime.len(), 2);
+
+    let rules = config.mime_rules()?;
+    assert_eq!(
+        rules.lookup(Path::new("App.xcodeproj/project.pbxproj")),
+        Some("text/x-xcode-project")
+    );
+    assert_eq!(rules.lookup(Path::new("main.rs")), None);
+
+    Ok(())
+}
@@ -0,0 +1,91 @@
+//! Daemon mode
+//! Background tasks that keep a long-running index fresh.
+//!
+//! The index is shared as an `Arc<ArcSwap<Index>>`. Readers load the current index
+//! and query it for as long as they like, without ever blocking or being blocked:
+//!
+//! ```ignore
+//! let snapshot = index.load_full();
+//! let results = query(&q, &snapshot)?;
+//! ```
+//!
+//! Loading is O(1), so the files don't need to live behind an `Arc<[IndexedFile]>` for
+//! queries to be cheap. `Index::snapshot` does clone every entry, see its docs for the cost.
+//!
+//! Writers never edit the index in place. They build a complete new index and swap it in
+//! atomically, so queries keep being served from the old one while a rebuild runs,
+//! and a reader never sees a half-updated index. The catch is memory: until the swap
+//! (and until the last reader drops it), the old index is alive next to the new one,
+//! so a rebuild needs room for two full copies. Small updates like eviction pay for a
+//! full clone as well, which is fine at the intervals they run at.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use arc_swap::ArcSwap;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::files::Index;
+use crate::indexer::{self, IndexConfig};
+
+/// How often stale entries are checked for
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Default)]
+pub struct DaemonConfig {
+    /// Evict entries that have not been re-indexed in this many days.
+    /// `None` keeps entries forever
+    pub max_entry_age_days: Option<u64>,
+    /// Rebuild the whole index this often, `None` never does
+    pub reindex_interval: Option<Duration>,
+}
+
+/// Spawn a task that periodically evicts stale entries from the index,
+/// according to `max_entry_age_days`.
+/// Returns `None` if eviction is disabled.
+pub fn spawn_eviction(index: Arc<ArcSwap<Index>>, config: &DaemonConfig) -> Option<JoinHandle<()>> {
+    let days = config.max_entry_age_days?;
+    // a huge `max_entry_age_days` just never evicts anything, see `Index::evict_older_than`
+    let age = Duration::from_secs(days.saturating_mul(24 * 60 * 60));
+
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+        loop {
+            interval.tick().await;
+            let mut removed = 0;
+            // `rcu` retries if a rebuild swapped in a new index in the meantime
+            index.rcu(|current| {
+                let mut next = Index::clone(current);
+                removed = next.evict_older_than(age);
+                next
+            });
+            debug!("Evicted {} stale entries", removed);
+        }
+    }))
+}
+
+/// Spawn a task that rebuilds the index from `root` every `reindex_interval`,
+/// serving queries from the old index until each rebuild is done, see `indexer::reindex_swap`.
+/// Returns `None` if rebuilding is disabled.
+pub fn spawn_reindex(
+    index: Arc<ArcSwap<Index>>,
+    root: PathBuf,
+    index_config: IndexConfig,
+    config: &DaemonConfig,
+) -> Option<JoinHandle<()>> {
+    let period = config.reindex_interval?;
+
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match indexer::reindex_swap(&index, &root, &index_config).await {
+                Ok(stats) => debug!(
+                    "Rebuilt the index: removed {}, added {}",
+                    stats.removed, stats.added
+                ),
+                Err(e) => warn!("Rebuilding the index failed: {:?}", e),
+            }
+        }
+    }))
+}
@@ -7,31 +7,467 @@
 //! This is currently a stub. The database will be implemented in the future. Right now make do with a JSON file.
 
 use crate::files::Index;
-use crate::query::query;
+use crate::query::{eval_score, query, MatchContext, Term};
 // TODO Implement database
 use crate::{files::IndexedFile, query::Query};
+use async_stream::stream;
 use async_trait::async_trait;
-use color_eyre::Result;
+use color_eyre::{eyre::eyre, Result};
+use std::path::Path;
+use futures_util::stream::BoxStream;
+use serde::Deserialize;
 use surrealdb::engines::any::{connect, Any};
-use surrealdb::sql;
+use surrealdb::opt::auth::Root;
 use surrealdb::Surreal;
+use tracing::debug;
+
+/// Where `SurrealBackend::new` keeps its database
+const DEFAULT_DB_URL: &str = "file://datchani.db";
+
+/// Number of records fetched per round trip when streaming query results
+const QUERY_PAGE_SIZE: usize = 100;
+
+/// Number of records fetched per round trip by `SurrealBackend::all_files`
+const ALL_FILES_PAGE_SIZE: usize = 500;
+
+/// Version of the `file` records written by this build, see `SurrealBackend::migrate_schema`
+pub const SCHEMA_VERSION: &str = "5";
+
+/// Version of a database without a `meta:schema` record, from before versions were stored
+pub const UNVERSIONED_SCHEMA: &str = "1";
+
+/// A single upgrade step between two schema versions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Migration {
+    pub from: &'static str,
+    pub to: &'static str,
+    /// Run inside the transaction that also records the new version
+    pub surrealql: &'static str,
+}
+
+/// Every known migration, in order.
+/// Fields added to `IndexedFile` get the value `#[serde(default)]` would give them
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            from: "1",
+            to: "2",
+            surrealql: "UPDATE file SET file_size = 0 WHERE file_size = NONE;",
+        },
+        Migration {
+            from: "2",
+            to: "3",
+            surrealql: "UPDATE file SET inode = 0 WHERE inode = NONE;",
+        },
+        Migration {
+            from: "3",
+            to: "4",
+            // 0o644, see `default_permissions`
+            surrealql: "UPDATE file SET permissions = 420 WHERE permissions = NONE;",
+        },
+        Migration {
+            from: "4",
+            to: "5",
+            surrealql: "UPDATE file SET content_hash = NULL WHERE content_hash = NONE;",
+        },
+    ]
+}
+
+/// What `SurrealBackend::migrate_schema` did, as `from -> to` for each migration
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub applied: Vec<String>,
+    /// Known migrations outside of the requested range
+    pub skipped: Vec<String>,
+}
+
+/// The migrations leading from `current` to `target`, in the order they have to run.
+/// Fails if there's no such chain, e.g. for a downgrade
+fn plan_migrations<'a>(
+    migrations: &'a [Migration],
+    current: &str,
+    target: &str,
+) -> Result<Vec<&'a Migration>> {
+    let mut plan = Vec::new();
+    let mut version = current;
+    while version != target {
+        let next = migrations
+            .iter()
+            .find(|m| m.from == version)
+            .ok_or_else(|| eyre!("No migration from schema {} to {}", version, target))?;
+        plan.push(next);
+        version = next.to;
+        if plan.len() > migrations.len() {
+            return Err(eyre!("The migrations from schema {} loop", current));
+        }
+    }
+    Ok(plan)
+}
+
+/// A single transaction running every migration in `plan`, then storing `target` in `meta`
+fn migration_statement(plan: &[&Migration]) -> String {
+    let mut sql = String::from("BEGIN TRANSACTION;\n");
+    for migration in plan {
+        sql.push_str(migration.surrealql);
+        sql.push('\n');
+    }
+    sql.push_str("UPDATE meta:schema SET version = $version;\nCOMMIT TRANSACTION;\n");
+    sql
+}
+
+/// The `meta:schema` record
+#[derive(Debug, Deserialize)]
+struct SchemaMeta {
+    version: String,
+}
 
 #[async_trait]
-pub trait IndexBackend {
+pub trait IndexBackend: Send {
     async fn push_file(&mut self, entry: IndexedFile) -> Result<IndexedFile>;
-    async fn query(&mut self, query: &Query) -> Result<()>;
+    async fn remove_file(&mut self, path: &Path) -> Result<()>;
+
+    /// Insert or replace many entries at once, returns the number of entries written.
+    /// The default implementation calls `push_file` for each entry,
+    /// backends that can write in bulk should override this.
+    /// This doesn't split `entries` up, callers should send reasonably sized batches,
+    /// see `IndexConfig::batch_size`
+    async fn batch_upsert(&mut self, entries: Vec<IndexedFile>) -> Result<usize> {
+        let count = entries.len();
+        for entry in entries {
+            self.push_file(entry).await?;
+        }
+        Ok(count)
+    }
+
+    /// Replace the stored entry for `entry.path`, e.g. after its tags changed.
+    /// The default implementation upserts with `push_file`
+    async fn update_file(&mut self, entry: IndexedFile) -> Result<IndexedFile> {
+        self.push_file(entry).await
+    }
+
+    async fn query(&mut self, query: &Query) -> Result<Vec<(i64, IndexedFile)>>;
+
+    /// Count the results of `query` without returning them, e.g. for `--count`.
+    /// The default implementation runs `query` and counts its results.
+    /// `SurrealBackend` overrides this to count in the database where it can
+    async fn query_count(&mut self, query: &Query) -> Result<usize> {
+        Ok(self.query(query).await?.len())
+    }
+
+    /// Stream scored results instead of collecting them.
+    /// The default implementation runs `query` and yields its results,
+    /// backends that can page through their records should override this
+    fn query_stream<'a>(
+        &'a mut self,
+        query: &'a Query,
+    ) -> BoxStream<'a, Result<(i64, IndexedFile)>> {
+        Box::pin(stream! {
+            match self.query(query).await {
+                Ok(results) => {
+                    for result in results {
+                        yield Ok(result);
+                    }
+                }
+                Err(e) => yield Err(e),
+            }
+        })
+    }
+
+    /// Stream every stored entry, unscored and in no particular order,
+    /// e.g. for `Index::rebuild_from_backend`.
+    /// There's no sensible generic way to do this, so every backend has to implement it
+    fn all_files(&mut self) -> BoxStream<'_, Result<IndexedFile>>;
+}
+
+/// Backend keeping everything in an in-memory `Index`, mostly useful for tests
+#[derive(Debug, Default)]
+pub struct InMemoryBackend {
+    pub index: Index,
+}
+
+#[async_trait]
+impl IndexBackend for InMemoryBackend {
+    async fn push_file(&mut self, entry: IndexedFile) -> Result<IndexedFile> {
+        match self.index.files.iter_mut().find(|f| f.path == entry.path) {
+            Some(file) => *file = entry.clone(),
+            None => self.index.files.push(entry.clone()),
+        }
+        Ok(entry)
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.index.files.retain(|f| f.path != path);
+        Ok(())
+    }
+
+    async fn query(&mut self, q: &Query) -> Result<Vec<(i64, IndexedFile)>> {
+        Ok(query(q, &self.index)?)
+    }
+
+    fn all_files(&mut self) -> BoxStream<'_, Result<IndexedFile>> {
+        Box::pin(futures_util::stream::iter(self.index.files.iter().cloned().map(Ok)))
+    }
 }
 
 pub struct SurrealBackend(Surreal<Any>);
 
+/// Connection settings for `SurrealBackend`.
+/// The engine is picked from the URL scheme, e.g. `file://`, `mem://` or `ws://`.
+/// `wss://` and `https://` URLs connect over TLS, verified against the system's root certificates
+#[derive(Debug, Clone)]
+pub struct SurrealBackendBuilder {
+    namespace: String,
+    database: String,
+    url: String,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl Default for SurrealBackendBuilder {
+    fn default() -> Self {
+        Self {
+            namespace: String::from("datchani"),
+            database: String::from("datchani"),
+            url: String::from(DEFAULT_DB_URL),
+            username: None,
+            password: None,
+        }
+    }
+}
+
+impl SurrealBackendBuilder {
+    /// The defaults, overridden by `DATCHANI_DB_URL`, `DATCHANI_DB_USER` and `DATCHANI_DB_PASS`
+    pub fn from_env() -> Self {
+        let mut builder = Self::default();
+        if let Ok(url) = std::env::var("DATCHANI_DB_URL") {
+            builder.url = url;
+        }
+        builder.username = std::env::var("DATCHANI_DB_USER").ok();
+        builder.password = std::env::var("DATCHANI_DB_PASS").ok();
+        builder
+    }
+
+    pub fn namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_string();
+        self
+    }
+
+    pub fn database(mut self, database: &str) -> Self {
+        self.database = database.to_string();
+        self
+    }
+
+    pub fn url(mut self, url: &str) -> Self {
+        self.url = url.to_string();
+        self
+    }
+
+    pub fn username(mut self, username: &str) -> Self {
+        self.username = Some(username.to_string());
+        self
+    }
+
+    pub fn password(mut self, password: &str) -> Self {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Whether the connection is encrypted, going by the URL scheme
+    pub fn is_tls(&self) -> bool {
+        self.url.starts_with("wss://") || self.url.starts_with("https://")
+    }
+
+    /// Connect, sign in if a username was given, and select the namespace and database
+    pub async fn build(self) -> Result<SurrealBackend> {
+        debug!(
+            "Connecting to {} ({})",
+            self.url,
+            if self.is_tls() { "TLS" } else { "no TLS" }
+        );
+        let db = connect(self.url.as_str()).await?;
+        if let Some(username) = &self.username {
+            db.signin(Root {
+                username,
+                password: self.password.as_deref().unwrap_or_default(),
+            })
+            .await?;
+        }
+        db.use_ns(self.namespace).use_db(self.database).await?;
+        Ok(SurrealBackend(db))
+    }
+}
+
 impl SurrealBackend {
     pub async fn new() -> Result<Self> {
-        let db = connect("file://owo.db").await?;
-        db.use_ns("datchani").use_db("datchani").await?;
-        Ok(Self(db))
+        Self::connect(DEFAULT_DB_URL).await
+    }
+
+    /// Connect to `url` without signing in
+    pub async fn connect(url: &str) -> Result<Self> {
+        SurrealBackendBuilder::default().url(url).build().await
+    }
+
+    /// Connect to `url` and sign in as a root user
+    pub async fn connect_with_auth(url: &str, user: &str, pass: &str) -> Result<Self> {
+        SurrealBackendBuilder::default()
+            .url(url)
+            .username(user)
+            .password(pass)
+            .build()
+            .await
+    }
+
+    /// The schema version stored in the `meta` table, see `migrate_schema`
+    pub async fn schema_version(&self) -> Result<String> {
+        let mut response = self.0.query("SELECT version FROM meta:schema").await?;
+        let meta: Option<SchemaMeta> = response.take(0)?;
+        Ok(meta.map_or_else(|| UNVERSIONED_SCHEMA.to_string(), |meta| meta.version))
+    }
+
+    /// Upgrade the `file` records from schema `current_version` to `target_version`,
+    /// e.g. `migrate_schema(&backend.schema_version().await?, SCHEMA_VERSION)`.
+    /// Everything runs in one transaction, so a failing migration leaves the database as it was
+    pub async fn migrate_schema(
+        &mut self,
+        current_version: &str,
+        target_version: &str,
+    ) -> Result<MigrationReport> {
+        let migrations = migrations();
+        let plan = plan_migrations(&migrations, current_version, target_version)?;
+        let name = |m: &Migration| format!("{} -> {}", m.from, m.to);
+        let report = MigrationReport {
+            applied: plan.iter().map(|m| name(m)).collect(),
+            skipped: migrations
+                .iter()
+                .filter(|m| !plan.contains(m))
+                .map(name)
+                .collect(),
+        };
+        if plan.is_empty() {
+            return Ok(report);
+        }
+
+        debug!("Migrating schema: {:?}", report.applied);
+        let mut response = self
+            .0
+            .query(migration_statement(&plan))
+            .bind(("version", target_version))
+            .await?;
+        // every statement fails when the transaction is rolled back, the first error is enough
+        for i in 0..=plan.len() {
+            let _: Vec<serde_json::Value> = response.take(i)?;
+        }
+        Ok(report)
+    }
+
+    /// Fetch a single page of up to `limit` file records, starting at `offset`.
+    /// Records are ordered by id, so pages neither overlap nor skip any records
+    async fn select_page(&self, offset: usize, limit: usize) -> Result<Vec<IndexedFile>> {
+        let mut response = self
+            .0
+            .query(format!(
+                "SELECT * FROM file ORDER BY id LIMIT {} START $offset",
+                limit
+            ))
+            .bind(("offset", offset))
+            .await?;
+        Ok(response.take(0)?)
+    }
+}
+
+/// The part of a query that can be evaluated by SurrealDB itself.
+/// Only extension, MIME type and tag terms are translated, anything else
+/// has to be matched in-process on the records the filter lets through
+#[derive(Debug, Default, PartialEq, Eq)]
+struct SurrealFilter {
+    conditions: Vec<String>,
+    /// Values bound to `$p0`, `$p1`, ... in the conditions
+    params: Vec<String>,
+    /// Whether the conditions match exactly the same records as the query.
+    /// If not, they match a superset of them
+    exact: bool,
+}
+
+impl SurrealFilter {
+    fn new(q: &Query) -> Self {
+        let mut filter = Self {
+            exact: true,
+            ..Default::default()
+        };
+        for term in &q.includes {
+            match filter.translate(term) {
+                Some((condition, value, exact)) => {
+                    filter.push(condition, value);
+                    filter.exact &= exact;
+                }
+                // a case modifier matches everything
+                None if matches!(term, Term::Case(_)) => {}
+                None => filter.exact = false,
+            }
+        }
+        for term in &q.excludes {
+            // negating a superset would drop records that should match
+            match filter.translate(term) {
+                Some((condition, value, true)) => filter.push(format!("!({})", condition), value),
+                _ => filter.exact = false,
+            }
+        }
+        filter
+    }
+
+    /// SurrealQL for a single term, the value to bind to it,
+    /// and whether it matches exactly like the term does
+    fn translate(&self, term: &Term) -> Option<(String, String, bool)> {
+        let param = format!("$p{}", self.params.len());
+        Some(match term {
+            // `.rs` is a hidden file without an extension, but it does end with `.rs`.
+            // The term compares NFC normalised extensions, which SurrealQL can't do,
+            // so only ASCII extensions are pushed down
+            Term::Extension(ext) if ext.is_ascii() => (
+                format!("string::endsWith(path, {})", param),
+                format!(".{}", ext),
+                false,
+            ),
+            Term::Mime(mime) => (format!("data_type = {}", param), mime.clone(), true),
+            Term::MimeGlob(pattern) => match pattern.split_once('*') {
+                Some((prefix, "")) => (
+                    format!("string::startsWith(data_type, {})", param),
+                    prefix.to_string(),
+                    true,
+                ),
+                _ => return None,
+            },
+            Term::Tag(tag) => (format!("tags CONTAINS {}", param), tag.clone(), true),
+            _ => return None,
+        })
+    }
+
+    fn push(&mut self, condition: String, value: String) {
+        self.conditions.push(condition);
+        self.params.push(value);
+    }
+
+    /// ` WHERE ...` to append to a `SELECT`, empty if there are no conditions
+    fn where_clause(&self) -> String {
+        if self.conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", self.conditions.join(" AND "))
+        }
     }
 }
 
+/// A row returned by `SELECT count() ... GROUP ALL`
+#[derive(Debug, Deserialize)]
+struct CountRow {
+    count: usize,
+}
+
+/// SurrealQL upserting a single record, the same as `push_file` does
+fn upsert_statement(i: usize) -> String {
+    format!("UPDATE type::thing('file', $path{i}) CONTENT $entry{i};", i = i)
+}
+
 #[async_trait]
 impl IndexBackend for SurrealBackend {
     async fn push_file(&mut self, entry: IndexedFile) -> Result<IndexedFile> {
@@ -40,21 +476,297 @@ impl IndexBackend for SurrealBackend {
         Ok(res)
     }
 
-    async fn query(&mut self, q: &Query) -> Result<()> {
-        let mut results = self.0.select("file").await?;
+    /// Upserts every entry in a single round trip,
+    /// sending one `UPDATE` statement per entry in the same query
+    async fn batch_upsert(&mut self, entries: Vec<IndexedFile>) -> Result<usize> {
+        if entries.is_empty() {
+            return Ok(0);
+        }
+
+        let sql = (0..entries.len()).map(upsert_statement).collect::<String>();
+        let mut query = self.0.query(sql);
+        for (i, entry) in entries.iter().enumerate() {
+            let path = entry
+                .path
+                .to_str()
+                .ok_or_else(|| eyre!("{} is not valid UTF-8", entry.path.display()))?;
+            query = query
+                .bind((format!("path{}", i), path))
+                .bind((format!("entry{}", i), entry));
+        }
 
-        let res: Vec<IndexedFile> = results;
+        let mut response = query.await?;
+        // every statement has its own result, which carries its error if it failed
+        for i in 0..entries.len() {
+            let _: Vec<IndexedFile> = response.take(i)?;
+        }
+        Ok(entries.len())
+    }
+
+    async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        let path = path
+            .to_str()
+            .ok_or_else(|| eyre!("{} is not valid UTF-8", path.display()))?;
+        let _: Option<IndexedFile> = self.0.delete(("file", path)).await?;
+        Ok(())
+    }
+
+    async fn query(&mut self, q: &Query) -> Result<Vec<(i64, IndexedFile)>> {
+        let res: Vec<IndexedFile> = self.0.select("file").await?;
 
         // println!("{:#?}", res);
         // ! TEMP
         let index = Index {
             files: res,
+            ..Index::new()
         };
 
-        let res = query(q, &index);
+        Ok(query(q, &index)?)
+    }
 
-        println!("{:#?}", res);
+    /// Counts with `SELECT count()` when every term can be expressed in SurrealQL.
+    /// Otherwise only the records passing the translatable terms are fetched,
+    /// and the rest of the query is matched in-process
+    async fn query_count(&mut self, q: &Query) -> Result<usize> {
+        let filter = SurrealFilter::new(q);
+        let sql = if filter.exact {
+            format!("SELECT count() FROM file{} GROUP ALL", filter.where_clause())
+        } else {
+            format!("SELECT * FROM file{}", filter.where_clause())
+        };
 
-        Ok(())
+        let mut request = self.0.query(sql);
+        for (i, value) in filter.params.iter().enumerate() {
+            request = request.bind((format!("p{}", i), value));
+        }
+        let mut response = request.await?;
+
+        if filter.exact {
+            // no matches means no group, rather than a count of 0
+            let row: Option<CountRow> = response.take(0)?;
+            return Ok(row.map_or(0, |row| row.count));
+        }
+        let index = Index {
+            files: response.take(0)?,
+            ..Index::new()
+        };
+        Ok(query(q, &index)?.len())
+    }
+
+    fn query_stream<'a>(&'a mut self, q: &'a Query) -> BoxStream<'a, Result<(i64, IndexedFile)>> {
+        Box::pin(stream! {
+            let ctx = MatchContext::default().with_query(q);
+            // `top:` has to see every page before it knows the best N
+            let limit = q.result_limit();
+            let mut best = Vec::new();
+            let mut offset = 0;
+            loop {
+                let page = match self.select_page(offset, QUERY_PAGE_SIZE).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        yield Err(e);
+                        return;
+                    }
+                };
+                let len = page.len();
+
+                for file in page {
+                    match eval_score(q, file, &ctx) {
+                        Ok(Some(result)) if limit.is_some() => best.push(result),
+                        Ok(Some(result)) => yield Ok(result),
+                        Ok(None) => {}
+                        Err(e) => {
+                            yield Err(e.into());
+                            return;
+                        }
+                    }
+                }
+
+                // a short page means we've reached the end of the table
+                if len < QUERY_PAGE_SIZE {
+                    break;
+                }
+                offset += len;
+            }
+            if let Some(limit) = limit {
+                Index::sort_by_score_desc(&mut best);
+                best.truncate(limit);
+                for result in best {
+                    yield Ok(result);
+                }
+            }
+        })
+    }
+
+    fn all_files(&mut self) -> BoxStream<'_, Result<IndexedFile>> {
+        Box::pin(stream! {
+            let mut offset = 0;
+            loop {
+                let page = match self.select_page(offset, ALL_FILES_PAGE_SIZE).await {
+                    Ok(page) => page,
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                };
+                let len = page.len();
+                for file in page {
+                    yield Ok(file);
+                }
+                if len < ALL_FILES_PAGE_SIZE {
+                    break;
+                }
+                offset += len;
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_query_count() -> Result<()> {
+    use std::path::PathBuf;
+
+    let mut backend = InMemoryBackend::default();
+    for (path, tags) in [("main.rs", vec!["owo"]), ("lib.rs", vec![]), ("README.md", vec!["owo"])] {
+        backend
+            .push_file(IndexedFile {
+                path: PathBuf::from(path),
+                tags: tags.into_iter().map(String::from).collect(),
+                ..Default::default()
+            })
+            .await?;
     }
+
+    let q = crate::query::parse_query("ext:rs")?;
+    assert_eq!(backend.query_count(&q).await?, 2);
+    let q = crate::query::parse_query("#owo -ext:md")?;
+    assert_eq!(backend.query_count(&q).await?, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rebuild_from_backend() -> Result<()> {
+    use std::path::PathBuf;
+
+    let mut backend = InMemoryBackend::default();
+    let files = (0..1000)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("{}.txt", i)),
+            ..Default::default()
+        })
+        .collect();
+    backend.batch_upsert(files).await?;
+
+    let index = Index::rebuild_from_backend(&mut backend).await?;
+    assert_eq!(index.files.len(), 1000);
+    assert_eq!(index.metadata().entry_count, 1000);
+    assert!(!index.has_duplicates());
+    Ok(())
+}
+
+#[test]
+fn test_surreal_filter() -> Result<()> {
+    let filter = SurrealFilter::new(&crate::query::parse_query("mime:text/plain #owo -#uwu")?);
+    assert!(filter.exact);
+    assert_eq!(
+        filter.where_clause(),
+        " WHERE data_type = $p0 AND tags CONTAINS $p1 AND !(tags CONTAINS $p2)"
+    );
+    assert_eq!(filter.params, ["text/plain", "owo", "uwu"]);
+
+    // extensions and fuzzy terms still need to be matched in-process
+    let filter = SurrealFilter::new(&crate::query::parse_query("ext:rs main -ext:md")?);
+    assert!(!filter.exact);
+    assert_eq!(filter.where_clause(), " WHERE string::endsWith(path, $p0)");
+    assert_eq!(filter.params, [".rs"]);
+    // `café` could be stored decomposed, so it's left to the term
+    let filter = SurrealFilter::new(&crate::query::parse_query("ext:café")?);
+    assert!(!filter.exact);
+    assert_eq!(filter.where_clause(), "");
+
+    let filter = SurrealFilter::new(&crate::query::parse_query("mime:image/* mime:*/pdf")?);
+    assert!(!filter.exact);
+    assert_eq!(filter.where_clause(), " WHERE string::startsWith(data_type, $p0)");
+    assert_eq!(filter.params, ["image/"]);
+
+    let empty = Query {
+        includes: Vec::new(),
+        excludes: Vec::new(),
+    };
+    assert_eq!(SurrealFilter::new(&empty).where_clause(), "");
+    Ok(())
+}
+
+#[test]
+fn test_plan_migrations() -> Result<()> {
+    let migrations = migrations();
+    let versions = |plan: Vec<&Migration>| plan.iter().map(|m| m.to).collect::<Vec<_>>();
+
+    assert_eq!(
+        versions(plan_migrations(&migrations, UNVERSIONED_SCHEMA, SCHEMA_VERSION)?),
+        ["2", "3", "4", "5"]
+    );
+    assert_eq!(versions(plan_migrations(&migrations, "3", "5")?), ["4", "5"]);
+    assert_eq!(versions(plan_migrations(&migrations, "2", "3")?), ["3"]);
+    assert!(plan_migrations(&migrations, SCHEMA_VERSION, SCHEMA_VERSION)?.is_empty());
+    // no downgrades
+    assert!(plan_migrations(&migrations, "5", "3").is_err());
+    assert!(plan_migrations(&migrations, "0", SCHEMA_VERSION).is_err());
+
+    let plan = plan_migrations(&migrations, "3", "5")?;
+    let sql = migration_statement(&plan);
+    assert!(sql.starts_with("BEGIN TRANSACTION;"));
+    assert!(sql.ends_with("COMMIT TRANSACTION;\n"));
+    assert!(sql.contains("SET permissions = 420"));
+    assert!(!sql.contains("file_size"));
+
+    Ok(())
+}
+
+#[test]
+fn test_backend_builder() {
+    let builder = SurrealBackendBuilder::default();
+    assert_eq!(builder.url, DEFAULT_DB_URL);
+    assert!(!builder.is_tls());
+    assert!(builder.username.is_none());
+
+    let builder = builder
+        .url("wss://db.example.com")
+        .namespace("owo")
+        .username("root")
+        .password("hunter2");
+    assert!(builder.is_tls());
+    assert_eq!(builder.namespace, "owo");
+    assert_eq!(builder.database, "datchani");
+    assert_eq!(builder.username.as_deref(), Some("root"));
+}
+
+// Needs a real database, run with `cargo test --release -- --ignored bench_batch_upsert`
+#[tokio::test]
+#[ignore]
+async fn bench_batch_upsert() -> Result<()> {
+    use std::path::PathBuf;
+    use std::time::Instant;
+
+    let entries = (0..10_000)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("/bench/file_{}.rs", i)),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let mut backend = SurrealBackend::new().await?;
+
+    let start = Instant::now();
+    for entry in entries.clone() {
+        backend.push_file(entry).await?;
+    }
+    println!("push_file: {:?} for {} files", start.elapsed(), entries.len());
+
+    let start = Instant::now();
+    for batch in entries.chunks(crate::indexer::DEFAULT_BATCH_SIZE) {
+        backend.batch_upsert(batch.to_vec()).await?;
+    }
+    println!("batch_upsert: {:?} for {} files", start.elapsed(), entries.len());
+
+    Ok(())
 }
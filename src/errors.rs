@@ -1,7 +1,101 @@
 // error types n stuff
 
-
+use std::{fmt, path::PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct IndexError;
 
+/// Errors that callers may want to tell apart,
+/// these are wrapped in a `color_eyre::Report` and can be recovered with `downcast_ref`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatchaniError {
+    /// A binary index snapshot was written in a different format version
+    IncompatibleVersion { found: u8, expected: u8 },
+    /// An index file was written by a different major version of datchani
+    IncompatibleIndexVersion { found: String, expected: String },
+    /// A file is larger than `IndexConfig::max_file_size`, so it wasn't indexed
+    FileTooLarge { path: PathBuf, size: u64 },
+    /// A `regex:` term isn't a valid regular expression
+    InvalidRegex { pattern: String, message: String },
+    /// A `before:` or `after:` term isn't a valid date
+    InvalidDate(String),
+    /// A file couldn't be indexed, e.g. a symlink pointing nowhere
+    Unreadable(String),
+}
+
+impl fmt::Display for DatchaniError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatchaniError::IncompatibleVersion { found, expected } => write!(
+                f,
+                "Incompatible index snapshot version {} (expected {})",
+                found, expected
+            ),
+            DatchaniError::IncompatibleIndexVersion { found, expected } => write!(
+                f,
+                "The index was created by datchani {}, which this version ({}) can't read. \
+                Run `datchani rebuild` to index the directory again",
+                found, expected
+            ),
+            DatchaniError::FileTooLarge { path, size } => write!(
+                f,
+                "{} is too large to index ({} bytes)",
+                path.display(),
+                size
+            ),
+            DatchaniError::InvalidRegex { pattern, message } => {
+                write!(f, "Invalid regex `{}`: {}", pattern, message)
+            }
+            DatchaniError::InvalidDate(date) => write!(
+                f,
+                "Invalid date `{}`, expected YYYY-MM-DD or an RFC 3339 timestamp",
+                date
+            ),
+            DatchaniError::Unreadable(message) => write!(f, "Can't index file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DatchaniError {}
+
+/// A term that can't be evaluated, found by `Query::validate` before any file is matched
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum QueryValidationError {
+    /// A `regex:` term isn't a valid regular expression
+    InvalidRegex { pattern: String, source: regex::Error },
+    /// A `before:` or `after:` term isn't a valid date
+    InvalidDate { raw: String },
+    /// A `size:` range ends below where it starts
+    InvalidSizeRange { lo: u64, hi: u64 },
+}
+
+impl fmt::Display for QueryValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryValidationError::InvalidRegex { pattern, source } => {
+                write!(f, "Invalid regex `{}`: {}", pattern, source)
+            }
+            QueryValidationError::InvalidDate { raw } => write!(
+                f,
+                "Invalid date `{}`, expected YYYY-MM-DD or an RFC 3339 timestamp",
+                raw
+            ),
+            QueryValidationError::InvalidSizeRange { lo, hi } => write!(
+                f,
+                "Invalid size range, {} bytes is larger than {} bytes",
+                lo, hi
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueryValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            QueryValidationError::InvalidRegex { source, .. } => Some(source),
+            QueryValidationError::InvalidDate { .. }
+            | QueryValidationError::InvalidSizeRange { .. } => None,
+        }
+    }
+}
@@ -1,93 +1,3401 @@
 use chrono::{DateTime, Utc};
-use color_eyre::{Report, Result};
+use color_eyre::{eyre::eyre, Report, Result};
 use file_type_enum::FileType;
+use futures_util::StreamExt;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use md5::Md5;
+use once_cell::sync::OnceCell;
+use rand::{rngs::SmallRng, seq::SliceRandom, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, path::PathBuf};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{sync::Mutex, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::db::IndexBackend;
+use crate::errors::DatchaniError;
+use crate::indexer::{self, IndexConfig};
+#[cfg(unix)]
+use crate::query::AccessMode;
+use crate::query::{self, MatchContext, MatchExplanation, Query, ScoringConfig};
+
+/// Bumped whenever the layout of `Index` changes in a way bincode can't read
+const BYTES_FORMAT_VERSION: u8 = 4;
+
+/// How long `Index::watch_and_update` collects changes before writing them
+const WATCH_BATCH_WINDOW: Duration = Duration::from_millis(250);
+
+/// `Index::to_toml` warns about indexes with more entries than this
+const TOML_WARN_ENTRIES: usize = 1000;
+
+/// CBOR's self-describe tag, written at the start of `save_cbor` files so `load_auto`
+/// can recognise them whatever they're called
+const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+
+/// The formats `Index::load_auto` can read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IndexFormat {
+    Json,
+    Cbor,
+    MessagePack,
+    Toml,
+}
+
+impl IndexFormat {
+    /// Guess the format from the extension of `path`, or failing that the first bytes
+    fn detect(path: &Path, bytes: &[u8]) -> Option<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => return Some(Self::Json),
+            Some("cbor") => return Some(Self::Cbor),
+            Some("msgpack") => return Some(Self::MessagePack),
+            Some("toml") => return Some(Self::Toml),
+            _ => {}
+        }
+        if bytes.starts_with(&CBOR_MAGIC) {
+            return Some(Self::Cbor);
+        }
+        // the index is always a map at the top level
+        match bytes.iter().find(|b| !b.is_ascii_whitespace())? {
+            b'{' => Some(Self::Json),
+            0x80..=0x8f | 0xde | 0xdf => Some(Self::MessagePack),
+            0xa0..=0xbf => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+}
 
 fn default_file_type() -> FileType {
     FileType::Regular
 }
 
-fn filetype_serializer<S>(file_type: &FileType, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: serde::Serializer,
-{
-    serializer.serialize_str(&file_type.to_string())
+/// Hashes from before `IndexedFile::content_hash_algorithm` existed are all SHA-256
+fn legacy_hash_algorithm() -> HashAlgorithm {
+    HashAlgorithm::Sha256
+}
+
+/// How `IndexedFile::content_hash` is computed
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    #[default]
+    Blake3,
+    /// Deprecated, MD5 is broken. Only for comparing against existing MD5 checksums
+    Md5,
+}
+
+impl HashAlgorithm {
+    /// The prefix of hashes computed with this algorithm, e.g. `blake3` in `blake3:af13...`
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Md5 => "md5",
+        }
+    }
+
+    /// Hex encoded hash of everything read from `reader`, without the prefix
+    fn hash_reader(&self, reader: &mut impl Read) -> Result<String> {
+        Ok(match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                std::io::copy(reader, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                std::io::copy(reader, &mut hasher)?;
+                hasher.finalize().to_hex().to_string()
+            }
+            HashAlgorithm::Md5 => {
+                let mut hasher = Md5::new();
+                std::io::copy(reader, &mut hasher)?;
+                format!("{:x}", hasher.finalize())
+            }
+        })
+    }
+}
+
+fn default_permissions() -> u32 {
+    0o644
+}
+
+fn default_hardlink_count() -> u32 {
+    1
+}
+
+fn filetype_serializer<S>(file_type: &FileType, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&file_type.to_string())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryResult {
+    pub file: IndexedFile,
+    pub score: i64,
+    /// How each term matched, only filled in for `datchani search --explain`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub explanation: Vec<MatchExplanation>,
+}
+
+/// A single page of ranked query results, see `Index::search_ranked`.
+/// Pages are numbered from 0
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueryResultSet {
+    /// The results on this page, best match first
+    pub results: Vec<QueryResult>,
+    /// Number of matches across all pages
+    pub total: usize,
+    pub page: usize,
+    pub per_page: usize,
+}
+
+impl QueryResultSet {
+    /// Whether there are more results after this page
+    pub fn has_next_page(&self) -> bool {
+        (self.page + 1).saturating_mul(self.per_page) < self.total
+    }
+
+    /// The page before this one, `None` on the first page
+    pub fn prev_page(&self) -> Option<usize> {
+        self.page.checked_sub(1)
+    }
+}
+
+fn filetype_deserializer<'de, D>(deserializer: D) -> Result<FileType, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.as_str().to_lowercase().as_str() {
+        "regular file" => Ok(FileType::Regular),
+        "directory" => Ok(FileType::Directory),
+        "symlink" => Ok(FileType::Symlink),
+        "block device" => Ok(FileType::BlockDevice),
+        "char device" => Ok(FileType::CharDevice),
+        "fifo" => Ok(FileType::Fifo),
+        "socket" => Ok(FileType::Socket),
+        _ => Err(serde::de::Error::custom("Invalid file type")),
+    }
+}
+
+/// One row of `Index::write_csv`. Every column but `path` may be missing when reading
+#[derive(Serialize, Deserialize)]
+struct CsvRecord {
+    path: String,
+    #[serde(
+        default = "default_file_type",
+        serialize_with = "filetype_serializer",
+        deserialize_with = "filetype_deserializer"
+    )]
+    file_type: FileType,
+    #[serde(default)]
+    data_type: Option<String>,
+    /// Joined with `|`, since tags may contain commas
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    last_modified: DateTime<Utc>,
+    #[serde(default = "Utc::now")]
+    last_indexed: DateTime<Utc>,
+    #[serde(default)]
+    file_size: u64,
+}
+
+/// Separator of the tags in a CSV `tags` column
+const CSV_TAG_SEPARATOR: char = '|';
+
+/// Start of the comment line holding the `IndexMetadata` as JSON, see `write_csv_with_metadata`
+const CSV_METADATA_PREFIX: &str = "# metadata: ";
+
+impl From<&IndexedFile> for CsvRecord {
+    fn from(file: &IndexedFile) -> Self {
+        Self {
+            path: file.path.to_string_lossy().into_owned(),
+            file_type: file.file_type,
+            data_type: file.data_type.clone(),
+            tags: file.tags.join(&CSV_TAG_SEPARATOR.to_string()),
+            last_modified: file.last_modified,
+            last_indexed: file.last_indexed,
+            file_size: file.file_size,
+        }
+    }
+}
+
+impl From<CsvRecord> for IndexedFile {
+    fn from(record: CsvRecord) -> Self {
+        Self {
+            path: PathBuf::from(record.path),
+            file_type: record.file_type,
+            data_type: record.data_type,
+            tags: record
+                .tags
+                .split(CSV_TAG_SEPARATOR)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect(),
+            last_modified: record.last_modified,
+            last_indexed: record.last_indexed,
+            file_size: record.file_size,
+            ..Default::default()
+        }
+    }
+}
+
+/// Version of datchani writing new indexes, see `IndexMetadata::version`
+const INDEX_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    /// Version of datchani that created the index, indexes from a different major version
+    /// can't be loaded
+    pub version: String,
+    pub created: DateTime<Utc>,
+    /// When the index was last saved
+    pub updated: DateTime<Utc>,
+    /// Number of entries as of the last save
+    pub entry_count: usize,
+    /// The directories that were indexed, see `Index::with_root`
+    pub root_paths: Vec<PathBuf>,
+}
+
+impl Default for IndexMetadata {
+    /// Metadata for an index created just now. Indexes from before metadata was
+    /// tracked get this too, since they're still readable
+    fn default() -> Self {
+        let now = Utc::now();
+        Self {
+            version: INDEX_VERSION.to_string(),
+            created: now,
+            updated: now,
+            entry_count: 0,
+            root_paths: Vec::new(),
+        }
+    }
+}
+
+/// Serialize an index with `entries` entries as TOML, see `Index::to_toml`
+fn to_toml_string(index: &impl Serialize, entries: usize) -> Result<String> {
+    if entries > TOML_WARN_ENTRIES {
+        warn!(
+            "Writing {} entries as TOML, consider JSON or CBOR for large indexes",
+            entries
+        );
+    }
+    // going through `Value` puts plain values before tables, which TOML requires
+    Ok(toml::to_string(&toml::Value::try_from(index)?)?)
+}
+
+/// The major component of a semver `version`, e.g. `1` for `1.2.3`
+fn major_version(version: &str) -> &str {
+    version.split('.').next().unwrap_or(version)
+}
+
+/// What changed during `Index::gc`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Entries whose path no longer exists
+    pub removed: usize,
+    /// Entries that weren't in the old index
+    pub added: usize,
+    /// Entries present in both the old and the new index
+    pub unchanged: usize,
+}
+
+/// How far an interrupted indexing run got, see `Index::checkpoint_save`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexCheckpoint {
+    /// The last path added to the index before the checkpoint was taken
+    pub last_path: PathBuf,
+    /// Number of entries indexed so far
+    pub count: usize,
+}
+
+/// On-disk layout of a checkpoint sidecar file
+#[derive(Serialize, Deserialize)]
+struct CheckpointFile<I> {
+    checkpoint: IndexCheckpoint,
+    index: I,
+}
+
+/// What `save` and the other save methods write: the index, with its metadata as of now
+#[derive(Serialize)]
+struct SavedIndex<'a> {
+    files: &'a [IndexedFile],
+    root: &'a Option<PathBuf>,
+    metadata: IndexMetadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Index {
+    pub files: Vec<IndexedFile>,
+    /// The directory this index was built from, if any.
+    /// Older indexes don't have this, so it defaults to `None`
+    #[serde(default)]
+    pub root: Option<PathBuf>,
+    #[serde(default)]
+    pub metadata: IndexMetadata,
+    /// Position of each path in `files`, so replacing an entry doesn't scan every entry.
+    /// `files` can be changed directly, so this is rebuilt whenever it's out of sync
+    #[cfg(feature = "fast-lookup")]
+    #[serde(skip)]
+    pub(crate) index_map: HashMap<PathBuf, usize>,
+    /// Positions in `files` of the entries with each tag, in order, see `search_by_tags`.
+    /// Kept up to date by `add_file` and evictions,
+    /// call `rebuild_tag_index` after changing tags directly
+    #[serde(skip)]
+    pub(crate) tag_index: BTreeMap<String, Vec<usize>>,
+    /// Length of `files` when `tag_index` was last updated,
+    /// so entries added or removed directly are noticed
+    #[serde(skip)]
+    pub(crate) tag_index_len: usize,
+    /// Positions in `files` of the entries with each extension, see `files_by_extension_fast`.
+    /// Built on first use, and dropped whenever entries are added through the index
+    #[serde(skip)]
+    pub(crate) extension_index: OnceCell<PositionIndex>,
+    /// Positions in `files` of the entries with each MIME type, see `files_by_mime_fast`
+    #[serde(skip)]
+    pub(crate) mime_index: OnceCell<PositionIndex>,
+}
+
+/// Positions in `Index::files` of the entries with each value of a key, like their extension
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PositionIndex {
+    positions: HashMap<String, Vec<usize>>,
+    /// Length of `files` when this was built, so entries added or removed directly are noticed
+    len: usize,
+}
+
+impl PositionIndex {
+    fn new(files: &[IndexedFile], key: fn(&IndexedFile) -> Option<&str>) -> Self {
+        let mut positions = HashMap::<String, Vec<usize>>::new();
+        for (i, file) in files.iter().enumerate() {
+            if let Some(value) = key(file) {
+                positions.entry(value.to_string()).or_default().push(i);
+            }
+        }
+        Self {
+            positions,
+            len: files.len(),
+        }
+    }
+}
+
+impl Default for Index {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Index {
+    pub fn new() -> Self {
+        Self {
+            files: Vec::new(),
+            root: None,
+            metadata: IndexMetadata::default(),
+            #[cfg(feature = "fast-lookup")]
+            index_map: HashMap::new(),
+            tag_index: BTreeMap::new(),
+            tag_index_len: 0,
+            extension_index: OnceCell::new(),
+            mime_index: OnceCell::new(),
+        }
+    }
+
+    /// Create an empty index for the files under `root`
+    pub fn with_root(root: PathBuf) -> Self {
+        let mut index = Self::new();
+        index.metadata.root_paths.push(root.clone());
+        index.root = Some(root);
+        index
+    }
+
+    pub fn metadata(&self) -> &IndexMetadata {
+        &self.metadata
+    }
+
+    /// The index as it's written by `save`, with `updated` and `entry_count` set for this save
+    fn to_saved(&self) -> SavedIndex<'_> {
+        SavedIndex {
+            files: &self.files,
+            root: &self.root,
+            metadata: IndexMetadata {
+                updated: Utc::now(),
+                entry_count: self.files.len(),
+                ..self.metadata.clone()
+            },
+        }
+    }
+
+    /// Fails with `DatchaniError::IncompatibleIndexVersion` if the index
+    /// was written by a different major version of datchani
+    fn check_version(&self) -> Result<()> {
+        if major_version(&self.metadata.version) != major_version(INDEX_VERSION) {
+            return Err(DatchaniError::IncompatibleIndexVersion {
+                found: self.metadata.version.clone(),
+                expected: INDEX_VERSION.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Iterate over the entries, in the order they were indexed
+    ///
+    /// ```ignore
+    /// for file in index.iter() {
+    ///     println!("{}", file.path.display());
+    /// }
+    /// ```
+    pub fn iter(&self) -> std::slice::Iter<'_, IndexedFile> {
+        self.files.iter()
+    }
+
+    /// The entry at position `idx`, or `None` if it's out of bounds
+    ///
+    /// ```ignore
+    /// let first = index.get(0);
+    /// ```
+    pub fn get(&self, idx: usize) -> Option<&IndexedFile> {
+        self.files.get(idx)
+    }
+
+    /// The entries on page `page` (counting from 0), without cloning them.
+    /// Pages past the end, or a `per_page` of 0, are empty
+    ///
+    /// ```ignore
+    /// let second = index.paginate(1, 50);
+    /// ```
+    pub fn paginate(&self, page: usize, per_page: usize) -> &[IndexedFile] {
+        let start = page.saturating_mul(per_page).min(self.files.len());
+        let end = start.saturating_add(per_page).min(self.files.len());
+        &self.files[start..end]
+    }
+
+    /// Number of pages of `per_page` entries, the last one may be shorter
+    pub fn page_count(&self, per_page: usize) -> usize {
+        match per_page {
+            0 => 0,
+            _ => self.files.len().div_ceil(per_page),
+        }
+    }
+
+    /// Every page of `per_page` entries in order, see `paginate`
+    pub fn iter_pages(&self, per_page: usize) -> impl Iterator<Item = &[IndexedFile]> {
+        // `chunks` panics on 0, which has no pages anyway
+        let files = if per_page == 0 { &[] } else { &self.files[..] };
+        files.chunks(per_page.max(1))
+    }
+
+    pub fn get_file(&self, path: PathBuf) -> Option<&IndexedFile> {
+        self.files.iter().find(|f| f.path == path)
+    }
+
+    /// All entries with the inode `ino`, i.e. hard links to the same file.
+    /// Inodes are only unique within a filesystem, so an index spanning
+    /// several filesystems may return unrelated files
+    pub fn find_by_inode(&self, ino: u64) -> Vec<&IndexedFile> {
+        self.files.iter().filter(|f| f.inode == ino).collect()
+    }
+
+    /// Groups of two or more entries sharing an inode, see `find_by_inode`.
+    /// Entries without an inode (`0`, on non-Unix platforms) are ignored
+    pub fn find_hardlinks(&self) -> Vec<Vec<&IndexedFile>> {
+        let mut groups = BTreeMap::<u64, Vec<&IndexedFile>>::new();
+        for file in self.files.iter().filter(|f| f.inode != 0) {
+            groups.entry(file.inode).or_default().push(file);
+        }
+        groups.into_values().filter(|group| group.len() > 1).collect()
+    }
+
+    /// The `k` most common extensions with their number of entries, most common first.
+    /// Entries without an extension aren't counted
+    pub fn top_k_by_extension(&self, k: usize) -> Vec<(String, usize)> {
+        top_k(
+            self.files
+                .iter()
+                .filter_map(|f| f.path.extension()?.to_str()),
+            k,
+        )
+    }
+
+    /// The `k` most common MIME types with their number of entries, most common first
+    pub fn top_k_by_mime(&self, k: usize) -> Vec<(String, usize)> {
+        top_k(self.files.iter().filter_map(|f| f.data_type.as_deref()), k)
+    }
+
+    /// The `k` most used tags with the number of entries tagged with them, most used first
+    pub fn top_k_by_tag(&self, k: usize) -> Vec<(String, usize)> {
+        top_k(
+            self.files
+                .iter()
+                .flat_map(|f| f.tags.iter().map(String::as_str)),
+            k,
+        )
+    }
+
+    /// Groups of two or more entries with the same contents, going by `content_hash`.
+    /// Hashes include their algorithm, so files hashed differently are never grouped.
+    /// Entries without a hash are ignored
+    pub fn find_duplicates(&self) -> Vec<Vec<&IndexedFile>> {
+        let mut groups = BTreeMap::<&str, Vec<&IndexedFile>>::new();
+        for file in &self.files {
+            if let Some(hash) = &file.content_hash {
+                groups.entry(hash).or_default().push(file);
+            }
+        }
+        groups
+            .into_values()
+            .filter(|group| group.len() > 1)
+            .collect()
+    }
+
+    /// Hash every entry that has a hash again with `algo`, e.g. to move an index over to
+    /// another algorithm. Entries whose file can't be read anymore lose their hash.
+    /// Returns the number of entries rehashed
+    pub fn rehash_all(&mut self, algo: HashAlgorithm) -> usize {
+        let mut rehashed = 0;
+        for file in self.files.iter_mut().filter(|f| f.content_hash.is_some()) {
+            match file.compute_hash_with_algo(algo) {
+                Ok(()) => rehashed += 1,
+                Err(e) => {
+                    debug!("Can't rehash {}: {}", file.path.display(), e);
+                    file.content_hash = None;
+                }
+            }
+        }
+        rehashed
+    }
+
+    /// Index the file at `path` with the default `IndexConfig`. If it's already indexed,
+    /// the existing entry is replaced, so racing walker threads can't leave duplicates behind
+    pub fn add_file(&mut self, path: PathBuf) -> Result<()> {
+        let file = IndexedFile::new_with_config(path, &IndexConfig::default())?;
+        self.insert_file(file);
+        Ok(())
+    }
+
+    /// Add `file`, or replace the entry with the same path if `file` was indexed later
+    fn insert_file(&mut self, file: IndexedFile) {
+        self.clear_position_indexes();
+        match self.position(&file.path) {
+            Some(i) => {
+                if file.last_indexed > self.files[i].last_indexed {
+                    self.update_tag_index(i, false);
+                    self.files[i] = file;
+                    self.update_tag_index(i, true);
+                }
+            }
+            None => {
+                #[cfg(feature = "fast-lookup")]
+                self.index_map.insert(file.path.clone(), self.files.len());
+                let in_sync = self.tag_index_in_sync();
+                self.files.push(file);
+                if in_sync {
+                    self.tag_index_len += 1;
+                    self.update_tag_index(self.files.len() - 1, true);
+                }
+            }
+        }
+    }
+
+    /// Entries with all of `tags` when `require_all` is set, otherwise with any of them.
+    /// All of no tags is every entry, any of them is none
+    pub fn search_by_tags(&self, tags: &[String], require_all: bool) -> Vec<&IndexedFile> {
+        let has_tags = |file: &IndexedFile| {
+            if require_all {
+                tags.iter().all(|tag| file.tags.contains(tag))
+            } else {
+                tags.iter().any(|tag| file.tags.contains(tag))
+            }
+        };
+        if !self.tag_index_in_sync() || (require_all && tags.is_empty()) {
+            return self.files.iter().filter(|f| has_tags(f)).collect();
+        }
+
+        let mut lists = tags
+            .iter()
+            .map(|tag| self.tag_index.get(tag).map_or(&[][..], Vec::as_slice))
+            .collect::<Vec<_>>();
+        let positions = if require_all {
+            // every match is in the shortest list
+            lists.sort_by_key(|list| list.len());
+            lists[0]
+                .iter()
+                .copied()
+                .filter(|i| lists[1..].iter().all(|list| list.binary_search(i).is_ok()))
+                .collect::<Vec<_>>()
+        } else {
+            let mut positions = lists.concat();
+            positions.sort_unstable();
+            positions.dedup();
+            positions
+        };
+        // tags changed directly since the index was built mustn't show up as matches
+        positions
+            .into_iter()
+            .filter_map(|i| self.files.get(i))
+            .filter(|f| has_tags(f))
+            .collect()
+    }
+
+    /// Entries with the extension `ext`, without the leading dot, found by checking every entry
+    pub fn files_by_extension(&self, ext: &str) -> Vec<&IndexedFile> {
+        self.files
+            .iter()
+            .filter(|f| extension_key(f) == Some(ext))
+            .collect()
+    }
+
+    /// Like `files_by_extension`, but only looks at the matching entries.
+    /// The extension index is built on the first call, see `build_extension_index`
+    pub fn files_by_extension_fast(&self, ext: &str) -> Vec<&IndexedFile> {
+        self.lookup_positions(&self.extension_index, extension_key, ext)
+    }
+
+    /// Build the index used by `files_by_extension_fast` now, instead of on its first call
+    pub fn build_extension_index(&mut self) {
+        self.extension_index = OnceCell::with_value(PositionIndex::new(&self.files, extension_key));
+    }
+
+    /// Entries with exactly the MIME type `mime`, found by checking every entry
+    pub fn files_by_mime(&self, mime: &str) -> Vec<&IndexedFile> {
+        self.files
+            .iter()
+            .filter(|f| mime_key(f) == Some(mime))
+            .collect()
+    }
+
+    /// Like `files_by_mime`, but only looks at the matching entries.
+    /// The MIME index is built on the first call, see `build_mime_index`
+    pub fn files_by_mime_fast(&self, mime: &str) -> Vec<&IndexedFile> {
+        self.lookup_positions(&self.mime_index, mime_key, mime)
+    }
+
+    /// Build the index used by `files_by_mime_fast` now, instead of on its first call
+    pub fn build_mime_index(&mut self) {
+        self.mime_index = OnceCell::with_value(PositionIndex::new(&self.files, mime_key));
+    }
+
+    /// Entries whose `key` is `value`, going by the position index in `cell`.
+    /// Falls back to checking every entry if `files` changed length since it was built
+    fn lookup_positions<'a>(
+        &'a self,
+        cell: &'a OnceCell<PositionIndex>,
+        key: fn(&IndexedFile) -> Option<&str>,
+        value: &str,
+    ) -> Vec<&'a IndexedFile> {
+        let index = cell.get_or_init(|| PositionIndex::new(&self.files, key));
+        if index.len != self.files.len() {
+            return self
+                .files
+                .iter()
+                .filter(|f| key(f) == Some(value))
+                .collect();
+        }
+        // entries replaced directly since the index was built mustn't show up as matches
+        index
+            .positions
+            .get(value)
+            .map_or(&[][..], Vec::as_slice)
+            .iter()
+            .filter_map(|&i| self.files.get(i))
+            .filter(|f| key(f) == Some(value))
+            .collect()
+    }
+
+    /// Drop the extension and MIME indexes, they're rebuilt on their next use
+    fn clear_position_indexes(&mut self) {
+        self.extension_index.take();
+        self.mime_index.take();
+    }
+
+    /// Rebuild the tag index used by `search_by_tags` from scratch
+    pub fn rebuild_tag_index(&mut self) {
+        self.tag_index.clear();
+        self.tag_index_len = self.files.len();
+        for i in 0..self.files.len() {
+            self.update_tag_index(i, true);
+        }
+    }
+
+    /// Build the tag index used by `search_by_tags` if it's out of sync, like
+    /// `build_extension_index`. Unlike the extension and MIME indexes it's kept up to date
+    /// by `add_file` and evictions, so this only matters after changing `files` directly
+    pub fn build_tag_index(&mut self) {
+        if !self.tag_index_in_sync() {
+            self.rebuild_tag_index();
+        }
+    }
+
+    fn tag_index_in_sync(&self) -> bool {
+        self.tag_index_len == self.files.len()
+    }
+
+    /// Add or remove the tags of the entry at position `i` in the tag index
+    fn update_tag_index(&mut self, i: usize, add: bool) {
+        if !self.tag_index_in_sync() {
+            return;
+        }
+        for tag in &self.files[i].tags {
+            let positions = self.tag_index.entry(tag.clone()).or_default();
+            match (positions.binary_search(&i), add) {
+                (Err(at), true) => positions.insert(at, i),
+                (Ok(at), false) => {
+                    positions.remove(at);
+                }
+                _ => {}
+            }
+            if positions.is_empty() {
+                self.tag_index.remove(tag);
+            }
+        }
+    }
+
+    /// Position of the entry for `path` in `files`
+    #[cfg(not(feature = "fast-lookup"))]
+    fn position(&mut self, path: &Path) -> Option<usize> {
+        self.files.iter().position(|f| f.path == path)
+    }
+
+    /// Position of the entry for `path` in `files`
+    #[cfg(feature = "fast-lookup")]
+    fn position(&mut self, path: &Path) -> Option<usize> {
+        // `files` may have been changed directly since the map was built.
+        // An entry replaced at the same length isn't noticed from the map alone,
+        // so a miss is checked against the entries themselves
+        let in_sync = self.index_map.len() == self.files.len()
+            && match self.index_map.get(path) {
+                Some(&i) => self.files.get(i).map_or(false, |f| f.path == path),
+                None => !self.files.iter().any(|f| f.path == path),
+            };
+        if !in_sync {
+            self.rebuild_index_map();
+        }
+        self.index_map.get(path).copied()
+    }
+
+    #[cfg(feature = "fast-lookup")]
+    fn rebuild_index_map(&mut self) {
+        self.index_map = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.path.clone(), i))
+            .collect();
+    }
+
+    /// Sort `files` by path, so entries in the same directory are next to each other.
+    /// Loading does this already, entries added after that are appended at the end
+    pub fn compact(&mut self) {
+        if self.is_compact() {
+            return;
+        }
+        self.files.sort_by(|a, b| a.path.cmp(&b.path));
+        self.rebuild_lookups();
+    }
+
+    /// Whether `files` is already sorted by path, see `compact`
+    pub fn is_compact(&self) -> bool {
+        self.files.windows(2).all(|w| w[0].path <= w[1].path)
+    }
+
+    /// An immutable copy of the index that can be shared between tasks,
+    /// e.g. to store in an `ArcSwap`, see the `daemon` module docs.
+    /// This is a full clone: about 1.3s and a second copy in memory for a million entries
+    /// (see `bench_snapshot`). That's fine for one-off copies, queries against the daemon
+    /// index get an `Arc` from the `ArcSwap` instead and never clone
+    pub fn snapshot(&self) -> Arc<Index> {
+        Arc::new(self.clone())
+    }
+
+    /// Remove entries whose path appears more than once, keeping the one with the
+    /// newer `last_indexed`. Returns the number of entries removed
+    pub fn deduplicate_by_path(&mut self) -> usize {
+        let before = self.files.len();
+        let files = std::mem::take(&mut self.files);
+        self.extend(files);
+        before - self.files.len()
+    }
+
+    /// Whether any path appears more than once, see `deduplicate_by_path`
+    pub fn has_duplicates(&self) -> bool {
+        let mut seen = HashSet::new();
+        !self.files.iter().all(|f| seen.insert(&f.path))
+    }
+
+    /// Remove all entries that were last indexed more than `age` ago,
+    /// returns the number of entries removed
+    pub fn evict_older_than(&mut self, age: std::time::Duration) -> usize {
+        // nothing can be older than an age chrono can't even represent
+        let age = match chrono::Duration::from_std(age) {
+            Ok(age) => age,
+            Err(_) => return 0,
+        };
+        let now = Utc::now();
+        let before = self.files.len();
+        self.files.retain(|f| now - f.last_indexed <= age);
+        self.rebuild_lookups();
+        before - self.files.len()
+    }
+
+    /// Remove all entries that were last modified on disk before `cutoff`,
+    /// returns the number of entries removed
+    pub fn evict_last_modified_before(&mut self, cutoff: DateTime<Utc>) -> usize {
+        let before = self.files.len();
+        self.files.retain(|f| f.last_modified >= cutoff);
+        self.rebuild_lookups();
+        before - self.files.len()
+    }
+
+    /// A new index with only the entries `predicate` returns `true` for,
+    /// keeping the root and metadata of this one
+    pub fn filter(&self, predicate: impl Fn(&IndexedFile) -> bool) -> Index {
+        let mut index = Index {
+            files: self
+                .files
+                .iter()
+                .filter(|f| predicate(f))
+                .cloned()
+                .collect(),
+            root: self.root.clone(),
+            metadata: self.metadata.clone(),
+            ..Index::new()
+        };
+        index.rebuild_lookups();
+        index
+    }
+
+    /// Like `filter`, but removes the other entries from this index instead
+    pub fn retain(&mut self, predicate: impl Fn(&IndexedFile) -> bool) {
+        self.files.retain(|f| predicate(f));
+        self.rebuild_lookups();
+    }
+
+    /// Only the entries with the extension `ext`, without the leading dot
+    pub fn filter_by_extension(&self, ext: &str) -> Index {
+        self.filter(|f| f.path.extension().map_or(false, |e| e == ext))
+    }
+
+    /// Only the entries whose MIME type is exactly `mime`
+    pub fn filter_by_mime(&self, mime: &str) -> Index {
+        self.filter(|f| f.data_type.as_deref() == Some(mime))
+    }
+
+    /// Only the entries tagged with `tag`
+    pub fn filter_by_tag(&self, tag: &str) -> Index {
+        self.filter(|f| f.tags.iter().any(|t| t == tag))
+    }
+
+    /// Rebuild the path and tag lookups after entries were moved or removed
+    fn rebuild_lookups(&mut self) {
+        #[cfg(feature = "fast-lookup")]
+        self.rebuild_index_map();
+        self.rebuild_tag_index();
+        self.clear_position_indexes();
+    }
+
+    /// Run `query` and return a single page of the results, best match first.
+    /// Every file is still scored, so `total` counts all matches
+    ///
+    /// ```ignore
+    /// let config = ScoringConfig::default();
+    /// let page = index.search_ranked(&query, 0, 20, &config)?;
+    /// println!("showing {} of {}", page.results.len(), page.total);
+    /// if page.has_next_page() {
+    ///     let next = index.search_ranked(&query, page.page + 1, 20, &config)?;
+    /// }
+    /// ```
+    pub fn search_ranked(
+        &self,
+        query: &Query,
+        page: usize,
+        per_page: usize,
+        config: &ScoringConfig,
+    ) -> std::result::Result<QueryResultSet, DatchaniError> {
+        let results = query::query_with_config(query, self, config)?;
+        let total = results.len();
+        let results = results
+            .into_iter()
+            .skip(page.saturating_mul(per_page))
+            .take(per_page)
+            .map(|(score, file)| QueryResult {
+                file,
+                score,
+                explanation: Vec::new(),
+            })
+            .collect();
+
+        Ok(QueryResultSet {
+            results,
+            total,
+            page,
+            per_page,
+        })
+    }
+
+    /// `n` random entries, in random order. The same `seed` always picks the same entries,
+    /// so a sample can be reproduced. Asking for more entries than there are returns all of them
+    pub fn sample(&self, n: usize, seed: u64) -> Vec<&IndexedFile> {
+        reservoir_sample(self.files.iter(), n, seed)
+    }
+
+    /// Like `sample`, but only from the entries matching `query`
+    pub fn sample_by_query(
+        &self,
+        query: &Query,
+        n: usize,
+        seed: u64,
+    ) -> std::result::Result<Vec<(i64, IndexedFile)>, DatchaniError> {
+        let ctx = MatchContext::from_index(self).with_query(query);
+        // matches are sampled as they're found, stopping at the first error
+        let mut error = None;
+        let matches = self
+            .files
+            .iter()
+            .map_while(|file| match query::eval_score(query, file.clone(), &ctx) {
+                Ok(result) => Some(result),
+                Err(e) => {
+                    error = Some(e);
+                    None
+                }
+            })
+            .flatten();
+        let sample = reservoir_sample(matches, n, seed);
+        match error {
+            Some(e) => Err(e),
+            None => Ok(sample),
+        }
+    }
+
+    /// Sort query results from the highest to the lowest score.
+    /// The sort is stable, so results with the same score keep their relative order
+    pub fn sort_by_score_desc(results: &mut [(i64, IndexedFile)]) {
+        results.sort_by(|a, b| b.0.cmp(&a.0));
+    }
+
+    /// Sort query results from the lowest to the highest score.
+    /// The sort is stable, so results with the same score keep their relative order
+    pub fn sort_by_score_asc(results: &mut [(i64, IndexedFile)]) {
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    /// Merge two indexes into one with a single entry per path.
+    /// When both have an entry for the same path, the one indexed last wins.
+    /// The entries of the result are sorted by path, and its root is only kept
+    /// if both indexes share the same root
+    pub fn union(self, other: Index) -> Index {
+        let root = if self.root == other.root {
+            self.root
+        } else {
+            None
+        };
+        let mut files = BTreeMap::new();
+        for file in self.files.into_iter().chain(other.files) {
+            Self::insert_newer(&mut files, file);
+        }
+        Index {
+            files: files.into_values().collect(),
+            root,
+            ..Index::new()
+        }
+    }
+
+    /// Only the paths indexed in both `a` and `b`, preferring the entry indexed last.
+    /// Like `union`, the entries are sorted by path and the root is only kept if both share it
+    pub fn intersection(a: &Index, b: &Index) -> Index {
+        let theirs = b
+            .files
+            .iter()
+            .map(|f| (f.path.as_path(), f))
+            .collect::<HashMap<_, _>>();
+
+        let mut files = BTreeMap::new();
+        for file in &a.files {
+            if let Some(other) = theirs.get(file.path.as_path()) {
+                Self::insert_newer(&mut files, file.clone());
+                Self::insert_newer(&mut files, (*other).clone());
+            }
+        }
+        Index {
+            files: files.into_values().collect(),
+            root: if a.root == b.root {
+                a.root.clone()
+            } else {
+                None
+            },
+            ..Index::new()
+        }
+    }
+
+    /// Insert `file` unless there's already an entry for its path that was indexed later
+    fn insert_newer(files: &mut BTreeMap<PathBuf, IndexedFile>, file: IndexedFile) {
+        match files.get(&file.path) {
+            Some(existing) if existing.last_indexed >= file.last_indexed => {}
+            _ => {
+                files.insert(file.path.clone(), file);
+            }
+        }
+    }
+
+    /// Build a brand new index by re-walking `root`, carrying over tags and
+    /// content hashes from this index for paths that still exist.
+    /// The old index is left untouched, so the caller can swap the two
+    /// once the new one is complete, see `indexer::reindex_swap`
+    pub fn gc(&self, root: &Path, config: &IndexConfig) -> Result<(Index, GcStats)> {
+        let old = self
+            .files
+            .iter()
+            .map(|f| (f.path.as_path(), f))
+            .collect::<HashMap<_, _>>();
+
+        let mut stats = GcStats::default();
+        // hashes of unchanged files are reused, so only hash after comparing
+        let walk_config = IndexConfig {
+            compute_hash: false,
+            ..config.clone()
+        };
+        let mut files = indexer::walk(root, &walk_config);
+        for file in files.iter_mut() {
+            match old.get(file.path.as_path()) {
+                Some(previous) => {
+                    stats.unchanged += 1;
+                    file.tags = previous.tags.clone();
+                    // the hash is only still valid if the file hasn't been touched since
+                    if previous.last_modified == file.last_modified
+                        && previous.content_hash_algorithm == config.hash_algorithm
+                    {
+                        file.content_hash = previous.content_hash.clone();
+                        file.content_hash_algorithm = previous.content_hash_algorithm;
+                    }
+                }
+                None => stats.added += 1,
+            }
+            if config.compute_hash && file.content_hash.is_none() {
+                file.compute_hash_with_algo(config.hash_algorithm)?;
+            }
+        }
+        stats.removed = self.files.len() - stats.unchanged;
+
+        let index = Index {
+            files,
+            root: Some(root.to_path_buf()),
+            metadata: IndexMetadata {
+                root_paths: vec![root.to_path_buf()],
+                ..self.metadata.clone()
+            },
+            ..Index::new()
+        };
+        Ok((index, stats))
+    }
+
+    /// Watch `root` and write every change to `backend` until `cancel` is cancelled.
+    /// Changes are collected for `WATCH_BATCH_WINDOW` and then written together,
+    /// so a burst of writes to the same file is only indexed once.
+    /// Failing writes are logged and skipped, only failing to watch `root` ends the task
+    pub fn watch_and_update(
+        root: &Path,
+        backend: Arc<Mutex<dyn IndexBackend + Send>>,
+        cancel: CancellationToken,
+    ) -> JoinHandle<Result<()>> {
+        // watching starts before returning, so changes made right after the call aren't missed
+        let watched = indexer::watch_paths(root);
+
+        tokio::spawn(async move {
+            let (_watcher, mut rx) = watched?;
+            loop {
+                let first = tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => return Ok(()),
+                    path = rx.recv() => match path {
+                        Some(path) => path,
+                        None => return Ok(()),
+                    },
+                };
+                let cancelled = tokio::select! {
+                    _ = cancel.cancelled() => true,
+                    _ = tokio::time::sleep(WATCH_BATCH_WINDOW) => false,
+                };
+                let mut paths = BTreeSet::from([first]);
+                while let Ok(path) = rx.try_recv() {
+                    paths.insert(path);
+                }
+                Self::commit_changes(&backend, paths).await;
+                if cancelled {
+                    return Ok(());
+                }
+            }
+        })
+    }
+
+    /// Index or remove every path in `paths`, see `watch_and_update`
+    async fn commit_changes(backend: &Mutex<dyn IndexBackend + Send>, paths: BTreeSet<PathBuf>) {
+        let mut upserts = Vec::new();
+        let mut removals = Vec::new();
+        for path in paths {
+            if path.symlink_metadata().is_err() {
+                removals.push(path);
+                continue;
+            }
+            // `new` hashes and reads xattrs, which is too slow for every change
+            match IndexedFile::new_with_config(path.clone(), &IndexConfig::default()) {
+                Ok(file) => upserts.push(file),
+                Err(e) => warn!("Failed to index {}: {}", path.display(), e),
+            }
+        }
+
+        let mut backend = backend.lock().await;
+        if !upserts.is_empty() {
+            if let Err(e) = backend.batch_upsert(upserts).await {
+                warn!("Failed to write changes to the backend: {}", e);
+            }
+        }
+        for path in removals {
+            if let Err(e) = backend.remove_file(&path).await {
+                warn!(
+                    "Failed to remove {} from the backend: {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Build a fresh index from every entry stored in `backend`.
+    /// The backend is the authoritative copy when it and the JSON index disagree
+    pub async fn rebuild_from_backend(backend: &mut dyn IndexBackend) -> Result<Index> {
+        let mut index = Index::new();
+        let mut files = backend.all_files();
+        while let Some(file) = files.next().await {
+            index.files.push(file?);
+        }
+        index.metadata.entry_count = index.files.len();
+        Ok(index)
+    }
+
+    /// Save to JSON file, recording the save in the written metadata
+    /// Note: this shouldn't be used
+    pub fn save(&self, path: PathBuf) -> Result<()> {
+        let file = File::create(path)?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &self.to_saved())?;
+        Ok(())
+    }
+
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let file = File::open(path)?;
+        Self::from_json_reader(BufReader::new(file))
+    }
+
+    /// Shared by every loader once the index is deserialized: checks the version,
+    /// sorts the entries with `compact` and builds the tag index used by `search_by_tags`
+    fn finish_load(mut self) -> Result<Self> {
+        self.check_version()?;
+        self.compact();
+        self.build_tag_index();
+        Ok(self)
+    }
+
+    /// Load the index at `path`, or an empty one if there's no file there yet.
+    /// A file that exists but can't be parsed is still an error
+    pub fn load_or_default(path: &Path) -> Result<Self> {
+        Self::load_or_else(path, Self::new)
+    }
+
+    /// Load the index at `path`, or build one with `f` if there's no file there yet
+    pub fn load_or_else(path: &Path, f: impl FnOnce() -> Self) -> Result<Self> {
+        match File::open(path) {
+            Ok(file) => Self::from_json_reader(BufReader::new(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(f()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save to `path` only if this index differs from `previous`,
+    /// returns whether anything was written.
+    /// `updated` and `entry_count` are set on every save, so they aren't compared
+    pub fn save_if_changed(&self, path: &Path, previous: &Self) -> Result<bool> {
+        let metadata_changed = self.metadata.version != previous.metadata.version
+            || self.metadata.created != previous.metadata.created
+            || self.metadata.root_paths != previous.metadata.root_paths;
+        if self.files == previous.files && self.root == previous.root && !metadata_changed {
+            return Ok(false);
+        }
+        self.save(path.to_path_buf())?;
+        Ok(true)
+    }
+
+    /// Path of the checkpoint sidecar file for the index at `path`,
+    /// e.g. `index.json.checkpoint` for `index.json`
+    pub fn checkpoint_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".checkpoint");
+        PathBuf::from(name)
+    }
+
+    /// Save the index so far, along with how many entries were indexed, to the
+    /// checkpoint sidecar of the index at `path`. An interrupted indexing run
+    /// can then pick up where it left off with `checkpoint_load`.
+    /// The sidecar is written to a temporary file first, so a crash while
+    /// saving leaves the previous checkpoint intact
+    pub fn checkpoint_save(&self, path: &Path, position: usize) -> Result<()> {
+        let checkpoint = IndexCheckpoint {
+            last_path: self.files.last().map(|f| f.path.clone()).unwrap_or_default(),
+            count: position,
+        };
+        let sidecar = Self::checkpoint_path(path);
+        let mut tmp = sidecar.clone().into_os_string();
+        tmp.push(".tmp");
+
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(
+            &mut writer,
+            &CheckpointFile {
+                checkpoint,
+                index: self,
+            },
+        )?;
+        writer.flush()?;
+        std::fs::rename(tmp, sidecar)?;
+        Ok(())
+    }
+
+    /// Load the checkpoint sidecar of the index at `path`, as written by `checkpoint_save`
+    pub fn checkpoint_load(path: &Path) -> Result<(Index, IndexCheckpoint)> {
+        let file = File::open(Self::checkpoint_path(path))?;
+        let CheckpointFile { checkpoint, index } = serde_json::from_reader(BufReader::new(file))?;
+        Ok((index, checkpoint))
+    }
+
+    /// Delete the checkpoint sidecar of the index at `path`, if there is one
+    pub fn checkpoint_clear(path: &Path) -> Result<()> {
+        match std::fs::remove_file(Self::checkpoint_path(path)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Async version of `save`, for use inside the tokio runtime.
+    /// The index is written to a temporary file next to `path` first and then renamed over it,
+    /// so readers never see a half written index
+    pub async fn save_async(&self, path: &Path) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let json = serde_json::to_vec_pretty(&self.to_saved())?;
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let mut writer = tokio::io::BufWriter::new(tokio::fs::File::create(&tmp).await?);
+        writer.write_all(&json).await?;
+        writer.flush().await?;
+        tokio::fs::rename(tmp, path).await?;
+        Ok(())
+    }
+
+    /// Async version of `load`, for use inside the tokio runtime
+    pub async fn load_async(path: &Path) -> Result<Self> {
+        let json = tokio::fs::read(path).await?;
+        let index: Self = serde_json::from_slice(&json)?;
+        index.finish_load()
+    }
+
+    /// Serialize the whole index as a single pretty-printed JSON document,
+    /// the same format used by `save` and `load`
+    pub fn to_json_writer(&self, writer: impl Write) -> Result<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Deserialize an index from a single JSON document, as written by `to_json_writer`.
+    /// Fails with `DatchaniError::IncompatibleIndexVersion` if it's from another major version
+    pub fn from_json_reader(reader: impl BufRead) -> Result<Self> {
+        let index: Self = serde_json::from_reader(reader)?;
+        index.finish_load()
+    }
+
+    /// Serialize the index to a compact binary snapshot using bincode,
+    /// e.g. for caching it in another process.
+    /// The first byte is the snapshot format version, see `deserialize_from_bytes`
+    pub fn serialize_to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = vec![BYTES_FORMAT_VERSION];
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Deserialize a snapshot written by `serialize_to_bytes`.
+    /// Fails with `DatchaniError::IncompatibleVersion` if the snapshot
+    /// was written in a different format version
+    pub fn deserialize_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let (&version, bytes) = bytes
+            .split_first()
+            .ok_or_else(|| eyre!("Index snapshot is empty"))?;
+        if version != BYTES_FORMAT_VERSION {
+            return Err(DatchaniError::IncompatibleVersion {
+                found: version,
+                expected: BYTES_FORMAT_VERSION,
+            }
+            .into());
+        }
+        let index: Self = bincode::deserialize(bytes)?;
+        index.finish_load()
+    }
+
+    /// Save to a CBOR file, updating the metadata.
+    /// Much smaller and faster to read than JSON for large indexes, see `load_cbor`
+    pub fn save_cbor(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&CBOR_MAGIC)?;
+        ciborium::ser::into_writer(&self.to_saved(), &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load a CBOR file written by `save_cbor`
+    pub fn load_cbor(path: &Path) -> Result<Self> {
+        Self::from_cbor_slice(&std::fs::read(path)?)
+    }
+
+    fn from_cbor_slice(bytes: &[u8]) -> Result<Self> {
+        let bytes = bytes.strip_prefix(&CBOR_MAGIC).unwrap_or(bytes);
+        let index: Self = ciborium::de::from_reader(bytes)?;
+        index.finish_load()
+    }
+
+    /// Save to a MessagePack file, updating the metadata, see `load_msgpack`
+    pub fn save_msgpack(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        // with field names, so fields added later can still fall back to their defaults
+        rmp_serde::encode::write_named(&mut writer, &self.to_saved())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Load a MessagePack file written by `save_msgpack`
+    pub fn load_msgpack(path: &Path) -> Result<Self> {
+        Self::from_msgpack_slice(&std::fs::read(path)?)
+    }
+
+    fn from_msgpack_slice(bytes: &[u8]) -> Result<Self> {
+        let index: Self = rmp_serde::from_slice(bytes)?;
+        index.finish_load()
+    }
+
+    /// The index as TOML, which is easy to edit by hand but slow to read for large indexes.
+    /// Paths and dates are written as strings. TOML integers are signed,
+    /// so this fails for sizes, inodes or devices above `i64::MAX`
+    pub fn to_toml(&self) -> Result<String> {
+        to_toml_string(self, self.files.len())
+    }
+
+    /// Read an index written by `to_toml`
+    pub fn from_toml(s: &str) -> Result<Self> {
+        let index: Self = toml::from_str(s)?;
+        index.finish_load()
+    }
+
+    /// Save to a TOML file, updating the metadata, see `to_toml`
+    pub fn save_toml(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, to_toml_string(&self.to_saved(), self.files.len())?)?;
+        Ok(())
+    }
+
+    /// Load a TOML file written by `save_toml`
+    pub fn load_toml(path: &Path) -> Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(path)?)
+    }
+
+    /// Load an index saved as JSON, CBOR, MessagePack or TOML.
+    /// The format is taken from the extension (`.json`, `.cbor`, `.msgpack` or `.toml`),
+    /// or detected from the start of the file for anything but TOML
+    pub fn load_auto(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        match IndexFormat::detect(path, &bytes) {
+            Some(IndexFormat::Json) => Self::from_json_reader(bytes.as_slice()),
+            Some(IndexFormat::Cbor) => Self::from_cbor_slice(&bytes),
+            Some(IndexFormat::MessagePack) => Self::from_msgpack_slice(&bytes),
+            Some(IndexFormat::Toml) => Self::from_toml(std::str::from_utf8(&bytes)?),
+            None => Err(eyre!("Unknown index format: {}", path.display())),
+        }
+    }
+
+    /// Export the entries as CSV, e.g. to open in a spreadsheet. The columns are
+    /// `path,file_type,data_type,tags,last_modified,last_indexed,file_size`,
+    /// with the tags joined by `|`. Paths that aren't valid UTF-8 are written lossily
+    pub fn write_csv(&self, writer: &mut dyn Write) -> Result<()> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(true)
+            .from_writer(writer);
+        for file in &self.files {
+            writer.serialize(CsvRecord::from(file))?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Like `write_csv`, but preceded by a `# metadata: {...}` comment line with the
+    /// metadata as JSON, so `read_csv` can restore it. Spreadsheets show it as an extra row
+    pub fn write_csv_with_metadata(&self, writer: &mut dyn Write) -> Result<()> {
+        writeln!(
+            writer,
+            "{}{}",
+            CSV_METADATA_PREFIX,
+            serde_json::to_string(&self.metadata)?
+        )?;
+        self.write_csv(writer)
+    }
+
+    /// Read entries exported by `write_csv`. Only the `path` column is required,
+    /// any other missing column leaves the field at its default.
+    /// Leading `#` comment lines are skipped, except for the metadata written by
+    /// `write_csv_with_metadata`
+    pub fn read_csv(reader: impl Read) -> Result<Self> {
+        let mut reader = BufReader::new(reader);
+        let mut index = Self::new();
+        while reader.fill_buf()?.first() == Some(&b'#') {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            if let Some(json) = line.strip_prefix(CSV_METADATA_PREFIX) {
+                index.metadata = serde_json::from_str(json)?;
+            }
+        }
+        for record in csv::Reader::from_reader(reader).deserialize::<CsvRecord>() {
+            index.files.push(record?.into());
+        }
+        index.finish_load()
+    }
+
+    /// Save to a newline-delimited JSON (ndjson) file
+    /// Each line is a single `IndexedFile` object, with no surrounding index document,
+    /// so the file can be read back one entry at a time with `load_streaming`
+    pub fn save_ndjson(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        for file in &self.files {
+            serde_json::to_writer(&mut writer, file)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Lazily read entries from a newline-delimited JSON (ndjson) file written by `save_ndjson`
+    /// Only one entry is held in memory at a time, which makes this suitable for very large indexes
+    pub fn load_streaming(path: &Path) -> Result<impl Iterator<Item = Result<IndexedFile>>> {
+        let file = File::open(path)?;
+        let entries = serde_json::Deserializer::from_reader(BufReader::new(file))
+            .into_iter::<IndexedFile>()
+            .map(|entry| entry.map_err(Report::from));
+        Ok(entries)
+    }
+}
+
+/// Borrowing iteration over the entries
+///
+/// ```ignore
+/// for file in &index {
+///     println!("{}", file.path.display());
+/// }
+/// ```
+impl<'a> IntoIterator for &'a Index {
+    type Item = &'a IndexedFile;
+    type IntoIter = std::slice::Iter<'a, IndexedFile>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.iter()
+    }
+}
+
+/// Consuming iteration over the entries
+///
+/// ```ignore
+/// let paths = index.into_iter().map(|file| file.path).collect::<Vec<_>>();
+/// ```
+impl IntoIterator for Index {
+    type Item = IndexedFile;
+    type IntoIter = std::vec::IntoIter<IndexedFile>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.files.into_iter()
+    }
+}
+
+/// Build an index from entries, without a root.
+/// Entries are added with `Extend`, so duplicate paths are collapsed the same way
+///
+/// ```ignore
+/// let index: Index = paths
+///     .iter()
+///     .filter_map(|p| IndexedFile::new(p.clone()).ok())
+///     .collect();
+/// ```
+impl FromIterator<IndexedFile> for Index {
+    fn from_iter<I: IntoIterator<Item = IndexedFile>>(iter: I) -> Self {
+        let mut index = Index::new();
+        index.extend(iter);
+        index
+    }
+}
+
+/// Add entries to the index, keeping a single entry per path.
+/// When a path is already indexed the entry indexed last wins, like `Index::union`
+///
+/// ```ignore
+/// index.extend(indexer::walk(&other_dir, &IndexConfig::default()));
+/// ```
+impl Extend<IndexedFile> for Index {
+    fn extend<I: IntoIterator<Item = IndexedFile>>(&mut self, iter: I) {
+        self.clear_position_indexes();
+        let mut positions = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.path.clone(), i))
+            .collect::<HashMap<_, _>>();
+
+        for file in iter {
+            match positions.get(&file.path) {
+                Some(&i) => {
+                    if file.last_indexed > self.files[i].last_indexed {
+                        self.files[i] = file;
+                    }
+                }
+                None => {
+                    positions.insert(file.path.clone(), self.files.len());
+                    self.files.push(file);
+                }
+            }
+        }
+    }
+}
+
+/// Positional access to the entries, panics if `idx` is out of bounds.
+/// Use `Index::get` for a non-panicking version
+///
+/// ```ignore
+/// let first = &index[0];
+/// ```
+impl std::ops::Index<usize> for Index {
+    type Output = IndexedFile;
+
+    fn index(&self, idx: usize) -> &Self::Output {
+        &self.files[idx]
+    }
+}
+
+#[test]
+fn test_iter() {
+    let index = Index {
+        files: ["a", "b", "c"]
+            .iter()
+            .map(|name| IndexedFile {
+                path: PathBuf::from(name),
+                ..Default::default()
+            })
+            .collect(),
+        ..Index::new()
+    };
+
+    let mut borrowed = Vec::new();
+    for file in &index {
+        borrowed.push(file.path.clone());
+    }
+    assert_eq!(borrowed, index.iter().map(|f| f.path.clone()).collect::<Vec<_>>());
+
+    assert_eq!(index[1].path, PathBuf::from("b"));
+    assert_eq!(index.get(2).map(|f| f.path.clone()), Some(PathBuf::from("c")));
+    assert!(index.get(3).is_none());
+
+    let owned = index.into_iter().map(|f| f.path).collect::<Vec<_>>();
+    assert_eq!(owned, borrowed);
+}
+
+#[test]
+fn test_paginate() {
+    let index = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|name| IndexedFile {
+            path: PathBuf::from(name),
+            ..Default::default()
+        })
+        .collect::<Index>();
+    let paths = |files: &[IndexedFile]| {
+        files
+            .iter()
+            .map(|f| f.path.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(paths(index.paginate(0, 2)), ["a", "b"]);
+    assert_eq!(paths(index.paginate(2, 2)), ["e"]);
+    assert!(index.paginate(3, 2).is_empty());
+    assert!(index.paginate(usize::MAX, 2).is_empty());
+    assert_eq!(paths(index.paginate(0, usize::MAX)).len(), 5);
+    assert!(index.paginate(0, 0).is_empty());
+
+    assert_eq!(index.page_count(2), 3);
+    assert_eq!(index.page_count(5), 1);
+    assert_eq!(index.page_count(6), 1);
+    assert_eq!(index.page_count(0), 0);
+    assert_eq!(Index::new().page_count(2), 0);
+
+    let pages = index.iter_pages(2).map(paths).collect::<Vec<_>>();
+    assert_eq!(pages, [vec!["a", "b"], vec!["c", "d"], vec!["e"]]);
+    assert_eq!(pages.len(), index.page_count(2));
+    for (page, files) in index.iter_pages(2).enumerate() {
+        assert_eq!(files, index.paginate(page, 2));
+    }
+    assert_eq!(index.iter_pages(0).count(), 0);
+    assert_eq!(Index::new().iter_pages(2).count(), 0);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hardlinks() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let original = dir.path().join("original");
+    let link = dir.path().join("link");
+    let other = dir.path().join("other");
+    File::create(&original)?;
+    std::fs::hard_link(&original, &link)?;
+    File::create(&other)?;
+
+    let mut index = Index::new();
+    for path in [&original, &link, &other] {
+        index.add_file(path.clone())?;
+    }
+
+    let ino = index.get_file(original.clone()).unwrap().inode;
+    let mut linked = index
+        .find_by_inode(ino)
+        .iter()
+        .map(|f| f.path.clone())
+        .collect::<Vec<_>>();
+    linked.sort();
+    assert_eq!(linked, vec![link.clone(), original.clone()]);
+
+    let groups = index.find_hardlinks();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].len(), 2);
+    assert!(groups[0].iter().all(|f| f.path != other));
+
+    Ok(())
+}
+
+#[test]
+fn test_add_file_existing() -> Result<()> {
+    use chrono::TimeZone;
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("owo");
+    std::fs::write(&path, "")?;
+    std::fs::write(dir.path().join("uwu"), "")?;
+
+    let mut index = Index::new();
+    index.add_file(path.clone())?;
+    index.add_file(dir.path().join("uwu"))?;
+    index.add_file(path.clone())?;
+    assert_eq!(index.files.len(), 2);
+    assert!(!index.has_duplicates());
+
+    // an older entry doesn't replace a newer one
+    let newest = index.files[0].last_indexed;
+    index.insert_file(IndexedFile {
+        path: path.clone(),
+        last_indexed: Utc.timestamp_opt(0, 0).unwrap(),
+        ..Default::default()
+    });
+    assert_eq!(index.files[0].last_indexed, newest);
+
+    // entries changed behind its back are still found
+    index.files.retain(|f| f.path == path);
+    index
+        .files
+        .insert(0, IndexedFile::new(dir.path().join("uwu"))?);
+    index.add_file(path.clone())?;
+    index.add_file(dir.path().join("uwu"))?;
+    assert_eq!(index.files.len(), 2);
+    assert!(!index.has_duplicates());
+    // even when replaced with a different path at the same position
+    let moved = dir.path().join("moved");
+    std::fs::write(&moved, "")?;
+    index.files[1] = IndexedFile::new(moved.clone())?;
+    index.add_file(moved)?;
+    assert_eq!(index.files.len(), 2);
+    assert!(!index.has_duplicates());
+
+    Ok(())
+}
+
+// Run with `cargo test --release --features fast-lookup -- --ignored bench_`
+// to compare a linear scan against the `index_map` lookup in `add_file`
+#[test]
+#[ignore]
+fn bench_add_file() {
+    use std::time::Instant;
+
+    let mut index = Index::new();
+    index.files = (0..100_000)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("home/user/file_{}", i)),
+            ..Default::default()
+        })
+        .collect();
+    let paths = (0..1000)
+        .map(|i| PathBuf::from(format!("home/user/file_{}", i * 97)))
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let found = paths
+        .iter()
+        .filter(|path| index.files.iter().any(|f| &f.path == *path))
+        .count();
+    println!("linear: {:?} for {} lookups", start.elapsed(), found);
+
+    // the first lookup builds the map, so it's timed too
+    let start = Instant::now();
+    let found = paths
+        .iter()
+        .filter(|path| index.position(path).is_some())
+        .count();
+    println!("position: {:?} for {} lookups", start.elapsed(), found);
+}
+
+#[test]
+fn test_compact() -> Result<()> {
+    let mut index = ["src/main.rs", "Cargo.toml", "src/lib.rs", "README.md"]
+        .iter()
+        .map(|name| IndexedFile {
+            path: PathBuf::from(name),
+            ..Default::default()
+        })
+        .collect::<Index>();
+    assert!(!index.is_compact());
+
+    index.compact();
+    assert!(index.is_compact());
+    let paths = index
+        .iter()
+        .map(|f| f.path.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        ["Cargo.toml", "README.md", "src/lib.rs", "src/main.rs"]
+    );
+    assert!(Index::new().is_compact());
+
+    // loading sorts, adding doesn't
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("index.json");
+    index.files.reverse();
+    index.save(path.clone())?;
+    let mut index = Index::load(path)?;
+    assert!(index.is_compact());
+    let file = dir.path().join("a");
+    std::fs::write(&file, "")?;
+    index.add_file(file.clone())?;
+    assert!(!index.is_compact());
+    assert_eq!(index.files.last().map(|f| &f.path), Some(&file));
+
+    // the path lookup still finds entries after they've moved
+    index.compact();
+    assert_eq!(index.files[0].path, file);
+    index.add_file(file)?;
+    assert_eq!(index.files.len(), 5);
+
+    Ok(())
+}
+
+// Run with `cargo test --release -- --ignored bench_`
+#[test]
+#[ignore]
+fn bench_compact() -> Result<()> {
+    use std::time::Instant;
+
+    // walk order, which is roughly random
+    let mut index = (0..100_000u64)
+        .map(|i| {
+            let i = i.wrapping_mul(0x9E37_79B9_7F4A_7C15) % 100_000;
+            IndexedFile {
+                path: PathBuf::from(format!("home/user/dir_{}/file_{}.rs", i % 100, i)),
+                ..Default::default()
+            }
+        })
+        .collect::<Index>();
+    let query = query::parse_query("ext:rs file_5")?;
+
+    let start = Instant::now();
+    let found = query::query(&query, &index)?.len();
+    println!("unsorted: {:?} for {} results", start.elapsed(), found);
+
+    let start = Instant::now();
+    index.compact();
+    println!("compact: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let found = query::query(&query, &index)?.len();
+    println!("sorted: {:?} for {} results", start.elapsed(), found);
+
+    Ok(())
+}
+
+#[test]
+fn test_search_by_tags() -> Result<()> {
+    let file = |path: &str, tags: &[&str]| IndexedFile {
+        path: PathBuf::from(path),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+        last_indexed: Utc::now(),
+        ..Default::default()
+    };
+    let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+    let paths = |files: Vec<&IndexedFile>| {
+        files
+            .iter()
+            .map(|f| f.path.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let mut index = Index::new();
+    index.insert_file(file("beach.jpg", &["holiday", "sea"]));
+    index.insert_file(file("city.jpg", &["holiday"]));
+    index.insert_file(file("notes.txt", &["work"]));
+    index.insert_file(file("untagged", &[]));
+    assert!(index.tag_index_in_sync());
+    assert_eq!(index.tag_index["holiday"], [0, 1]);
+
+    let search = |index: &Index, tags_: &[&str], require_all| {
+        paths(index.search_by_tags(&tags(tags_), require_all))
+    };
+    assert_eq!(
+        search(&index, &["holiday"], true),
+        ["beach.jpg", "city.jpg"]
+    );
+    assert_eq!(search(&index, &["holiday", "sea"], true), ["beach.jpg"]);
+    assert!(search(&index, &["holiday", "work"], true).is_empty());
+    assert_eq!(
+        search(&index, &["sea", "work"], false),
+        ["beach.jpg", "notes.txt"]
+    );
+    assert!(search(&index, &["missing"], false).is_empty());
+    assert!(search(&index, &[], false).is_empty());
+    assert_eq!(search(&index, &[], true).len(), 4);
+
+    // replacing an entry moves it between tags
+    index.insert_file(file("city.jpg", &["work"]));
+    assert_eq!(search(&index, &["holiday"], true), ["beach.jpg"]);
+    assert_eq!(search(&index, &["work"], true), ["city.jpg", "notes.txt"]);
+
+    // changed directly, so the index is stale until it's rebuilt
+    index.files[3].tags.push("holiday".to_string());
+    assert_eq!(search(&index, &["holiday"], true), ["beach.jpg"]);
+    index.rebuild_tag_index();
+    assert_eq!(
+        search(&index, &["holiday"], true),
+        ["beach.jpg", "untagged"]
+    );
+    // a different length falls back to scanning every entry
+    index.files.push(file("new.jpg", &["holiday"]));
+    assert_eq!(search(&index, &["holiday"], false).len(), 3);
+    index.build_tag_index();
+    assert!(index.tag_index_in_sync());
+    assert_eq!(search(&index, &["holiday"], false).len(), 3);
+
+    // the tag index isn't saved, it's rebuilt on load
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("index.json");
+    index.save(path.clone())?;
+    let mut index = Index::load(path)?;
+    assert!(index.tag_index_in_sync());
+    assert_eq!(
+        search(&index, &["holiday"], true),
+        ["beach.jpg", "new.jpg", "untagged"]
+    );
+
+    // evicting moves the other entries, adding one back mustn't leave stale positions
+    index.files[0].last_indexed = Utc::now() - chrono::Duration::days(30);
+    assert_eq!(index.evict_older_than(std::time::Duration::from_secs(60)), 1);
+    index.insert_file(file("beach.jpg", &["holiday", "sea"]));
+    assert_eq!(
+        search(&index, &["holiday"], true),
+        ["new.jpg", "untagged", "beach.jpg"]
+    );
+
+    Ok(())
+}
+
+// Run with `cargo test --release -- --ignored bench_`
+#[test]
+#[ignore]
+fn bench_search_by_tags() {
+    use std::time::Instant;
+
+    let mut index = (0..100_000)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("home/user/file_{}", i)),
+            tags: vec![format!("tag_{}", i % 100), format!("tag_{}", i % 7)],
+            ..Default::default()
+        })
+        .collect::<Index>();
+    let tags = vec!["tag_3".to_string(), "tag_5".to_string()];
+
+    // not built yet, so this scans every entry
+    let start = Instant::now();
+    let found = index.search_by_tags(&tags, true).len();
+    println!("linear: {:?} for {} results", start.elapsed(), found);
+
+    let start = Instant::now();
+    index.rebuild_tag_index();
+    println!("rebuild: {:?}", start.elapsed());
+
+    let start = Instant::now();
+    let found = index.search_by_tags(&tags, true).len();
+    println!("indexed: {:?} for {} results", start.elapsed(), found);
+}
+
+#[test]
+fn test_deduplicate_by_path() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("owo");
+    std::fs::write(&path, "")?;
+    std::fs::write(dir.path().join("uwu"), "")?;
+
+    // `add_file` doesn't add duplicates, so push them directly
+    let mut index = Index::new();
+    index.files.push(IndexedFile::new(path.clone())?);
+    index.files.push(IndexedFile::new(dir.path().join("uwu"))?);
+    index.files.push(IndexedFile::new(path.clone())?);
+    assert!(index.has_duplicates());
+
+    let newest = index.files[2].last_indexed;
+    assert_eq!(index.deduplicate_by_path(), 1);
+    assert!(!index.has_duplicates());
+    assert_eq!(index.files.len(), 2);
+    let file = index.files.iter().find(|f| f.path == path).unwrap();
+    assert_eq!(file.last_indexed, newest);
+
+    assert_eq!(index.deduplicate_by_path(), 0);
+    Ok(())
+}
+
+#[test]
+fn test_from_iter_extend() {
+    use chrono::TimeZone;
+
+    let file = |path: &str, indexed: i64| IndexedFile {
+        path: PathBuf::from(path),
+        last_indexed: Utc.timestamp_opt(indexed, 0).unwrap(),
+        ..Default::default()
+    };
+
+    let mut index = [file("a", 2), file("b", 1), file("a", 1)]
+        .into_iter()
+        .collect::<Index>();
+    assert_eq!(index.files, vec![file("a", 2), file("b", 1)]);
+    assert_eq!(index.root, None);
+
+    index.extend([file("b", 3), file("c", 1), file("a", 1)]);
+    assert_eq!(index.files, vec![file("a", 2), file("b", 3), file("c", 1)]);
+}
+
+#[tokio::test]
+async fn test_watch_and_update() -> Result<()> {
+    use crate::db::InMemoryBackend;
+
+    let dir = tempfile::tempdir()?;
+    let existing = dir.path().join("existing");
+    std::fs::write(&existing, "")?;
+    let memory = Arc::new(Mutex::new(InMemoryBackend {
+        index: Index {
+            files: vec![IndexedFile::new(existing.clone())?],
+            ..Index::new()
+        },
+    }));
+    let backend: Arc<Mutex<dyn IndexBackend + Send>> = memory.clone();
+    let cancel = CancellationToken::new();
+    let handle = Index::watch_and_update(dir.path(), backend, cancel.clone());
+
+    // waits until the backend has an entry for every path in `present` and none for `absent`
+    let wait_for = |present: Vec<PathBuf>, absent: Vec<PathBuf>| {
+        let memory = memory.clone();
+        async move {
+            for _ in 0..100 {
+                let index = &memory.lock().await.index;
+                let has = |path: &PathBuf| index.files.iter().any(|f| &f.path == path);
+                if present.iter().all(has) && !absent.iter().any(has) {
+                    return true;
+                }
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            false
+        }
+    };
+
+    let added = dir.path().join("added");
+    for i in 0..10 {
+        std::fs::write(&added, i.to_string())?;
+    }
+    assert!(wait_for(vec![added.clone()], vec![]).await);
+    // every write in the burst updates the same entry
+    let entries = memory
+        .lock()
+        .await
+        .index
+        .files
+        .iter()
+        .filter(|f| f.path == added)
+        .count();
+    assert_eq!(entries, 1);
+
+    std::fs::remove_file(&existing)?;
+    assert!(wait_for(vec![added.clone()], vec![existing]).await);
+
+    cancel.cancel();
+    handle.await??;
+
+    // watching a missing directory fails the task instead of panicking
+    let backend: Arc<Mutex<dyn IndexBackend + Send>> = memory;
+    let missing = dir.path().join("missing");
+    assert!(
+        Index::watch_and_update(&missing, backend, CancellationToken::new())
+            .await?
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_round_trip() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("file"), "owo")?;
+    let (index, _) = Index::new().gc(dir.path(), &IndexConfig::default())?;
+    let path = dir.path().join("index.json");
+
+    index.save_async(&path).await?;
+    let loaded = Index::load_async(&path).await?;
+    assert_eq!(loaded.files, index.files);
+    assert_eq!(loaded.root, index.root);
+
+    // same format as the sync methods, and the temporary file is gone
+    assert_eq!(Index::load(path.clone())?.files, index.files);
+    assert!(!dir.path().join("index.json.tmp").exists());
+
+    // overwriting an existing index
+    Index::new().save_async(&path).await?;
+    assert!(Index::load_async(&path).await?.files.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn test_load_or_default() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("index.json");
+
+    // nothing there yet
+    assert!(Index::load_or_default(&path)?.files.is_empty());
+    let built = Index::load_or_else(&path, || Index::with_root(dir.path().to_path_buf()))?;
+    assert_eq!(built.root.as_deref(), Some(dir.path()));
+
+    // an existing index is loaded as is
+    built.save(path.clone())?;
+    assert_eq!(Index::load_or_default(&path)?.root, built.root);
+    assert_eq!(Index::load_or_else(&path, Index::new)?.root, built.root);
+
+    // a corrupt index isn't silently replaced
+    std::fs::write(&path, "owo")?;
+    assert!(Index::load_or_default(&path).is_err());
+    assert!(Index::load_or_else(&path, Index::new).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_load_or_default_prepares_index() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("index.json");
+    let file = |path: &str, tag: &str| IndexedFile {
+        path: PathBuf::from(path),
+        tags: vec![tag.to_string()],
+        ..Default::default()
+    };
+    // saved out of order, as `files` can be changed directly
+    let index = Index {
+        files: vec![file("b.jpg", "holiday"), file("a.txt", "work"), file("c.jpg", "holiday")],
+        ..Index::new()
+    };
+    index.save(path.clone())?;
+
+    // every loader sorts the entries and builds the tag index, not just `load`
+    let loaded = Index::load_or_default(&path)?;
+    assert!(loaded.is_compact());
+    assert!(loaded.tag_index_in_sync());
+    let holiday = vec![String::from("holiday")];
+    let tagged = loaded.search_by_tags(&holiday, true);
+    assert_eq!(
+        tagged.iter().map(|f| f.path.as_path()).collect::<Vec<_>>(),
+        [Path::new("b.jpg"), Path::new("c.jpg")]
+    );
+    let mut results = query::query(&query::parse_query("tag:holiday")?, &loaded)?;
+    results.sort_by(|a, b| a.1.path.cmp(&b.1.path));
+    assert_eq!(
+        results.iter().map(|(_, f)| f.path.as_path()).collect::<Vec<_>>(),
+        [Path::new("b.jpg"), Path::new("c.jpg")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_save_if_changed() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("file"), "owo")?;
+    let path = dir.path().join("index.json");
+
+    let previous = Index::new();
+    assert!(!previous.save_if_changed(&path, &previous.clone())?);
+    assert!(!path.exists());
+
+    let (index, _) = Index::new().gc(dir.path(), &IndexConfig::default())?;
+    assert!(index.save_if_changed(&path, &previous)?);
+    let loaded = Index::load(path.clone())?;
+    assert_eq!(loaded.files, index.files);
+
+    // the loaded copy matches, so nothing is written
+    std::fs::remove_file(&path)?;
+    assert!(!index.save_if_changed(&path, &loaded)?);
+    assert!(!path.exists());
+
+    // only the metadata changed
+    let mut moved = loaded.clone();
+    moved.metadata.root_paths.push(PathBuf::from("/elsewhere"));
+    assert!(moved.save_if_changed(&path, &loaded)?);
+    assert_eq!(Index::load(path)?.metadata.root_paths, moved.metadata.root_paths);
+
+    Ok(())
+}
+
+#[test]
+fn test_index_metadata() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("index.json");
+
+    let mut index = Index::with_root(dir.path().to_path_buf());
+    assert_eq!(index.metadata().version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(index.metadata().root_paths, [dir.path()]);
+    let created = index.metadata().created;
+
+    index.files.push(IndexedFile::default());
+    index.save(path.clone())?;
+    let loaded = Index::load(path.clone())?;
+    assert_eq!(loaded.metadata().entry_count, 1);
+    assert_eq!(loaded.metadata().created, created);
+    assert!(loaded.metadata().updated >= created);
+    // the save is only recorded in the file
+    assert_eq!(index.metadata().entry_count, 0);
+
+    // indexes from before metadata was tracked still load
+    std::fs::write(&path, r#"{"files": []}"#)?;
+    assert_eq!(Index::load(path.clone())?.metadata().entry_count, 0);
+
+    index.metadata.version = String::from("999.0.0");
+    index.to_json_writer(File::create(&path)?)?;
+    let expected = DatchaniError::IncompatibleIndexVersion {
+        found: String::from("999.0.0"),
+        expected: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    let err = Index::load(path).unwrap_err();
+    assert_eq!(err.downcast_ref::<DatchaniError>(), Some(&expected));
+    let err = Index::deserialize_from_bytes(&index.serialize_to_bytes()?).unwrap_err();
+    assert_eq!(err.downcast_ref::<DatchaniError>(), Some(&expected));
+
+    Ok(())
+}
+
+#[test]
+fn test_snapshot() {
+    use std::sync::RwLock;
+
+    let index = RwLock::new(Index::new());
+    index.write().unwrap().files.push(IndexedFile {
+        path: PathBuf::from("owo"),
+        ..Default::default()
+    });
+
+    let snapshot = index.read().unwrap().snapshot();
+    // the lock is free again while the snapshot is alive
+    index.write().unwrap().files.clear();
+    assert_eq!(snapshot.files.len(), 1);
+    assert!(index.read().unwrap().files.is_empty());
+}
+
+// Run with `cargo test --release -- --ignored bench_snapshot`
+#[test]
+#[ignore]
+fn bench_snapshot() {
+    use std::time::Instant;
+
+    let index = (0..1_000_000)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("home/user/dir_{}/file_{}.rs", i % 1000, i)),
+            data_type: Some(String::from("text/x-rust")),
+            tags: vec![String::from("code")],
+            ..Default::default()
+        })
+        .collect::<Index>();
+
+    let start = Instant::now();
+    let snapshot = index.snapshot();
+    println!("snapshot of {} entries: {:?}", snapshot.files.len(), start.elapsed());
+}
+
+#[test]
+fn test_custom_mime_rules() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let project = dir.path().join("App.xcodeproj").join("project.pbxproj");
+    std::fs::create_dir(project.parent().unwrap())?;
+    std::fs::write(&project, "// !$*UTF8*$!")?;
+    std::fs::write(dir.path().join("notes.txt"), "")?;
+
+    let config = IndexConfig {
+        custom_mime_rules: MimeRules::new(vec![
+            (
+                String::from("*.pbxproj"),
+                String::from("text/x-xcode-project"),
+            ),
+            (String::from("**/App.*/*"), String::from("text/plain")),
+        ])?,
+        ..Default::default()
+    };
+    let file = IndexedFile::new_with_config(project, &config)?;
+    assert_eq!(file.data_type.as_deref(), Some("text/x-xcode-project"));
+    // only the path matched
+    assert_eq!(file.content_type_confidence, Some(50));
+    // no rule matches, so it's detected by its extension
+    let file = IndexedFile::new_with_config(dir.path().join("notes.txt"), &config)?;
+    assert_eq!(file.data_type.as_deref(), Some("text/plain"));
+    assert_eq!(file.content_type_confidence, Some(50));
+
+    assert!(MimeRules::new(vec![(String::from("[a-"), String::from("text/plain"))]).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_detect_mime() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    // only the magic bytes, the extension says otherwise
+    let png = dir.path().join("image.txt");
+    std::fs::write(&png, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+    let blob = dir.path().join("blob");
+    std::fs::write(&blob, [1u8, 2, 3])?;
+
+    let file = IndexedFile::new(png)?;
+    assert_eq!(file.data_type.as_deref(), Some("image/png"));
+    assert_eq!(file.content_type_confidence, Some(100));
+    let file = IndexedFile::new(blob)?;
+    assert_eq!(file.data_type.as_deref(), Some("application/octet-stream"));
+    assert_eq!(file.content_type_confidence, Some(0));
+    // directories don't have a MIME type
+    let file = IndexedFile::new(dir.path().to_path_buf())?;
+    assert_eq!((file.data_type, file.content_type_confidence), (None, None));
+
+    #[cfg(target_os = "linux")]
+    {
+        let exe = IndexedFile::new(std::env::current_exe()?)?;
+        assert_eq!(exe.data_type.as_deref(), Some("application/x-executable"));
+        assert_eq!(exe.content_type_confidence, Some(100));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_new_with_config_options() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("project.pbxproj");
+    std::fs::write(&path, "owo")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    let custom_mime_rules = MimeRules::new(vec![(
+        String::from("*.pbxproj"),
+        String::from("text/x-xcode-project"),
+    )])?;
+
+    let file = IndexedFile::new(path.clone())?;
+    assert!(file.content_hash.is_some());
+    assert!(file.accessed.is_some());
+    #[cfg(unix)]
+    assert_eq!(file.permissions, 0o600);
+
+    let config = IndexConfig {
+        compute_mime: false,
+        compute_hash: false,
+        read_access_time: false,
+        read_permissions: false,
+        custom_mime_rules: custom_mime_rules.clone(),
+        ..Default::default()
+    };
+    let file = IndexedFile::new_with_config(path.clone(), &config)?;
+    assert_eq!(file.data_type, None);
+    assert_eq!(file.content_type_confidence, None);
+    assert_eq!(file.content_hash, None);
+    assert_eq!(file.accessed, None);
+    assert_eq!(file.permissions, default_permissions());
+    assert_eq!(file.owner_uid, 0);
+    // everything else is still read
+    assert_eq!(file.file_size, 3);
+
+    let config = IndexConfig {
+        custom_mime_rules,
+        ..Default::default()
+    };
+    let file = IndexedFile::new_with_config(path.clone(), &config)?;
+    assert_eq!(file.data_type.as_deref(), Some("text/x-xcode-project"));
+    assert_eq!(file.content_hash, None);
+
+    // adding to an index doesn't pay for everything `new` does
+    let mut index = Index::new();
+    index.add_file(path)?;
+    assert_eq!(index.files[0].content_hash, None);
+
+    // detection doesn't depend on custom rules
+    let png = dir.path().join("image");
+    std::fs::write(&png, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+    let detect = |compute_mime| {
+        let config = IndexConfig {
+            compute_mime,
+            ..Default::default()
+        };
+        IndexedFile::new_with_config(png.clone(), &config).map(|file| file.data_type)
+    };
+    assert_eq!(detect(false)?, None);
+    assert_eq!(detect(true)?.as_deref(), Some("image/png"));
+
+    Ok(())
+}
+
+#[test]
+fn test_load_preview() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let text = "ดัชนี\n".repeat(100);
+    std::fs::write(dir.path().join("text"), &text)?;
+    std::fs::write(dir.path().join("binary"), [0x7f, b'E', b'L', b'F', 0, 1, 2])?;
+    std::fs::write(dir.path().join("empty"), "")?;
+
+    let mut file = IndexedFile::new(dir.path().join("text"))?;
+    let preview = file.load_preview(4096)?.unwrap();
+    assert_eq!(preview.chars().count(), PREVIEW_CHARS);
+    assert!(text.starts_with(&preview));
+    assert_eq!(file.content_preview, Some(preview));
+
+    // cutting a character in half still gives a preview of everything before it
+    let preview = file.load_preview(4)?.unwrap();
+    assert_eq!(preview, "ด");
+
+    let mut file = IndexedFile::new(dir.path().join("binary"))?;
+    assert_eq!(file.load_preview(4096)?, None);
+    let mut file = IndexedFile::new(dir.path().join("empty"))?;
+    assert_eq!(file.load_preview(4096)?.as_deref(), Some(""));
+    let mut file = IndexedFile::new(dir.path().to_path_buf())?;
+    assert_eq!(file.load_preview(4096)?, None);
+
+    // previews aren't persisted
+    let mut file = IndexedFile::new(dir.path().join("text"))?;
+    file.load_preview(4096)?;
+    let json = serde_json::to_string(&file)?;
+    assert_eq!(
+        serde_json::from_str::<IndexedFile>(&json)?.content_preview,
+        None
+    );
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_index_keeps_atime() -> Result<()> {
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("text");
+    std::fs::write(&path, "owo")?;
+    // older than the modification time, so even `relatime` would update it
+    let atime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    File::options()
+        .write(true)
+        .open(&path)?
+        .set_times(std::fs::FileTimes::new().set_accessed(atime))?;
+
+    let file = IndexedFile::new_with_config(path.clone(), &IndexConfig::default())?;
+    assert_eq!(file.is_text, Some(true));
+    assert_eq!(path.metadata()?.accessed()?, atime);
+
+    Ok(())
+}
+
+#[test]
+fn test_sample() -> Result<()> {
+    let index = (0..100)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!(
+                "file_{}.{}",
+                i,
+                if i % 2 == 0 { "rs" } else { "md" }
+            )),
+            ..Default::default()
+        })
+        .collect::<Index>();
+    let paths = |files: Vec<&IndexedFile>| files.into_iter().map(|f| f.path.clone()).collect();
+
+    let sample: Vec<_> = paths(index.sample(10, 42));
+    assert_eq!(sample.len(), 10);
+    assert_eq!(sample, paths(index.sample(10, 42)));
+    assert_ne!(sample, paths(index.sample(10, 43)));
+    assert_eq!(sample.iter().collect::<HashSet<_>>().len(), 10);
+
+    // everything, just shuffled
+    let all: Vec<_> = paths(index.sample(1000, 42));
+    assert_eq!(all.len(), 100);
+    assert_ne!(all, paths(index.iter().collect()));
+    assert_eq!(all.iter().collect::<HashSet<_>>().len(), 100);
+    assert!(index.sample(0, 42).is_empty());
+    assert_eq!(index.sample(usize::MAX, 42).len(), 100);
+
+    let query = query::parse_query("ext:rs")?;
+    let sample = index.sample_by_query(&query, 10, 42)?;
+    assert_eq!(sample.len(), 10);
+    assert!(sample
+        .iter()
+        .all(|(_, f)| f.path.extension().unwrap() == "rs"));
+    assert_eq!(sample, index.sample_by_query(&query, 10, 42)?);
+    assert_eq!(index.sample_by_query(&query, 100, 42)?.len(), 50);
+    let invalid = query::parse_query("regex:(")?;
+    assert!(index.sample_by_query(&invalid, 10, 42).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_search_ranked() -> Result<()> {
+    let mut index = Index::new();
+    index.files = (0..25)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("file_{:02}.rs", i)),
+            ..Default::default()
+        })
+        .collect();
+    let q = query::parse_query("ext:rs")?;
+    let config = ScoringConfig::default();
+
+    let first = index.search_ranked(&q, 0, 10, &config)?;
+    assert_eq!(first.total, 25);
+    assert_eq!(first.results.len(), 10);
+    assert!(first.has_next_page());
+    assert_eq!(first.prev_page(), None);
+
+    let last = index.search_ranked(&q, 2, 10, &config)?;
+    assert_eq!(last.results.len(), 5);
+    assert_eq!(last.results[0].file.path, PathBuf::from("file_20.rs"));
+    assert!(!last.has_next_page());
+    assert_eq!(last.prev_page(), Some(1));
+
+    // past the end, or an exactly full last page
+    assert!(index.search_ranked(&q, 3, 10, &config)?.results.is_empty());
+    assert!(!index.search_ranked(&q, 4, 5, &config)?.has_next_page());
+    Ok(())
+}
+
+#[test]
+fn test_evict() {
+    let now = Utc::now();
+    let mut index = Index::new();
+    index.files = vec![
+        IndexedFile {
+            path: PathBuf::from("fresh"),
+            last_modified: now,
+            ..Default::default()
+        },
+        IndexedFile {
+            path: PathBuf::from("stale"),
+            last_modified: now - chrono::Duration::days(60),
+            last_indexed: now - chrono::Duration::days(30),
+            ..Default::default()
+        },
+    ];
+
+    let week = std::time::Duration::from_secs(7 * 24 * 60 * 60);
+    assert_eq!(index.evict_older_than(week), 1);
+    assert_eq!(index.files[0].path, PathBuf::from("fresh"));
+    assert_eq!(index.evict_older_than(week), 0);
+
+    index.files.push(IndexedFile {
+        path: PathBuf::from("old"),
+        last_modified: now - chrono::Duration::days(60),
+        ..Default::default()
+    });
+    assert_eq!(index.evict_last_modified_before(now - chrono::Duration::days(1)), 1);
+    assert_eq!(index.files.len(), 1);
+}
+
+#[test]
+fn test_gc() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    File::create(dir.path().join("kept"))?;
+    File::create(dir.path().join("removed"))?;
+
+    let config = IndexConfig {
+        compute_hash: true,
+        ..Default::default()
+    };
+    let (mut index, stats) = Index::new().gc(dir.path(), &config)?;
+    // the root directory itself is indexed too
+    assert_eq!(stats.added, 3);
+    for file in index.files.iter_mut() {
+        file.tags.push(String::from("owo"));
+    }
+
+    std::fs::remove_file(dir.path().join("removed"))?;
+    File::create(dir.path().join("added"))?;
+
+    let (new, stats) = index.gc(dir.path(), &config)?;
+    assert_eq!(
+        stats,
+        GcStats {
+            removed: 1,
+            added: 1,
+            unchanged: 2,
+        }
+    );
+    let kept = new.get_file(dir.path().join("kept")).unwrap();
+    assert_eq!(kept.tags, vec![String::from("owo")]);
+    assert!(kept.content_hash.is_some());
+    assert!(new.get_file(dir.path().join("added")).unwrap().tags.is_empty());
+    assert!(new.get_file(dir.path().join("removed")).is_none());
+
+    Ok(())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct QueryResult {
-    pub file: IndexedFile,
-    pub score: i64,
+#[cfg(test)]
+fn arb_indexed_file() -> impl proptest::strategy::Strategy<Value = IndexedFile> {
+    use chrono::TimeZone;
+    use proptest::prelude::*;
+
+    (
+        "[a-z0-9/._-]{1,32}",
+        prop_oneof![Just(FileType::Regular), Just(FileType::Directory)],
+        any::<u32>(),
+        proptest::option::of("[a-z]+/[a-z0-9.+-]+"),
+        proptest::collection::vec("[a-z]{1,8}(/[a-z]{1,8})?", 0..4),
+        0i64..4_000_000_000,
+        proptest::option::of(0i64..4_000_000_000),
+        proptest::option::of("[0-9a-f]{64}"),
+        proptest::option::of(0i64..4_000_000_000),
+        // proptest only implements `Strategy` for tuples of up to 12 elements
+        (
+            any::<u64>(),
+            any::<u64>(),
+            1u32..8,
+            any::<u64>(),
+            proptest::option::of(0u8..=100),
+            any::<bool>(),
+            prop_oneof![
+                Just(HashAlgorithm::Sha256),
+                Just(HashAlgorithm::Blake3),
+                Just(HashAlgorithm::Md5)
+            ],
+            proptest::collection::btree_map(
+                "user\\.[a-z]{1,8}",
+                proptest::collection::vec(any::<u8>(), 0..8),
+                0..3,
+            ),
+        ),
+    )
+        .prop_map(
+            |(
+                path,
+                file_type,
+                mode,
+                data_type,
+                tags,
+                modified,
+                created,
+                content_hash,
+                accessed,
+                (
+                    file_size,
+                    inode,
+                    hardlink_count,
+                    device,
+                    content_type_confidence,
+                    sparse,
+                    content_hash_algorithm,
+                    xattrs,
+                ),
+            )| {
+                IndexedFile {
+                    path: PathBuf::from(path),
+                    file_type,
+                    permissions: mode & 0o7777,
+                    owner_uid: mode,
+                    owner_gid: mode / 2,
+                    tags,
+                    last_modified: Utc.timestamp_opt(modified, 0).unwrap(),
+                    last_indexed: Utc.timestamp_opt(modified, 0).unwrap(),
+                    created: created.map(|created| Utc.timestamp_opt(created, 0).unwrap()),
+                    accessed: accessed.map(|accessed| Utc.timestamp_opt(accessed, 0).unwrap()),
+                    content_hash,
+                    content_hash_algorithm,
+                    file_size,
+                    inode,
+                    hardlink_count,
+                    device,
+                    is_text: data_type.as_ref().map(|mime| mime.starts_with("text/")),
+                    sparse,
+                    xattrs,
+                    data_type,
+                    content_type_confidence,
+                    content_preview: None,
+                }
+            },
+        )
 }
 
-fn filetype_deserializer<'de, D>(deserializer: D) -> Result<FileType, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s = String::deserialize(deserializer)?;
-    match s.as_str().to_lowercase().as_str() {
-        "regular file" => Ok(FileType::Regular),
-        "directory" => Ok(FileType::Directory),
-        "symlink" => Ok(FileType::Symlink),
-        "block device" => Ok(FileType::BlockDevice),
-        "char device" => Ok(FileType::CharDevice),
-        "fifo" => Ok(FileType::Fifo),
-        "socket" => Ok(FileType::Socket),
-        _ => Err(serde::de::Error::custom("Invalid file type")),
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_bytes_round_trip(
+        files in proptest::collection::vec(arb_indexed_file(), 0..16),
+        root in proptest::option::of("/[a-z]{1,8}"),
+    ) {
+        let mut index = Index {
+            files,
+            root: root.map(PathBuf::from),
+            ..Index::new()
+        };
+        // loading sorts the entries by path
+        index.compact();
+        let bytes = index.serialize_to_bytes().unwrap();
+        let loaded = Index::deserialize_from_bytes(&bytes).unwrap();
+        proptest::prop_assert_eq!(loaded.files, index.files);
+        proptest::prop_assert_eq!(loaded.root, index.root);
+        proptest::prop_assert_eq!(loaded.metadata, index.metadata);
+    }
+
+    #[test]
+    fn test_toml_round_trip_any(
+        files in proptest::collection::vec(arb_indexed_file(), 0..16),
+    ) {
+        let max = i64::MAX as u64;
+        let files = files
+            .into_iter()
+            .map(|f| IndexedFile {
+                file_size: f.file_size.min(max),
+                inode: f.inode.min(max),
+                device: f.device.min(max),
+                ..f
+            })
+            .collect();
+        let mut index = Index {
+            files,
+            ..Index::new()
+        };
+        index.compact();
+        let loaded = Index::from_toml(&index.to_toml().unwrap()).unwrap();
+        proptest::prop_assert_eq!(loaded.files, index.files);
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct IndexMetadata {
-    pub version: String,
-    pub created: String,
-    pub updated: String,
+#[test]
+fn test_top_k() {
+    let file = |path: &str, mime: Option<&str>, tags: &[&str]| IndexedFile {
+        path: PathBuf::from(path),
+        data_type: mime.map(str::to_string),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+        ..Default::default()
+    };
+    let index = [
+        file("src/main.rs", Some("text/x-rust"), &["code"]),
+        file("src/lib.rs", Some("text/x-rust"), &["code", "todo"]),
+        file("build.rs", Some("text/x-rust"), &[]),
+        file("README.md", Some("text/markdown"), &["docs", "todo"]),
+        file("CHANGELOG.md", Some("text/markdown"), &["docs"]),
+        file("Cargo.toml", None, &["code"]),
+        file("LICENSE", Some("text/plain"), &[]),
+    ]
+    .into_iter()
+    .collect::<Index>();
+    let pairs = |pairs: &[(&str, usize)]| {
+        pairs
+            .iter()
+            .map(|(value, count)| (value.to_string(), *count))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        index.top_k_by_extension(10),
+        pairs(&[("rs", 3), ("md", 2), ("toml", 1)])
+    );
+    assert_eq!(index.top_k_by_extension(1), pairs(&[("rs", 3)]));
+    assert_eq!(
+        index.top_k_by_mime(2),
+        pairs(&[("text/x-rust", 3), ("text/markdown", 2)])
+    );
+    // ties are alphabetical
+    assert_eq!(
+        index.top_k_by_tag(3),
+        pairs(&[("code", 3), ("docs", 2), ("todo", 2)])
+    );
+    assert!(index.top_k_by_tag(0).is_empty());
+    assert!(Index::new().top_k_by_extension(10).is_empty());
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Index {
-    pub files: Vec<IndexedFile>,
-    // pub metadata: IndexMetadata,
+#[test]
+fn test_filter() {
+    let file = |path: &str, mime: &str, tags: &[&str]| IndexedFile {
+        path: PathBuf::from(path),
+        data_type: Some(mime.to_string()),
+        tags: tags.iter().map(|t| t.to_string()).collect(),
+        ..Default::default()
+    };
+    let mut index = Index {
+        root: Some(PathBuf::from("/project")),
+        ..[
+            file("src/main.rs", "text/x-rust", &["code"]),
+            file("src/lib.rs", "text/x-rust", &["code", "todo"]),
+            file("README.md", "text/markdown", &["docs", "todo"]),
+            file("Cargo.toml", "text/x-toml", &["code"]),
+        ]
+        .into_iter()
+        .collect()
+    };
+    index.metadata.entry_count = 4;
+    let paths = |index: &Index| {
+        index
+            .iter()
+            .map(|f| f.path.to_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let small = index.filter(|f| f.path.starts_with("src"));
+    assert_eq!(paths(&small), ["src/main.rs", "src/lib.rs"]);
+    assert_eq!(small.root, index.root);
+    assert_eq!(small.metadata, index.metadata);
+    assert_eq!(index.files.len(), 4);
+
+    assert_eq!(
+        paths(&index.filter_by_extension("rs")),
+        ["src/main.rs", "src/lib.rs"]
+    );
+    assert!(index.filter_by_extension(".rs").files.is_empty());
+    assert_eq!(paths(&index.filter_by_mime("text/markdown")), ["README.md"]);
+    assert!(index.filter_by_mime("text").files.is_empty());
+    let todo = index.filter_by_tag("todo");
+    assert_eq!(paths(&todo), ["src/lib.rs", "README.md"]);
+    // the tag lookup points into the new, smaller index
+    assert_eq!(
+        todo.search_by_tags(&["docs".to_string()], true)[0].path,
+        PathBuf::from("README.md")
+    );
+    assert_eq!(todo.tag_index_len, 2);
+
+    index.retain(|f| f.tags.iter().any(|t| t == "code"));
+    assert_eq!(paths(&index), ["src/main.rs", "src/lib.rs", "Cargo.toml"]);
+    assert_eq!(index.root, Some(PathBuf::from("/project")));
+    assert_eq!(index.tag_index.get("todo"), Some(&vec![1]));
+    #[cfg(feature = "fast-lookup")]
+    assert_eq!(
+        index.index_map.get(Path::new("Cargo.toml")).copied(),
+        Some(2)
+    );
 }
 
-impl Index {
-    pub fn new() -> Self {
-        Self {
-            files: Vec::new(),
-            // metadata: IndexMetadata {
-            //     version: String::from("0.1.0"),
-            //     created: String::from(""),
-            //     updated: String::from(""),
-            // },
+#[test]
+fn test_position_indexes() -> Result<()> {
+    let file = |path: &str, mime: &str| IndexedFile {
+        path: PathBuf::from(path),
+        data_type: Some(mime.to_string()),
+        ..Default::default()
+    };
+    let mut index = [
+        file("src/main.rs", "text/x-rust"),
+        file("README.md", "text/markdown"),
+        file("src/lib.rs", "text/x-rust"),
+        file("Makefile", "text/x-makefile"),
+    ]
+    .into_iter()
+    .collect::<Index>();
+    let paths = |files: Vec<&IndexedFile>| {
+        files
+            .iter()
+            .map(|f| f.path.to_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // built on first use
+    assert!(index.extension_index.get().is_none());
+    assert_eq!(
+        paths(index.files_by_extension_fast("rs")),
+        ["src/main.rs", "src/lib.rs"]
+    );
+    assert!(index.extension_index.get().is_some());
+    assert_eq!(
+        index.files_by_extension_fast("rs"),
+        index.files_by_extension("rs")
+    );
+    assert!(index.files_by_extension_fast("py").is_empty());
+    assert_eq!(
+        paths(index.files_by_mime_fast("text/markdown")),
+        ["README.md"]
+    );
+    assert_eq!(
+        index.files_by_mime_fast("text/x-rust"),
+        index.files_by_mime("text/x-rust")
+    );
+
+    // adding through the index drops the lookups
+    let dir = tempfile::tempdir()?;
+    let added = dir.path().join("build.rs");
+    std::fs::write(&added, "fn main() {}")?;
+    index.add_file(added.clone())?;
+    assert!(index.extension_index.get().is_none());
+    assert_eq!(index.files_by_extension_fast("rs").len(), 3);
+
+    // entries changed directly are still found, or no longer found
+    index.files.push(file("tests/cli.rs", "text/x-rust"));
+    assert_eq!(index.files_by_extension_fast("rs").len(), 4);
+    index.files[0].path = PathBuf::from("src/main.py");
+    assert_eq!(index.files_by_extension_fast("rs").len(), 3);
+    index.build_extension_index();
+    assert_eq!(paths(index.files_by_extension_fast("py")), ["src/main.py"]);
+
+    index.build_mime_index();
+    index.retain(|f| f.data_type.as_deref() != Some("text/x-rust"));
+    assert!(index.files_by_mime_fast("text/x-rust").is_empty());
+    assert_eq!(index.files_by_mime_fast("text/x-makefile").len(), 1);
+
+    // evicting drops the lookups too, so an entry pushed back at the same length is found
+    let now = Utc::now();
+    for f in index.files.iter_mut() {
+        f.last_modified = now;
+    }
+    index.files[0].last_modified = now - chrono::Duration::days(1);
+    index.build_extension_index();
+    assert_eq!(index.evict_last_modified_before(now), 1);
+    index.files.push(file("docs/guide.md", "text/markdown"));
+    assert_eq!(paths(index.files_by_extension_fast("md")), ["docs/guide.md"]);
+
+    Ok(())
+}
+
+// Run with `cargo test --release -- --ignored bench_`
+#[test]
+#[ignore]
+fn bench_position_indexes() {
+    use std::time::Instant;
+
+    let extensions = ["rs", "md", "toml", "json", "txt", "png", "c", "h"];
+    let index = (0..1_000_000)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("dir_{}/file_{}.{}", i % 1000, i, extensions[i % 8])),
+            ..Default::default()
+        })
+        .collect::<Index>();
+
+    let start = Instant::now();
+    let found = index.files_by_extension("toml").len();
+    println!("linear: {:?} for {} files", start.elapsed(), found);
+
+    let start = Instant::now();
+    let found = index.files_by_extension_fast("toml").len();
+    println!(
+        "first indexed lookup: {:?} for {} files",
+        start.elapsed(),
+        found
+    );
+
+    let start = Instant::now();
+    let found = index.files_by_extension_fast("toml").len();
+    println!("indexed: {:?} for {} files", start.elapsed(), found);
+}
+
+#[test]
+fn test_union_intersection() {
+    use chrono::TimeZone;
+
+    let file = |path: &str, indexed: i64| IndexedFile {
+        path: PathBuf::from(path),
+        last_indexed: Utc.timestamp_opt(indexed, 0).unwrap(),
+        ..Default::default()
+    };
+    let a = Index {
+        files: vec![file("shared", 1), file("only-a", 1)],
+        root: Some(PathBuf::from("/a")),
+        ..Index::new()
+    };
+    let b = Index {
+        files: vec![file("shared", 2), file("only-b", 2)],
+        root: Some(PathBuf::from("/b")),
+        ..Index::new()
+    };
+
+    let both = Index::intersection(&a, &b);
+    assert_eq!(both.files, vec![file("shared", 2)]);
+    assert_eq!(both.root, None);
+
+    let all = a.union(b);
+    assert_eq!(
+        all.files,
+        vec![file("only-a", 1), file("only-b", 2), file("shared", 2)]
+    );
+}
+
+#[cfg(test)]
+fn arb_small_index() -> impl proptest::strategy::Strategy<Value = Index> {
+    use proptest::prelude::*;
+
+    // only a handful of possible paths, so indexes regularly overlap
+    proptest::collection::vec((arb_indexed_file(), "[a-d]{1,2}"), 0..12).prop_map(|files| Index {
+        files: files
+            .into_iter()
+            .map(|(file, path)| IndexedFile {
+                path: PathBuf::from(path),
+                ..file
+            })
+            .collect(),
+        ..Index::new()
+    })
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_union_commutative(a in arb_small_index(), b in arb_small_index()) {
+        let paths = |index: Index| index.files.into_iter().map(|f| f.path).collect::<Vec<_>>();
+        let ab = a.clone().union(b.clone());
+        let ba = b.union(a);
+        proptest::prop_assert_eq!(paths(ab), paths(ba));
+    }
+
+    #[test]
+    fn test_union_associative(
+        a in arb_small_index(),
+        b in arb_small_index(),
+        c in arb_small_index(),
+    ) {
+        let left = a.clone().union(b.clone()).union(c.clone());
+        let right = a.union(b.union(c));
+        proptest::prop_assert_eq!(left.files.len(), right.files.len());
+    }
+}
+
+#[test]
+fn test_bytes_version() {
+    let mut bytes = Index::new().serialize_to_bytes().unwrap();
+    bytes[0] = BYTES_FORMAT_VERSION + 1;
+
+    let err = Index::deserialize_from_bytes(&bytes).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DatchaniError>(),
+        Some(DatchaniError::IncompatibleVersion { .. })
+    ));
+}
+
+#[test]
+fn test_json_round_trip() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut index = Index::new();
+    for name in ["foo.rs", "bar.md"] {
+        let path = dir.path().join(name);
+        File::create(&path)?;
+        index.add_file(path)?;
+    }
+    // loading sorts the entries by path
+    index.compact();
+
+    let mut buf = Vec::new();
+    index.to_json_writer(&mut buf)?;
+    let loaded = Index::from_json_reader(buf.as_slice())?;
+    assert_eq!(loaded.files, index.files);
+
+    let ndjson = dir.path().join("index.ndjson");
+    index.save_ndjson(&ndjson)?;
+    let loaded = Index::load_streaming(&ndjson)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(loaded, index.files);
+
+    Ok(())
+}
+
+#[test]
+fn test_cbor_msgpack_round_trip() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut index = Index::with_root(dir.path().to_path_buf());
+    for name in ["foo.rs", "bar.md"] {
+        let path = dir.path().join(name);
+        File::create(&path)?;
+        index.add_file(path)?;
+    }
+    index.compact();
+
+    let cbor = dir.path().join("index.cbor");
+    index.save_cbor(&cbor)?;
+    assert_eq!(Index::load_cbor(&cbor)?.files, index.files);
+    let msgpack = dir.path().join("index.msgpack");
+    index.save_msgpack(&msgpack)?;
+    assert_eq!(Index::load_msgpack(&msgpack)?.files, index.files);
+
+    // without a known extension the format is detected from the contents
+    let json = dir.path().join("index");
+    index.save(json.clone())?;
+    for path in [&cbor, &msgpack, &json] {
+        let renamed = dir.path().join("index.bin");
+        std::fs::copy(path, &renamed)?;
+        for path in [path, &renamed] {
+            let loaded = Index::load_auto(path)?;
+            assert_eq!(loaded.files, index.files, "{}", path.display());
+            assert_eq!(loaded.root, index.root);
         }
     }
 
-    pub fn get_file(&self, path: PathBuf) -> Option<&IndexedFile> {
-        self.files.iter().find(|f| f.path == path)
+    std::fs::write(dir.path().join("garbage"), [0u8, 1, 2])?;
+    assert!(Index::load_auto(&dir.path().join("garbage")).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_toml_round_trip() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut index = Index::with_root(dir.path().to_path_buf());
+    for name in ["foo.rs", "bar.md"] {
+        let path = dir.path().join(name);
+        std::fs::write(&path, name)?;
+        index.add_file(path)?;
     }
+    index.compact();
+    index.files[0].tags = vec![String::from("todo"), String::from("with space")];
+    index.files[0].compute_hash()?;
 
-    pub fn add_file(&mut self, path: PathBuf) -> Result<()> {
-        let file = IndexedFile::new(path)?;
-        self.files.push(file);
-        Ok(())
+    let toml = index.to_toml()?;
+    // readable, with one table per entry
+    assert_eq!(toml.matches("[[files]]").count(), 2);
+    assert!(toml.contains(&format!(
+        "path = {:?}",
+        index.files[0].path.to_str().unwrap()
+    )));
+    let loaded = Index::from_toml(&toml)?;
+    assert_eq!(loaded.files, index.files);
+    assert_eq!(loaded.root, index.root);
+    assert_eq!(loaded.metadata, index.metadata);
+
+    let path = dir.path().join("index.toml");
+    index.save_toml(&path)?;
+    assert_eq!(Index::load_toml(&path)?.files, index.files);
+    assert_eq!(Index::load_auto(&path)?.files, index.files);
+
+    assert!(Index::from_toml("files = 1").is_err());
+    index.files[0].file_size = u64::MAX;
+    assert!(index.to_toml().is_err());
+    Ok(())
+}
+
+// Run with `cargo test --release -- --ignored bench_` to compare index formats
+#[test]
+#[ignore]
+fn bench_formats() -> Result<()> {
+    use std::time::Instant;
+
+    let index = Index {
+        files: (0..50_000)
+            .map(|i| IndexedFile {
+                path: PathBuf::from(format!("home/user/project-{}/src/file_{}.rs", i % 97, i)),
+                data_type: Some(String::from("text/x-rust")),
+                tags: vec![String::from("owo")],
+                created: Some(Utc::now()),
+                accessed: Some(Utc::now()),
+                ..Default::default()
+            })
+            .collect(),
+        ..Index::new()
+    };
+
+    type Write = fn(&Index) -> Result<Vec<u8>>;
+    type Read = fn(&[u8]) -> Result<Index>;
+    let formats: [(&str, Write, Read); 4] = [
+        (
+            "json",
+            |index| {
+                let mut buf = Vec::new();
+                index.to_json_writer(&mut buf)?;
+                Ok(buf)
+            },
+            |bytes| Index::from_json_reader(bytes),
+        ),
+        (
+            "cbor",
+            |index| {
+                let mut buf = CBOR_MAGIC.to_vec();
+                ciborium::ser::into_writer(index, &mut buf)?;
+                Ok(buf)
+            },
+            Index::from_cbor_slice,
+        ),
+        (
+            "msgpack",
+            |index| Ok(rmp_serde::to_vec_named(index)?),
+            Index::from_msgpack_slice,
+        ),
+        (
+            "bincode",
+            Index::serialize_to_bytes,
+            Index::deserialize_from_bytes,
+        ),
+    ];
+    for (name, write, read) in formats {
+        let start = Instant::now();
+        let bytes = write(&index)?;
+        let written = start.elapsed();
+        let start = Instant::now();
+        assert_eq!(read(&bytes)?.files.len(), index.files.len());
+        let read = start.elapsed();
+
+        let mb = bytes.len() as f64 / 1_000_000.0;
+        println!(
+            "{}: {:.1} MB, write {:?} ({:.0} MB/s), read {:?} ({:.0} MB/s)",
+            name,
+            mb,
+            written,
+            mb / written.as_secs_f64(),
+            read,
+            mb / read.as_secs_f64()
+        );
     }
+    Ok(())
+}
 
-    /// Save to JSON file
-    /// Note: this shouldn't be used
-    pub fn save(&self, path: PathBuf) -> Result<()> {
-        let file = File::create(path)?;
-        serde_json::to_writer_pretty(file, self)?;
-        Ok(())
+#[test]
+fn test_csv_round_trip() -> Result<()> {
+    use chrono::TimeZone;
+
+    let mut index = Index::new();
+    index.files = vec![
+        IndexedFile {
+            path: PathBuf::from("/home/user/notes, v2.md"),
+            data_type: Some(String::from("text/markdown")),
+            tags: vec![String::from("work"), String::from("a, b")],
+            last_modified: Utc.timestamp_opt(1_600_000_000, 0).unwrap(),
+            file_size: 42,
+            ..Default::default()
+        },
+        IndexedFile {
+            path: PathBuf::from("/home/user"),
+            file_type: FileType::Directory,
+            ..Default::default()
+        },
+    ];
+    index.compact();
+
+    let mut buf = Vec::new();
+    index.write_csv(&mut buf)?;
+    let csv = String::from_utf8(buf.clone())?;
+    let header = "path,file_type,data_type,tags,last_modified,last_indexed,file_size\n";
+    assert!(csv.starts_with(header));
+    assert!(csv.contains("\"work|a, b\""));
+
+    let loaded = Index::read_csv(buf.as_slice())?;
+    assert_eq!(loaded.files, index.files);
+
+    let mut buf = Vec::new();
+    index.write_csv_with_metadata(&mut buf)?;
+    assert!(buf.starts_with(CSV_METADATA_PREFIX.as_bytes()));
+    let loaded = Index::read_csv(buf.as_slice())?;
+    assert_eq!(loaded.files, index.files);
+    assert_eq!(loaded.metadata, index.metadata);
+
+    // only the path is required
+    let loaded = Index::read_csv("path,file_size\n/owo,3\n".as_bytes())?;
+    assert_eq!(loaded.files[0].path, PathBuf::from("/owo"));
+    assert_eq!(loaded.files[0].file_size, 3);
+    assert_eq!(loaded.files[0].data_type, None);
+    assert!(loaded.files[0].tags.is_empty());
+    assert!(Index::read_csv("file_size\n3\n".as_bytes()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_relative_path() {
+    let root = Path::new("/home/user/project");
+    let file = |path: &str| IndexedFile {
+        path: PathBuf::from(path),
+        ..Default::default()
+    };
+
+    let inside = file("/home/user/project/src/main.rs");
+    assert_eq!(
+        inside.relative_path(root).unwrap(),
+        Path::new("src/main.rs")
+    );
+    assert_eq!(inside.display_path(Some(root)), Path::new("src/main.rs"));
+    assert_eq!(inside.display_path(None), inside.path);
+
+    // only whole components count, so a sibling with a longer name is outside
+    for outside in ["/home/user/other/main.rs", "/home/user/project2/main.rs"] {
+        let outside = file(outside);
+        assert!(outside.relative_path(root).is_err());
+        assert_eq!(outside.display_path(Some(root)), outside.path);
     }
 
-    pub fn load(path: PathBuf) -> Result<Self> {
-        let file = File::open(path)?;
-        let index = serde_json::from_reader(file)?;
-        Ok(index)
+    let root_entry = file("/home/user/project");
+    assert_eq!(root_entry.relative_path(root).unwrap(), Path::new(""));
+    assert_eq!(root_entry.display_path(Some(root)), Path::new("."));
+}
+
+#[test]
+fn test_openers() -> Result<()> {
+    use std::io::Seek;
+
+    let dir = tempfile::tempdir()?;
+    // the file doesn't exist yet, so it can't be indexed
+    let file = IndexedFile {
+        path: dir.path().join("notes.txt"),
+        ..Default::default()
+    };
+    assert!(file.open_readonly().is_err());
+    assert!(file.open_readwrite().is_err());
+    assert!(file.try_open_readonly().is_none());
+    assert!(!file.is_readable());
+    assert!(!file.is_writable());
+    // opening for writing doesn't create it
+    assert!(!file.path.exists());
+
+    std::fs::write(&file.path, "owo")?;
+    let mut contents = String::new();
+    file.try_open_readonly()
+        .unwrap()
+        .read_to_string(&mut contents)?;
+    assert_eq!(contents, "owo");
+    assert!(file.open_readonly()?.write_all(b"uwu").is_err());
+
+    let mut rw = file.open_readwrite()?;
+    rw.write_all(b"uwu")?;
+    rw.rewind()?;
+    contents.clear();
+    rw.read_to_string(&mut contents)?;
+    assert_eq!(contents, "uwu");
+    assert!(file.is_readable());
+    assert!(file.is_writable());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        std::fs::set_permissions(&file.path, std::fs::Permissions::from_mode(0o400))?;
+        // root can write anything, no matter the permissions
+        let root = nix::unistd::geteuid().is_root();
+        assert!(file.is_readable());
+        assert_eq!(file.is_writable(), root);
+        assert_eq!(file.open_readwrite().is_ok(), root);
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_xattrs() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("notes.txt");
+    std::fs::write(&path, "owo")?;
+    match xattr::set(&path, "user.comment", b"hello") {
+        // e.g. a tmpfs without user xattrs, there's nothing to test then
+        Err(e) if e.raw_os_error() == Some(nix::errno::Errno::ENOTSUP as i32) => return Ok(()),
+        result => result?,
+    }
+
+    let file = IndexedFile::new_with_config(path.clone(), &IndexConfig::default())?;
+    assert!(file.xattrs.is_empty());
+
+    let file = IndexedFile::new(path)?;
+    assert_eq!(file.xattrs["user.comment"], b"hello");
+
+    let json = serde_json::to_value(&file)?;
+    assert_eq!(json["xattrs"]["user.comment"], "aGVsbG8=");
+    assert_eq!(serde_json::from_value::<IndexedFile>(json)?, file);
+
+    Ok(())
+}
+
+#[test]
+fn test_hash_algorithms() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut index = Index::new();
+    for name in ["a", "b", "c", "different"] {
+        let path = dir.path().join(name);
+        std::fs::write(&path, if name == "different" { "owo" } else { "" })?;
+        // hashed with the default algorithm
+        index.files.push(IndexedFile::new(path)?);
     }
+
+    index.files[0].compute_hash_with_algo(HashAlgorithm::Sha256)?;
+    assert_eq!(
+        index.files[0].content_hash.as_deref(),
+        Some("sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+    );
+    assert_eq!(index.files[0].content_hash_algorithm, HashAlgorithm::Sha256);
+    index.files[1].compute_hash_with_algo(HashAlgorithm::Md5)?;
+    assert_eq!(
+        index.files[1].content_hash.as_deref(),
+        Some("md5:d41d8cd98f00b204e9800998ecf8427e")
+    );
+    index.files[1].compute_hash()?;
+    assert_eq!(
+        index.files[1].content_hash.as_deref(),
+        Some("blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262")
+    );
+
+    // the same contents hashed differently aren't duplicates
+    let groups = index.find_duplicates();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].len(), 2);
+    assert!(groups[0]
+        .iter()
+        .all(|f| f.content_hash_algorithm == HashAlgorithm::Blake3));
+
+    index.files[2].path = dir.path().join("missing");
+    assert_eq!(index.rehash_all(HashAlgorithm::Sha256), 3);
+    assert_eq!(index.files[2].content_hash, None);
+    let groups = index.find_duplicates();
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups[0].len(), 2);
+    assert!(groups[0]
+        .iter()
+        .all(|f| f.content_hash_algorithm == HashAlgorithm::Sha256));
+
+    // hashes in older indexes are SHA-256
+    let mut json = serde_json::to_value(&index.files[0])?;
+    json.as_object_mut()
+        .unwrap()
+        .remove("content_hash_algorithm");
+    let file: IndexedFile = serde_json::from_value(json)?;
+    assert_eq!(file.content_hash_algorithm, HashAlgorithm::Sha256);
+    Ok(())
 }
 
+// Every field must always be serialized (no `skip_serializing_if`),
+// otherwise non self-describing formats like bincode can't read it back.
+// New fields need a `#[serde(default)]` so older indexes still load
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub struct IndexedFile {
     pub path: PathBuf,
@@ -98,12 +3406,78 @@ pub struct IndexedFile {
         deserialize_with = "filetype_deserializer"
     )]
     pub file_type: FileType,
+    /// Permission bits of the file (`mode & 0o7777`), `0o644` on non-Unix platforms
+    #[serde(default = "default_permissions")]
+    pub permissions: u32,
+    /// UID of the file owner, `0` on non-Unix platforms
+    #[serde(default)]
+    pub owner_uid: u32,
+    /// GID of the file owner, `0` on non-Unix platforms
+    #[serde(default)]
+    pub owner_gid: u32,
     // optional because sometimes we don't know the type
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
     pub data_type: Option<String>,
+    /// How sure MIME detection was about `data_type`, as a percentage.
+    /// `infer` doesn't report this itself, so it's approximated: `100` when the magic bytes
+    /// matched, `50` when only the path did (the extension or a custom MIME rule),
+    /// `0` for unknown types. `None` when the MIME type wasn't detected at all
+    #[serde(default)]
+    pub content_type_confidence: Option<u8>,
     pub tags: Vec<String>,
     pub last_modified: DateTime<Utc>,
     pub last_indexed: DateTime<Utc>,
+    /// Birth time of the file.
+    /// Only available on platforms and filesystems that record it
+    /// (macOS, Windows, and Linux with statx on e.g. ext4 or btrfs), `None` everywhere else
+    #[serde(default)]
+    pub created: Option<DateTime<Utc>>,
+    /// Last access time of the file.
+    /// Not reliable: filesystems mounted with `noatime` never update it (so it usually stays
+    /// at the creation or modification time), and `relatime`, the default on Linux, only
+    /// updates it about once a day. Indexing reads the start of every regular file,
+    /// but with `O_NOATIME` on Linux where permitted, see `read_head`.
+    /// Hashing and previews may still update it
+    #[serde(default)]
+    pub accessed: Option<DateTime<Utc>>,
+    /// Hash of the file contents prefixed with the algorithm, e.g. `blake3:af13...`,
+    /// only set for regular files when hashing is enabled, see `IndexConfig::compute_hash`.
+    /// Hashes from older indexes are plain hex encoded SHA-256, see `Index::rehash_all`
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// The algorithm `content_hash` was computed with
+    #[serde(default = "legacy_hash_algorithm")]
+    pub content_hash_algorithm: HashAlgorithm,
+    /// Size of the file in bytes, as reported by its metadata
+    #[serde(default)]
+    pub file_size: u64,
+    /// Inode number of the file, `0` on non-Unix platforms.
+    /// Hard links to the same file share an inode
+    #[serde(default)]
+    pub inode: u64,
+    /// Number of hard links to the file, `1` on non-Unix platforms.
+    /// For symlinks this is the link count of the symlink itself, not its target
+    #[serde(default = "default_hardlink_count")]
+    pub hardlink_count: u32,
+    /// ID of the device (filesystem) the file is on, `0` on non-Unix platforms
+    #[serde(default)]
+    pub device: u64,
+    /// Whether the start of the file looks like text, see `looks_like_text`.
+    /// `None` for anything other than regular files, or if the file couldn't be read
+    #[serde(default)]
+    pub is_text: Option<bool>,
+    /// Whether the file has holes, i.e. takes up less space on disk than its size.
+    /// Only detected on Linux, always `false` elsewhere
+    #[serde(default)]
+    pub sparse: bool,
+    /// Every extended attribute of the file, by name, e.g. `user.comment`.
+    /// Only read when `IndexConfig::index_xattrs` is set. Values are base64 in JSON
+    #[serde(default, with = "base64_values")]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// The start of the file, for showing next to results.
+    /// Never persisted, and only set once `load_preview` is called
+    #[serde(skip)]
+    pub content_preview: Option<String>,
 }
 
 impl Default for IndexedFile {
@@ -111,40 +3485,460 @@ impl Default for IndexedFile {
         Self {
             path: PathBuf::new(),
             file_type: FileType::Regular,
+            permissions: default_permissions(),
+            owner_uid: 0,
+            owner_gid: 0,
             data_type: None,
+            content_type_confidence: None,
             tags: Vec::new(),
             last_modified: DateTime::default(),
             last_indexed: Utc::now(),
+            created: None,
+            accessed: None,
+            content_hash: None,
+            content_hash_algorithm: HashAlgorithm::default(),
+            file_size: 0,
+            inode: 0,
+            hardlink_count: default_hardlink_count(),
+            device: 0,
+            is_text: None,
+            sparse: false,
+            xattrs: BTreeMap::new(),
+            content_preview: None,
         }
     }
 }
 
 impl IndexedFile {
+    /// Index the file at `path` with everything `IndexConfig` can read enabled,
+    /// including the content hash and extended attributes
     pub fn new(path: PathBuf) -> Result<Self> {
+        let config = IndexConfig {
+            compute_hash: true,
+            index_xattrs: true,
+            ..Default::default()
+        };
+        Self::new_with_config(path, &config)
+    }
+
+    /// Like `new`, but following `config`:
+    /// fails with `DatchaniError::FileTooLarge` for regular files larger than
+    /// `max_file_size` bytes, before anything is read from them,
+    /// and applies `custom_mime_rules` on top of the detected MIME type.
+    /// `compute_mime`, `compute_hash`, `read_access_time` and `read_permissions`
+    /// leave their fields at the defaults when disabled
+    pub fn new_with_config(path: PathBuf, config: &IndexConfig) -> Result<Self> {
         let file_type = FileType::from_path(&path).map_err(Report::from)?;
-        let data_type = {
-            let d: Option<Option<infer::Type>> = None;
-            d.map(|t| {
-                t.map(|t| t.to_string())
-                    .unwrap_or_else(|| "application/octet-stream".to_string())
-            })
+        let metadata = path.metadata()?;
+        if let Some(max) = config.max_file_size {
+            if metadata.is_file() && metadata.len() > max {
+                return Err(DatchaniError::FileTooLarge {
+                    path,
+                    size: metadata.len(),
+                }
+                .into());
+            }
+        }
+
+        // read once, for both MIME detection and `is_text`
+        let head = if metadata.is_file() {
+            read_head(&path, MIME_SNIFF_LEN).ok()
+        } else {
+            None
+        };
+        let (data_type, content_type_confidence) = if !config.compute_mime {
+            (None, None)
+        } else if let Some(mime) = config.custom_mime_rules.lookup(&path) {
+            // user rules win over whatever would be detected, but only go by the path
+            (Some(mime.to_string()), Some(50))
+        } else if metadata.is_file() {
+            let (mime, confidence) = detect_mime(&path, head.as_deref().unwrap_or_default());
+            (Some(mime), Some(confidence))
+        } else {
+            (None, None)
         };
 
-        let last_modified: chrono::DateTime<Utc> = path.metadata()?.modified()?.into();
+        let last_modified: chrono::DateTime<Utc> = metadata.modified()?.into();
+        let created = metadata.created().ok().map(Into::into);
+        let accessed = if config.read_access_time {
+            metadata.accessed().ok().map(Into::into)
+        } else {
+            None
+        };
+        let is_text = head.map(|head| looks_like_text(&head));
 
-        Ok(Self {
+        #[cfg(unix)]
+        let (permissions, owner_uid, owner_gid, inode, hardlink_count, device) = {
+            use std::os::unix::fs::MetadataExt;
+            // `metadata` follows symlinks, but the link count should be the symlink's own
+            let nlink = path.symlink_metadata()?.nlink();
+            (
+                metadata.mode() & 0o7777,
+                metadata.uid(),
+                metadata.gid(),
+                metadata.ino(),
+                u32::try_from(nlink).unwrap_or(u32::MAX),
+                metadata.dev(),
+            )
+        };
+        #[cfg(not(unix))]
+        let (permissions, owner_uid, owner_gid, inode, hardlink_count, device) =
+            (default_permissions(), 0, 0, 0, default_hardlink_count(), 0);
+        let (permissions, owner_uid, owner_gid) = if config.read_permissions {
+            (permissions, owner_uid, owner_gid)
+        } else {
+            (default_permissions(), 0, 0)
+        };
+
+        // `blocks` counts 512 byte units, whatever the filesystem's block size
+        #[cfg(target_os = "linux")]
+        let sparse = {
+            use std::os::unix::fs::MetadataExt;
+            metadata.is_file() && metadata.blocks() * 512 < metadata.len()
+        };
+        #[cfg(not(target_os = "linux"))]
+        let sparse = false;
+
+        let xattrs = if config.index_xattrs {
+            read_xattrs(&path)
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut file = Self {
             path,
             file_type,
+            permissions,
+            owner_uid,
+            owner_gid,
             data_type,
+            content_type_confidence,
             tags: Vec::new(),
             last_modified,
+            created,
+            accessed,
+            file_size: metadata.len(),
+            inode,
+            hardlink_count,
+            device,
+            is_text,
+            sparse,
+            xattrs,
 
             ..Default::default()
-        })
+        };
+        if config.compute_hash {
+            file.compute_hash_with_algo(config.hash_algorithm)?;
+        }
+        Ok(file)
+    }
+
+    /// The path relative to `root`, fails if the file isn't inside `root`
+    pub fn relative_path(&self, root: &Path) -> Result<PathBuf> {
+        self.path
+            .strip_prefix(root)
+            .map(Path::to_path_buf)
+            .map_err(|_| eyre!("{} is not inside {}", self.path.display(), root.display()))
+    }
+
+    /// The path to show to the user, relative to `root` if the file is inside it.
+    /// `root` itself is shown as `.`, files outside of it keep their full path
+    pub fn display_path(&self, root: Option<&Path>) -> Cow<'_, Path> {
+        match root.and_then(|root| self.path.strip_prefix(root).ok()) {
+            Some(relative) if relative.as_os_str().is_empty() => Cow::Owned(PathBuf::from(".")),
+            Some(relative) => Cow::Borrowed(relative),
+            None => Cow::Borrowed(&self.path),
+        }
+    }
+
+    /// Read up to `max_bytes` from the start of the file and keep the first
+    /// `PREVIEW_CHARS` characters in `content_preview`, if they're text.
+    /// Binary files and anything other than regular files get no preview
+    pub fn load_preview(&mut self, max_bytes: usize) -> Result<Option<String>> {
+        self.content_preview = None;
+        if self.file_type == FileType::Regular {
+            let mut buf = Vec::new();
+            self.open_readonly()?
+                .take(max_bytes as u64)
+                .read_to_end(&mut buf)?;
+            self.content_preview =
+                text_prefix(&buf).map(|text| text.chars().take(PREVIEW_CHARS).collect());
+        }
+        Ok(self.content_preview.clone())
+    }
+
+    /// Hash the file contents with the default algorithm, see `compute_hash_with_algo`
+    pub fn compute_hash(&mut self) -> Result<()> {
+        self.compute_hash_with_algo(HashAlgorithm::default())
+    }
+
+    /// Hash the file contents with `algo` and store it in `content_hash`.
+    /// Does nothing for anything other than regular files
+    pub fn compute_hash_with_algo(&mut self, algo: HashAlgorithm) -> Result<()> {
+        if self.file_type != FileType::Regular {
+            return Ok(());
+        }
+        if algo == HashAlgorithm::Md5 {
+            warn!(
+                "MD5 is deprecated, hashing {} with it anyway",
+                self.path.display()
+            );
+        }
+        let hash = algo.hash_reader(&mut self.open_readonly()?)?;
+        self.content_hash = Some(format!("{}:{}", algo.name(), hash));
+        self.content_hash_algorithm = algo;
+        Ok(())
     }
 
     /// Tries to open the file and returns a `File` pointer.
+    #[deprecated(note = "use `open_readonly`, which says what it opens the file for")]
     pub fn open(&self) -> Result<File> {
+        self.open_readonly()
+    }
+
+    /// Open the file for reading
+    pub fn open_readonly(&self) -> Result<File> {
         File::open(&self.path).map_err(Report::from)
     }
+
+    /// Open the file for reading and writing, without truncating or creating it
+    pub fn open_readwrite(&self) -> Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .map_err(Report::from)
+    }
+
+    /// Like `open_readonly`, but `None` if the file can't be opened
+    pub fn try_open_readonly(&self) -> Option<File> {
+        File::open(&self.path).ok()
+    }
+
+    /// Whether the current user can read the file, without opening it.
+    /// Checked with `access(2)` on Unix, elsewhere any file that exists counts
+    pub fn is_readable(&self) -> bool {
+        #[cfg(unix)]
+        {
+            AccessMode::Read.check_live(&self.path)
+        }
+        #[cfg(not(unix))]
+        {
+            std::fs::metadata(&self.path).is_ok()
+        }
+    }
+
+    /// Whether the current user can write to the file, without opening it.
+    /// Checked with `access(2)` on Unix, elsewhere by the read-only attribute
+    pub fn is_writable(&self) -> bool {
+        #[cfg(unix)]
+        {
+            AccessMode::Write.check_live(&self.path)
+        }
+        #[cfg(not(unix))]
+        {
+            std::fs::metadata(&self.path).map_or(false, |m| !m.permissions().readonly())
+        }
+    }
+}
+
+/// Key of the extension index, see `Index::files_by_extension_fast`
+fn extension_key(file: &IndexedFile) -> Option<&str> {
+    file.path.extension()?.to_str()
+}
+
+/// Key of the MIME index, see `Index::files_by_mime_fast`
+fn mime_key(file: &IndexedFile) -> Option<&str> {
+    file.data_type.as_deref()
+}
+
+/// Count every value and keep the `k` most frequent, ties in alphabetical order.
+/// Counting is a single pass, only the distinct values are sorted
+fn top_k<'a>(values: impl Iterator<Item = &'a str>, k: usize) -> Vec<(String, usize)> {
+    let mut counts = HashMap::new();
+    for value in values {
+        *counts.entry(value).or_insert(0) += 1;
+    }
+    let mut counts = counts
+        .into_iter()
+        .map(|(value, count)| (value.to_string(), count))
+        .collect::<Vec<_>>();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    counts.truncate(k);
+    counts
+}
+
+/// `n` random items using reservoir sampling (Algorithm R), so only `n` are kept at a time
+fn reservoir_sample<T>(items: impl Iterator<Item = T>, n: usize, seed: u64) -> Vec<T> {
+    let mut rng = SmallRng::seed_from_u64(seed);
+    // `n` can be far more than there are items, e.g. `usize::MAX` for all of them
+    let mut reservoir = Vec::with_capacity(n.min(items.size_hint().0));
+    for (i, item) in items.enumerate() {
+        if i < n {
+            reservoir.push(item);
+        } else {
+            let j = rng.gen_range(0..=i);
+            if j < n {
+                reservoir[j] = item;
+            }
+        }
+    }
+    // the first `n` items are kept in order, which shouldn't show
+    reservoir.shuffle(&mut rng);
+    reservoir
+}
+
+/// Every extended attribute of `path` that can be read.
+/// Filesystems without xattr support just have none
+fn read_xattrs(path: &Path) -> BTreeMap<String, Vec<u8>> {
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) => {
+            debug!("Can't list the xattrs of {}: {}", path.display(), e);
+            return BTreeMap::new();
+        }
+    };
+    names
+        .filter_map(|name| {
+            let value = xattr::get(path, &name).ok()??;
+            Some((name.into_string().ok()?, value))
+        })
+        .collect()
+}
+
+/// xattr values are arbitrary bytes, so they're stored as base64 strings
+mod base64_values {
+    use std::collections::BTreeMap;
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        values: &BTreeMap<String, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            values
+                .iter()
+                .map(|(key, value)| (key, STANDARD.encode(value))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(key, value)| {
+                let value = STANDARD.decode(value).map_err(serde::de::Error::custom)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
+
+/// User-defined MIME types for paths matching a glob, e.g. `*.pbxproj`,
+/// for formats MIME detection doesn't know about. See `IndexConfig::custom_mime_rules`
+#[derive(Debug, Clone, Default)]
+pub struct MimeRules {
+    mimes: Vec<String>,
+    globs: GlobSet,
+}
+
+impl MimeRules {
+    /// Compile `(glob_pattern, mime_type)` pairs, fails if any pattern is invalid
+    pub fn new(rules: Vec<(String, String)>) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        let mut mimes = Vec::with_capacity(rules.len());
+        for (pattern, mime) in rules {
+            builder.add(Glob::new(&pattern)?);
+            mimes.push(mime);
+        }
+        Ok(Self {
+            mimes,
+            globs: builder.build()?,
+        })
+    }
+
+    /// The MIME type for `path`, if a rule matches it.
+    /// If several rules match, the first one wins
+    pub fn lookup(&self, path: &Path) -> Option<&str> {
+        let first = self.globs.matches(path).into_iter().min()?;
+        Some(&self.mimes[first])
+    }
+}
+
+/// How many bytes `looks_like_text` looks at
+const SNIFF_LEN: u64 = 512;
+
+/// How many bytes MIME detection looks at, the same as `infer::get_from_path`
+const MIME_SNIFF_LEN: u64 = 8192;
+
+/// How many characters `IndexedFile::load_preview` keeps
+pub const PREVIEW_CHARS: usize = 256;
+
+/// How many bytes to read for a preview, enough for `PREVIEW_CHARS` characters of any UTF-8
+pub const PREVIEW_BYTES: usize = PREVIEW_CHARS * 4;
+
+/// The longest valid UTF-8 prefix of `buf`, or `None` if it doesn't look like text:
+/// it contains a null byte or invalid UTF-8. A multi-byte character cut off at the
+/// end of the buffer is fine, since `buf` is usually only the start of a file
+fn text_prefix(buf: &[u8]) -> Option<&str> {
+    if buf.contains(&0) {
+        return None;
+    }
+    match std::str::from_utf8(buf) {
+        Ok(text) => Some(text),
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&buf[..e.valid_up_to()]).ok(),
+        Err(_) => None,
+    }
+}
+
+/// Guess whether a file is text from `head`, its first bytes (only 512 are looked at):
+/// text is valid UTF-8 without any null bytes. Empty files count as text
+fn looks_like_text(head: &[u8]) -> bool {
+    text_prefix(&head[..head.len().min(SNIFF_LEN as usize)]).is_some()
+}
+
+/// The first `len` bytes of the file at `path`, read with `O_NOATIME` where permitted.
+/// Every indexed file is read like this, so this tries not to update its access time
+fn read_head(path: &Path, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    open_noatime(path)?.take(len).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// The MIME type of a regular file and how sure that is, in percent: `100` when
+/// `infer` recognised the magic bytes at the start of the file (`head`), `50` when only
+/// the extension is known, and `application/octet-stream` with `0` for anything else
+fn detect_mime(path: &Path, head: &[u8]) -> (String, u8) {
+    if let Some(kind) = infer::get(head) {
+        return (kind.mime_type().to_string(), 100);
+    }
+    match new_mime_guess::from_path(path).first_raw() {
+        Some(mime) => (mime.to_string(), 50),
+        None => (String::from("application/octet-stream"), 0),
+    }
+}
+
+/// Open `path` for reading with `O_NOATIME`.
+/// Only the owner of a file may do that, everyone else gets a normal open
+#[cfg(target_os = "linux")]
+fn open_noatime(path: &Path) -> std::io::Result<File> {
+    use nix::fcntl::OFlag;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    File::options()
+        .read(true)
+        .custom_flags(OFlag::O_NOATIME.bits())
+        .open(path)
+        .or_else(|e| match e.kind() {
+            std::io::ErrorKind::PermissionDenied => File::open(path),
+            _ => Err(e),
+        })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_noatime(path: &Path) -> std::io::Result<File> {
+    File::open(path)
 }
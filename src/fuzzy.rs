@@ -0,0 +1,88 @@
+//! Fuzzy matching backends
+//! Scoring in `query` goes through `FuzzyMatcherAbstraction`, so matchers
+//! can be swapped (or benchmarked against each other) without touching the scoring logic
+
+use std::{fmt, sync::Mutex};
+
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use nucleo_matcher::{
+    pattern::{AtomKind, CaseMatching, Normalization, Pattern},
+    Config, Matcher, Utf32Str,
+};
+
+/// A fuzzy matcher scoring how well `needle` matches `haystack`
+pub trait FuzzyMatcherAbstraction: Send + Sync {
+    /// Score `needle` against `haystack`, higher is better.
+    /// Returns `None` if it doesn't match at all
+    fn score(&self, haystack: &str, needle: &str) -> Option<i64>;
+}
+
+impl dyn FuzzyMatcherAbstraction {
+    /// The matcher used when none is configured, currently `SkimAdapter`
+    #[allow(clippy::should_implement_trait)]
+    pub fn default() -> Box<Self> {
+        Box::new(SkimAdapter::default())
+    }
+}
+
+impl fmt::Debug for dyn FuzzyMatcherAbstraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FuzzyMatcher")
+    }
+}
+
+/// Fuzzy matching with skim's algorithm, using smart case
+pub struct SkimAdapter(SkimMatcherV2);
+
+impl Default for SkimAdapter {
+    fn default() -> Self {
+        Self(SkimMatcherV2::default().smart_case().use_cache(true))
+    }
+}
+
+impl FuzzyMatcherAbstraction for SkimAdapter {
+    fn score(&self, haystack: &str, needle: &str) -> Option<i64> {
+        self.0.fuzzy_match(haystack, needle)
+    }
+}
+
+/// Fuzzy matching with nucleo, using smart case.
+/// The needle is matched literally, nucleo's `^foo`/`foo$` pattern syntax isn't applied
+pub struct NucleoAdapter(Mutex<Matcher>);
+
+impl Default for NucleoAdapter {
+    fn default() -> Self {
+        Self(Mutex::new(Matcher::new(Config::DEFAULT.match_paths())))
+    }
+}
+
+impl FuzzyMatcherAbstraction for NucleoAdapter {
+    fn score(&self, haystack: &str, needle: &str) -> Option<i64> {
+        let pattern = Pattern::new(
+            needle,
+            CaseMatching::Smart,
+            Normalization::Smart,
+            AtomKind::Fuzzy,
+        );
+        let mut buf = Vec::new();
+        let mut matcher = self.0.lock().unwrap();
+        pattern
+            .score(Utf32Str::new(haystack, &mut buf), &mut matcher)
+            .map(i64::from)
+    }
+}
+
+#[test]
+fn test_matchers() {
+    let matchers: Vec<Box<dyn FuzzyMatcherAbstraction>> = vec![
+        <dyn FuzzyMatcherAbstraction>::default(),
+        Box::new(NucleoAdapter::default()),
+    ];
+
+    for matcher in matchers {
+        let exact = matcher.score("src/query.rs", "query").unwrap();
+        let scattered = matcher.score("src/query.rs", "qrs").unwrap();
+        assert!(exact > scattered);
+        assert_eq!(matcher.score("src/query.rs", "xyz"), None);
+    }
+}
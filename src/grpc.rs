@@ -0,0 +1,235 @@
+//! gRPC interface
+//! Remote access to an index served by `datchani serve`, see `proto/datchani.proto`.
+//!
+//! Like the `daemon` tasks, the service shares the index as an `Arc<ArcSwap<Index>>`.
+//! Searches run against whatever index is current when they start, and an `Index` call
+//! only swaps in the rebuilt index once the walk is complete, see `indexer::reindex_swap`.
+
+use std::{cmp::Reverse, net::SocketAddr, path::PathBuf, pin::Pin, sync::Arc};
+
+use arc_swap::ArcSwap;
+use futures_core::Stream;
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::files::{Index, IndexedFile};
+use crate::indexer::{self, IndexConfig};
+use crate::query;
+
+/// Types and service traits generated from `proto/datchani.proto`
+pub mod proto {
+    tonic::include_proto!("datchani");
+}
+
+use proto::daemon_service_server::{DaemonService, DaemonServiceServer};
+use proto::{FileResult, IndexRequest, IndexResponse, SearchRequest, SearchResponse, SortKey};
+
+/// Port `serve` listens on unless `--grpc-port` is given
+pub const DEFAULT_GRPC_PORT: u16 = 50051;
+
+pub struct GrpcService {
+    index: Arc<ArcSwap<Index>>,
+    /// Used for every re-index requested over gRPC
+    config: IndexConfig,
+}
+
+impl GrpcService {
+    pub fn new(index: Arc<ArcSwap<Index>>, config: IndexConfig) -> Self {
+        Self { index, config }
+    }
+
+    /// Wrap the service so it can be added to a tonic `Server`
+    pub fn into_server(self) -> DaemonServiceServer<Self> {
+        DaemonServiceServer::new(self)
+    }
+}
+
+impl From<(i64, IndexedFile)> for FileResult {
+    fn from((score, file): (i64, IndexedFile)) -> Self {
+        Self {
+            path: file.path.to_string_lossy().into_owned(),
+            score,
+            mime: file.data_type.unwrap_or_default(),
+            tags: file.tags,
+            last_modified: file.last_modified.timestamp(),
+        }
+    }
+}
+
+/// Sort results that are already sorted by score, newest and largest first
+fn sort_results(results: &mut [(i64, IndexedFile)], key: SortKey) {
+    match key {
+        SortKey::Score => {}
+        SortKey::Path => results.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path)),
+        SortKey::Modified => results.sort_by_key(|(_, f)| Reverse(f.last_modified)),
+        SortKey::Size => results.sort_by_key(|(_, f)| Reverse(f.file_size)),
+    }
+}
+
+type SearchStream = Pin<Box<dyn Stream<Item = Result<SearchResponse, Status>> + Send>>;
+
+// `Status` is large, but it's the error type of the generated trait
+#[allow(clippy::result_large_err)]
+#[tonic::async_trait]
+impl DaemonService for GrpcService {
+    type SearchStream = SearchStream;
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<Self::SearchStream>, Status> {
+        let request = request.into_inner();
+        let sort_by = request.sort_by();
+        let q = query::parse_query(&request.query)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let index = self.index.load_full();
+        // scoring every file blocks, keep it off the async runtime
+        let mut results = tokio::task::spawn_blocking(move || query::query(&q, &index))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        sort_results(&mut results, sort_by);
+        if request.limit > 0 {
+            results.truncate(request.limit as usize);
+        }
+        let responses = results.into_iter().map(|result| {
+            Ok(SearchResponse {
+                file: Some(result.into()),
+            })
+        });
+        Ok(Response::new(Box::pin(futures_util::stream::iter(
+            responses,
+        ))))
+    }
+
+    async fn index(
+        &self,
+        request: Request<IndexRequest>,
+    ) -> Result<Response<IndexResponse>, Status> {
+        let root = match request.into_inner().root_path {
+            path if path.is_empty() => self.index.load().root.clone().ok_or_else(|| {
+                Status::failed_precondition("the index has no root, pass a root_path")
+            })?,
+            path => PathBuf::from(path),
+        };
+        if !root.is_dir() {
+            return Err(Status::not_found(format!(
+                "{} is not a directory",
+                root.display()
+            )));
+        }
+
+        let stats = indexer::reindex_swap(&self.index, &root, &self.config)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(IndexResponse {
+            added: stats.added as u64,
+            removed: stats.removed as u64,
+            unchanged: stats.unchanged as u64,
+        }))
+    }
+}
+
+/// Serve `index` over gRPC on `addr`, until the server fails
+pub async fn serve(
+    index: Arc<ArcSwap<Index>>,
+    config: IndexConfig,
+    addr: SocketAddr,
+) -> color_eyre::Result<()> {
+    Server::builder()
+        .add_service(GrpcService::new(index, config).into_server())
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_grpc_client() -> color_eyre::Result<()> {
+    use proto::daemon_service_client::DaemonServiceClient;
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{transport::Channel, Code};
+
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("main.rs"), "fn main() {}")?;
+    std::fs::write(
+        dir.path().join("lib.rs"),
+        "pub fn lib() {}\n// a bit longer",
+    )?;
+    std::fs::write(dir.path().join("README.md"), "# readme")?;
+    let index = Arc::new(ArcSwap::from_pointee(Index::with_root(
+        dir.path().to_path_buf(),
+    )));
+
+    // a free port, so tests can run side by side
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let service = GrpcService::new(index.clone(), IndexConfig::default());
+    tokio::spawn(
+        Server::builder()
+            .add_service(service.into_server())
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+    let channel = Channel::from_shared(format!("http://{}", addr))?
+        .connect()
+        .await?;
+    let mut client = DaemonServiceClient::new(channel);
+
+    let search = |query: &str, limit: u32, sort_by: SortKey| SearchRequest {
+        query: query.to_string(),
+        limit,
+        sort_by: sort_by.into(),
+    };
+    let mut results = client
+        .search(search("ext:rs", 0, SortKey::Score))
+        .await?
+        .into_inner();
+    assert!(results.message().await?.is_none());
+
+    // an empty root_path re-walks the root of the index
+    let stats = client.index(IndexRequest::default()).await?.into_inner();
+    // the root directory is indexed too
+    assert_eq!((stats.added, stats.removed, stats.unchanged), (4, 0, 0));
+    assert_eq!(index.load().files.len(), 4);
+
+    let mut results = client
+        .search(search("ext:rs", 0, SortKey::Path))
+        .await?
+        .into_inner();
+    let mut files = Vec::new();
+    while let Some(response) = results.message().await? {
+        files.push(response.file.unwrap());
+    }
+    let paths = files.iter().map(|f| f.path.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        [
+            dir.path().join("lib.rs").to_str().unwrap(),
+            dir.path().join("main.rs").to_str().unwrap()
+        ]
+    );
+    let lib = index
+        .load()
+        .get_file(dir.path().join("lib.rs"))
+        .and_then(|f| f.data_type.clone());
+    assert_eq!(Some(files[0].mime.clone()).filter(|m| !m.is_empty()), lib);
+    assert!(files[0].last_modified > 0);
+
+    let mut results = client
+        .search(search("ext:rs", 1, SortKey::Size))
+        .await?
+        .into_inner();
+    let largest = results.message().await?.unwrap().file.unwrap();
+    assert!(largest.path.ends_with("lib.rs"));
+    assert!(results.message().await?.is_none());
+
+    let invalid = client.search(search("regex:[a-", 0, SortKey::Score)).await;
+    assert_eq!(invalid.unwrap_err().code(), Code::InvalidArgument);
+    let missing = client
+        .index(IndexRequest {
+            root_path: dir.path().join("missing").to_string_lossy().into_owned(),
+        })
+        .await;
+    assert_eq!(missing.unwrap_err().code(), Code::NotFound);
+
+    Ok(())
+}
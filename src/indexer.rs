@@ -1,32 +1,255 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use crate::query::{parse_query, query};
-use crate::{db::IndexBackend, files::IndexedFile};
+use crate::{
+    db::{InMemoryBackend, IndexBackend},
+    errors::DatchaniError,
+    files::{GcStats, HashAlgorithm, Index, IndexedFile, MimeRules},
+    tags,
+};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Utc};
 use async_stream::{stream, AsyncStream};
 use color_eyre::Result;
 use futures_util::{pin_mut, StreamExt};
 use ignore::WalkState;
+use notify::{RecursiveMode, Watcher};
 use rayon::prelude::*;
 use tokio::sync::mpsc;
-use tracing::{debug, info, log::warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
 use walkdir::WalkDir;
 
+/// Default for `IndexConfig::batch_size`
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// Default for `IndexConfig::channel_capacity`
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 512;
+
+/// How many entries `index_resumable` indexes between checkpoints
+const CHECKPOINT_INTERVAL: usize = 1000;
+
+/// Decides whether a walked path gets indexed, see `IndexConfig::custom_filter`
+pub type PathFilter = Arc<dyn Fn(&Path) -> bool + Send + Sync>;
+
+/// Options controlling what gets indexed, and how
+#[derive(Clone)]
+pub struct IndexConfig {
+    /// Skip hidden files and directories
+    pub skip_hidden: bool,
+    /// Respect `.gitignore`, `.ignore` and git exclude files
+    pub respect_ignore: bool,
+    /// Compute a content hash for regular files, see `IndexedFile::compute_hash`
+    pub compute_hash: bool,
+    /// What to hash with when `compute_hash` is set
+    pub hash_algorithm: HashAlgorithm,
+    /// Detect the MIME type of every regular file from its contents and extension,
+    /// and apply `custom_mime_rules`, see `IndexedFile::content_type_confidence`.
+    /// Without it `IndexedFile::data_type` is always `None`
+    pub compute_mime: bool,
+    /// Read the last access time, see `IndexedFile::accessed`
+    pub read_access_time: bool,
+    /// Read the permission bits and owner, otherwise they're left at their defaults
+    pub read_permissions: bool,
+    /// Read every extended attribute into `IndexedFile::xattrs`.
+    /// Off by default, since it costs a few extra syscalls per file
+    pub index_xattrs: bool,
+    /// Skip regular files larger than this many bytes.
+    /// Skipped files are never read, so they also don't go through MIME detection or hashing
+    pub max_file_size: Option<u64>,
+    /// How many entries `Indexer` sends to the backend at once, see `IndexBackend::batch_upsert`
+    pub batch_size: usize,
+    /// Don't descend into other filesystems mounted below the root, like `find -xdev`.
+    /// Only supported on Unix, elsewhere this does nothing
+    pub one_filesystem: bool,
+    /// How many entries `streaming_index` buffers before the walk waits for the consumer.
+    /// A larger buffer uses more memory, but lets the walker run ahead of a slow consumer
+    /// (e.g. a remote database) instead of stalling on every entry. Values below 1 are treated as 1
+    pub channel_capacity: usize,
+    /// MIME types for paths matching user-defined globs, overriding detection.
+    /// Configured with `[[custom_mime]]` tables, see `Config`
+    pub custom_mime_rules: MimeRules,
+    /// Called with the full path of every walked entry before it's indexed,
+    /// entries it returns `false` for are skipped without an error.
+    /// Directories it rejects are still descended into.
+    /// It runs on the walker's blocking threads, so it must not wait on async I/O
+    pub custom_filter: Option<PathFilter>,
+}
+
+impl fmt::Debug for IndexConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IndexConfig")
+            .field("skip_hidden", &self.skip_hidden)
+            .field("respect_ignore", &self.respect_ignore)
+            .field("compute_hash", &self.compute_hash)
+            .field("hash_algorithm", &self.hash_algorithm)
+            .field("compute_mime", &self.compute_mime)
+            .field("read_access_time", &self.read_access_time)
+            .field("read_permissions", &self.read_permissions)
+            .field("index_xattrs", &self.index_xattrs)
+            .field("max_file_size", &self.max_file_size)
+            .field("batch_size", &self.batch_size)
+            .field("one_filesystem", &self.one_filesystem)
+            .field("channel_capacity", &self.channel_capacity)
+            .field("custom_mime_rules", &self.custom_mime_rules)
+            .field("custom_filter", &self.custom_filter.is_some())
+            .finish()
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            skip_hidden: true,
+            respect_ignore: true,
+            compute_hash: false,
+            hash_algorithm: HashAlgorithm::default(),
+            compute_mime: true,
+            read_access_time: true,
+            read_permissions: true,
+            index_xattrs: false,
+            max_file_size: None,
+            batch_size: DEFAULT_BATCH_SIZE,
+            one_filesystem: false,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            custom_mime_rules: MimeRules::default(),
+            custom_filter: None,
+        }
+    }
+}
+
+/// Build a directory walker for `path` following `config`
+fn walk_builder(path: &Path, config: &IndexConfig) -> ignore::WalkBuilder {
+    let mut builder = ignore::WalkBuilder::new(path);
+    // standard_filters also toggles hidden file filtering, so it has to go first
+    builder
+        .standard_filters(config.respect_ignore)
+        .require_git(false)
+        .hidden(config.skip_hidden);
+    builder
+}
+
+/// Build the entry for a single walked path.
+/// Paths rejected by `custom_filter` and files over `max_file_size` are skipped quietly,
+/// any other error is logged
+fn index_entry(path: &Path, config: &IndexConfig) -> Option<IndexedFile> {
+    match try_index_entry(path, config)? {
+        Ok(file) => Some(file),
+        Err(e) => {
+            warn!("Error adding file: {}", e);
+            None
+        }
+    }
+}
+
+/// Like `index_entry`, but returns the errors instead of logging them.
+/// Filtered out and too large files are still skipped
+fn try_index_entry(
+    path: &Path,
+    config: &IndexConfig,
+) -> Option<std::result::Result<IndexedFile, DatchaniError>> {
+    if let Some(filter) = &config.custom_filter {
+        if !filter(path) {
+            return None;
+        }
+    }
+    match IndexedFile::new_with_config(path.to_path_buf(), config) {
+        Ok(file) => Some(Ok(file)),
+        Err(e) => match e.downcast_ref::<DatchaniError>() {
+            Some(DatchaniError::FileTooLarge { .. }) => None,
+            Some(e) => Some(Err(e.clone())),
+            None => Some(Err(DatchaniError::Unreadable(format!("{}: {}", path.display(), e)))),
+        },
+    }
+}
+
+/// Device of the filesystem `path` is on, without following symlinks
+#[cfg(unix)]
+fn device_of(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    path.symlink_metadata().ok().map(|m| m.dev())
+}
+
+/// The device walked entries have to be on, if `one_filesystem` is set
+fn root_device(path: &Path, config: &IndexConfig) -> Option<u64> {
+    if !config.one_filesystem {
+        return None;
+    }
+    #[cfg(unix)]
+    return device_of(path);
+    #[cfg(not(unix))]
+    {
+        debug!("one_filesystem is only supported on Unix, ignoring it");
+        None
+    }
+}
+
+/// Whether `path` is on a different device than `root_device`.
+/// Mount points are on the mounted device, so they're left out as well
+fn on_other_device(path: &Path, root_device: Option<u64>) -> bool {
+    #[cfg(unix)]
+    if let Some(root_device) = root_device {
+        return device_of(path).map_or(false, |device| device != root_device);
+    }
+    false
+}
+
+/// Walk `path` in parallel and collect every file found.
+/// This blocks until the walk is done, use `streaming_index` in async code
+pub fn walk(path: &Path, config: &IndexConfig) -> Vec<IndexedFile> {
+    let files = Mutex::new(Vec::new());
+    let root_device = root_device(path, config);
+
+    walk_builder(path, config).build_parallel().run(|| {
+        Box::new(|entry| {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => return WalkState::Continue,
+            };
+            if on_other_device(entry.path(), root_device) {
+                return WalkState::Skip;
+            }
+
+            if let Some(file) = index_entry(entry.path(), config) {
+                files.lock().unwrap().push(file);
+            }
+            WalkState::Continue
+        })
+    });
+
+    files.into_inner().unwrap()
+}
+
 // streaming indexer
 /// Streaming implementation of the indexer
-pub fn streaming_index(path: &Path) -> AsyncStream<IndexedFile, impl futures_core::Future<Output = ()>> {
+/// If `resume` is given (usually the index restored from a checkpoint),
+/// paths already in it are skipped.
+/// The walk runs on its own blocking threads, which wait whenever `channel_capacity`
+/// entries are waiting to be consumed, so they never hold up the async runtime.
+/// Dropping the stream stops the walk
+pub fn streaming_index(
+    path: &Path,
+    config: &IndexConfig,
+    resume: Option<&Index>,
+) -> AsyncStream<IndexedFile, impl futures_core::Future<Output = ()>> {
     let path = path.to_path_buf();
-    let (tx, mut rx) = mpsc::channel(100);
+    let config = config.clone();
+    let root_device = root_device(&path, &config);
+    let (tx, mut rx) = mpsc::channel(config.channel_capacity.max(1));
+    let skip = resume
+        .map(|index| index.files.iter().map(|f| f.path.clone()).collect::<HashSet<_>>())
+        .unwrap_or_default();
 
     let path = path.to_path_buf();
 
     tokio::task::spawn_blocking(move || {
-        ignore::WalkBuilder::new(path)
-            .git_ignore(true)
-            .git_exclude(true)
-            .ignore(true)
-            .require_git(false)
-            .hidden(true)
-            .standard_filters(true)
+        walk_builder(&path, &config)
             .build_parallel()
             .run(|| {
                 Box::new(|entry| {
@@ -35,12 +258,22 @@ pub fn streaming_index(path: &Path) -> AsyncStream<IndexedFile, impl futures_cor
                         Err(e) => return WalkState::Continue,
                     };
 
-                    let entry = match IndexedFile::new(entry.path().to_path_buf()) {
-                        Ok(e) => e,
-                        Err(e) => return WalkState::Continue,
+                    if on_other_device(entry.path(), root_device) {
+                        return WalkState::Skip;
+                    }
+                    if skip.contains(entry.path()) {
+                        return WalkState::Continue;
+                    }
+
+                    let entry = match index_entry(entry.path(), &config) {
+                        Some(e) => e,
+                        None => return WalkState::Continue,
                     };
 
-                    tx.blocking_send(entry);
+                    // the receiver is gone, nobody wants the rest of the walk
+                    if tx.blocking_send(entry).is_err() {
+                        return WalkState::Quit;
+                    }
 
                     WalkState::Continue
                 })
@@ -53,14 +286,393 @@ pub fn streaming_index(path: &Path) -> AsyncStream<IndexedFile, impl futures_cor
     }
 }
 
+/// How a walked or watched path differs from the index it's compared against
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexEvent {
+    /// A path that isn't in the index
+    Added(IndexedFile),
+    /// A path whose modification time or size changed, as `(old, new)`
+    Modified(IndexedFile, IndexedFile),
+    /// An indexed path that doesn't exist anymore
+    Removed(PathBuf),
+    /// A path that couldn't be indexed
+    Error(PathBuf, DatchaniError),
+}
+
+/// Compares indexed entries to fresh ones, for `streaming_index_with_events` and `watch_stream`
+struct EventClassifier {
+    known: HashMap<PathBuf, IndexedFile>,
+}
+
+impl EventClassifier {
+    fn new(index: &Index, root: &Path) -> Self {
+        let known = index
+            .files
+            .iter()
+            .filter(|f| f.path.starts_with(root))
+            .map(|f| (f.path.clone(), f.clone()))
+            .collect();
+        Self { known }
+    }
+
+    /// The event for a freshly indexed `file`, `None` if it's unchanged
+    fn indexed(&mut self, file: IndexedFile) -> Option<IndexEvent> {
+        match self.known.insert(file.path.clone(), file.clone()) {
+            None => Some(IndexEvent::Added(file)),
+            Some(old)
+                if old.last_modified != file.last_modified || old.file_size != file.file_size =>
+            {
+                Some(IndexEvent::Modified(old, file))
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// The event for a path that doesn't exist anymore, `None` if it wasn't indexed
+    fn removed(&mut self, path: &Path) -> Option<IndexEvent> {
+        self.known
+            .remove(path)
+            .map(|_| IndexEvent::Removed(path.to_path_buf()))
+    }
+}
+
+/// Walk `path` like `streaming_index`, but compare every entry against `existing`:
+/// new paths are `Added`, changed ones `Modified` and unchanged ones left out.
+/// Once the walk is done, paths below `path` in `existing` that weren't seen are `Removed`.
+/// Cancelling `cancel` stops the walk, and then nothing is reported as removed
+pub fn streaming_index_with_events(
+    path: &Path,
+    config: &IndexConfig,
+    existing: &Index,
+    cancel: CancellationToken,
+) -> impl futures_core::Stream<Item = IndexEvent> {
+    let path = path.to_path_buf();
+    let config = config.clone();
+    let root_device = root_device(&path, &config);
+    let (tx, mut rx) = mpsc::channel(config.channel_capacity.max(1));
+    let mut classifier = EventClassifier::new(existing, &path);
+
+    let walk_path = path.clone();
+    let walk_cancel = cancel.clone();
+    tokio::task::spawn_blocking(move || {
+        walk_builder(&walk_path, &config).build_parallel().run(|| {
+            Box::new(|entry| {
+                if walk_cancel.is_cancelled() {
+                    return WalkState::Quit;
+                }
+                let entry = match entry {
+                    Ok(e) => e,
+                    Err(_) => return WalkState::Continue,
+                };
+                if on_other_device(entry.path(), root_device) {
+                    return WalkState::Skip;
+                }
+                let result = match try_index_entry(entry.path(), &config) {
+                    Some(result) => result.map_err(|e| (entry.path().to_path_buf(), e)),
+                    None => return WalkState::Continue,
+                };
+                if tx.blocking_send(result).is_err() {
+                    return WalkState::Quit;
+                }
+                WalkState::Continue
+            })
+        });
+    });
+
+    stream! {
+        let mut seen = HashSet::new();
+        loop {
+            let result = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return,
+                result = rx.recv() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+            };
+            match result {
+                Ok(file) => {
+                    seen.insert(file.path.clone());
+                    if let Some(event) = classifier.indexed(file) {
+                        yield event;
+                    }
+                }
+                Err((path, e)) => {
+                    seen.insert(path.clone());
+                    yield IndexEvent::Error(path, e);
+                }
+            }
+        }
+        // the walk finished, so anything not seen is gone
+        let mut removed = classifier
+            .known
+            .keys()
+            .filter(|path| !seen.contains(*path))
+            .cloned()
+            .collect::<Vec<_>>();
+        removed.sort();
+        for path in removed {
+            if let Some(event) = classifier.removed(&path) {
+                yield event;
+            }
+        }
+    }
+}
+
+/// Watch `path` recursively and send the path of every change, until the watcher is dropped.
+/// Watching has started once this returns, so changes made right after aren't missed
+pub(crate) fn watch_paths(
+    path: &Path,
+) -> Result<(notify::RecommendedWatcher, mpsc::UnboundedReceiver<PathBuf>)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // indexing reads files too, which mustn't cause more events
+        match event {
+            Ok(event) if !event.kind.is_access() => {
+                for path in event.paths {
+                    let _ = tx.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Watch error: {}", e),
+        }
+    })?;
+    watcher.watch(path, RecursiveMode::Recursive)?;
+    Ok((watcher, rx))
+}
+
+/// Watch `path` for changes and report them as `IndexEvent`s, compared against `index`
+/// and then against the earlier events. Runs until `cancel` is cancelled
+pub fn watch_stream(
+    path: &Path,
+    config: &IndexConfig,
+    index: &Index,
+    cancel: CancellationToken,
+) -> Result<impl futures_core::Stream<Item = IndexEvent>> {
+    let config = config.clone();
+    let mut classifier = EventClassifier::new(index, path);
+    let (watcher, mut rx) = watch_paths(path)?;
+
+    Ok(stream! {
+        // moved in, so watching stops when the stream is dropped
+        let _watcher = watcher;
+        loop {
+            let path = tokio::select! {
+                biased;
+                _ = cancel.cancelled() => return,
+                path = rx.recv() => match path {
+                    Some(path) => path,
+                    None => return,
+                },
+            };
+            let event = if path.symlink_metadata().is_err() {
+                classifier.removed(&path)
+            } else {
+                match try_index_entry(&path, &config) {
+                    Some(Ok(file)) => classifier.indexed(file),
+                    Some(Err(e)) => Some(IndexEvent::Error(path, e)),
+                    None => None,
+                }
+            };
+            if let Some(event) = event {
+                yield event;
+            }
+        }
+    })
+}
+
+/// Index `root` into the index file at `index_path`, checkpointing every
+/// `CHECKPOINT_INTERVAL` entries. If an earlier run was interrupted, indexing
+/// resumes from its checkpoint instead of starting over.
+/// The checkpoint is deleted once the index has been saved
+pub async fn index_resumable(
+    root: &Path,
+    index_path: &Path,
+    config: &IndexConfig,
+) -> Result<Index> {
+    let mut index = if Index::checkpoint_path(index_path).exists() {
+        let (index, checkpoint) = Index::checkpoint_load(index_path)?;
+        info!(
+            "Resuming from checkpoint after {} entries ({})",
+            checkpoint.count,
+            checkpoint.last_path.display()
+        );
+        index
+    } else {
+        Index::with_root(root.to_path_buf())
+    };
+
+    let entries = streaming_index(root, config, Some(&index));
+    pin_mut!(entries);
+    while let Some(entry) = entries.next().await {
+        index.files.push(entry);
+        if index.files.len() % CHECKPOINT_INTERVAL == 0 {
+            index.checkpoint_save(index_path, index.files.len())?;
+        }
+    }
+
+    index.save(index_path.to_path_buf())?;
+    Index::checkpoint_clear(index_path)?;
+    Ok(index)
+}
+
+/// Re-walk `root` and atomically replace the index in `index` with the result.
+/// Readers keep getting the old index until the new one is complete, and never block.
+/// Both copies are alive until the swap, so this needs memory for two full indexes.
+/// If the index is swapped by someone else during the walk, e.g. by an eviction,
+/// the walk starts over from their index, so their change isn't lost
+pub async fn reindex_swap(
+    index: &ArcSwap<Index>,
+    root: &Path,
+    config: &IndexConfig,
+) -> Result<GcStats> {
+    loop {
+        let current = index.load_full();
+        let (new, stats) = {
+            let current = current.clone();
+            let root = root.to_path_buf();
+            let config = config.clone();
+            // walking blocks, keep it off the async runtime
+            tokio::task::spawn_blocking(move || current.gc(&root, &config)).await??
+        };
+        let previous = index.compare_and_swap(&current, Arc::new(new));
+        if Arc::ptr_eq(&previous, &current) {
+            return Ok(stats);
+        }
+        debug!("The index changed while re-walking {}, walking again", root.display());
+    }
+}
+
+/// An indexed file whose xattr tags differ from the tags in its entry, see `sync_tags`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagChange {
+    pub path: PathBuf,
+    /// The tags in the index
+    pub old: Vec<String>,
+    /// The tags in the file's xattrs, which replace `old`
+    pub new: Vec<String>,
+}
+
+/// What changed during `sync_tags`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncStats {
+    /// Entries whose tags were updated, or would be on a dry run
+    pub changes: Vec<TagChange>,
+    /// Entries whose tags already matched their xattrs
+    pub unchanged: usize,
+}
+
+/// Copy the xattr tags of every file indexed in `backend` below `root` into its entry.
+/// Files that aren't indexed are ignored, and so are entries whose file can't be read anymore.
+/// Entries that already match are left alone, so running this twice changes nothing.
+/// With `dry_run`, the changes are only returned and nothing is written
+pub async fn sync_tags(
+    backend: &mut dyn IndexBackend,
+    root: &Path,
+    dry_run: bool,
+) -> Result<SyncStats> {
+    let mut stats = SyncStats::default();
+    let mut updates = Vec::new();
+    {
+        let files = backend.all_files();
+        pin_mut!(files);
+        while let Some(mut file) = files.next().await.transpose()? {
+            if !file.path.starts_with(root) {
+                continue;
+            }
+            let tags = match file.path.to_str().map(tags::get_tags) {
+                Some(Ok(tags)) => tags,
+                _ => {
+                    debug!("Can't read the tags of {}, skipping it", file.path.display());
+                    continue;
+                }
+            };
+            if tags == file.tags {
+                stats.unchanged += 1;
+                continue;
+            }
+            info!("Tags of {} changed: {:?} -> {:?}", file.path.display(), file.tags, tags);
+            stats.changes.push(TagChange {
+                path: file.path.clone(),
+                old: std::mem::replace(&mut file.tags, tags.clone()),
+                new: tags,
+            });
+            updates.push(file);
+        }
+    }
+
+    if !dry_run {
+        for file in updates {
+            backend.update_file(file).await?;
+        }
+    }
+    Ok(stats)
+}
+
 // TODO: Generic Indexer trait
 // we are gonna index files for now
 
 pub struct Indexer {
     backend: Box<dyn IndexBackend>,
+    config: IndexConfig,
+    /// In-memory copy of the index, swapped out whole by `reindex`
+    index: Arc<ArcSwap<Index>>,
 }
 
 impl Indexer {
+    pub fn new(backend: Box<dyn IndexBackend>) -> Self {
+        Self::with_config(backend, IndexConfig::default())
+    }
+
+    pub fn with_config(backend: Box<dyn IndexBackend>, config: IndexConfig) -> Self {
+        Self {
+            backend,
+            config,
+            index: Arc::new(ArcSwap::from_pointee(Index::new())),
+        }
+    }
+
+    /// The current in-memory index. Cheap, and never waits for a running `reindex`
+    pub fn current_index(&self) -> Arc<Index> {
+        self.index.load_full()
+    }
+
+    /// A handle to the in-memory index, for readers that outlive the `Indexer`
+    pub fn shared_index(&self) -> Arc<ArcSwap<Index>> {
+        self.index.clone()
+    }
+
+    /// Rebuild the in-memory index from `root`, see `reindex_swap`
+    pub async fn reindex(&mut self, root: &Path) -> Result<GcStats> {
+        reindex_swap(&self.index, root, &self.config).await
+    }
+
+    /// Only index walked paths `filter` returns `true` for, see `IndexConfig::custom_filter`
+    pub fn with_filter(mut self, filter: PathFilter) -> Self {
+        self.config.custom_filter = Some(filter);
+        self
+    }
+
+    /// Index a single file, replacing any existing entry for the same path
+    pub async fn index_file(&mut self, path: &Path) -> Result<()> {
+        let entry = IndexedFile::new_with_config(path.to_path_buf(), &self.config)?;
+        self.backend.push_file(entry).await?;
+        info!("Indexed {}", path.display());
+        Ok(())
+    }
+
+    /// Update the tags of every indexed file below `root` from their xattrs, see `sync_tags`
+    pub async fn sync_tags(&mut self, root: &Path) -> Result<SyncStats> {
+        sync_tags(self.backend.as_mut(), root, false).await
+    }
+
+    /// Remove a single file from the index
+    pub async fn remove_file(&mut self, path: &Path) -> Result<()> {
+        self.backend.remove_file(path).await?;
+        info!("Removed {} from the index", path.display());
+        Ok(())
+    }
+
     async fn index_all(&mut self, path: &Path) -> Result<()> {
         // let (tx, mut rx) = mpsc::channel(100);
 
@@ -98,16 +710,55 @@ impl Indexer {
         //     self.backend.push_file(entry).await?;
         // }
 
-        let idx = streaming_index(&path);
+        let idx = streaming_index(path, &self.config, None);
         pin_mut!(idx);
+        // a batch size of 0 would never flush
+        let batch_size = self.config.batch_size.max(1);
+        let mut batch = Vec::with_capacity(batch_size);
+        let mut persisted = HashMap::new();
         while let Some(entry) = idx.next().await {
-            self.backend.push_file(entry).await?;
+            batch.push(entry);
+            if batch.len() >= batch_size {
+                self.upsert_batch(std::mem::take(&mut batch), &mut persisted)
+                    .await?;
+            }
+        }
+        if !batch.is_empty() {
+            self.upsert_batch(batch, &mut persisted).await?;
         }
 
 
         Ok(())
     }
 
+    /// Persist a batch of entries, dropping any path the parallel walker yielded twice.
+    /// `persisted` has the `last_indexed` of every path written so far in this run,
+    /// so a duplicate from an earlier batch is only written again if it's newer
+    async fn upsert_batch(
+        &mut self,
+        files: Vec<IndexedFile>,
+        persisted: &mut HashMap<PathBuf, DateTime<Utc>>,
+    ) -> Result<()> {
+        let mut batch = Index::new();
+        batch.files = files;
+        let mut removed = batch.deduplicate_by_path();
+        let before = batch.files.len();
+        batch.files.retain(|f| {
+            persisted
+                .get(&f.path)
+                .map_or(true, |&last_indexed| f.last_indexed > last_indexed)
+        });
+        removed += before - batch.files.len();
+        for file in &batch.files {
+            persisted.insert(file.path.clone(), file.last_indexed);
+        }
+        if removed > 0 {
+            debug!("Dropped {} duplicate entries", removed);
+        }
+        self.backend.batch_upsert(batch.files).await?;
+        Ok(())
+    }
+
     async fn watch() -> Result<()> {
         todo!()
     }
@@ -116,18 +767,13 @@ impl Indexer {
 #[tokio::test]
 async fn test_indexer() -> Result<()> {
     color_eyre::install()?;
-    pretty_env_logger::formatted_builder()
-        .parse_filters(
-           "debug"
-        )
-        .init();
-    let mut indexer = Indexer {
-        backend: Box::new(crate::db::SurrealBackend::new().await?),
-    };
+    tracing_subscriber::fmt().with_env_filter("debug").init();
+    let mut indexer = Indexer::new(Box::new(crate::db::SurrealBackend::new().await?));
 
     indexer.index_all(&std::env::current_dir()?).await?;
 
-    indexer.backend.query(&parse_query("ext:rs")?).await?;
+    let results = indexer.backend.query(&parse_query("ext:rs")?).await?;
+    assert!(!results.is_empty());
 
     // let results = indexer.backend.search("test").await?;
 
@@ -137,3 +783,463 @@ async fn test_indexer() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_upsert_batch_duplicates() -> Result<()> {
+    use chrono::TimeZone;
+
+    let file = |secs| IndexedFile {
+        path: PathBuf::from("main.rs"),
+        last_indexed: Utc.timestamp_opt(secs, 0).unwrap(),
+        ..Default::default()
+    };
+    let mut indexer = Indexer::new(Box::new(InMemoryBackend::default()));
+    let mut persisted = HashMap::new();
+    indexer
+        .upsert_batch(vec![file(1), file(2)], &mut persisted)
+        .await?;
+    // a duplicate in a later batch only replaces the entry if it's newer
+    indexer.upsert_batch(vec![file(0)], &mut persisted).await?;
+
+    let results = indexer.backend.query(&parse_query("")?).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].1.last_indexed, Utc.timestamp_opt(2, 0).unwrap());
+    indexer.upsert_batch(vec![file(3)], &mut persisted).await?;
+    let results = indexer.backend.query(&parse_query("")?).await?;
+    assert_eq!(results[0].1.last_indexed, Utc.timestamp_opt(3, 0).unwrap());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_file() -> Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let config = IndexConfig {
+        compute_hash: true,
+        ..Default::default()
+    };
+    let mut indexer = Indexer::with_config(Box::new(InMemoryBackend::default()), config);
+    let everything = parse_query("*")?;
+
+    // indexing twice should update the entry, not duplicate it
+    indexer.index_file(file.path()).await?;
+    indexer.index_file(file.path()).await?;
+    let results = indexer.backend.query(&everything).await?;
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].1.path, file.path());
+    assert!(results[0].1.content_hash.is_some());
+
+    indexer.remove_file(file.path()).await?;
+    assert!(indexer.backend.query(&everything).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_resumable() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    for i in 0..1200 {
+        std::fs::write(dir.path().join(format!("{}.txt", i)), "")?;
+    }
+    // keep the index and its checkpoint out of the directory being indexed
+    let out = tempfile::tempdir()?;
+    let index_path = out.path().join("index.json");
+
+    // simulate a run that crashed after checkpointing 500 entries
+    let mut partial = Index::with_root(dir.path().to_path_buf());
+    partial.files = walk(dir.path(), &IndexConfig::default());
+    partial.files.truncate(500);
+    partial.checkpoint_save(&index_path, 500)?;
+
+    let (restored, checkpoint) = Index::checkpoint_load(&index_path)?;
+    assert_eq!(checkpoint.count, 500);
+    assert_eq!(checkpoint.last_path, partial.files[499].path);
+    assert_eq!(restored.files, partial.files);
+
+    let index = index_resumable(dir.path(), &index_path, &IndexConfig::default()).await?;
+    // every file plus the root itself, with nothing indexed twice
+    let paths = index.files.iter().map(|f| &f.path).collect::<HashSet<_>>();
+    assert_eq!(index.files.len(), 1201);
+    assert_eq!(paths.len(), 1201);
+    assert_eq!(index.files[..500], partial.files[..]);
+
+    assert!(!Index::checkpoint_path(&index_path).exists());
+    assert_eq!(Index::load(index_path)?.files.len(), 1201);
+
+    Ok(())
+}
+
+#[test]
+fn test_max_file_size() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("small"), [0; 10])?;
+    std::fs::write(dir.path().join("large"), [0; 100])?;
+
+    let config = IndexConfig {
+        max_file_size: Some(50),
+        ..Default::default()
+    };
+    let err = IndexedFile::new_with_config(dir.path().join("large"), &config).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<DatchaniError>(),
+        Some(&DatchaniError::FileTooLarge {
+            path: dir.path().join("large"),
+            size: 100,
+        })
+    );
+
+    let mut names = walk(dir.path(), &config)
+        .into_iter()
+        .filter_map(|f| f.path.file_name().map(|n| n.to_owned()))
+        .collect::<Vec<_>>();
+    names.sort();
+    // the directory itself is never too large
+    assert_eq!(names, [dir.path().file_name().unwrap(), "small".as_ref()]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streaming_index_backpressure() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    for i in 0..200 {
+        std::fs::write(dir.path().join(format!("{}.txt", i)), "")?;
+    }
+    let config = IndexConfig {
+        channel_capacity: 1,
+        ..Default::default()
+    };
+
+    // a slow consumer, so the walker is constantly waiting on a full buffer
+    let idx = streaming_index(dir.path(), &config, None);
+    pin_mut!(idx);
+    let mut count = 0;
+    while idx.next().await.is_some() {
+        count += 1;
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(count, 201);
+
+    // dropping the stream early doesn't hang or panic the walker
+    let idx = streaming_index(dir.path(), &config, None);
+    pin_mut!(idx);
+    assert!(idx.next().await.is_some());
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_streaming_index_with_events() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let (changed, unchanged, removed) = (
+        dir.path().join("changed"),
+        dir.path().join("unchanged"),
+        dir.path().join("removed"),
+    );
+    for path in [&changed, &unchanged, &removed] {
+        std::fs::write(path, "")?;
+    }
+    let mut existing = Index::new();
+    existing.files = walk(dir.path(), &IndexConfig::default());
+
+    std::fs::write(&changed, "owo")?;
+    std::fs::remove_file(&removed)?;
+    std::fs::write(dir.path().join("added"), "")?;
+    // a symlink pointing nowhere can't be indexed
+    std::os::unix::fs::symlink(dir.path().join("missing"), dir.path().join("broken"))?;
+
+    let config = IndexConfig::default();
+    let events = streaming_index_with_events(
+        dir.path(),
+        &config,
+        &existing,
+        CancellationToken::new(),
+    )
+    .collect::<Vec<_>>()
+    .await;
+    let name = |path: &Path| path.file_name().unwrap().to_str().unwrap().to_owned();
+    let mut summary = events
+        .iter()
+        .filter_map(|event| match event {
+            IndexEvent::Added(file) => Some(format!("added {}", name(&file.path))),
+            IndexEvent::Modified(old, new) if old.path == changed => {
+                assert_eq!((old.file_size, new.file_size), (0, 3));
+                Some(String::from("modified changed"))
+            }
+            // the directory itself changed too
+            IndexEvent::Modified(..) => None,
+            IndexEvent::Removed(path) => Some(format!("removed {}", name(path))),
+            IndexEvent::Error(path, e) => {
+                assert!(matches!(e, DatchaniError::Unreadable(_)));
+                Some(format!("error {}", name(path)))
+            }
+        })
+        .collect::<Vec<_>>();
+    summary.sort();
+    assert_eq!(
+        summary,
+        ["added added", "error broken", "modified changed", "removed removed"]
+    );
+
+    // cancelled before it starts, so nothing counts as removed
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let events = streaming_index_with_events(dir.path(), &config, &Index::new(), cancel);
+    assert!(events.collect::<Vec<_>>().await.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_watch_stream() -> Result<()> {
+    use std::time::Duration;
+
+    let dir = tempfile::tempdir()?;
+    let existing = dir.path().join("existing");
+    std::fs::write(&existing, "")?;
+    let mut index = Index::new();
+    index.files = walk(dir.path(), &IndexConfig::default());
+
+    let cancel = CancellationToken::new();
+    let events = watch_stream(dir.path(), &IndexConfig::default(), &index, cancel.clone())?;
+    pin_mut!(events);
+    // waits for the next event about `path`, skipping the ones for the directory
+    async fn next_for(
+        events: &mut (impl futures_core::Stream<Item = IndexEvent> + Unpin),
+        path: &Path,
+    ) -> IndexEvent {
+        let timeout = Duration::from_secs(10);
+        loop {
+            let event = tokio::time::timeout(timeout, events.next()).await.unwrap().unwrap();
+            let event_path = match &event {
+                IndexEvent::Added(file) | IndexEvent::Modified(_, file) => file.path.clone(),
+                IndexEvent::Removed(path) | IndexEvent::Error(path, _) => path.clone(),
+            };
+            if event_path == path {
+                return event;
+            }
+        }
+    }
+
+    let added = dir.path().join("added");
+    std::fs::write(&added, "")?;
+    assert!(matches!(next_for(&mut events, &added).await, IndexEvent::Added(_)));
+
+    // writing truncates first, so the new size depends on when the event is handled
+    std::fs::write(&existing, "owo")?;
+    assert!(matches!(
+        next_for(&mut events, &existing).await,
+        IndexEvent::Modified(old, _) if old.file_size == 0
+    ));
+
+    std::fs::remove_file(&existing)?;
+    assert_eq!(
+        next_for(&mut events, &existing).await,
+        IndexEvent::Removed(existing.clone())
+    );
+
+    #[cfg(unix)]
+    {
+        let broken = dir.path().join("broken");
+        std::os::unix::fs::symlink(dir.path().join("missing"), &broken)?;
+        assert!(matches!(
+            next_for(&mut events, &broken).await,
+            IndexEvent::Error(..)
+        ));
+    }
+
+    cancel.cancel();
+    assert!(events.next().await.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_index_all_batched() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    for i in 0..25 {
+        std::fs::write(dir.path().join(format!("{}.txt", i)), "")?;
+    }
+    let config = IndexConfig {
+        batch_size: 10,
+        ..Default::default()
+    };
+    let mut indexer = Indexer::with_config(Box::new(InMemoryBackend::default()), config);
+
+    // 26 entries with the root, so the last batch is a partial one
+    indexer.index_all(dir.path()).await?;
+    let results = indexer.backend.query(&parse_query("*")?).await?;
+    assert_eq!(results.len(), 26);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_sync_tags() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let sub = dir.path().join("sub");
+    std::fs::create_dir(&sub)?;
+    std::fs::write(sub.join("tagged"), "")?;
+    match xattr::set(sub.join("tagged"), "user.tags", b"owo") {
+        // e.g. a tmpfs without user xattrs, there's nothing to test then
+        Err(e) if e.raw_os_error() == Some(nix::errno::Errno::ENOTSUP as i32) => return Ok(()),
+        result => result?,
+    }
+    for path in [sub.join("untagged"), dir.path().join("outside")] {
+        std::fs::write(&path, "")?;
+        tags::add_tag(path.to_str().unwrap(), "owo")?;
+    }
+    let mut indexer = Indexer::new(Box::new(InMemoryBackend::default()));
+    indexer.index_all(dir.path()).await?;
+    std::fs::write(sub.join("not-indexed"), "")?;
+    tags::add_tag(sub.join("not-indexed").to_str().unwrap(), "owo")?;
+    let untagged = File::open(sub.join("untagged"))?;
+    xattr::FileExt::remove_xattr(&untagged, "user.tags")?;
+
+    // nothing is written on a dry run
+    let stats = sync_tags(indexer.backend.as_mut(), &sub, true).await?;
+    assert_eq!(stats.changes.len(), 1);
+    assert_eq!(stats.changes[0].path, sub.join("tagged"));
+    assert!(indexer.backend.query(&parse_query("#owo")?).await?.is_empty());
+
+    let stats = indexer.sync_tags(&sub).await?;
+    assert_eq!(
+        stats.changes,
+        vec![TagChange {
+            path: sub.join("tagged"),
+            old: vec![],
+            new: vec![String::from("owo")],
+        }]
+    );
+    // the directory and the untagged file
+    assert_eq!(stats.unchanged, 2);
+    let results = indexer.backend.query(&parse_query("#owo")?).await?;
+    assert_eq!(results.len(), 1);
+
+    let stats = indexer.sync_tags(&sub).await?;
+    assert!(stats.changes.is_empty());
+    assert_eq!(stats.unchanged, 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_filter() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("notes.txt"), "")?;
+    std::fs::write(dir.path().join("debug.log"), "")?;
+    std::fs::create_dir(dir.path().join("logs"))?;
+    std::fs::write(dir.path().join("logs").join("old.log"), "")?;
+
+    let no_logs: PathFilter = Arc::new(|path| path.extension().map_or(true, |ext| ext != "log"));
+    let config = IndexConfig {
+        custom_filter: Some(no_logs.clone()),
+        ..Default::default()
+    };
+    let files = walk(dir.path(), &config);
+    assert_eq!(files.len(), 3);
+    assert!(files.iter().all(|f| f.path.extension().map_or(true, |ext| ext != "log")));
+
+    let mut indexer = Indexer::new(Box::new(InMemoryBackend::default())).with_filter(no_logs);
+    indexer.index_all(dir.path()).await?;
+    let results = indexer.backend.query(&parse_query("ext:log")?).await?;
+    assert!(results.is_empty());
+    assert_eq!(indexer.backend.query(&parse_query("*")?).await?.len(), 3);
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reindex_swap() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let dir = tempfile::tempdir()?;
+    for i in 0..50 {
+        std::fs::write(dir.path().join(format!("{}.txt", i)), "")?;
+    }
+    let mut indexer = Indexer::new(Box::new(InMemoryBackend::default()));
+    assert!(indexer.current_index().files.is_empty());
+    indexer.reindex(dir.path()).await?;
+    assert_eq!(indexer.current_index().files.len(), 51);
+
+    // readers only ever see a complete index, before or after a swap
+    let index = indexer.shared_index();
+    let done = Arc::new(AtomicBool::new(false));
+    let readers = (0..4)
+        .map(|_| {
+            let (index, done) = (index.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let len = index.load().files.len();
+                    assert!(len == 51 || len == 101, "saw a partial index of {}", len);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for i in 50..100 {
+        std::fs::write(dir.path().join(format!("{}.txt", i)), "")?;
+    }
+    let stats = indexer.reindex(dir.path()).await?;
+    assert_eq!(stats.added, 50);
+    done.store(true, Ordering::Relaxed);
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert_eq!(indexer.current_index().files.len(), 101);
+
+    Ok(())
+}
+
+// Mounting needs root, so this only checks anything when run as root
+#[cfg(target_os = "linux")]
+#[test]
+fn test_one_filesystem() -> Result<()> {
+    use std::process::Command;
+
+    struct Unmount<'a>(&'a Path);
+    impl Drop for Unmount<'_> {
+        fn drop(&mut self) {
+            let _ = Command::new("umount").arg(self.0).status();
+        }
+    }
+
+    let dir = tempfile::tempdir()?;
+    let mnt = dir.path().join("mnt");
+    std::fs::create_dir(&mnt)?;
+    std::fs::write(dir.path().join("outer"), "")?;
+    let mounted = Command::new("mount")
+        .args(["-t", "tmpfs", "tmpfs"])
+        .arg(&mnt)
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map_or(false, |status| status.success());
+    if !mounted {
+        eprintln!("couldn't mount a tmpfs, skipping");
+        return Ok(());
+    }
+    let _unmount = Unmount(&mnt);
+    std::fs::write(mnt.join("inner"), "")?;
+
+    let names = |config: &IndexConfig| {
+        let mut names = walk(dir.path(), config)
+            .into_iter()
+            .map(|f| f.path.strip_prefix(dir.path()).unwrap().to_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    let all = names(&IndexConfig::default());
+    assert!(all.contains(&PathBuf::from("mnt/inner")));
+
+    let config = IndexConfig {
+        one_filesystem: true,
+        ..Default::default()
+    };
+    assert_eq!(names(&config), [PathBuf::new(), PathBuf::from("outer")]);
+
+    Ok(())
+}
@@ -1,109 +1,492 @@
+mod backends;
+mod cli;
+mod config;
+mod daemon;
 mod db;
 mod errors;
 mod files;
+mod fuzzy;
+mod grpc;
 mod query;
 mod indexer;
+mod output;
+mod store;
 mod tags;
+mod tui;
 
 use std::{
     env,
-    sync::{Arc, Mutex, RwLock},
+    fs::File,
+    io::{self, BufRead, BufWriter, IsTerminal, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
-use crate::query::{query, query_stream};
+use crate::cli::{
+    Cli, Command, ExportFormat, IndexCommand, LogFormat, LogLevel, OutputArgs, QueryCommand,
+    SearchArgs, TagCommand,
+};
+use crate::config::Config;
+use crate::db::{InMemoryBackend, IndexBackend, SurrealBackendBuilder};
+use crate::errors::DatchaniError;
+use crate::files::{QueryResult, PREVIEW_BYTES};
+use crate::indexer::IndexConfig;
+use crate::output::{
+    DisplayConfig, GroupedFormatter, OutputField, OutputFormat, OutputFormatter, PlainFormatter,
+    PrettyFormatter, TableFormatter,
+};
+use crate::query::{
+    eval_score_explain, query_stream_with_config, MatchContext, Query, ScoringConfig,
+};
+use crate::store::QueryStore;
+use arc_swap::ArcSwap;
+use clap::{CommandFactory, Parser};
+use crossterm::terminal;
 use color_eyre::Result;
 use futures_util::{pin_mut, StreamExt};
-use ignore::WalkState;
-use rayon::prelude::*;
-use tracing::{debug, info, log::warn};
-use walkdir::WalkDir;
+use notify::{RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+use tracing_subscriber::EnvFilter;
+
+#[cfg(unix)]
+use std::os::unix::ffi::OsStringExt;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    // default level is debug
-    pretty_env_logger::formatted_builder()
-        .parse_filters(
-            env::var("RUST_LOG")
-                .unwrap_or_else(|_| "debug".to_string())
-                .as_str(),
-        )
-        .init();
+
+    let cli = Cli::parse();
+    init_logging(cli.log_level, cli.log_format);
 
     // global rayon thread
     rayon::ThreadPoolBuilder::new()
         .num_threads(4)
         .build_global()?;
 
-    // lets walk dir
-    // let walker = WalkDir::new(env::current_dir().unwrap());
-
-    // for entry in walker.into_iter().filter_map(|e| e.ok()) {
-    //     debug!("Found entry: {:?}", entry);
-    // }
-
-    let args = env::args().collect::<Vec<String>>()[1..].to_vec().join(" ");
-
-    // use rayon to parallelize the walk
-
-    let index = Arc::new(RwLock::new(files::Index::new()));
-
-    ignore::WalkBuilder::new(env::current_dir().unwrap())
-        .git_ignore(true)
-        .git_exclude(true)
-        .ignore(true)
-        .require_git(false)
-        // .same_file_system(true)
-        .hidden(true)
-        .standard_filters(true)
-        .build_parallel()
-        .run(|| {
-            Box::new(|entry| {
-                let entry = match entry {
-                    Ok(e) => e,
-                    Err(e) => return WalkState::Continue
+    let config = IndexConfig {
+        max_file_size: cli.max_size,
+        one_filesystem: cli.one_filesystem,
+        custom_mime_rules: Config::open_default()?.mime_rules()?,
+        ..Default::default()
+    };
+    let scoring = ScoringConfig {
+        live_checks: cli.live_checks,
+        ..Default::default()
+    };
+
+    match cli.command {
+        Command::Search(args) => search(args, &config, &scoring).await,
+        Command::Interactive => {
+            let index = index_current_dir(&config)?;
+            // print the selection to stdout, so it can be used in `$(datchani interactive)`
+            if let Some(path) = tui::run(&index, &scoring)? {
+                println!("{}", path.display());
+            }
+            Ok(())
+        }
+        Command::Gc => {
+            let index = files::Index::load(cli.index.clone())?;
+            let root = match &index.root {
+                Some(root) => root.clone(),
+                None => env::current_dir()?,
+            };
+            let (index, stats) = index.gc(&root, &config)?;
+            index.save(cli.index)?;
+            println!(
+                "Removed {}, added {}, unchanged {}",
+                stats.removed, stats.added, stats.unchanged
+            );
+            Ok(())
+        }
+        Command::Rebuild { from_db } => {
+            // the old index may not even be readable, so it's never loaded
+            let index = if from_db {
+                let mut backend = SurrealBackendBuilder::from_env().build().await?;
+                files::Index::rebuild_from_backend(&mut backend).await?
+            } else {
+                index_current_dir(&config)?
+            };
+            index.save(cli.index)?;
+            println!("Indexed {} entries", index.files.len());
+            Ok(())
+        }
+        Command::Index { command } => match command {
+            IndexCommand::Export {
+                format,
+                output,
+                filter,
+            } => {
+                let mut index = files::Index::load(cli.index)?;
+                if let Some(filter) = filter {
+                    let filter = query::parse_query(&filter)?;
+                    index.files = query::query_with_config(&filter, &index, &scoring)?
+                        .into_iter()
+                        .map(|(_, file)| file)
+                        .collect();
+                }
+                export_index(&index, format, &output)?;
+                println!("Exported {} entries", index.files.len());
+                Ok(())
+            }
+            IndexCommand::Import { format, input } => {
+                let index = import_index(format, &input)?;
+                let mut backend = SurrealBackendBuilder::from_env().build().await?;
+                let mut imported = 0;
+                for batch in index.files.chunks(config.batch_size.max(1)) {
+                    imported += backend.batch_upsert(batch.to_vec()).await?;
+                }
+                println!("Imported {} entries", imported);
+                Ok(())
+            }
+        },
+        Command::Tag { command } => match command {
+            TagCommand::Sync { path, dry_run, db } => {
+                let root = match path {
+                    Some(path) => env::current_dir()?.join(path),
+                    None => env::current_dir()?,
                 };
-                let mut index = match index.write() {
-                    Ok(i) => i,
-                    Err(e) => return WalkState::Continue
+                let stats = if db {
+                    let mut backend = SurrealBackendBuilder::from_env().build().await?;
+                    indexer::sync_tags(&mut backend, &root, dry_run).await?
+                } else {
+                    let mut backend = InMemoryBackend {
+                        index: files::Index::load(cli.index.clone())?,
+                    };
+                    let stats = indexer::sync_tags(&mut backend, &root, dry_run).await?;
+                    if !dry_run && !stats.changes.is_empty() {
+                        backend.index.save(cli.index)?;
+                    }
+                    stats
                 };
+                for change in &stats.changes {
+                    println!(
+                        "{}: [{}] -> [{}]",
+                        change.path.display(),
+                        change.old.join(", "),
+                        change.new.join(", ")
+                    );
+                }
+                let verb = if dry_run { "Would update" } else { "Updated" };
+                println!("{} {}, unchanged {}", verb, stats.changes.len(), stats.unchanged);
+                Ok(())
+            }
+        },
+        Command::Query { command } => match command {
+            QueryCommand::Save {
+                name,
+                query,
+                description,
+            } => QueryStore::open_default()?.save(&name, &query.join(" "), description),
+            QueryCommand::Run { name, output } => {
+                let query = QueryStore::open_default()?.load(&name)?;
+                run_query(query, &output, &config, &scoring, None).await
+            }
+            QueryCommand::List => {
+                for name in QueryStore::open_default()?.list() {
+                    println!("{}", name);
+                }
+                Ok(())
+            }
+        },
+        Command::Stats { top } => {
+            let root = env::current_dir()?;
+            let index = files::Index::load_or_else(&cli.index, || files::Index {
+                files: indexer::walk(&root, &config),
+                ..files::Index::with_root(root.clone())
+            })?;
+            let sections = [
+                ("extension", index.top_k_by_extension(top)),
+                ("mime", index.top_k_by_mime(top)),
+                ("tag", index.top_k_by_tag(top)),
+            ];
+            for (i, (heading, counts)) in sections.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                print!("{}", output::format_counts(heading, counts));
+            }
+            Ok(())
+        }
+        Command::Serve { grpc_port } => {
+            let root = env::current_dir()?;
+            let index = files::Index::load_or_else(&cli.index, || files::Index {
+                files: indexer::walk(&root, &config),
+                ..files::Index::with_root(root.clone())
+            })?;
+            let addr = SocketAddr::from(([127, 0, 0, 1], grpc_port));
+            debug!("Serving gRPC on {}", addr);
+            grpc::serve(Arc::new(ArcSwap::from_pointee(index)), config, addr).await
+        }
+        Command::Completions { shell } => {
+            clap_complete::generate(shell, &mut Cli::command(), "datchani", &mut io::stdout());
+            Ok(())
+        }
+    }
+}
+
+/// Log to stderr, filtered by `level` if given, otherwise by `RUST_LOG`.
+/// The default level is debug
+fn init_logging(level: Option<LogLevel>, format: LogFormat) {
+    let filter = match level {
+        Some(level) => EnvFilter::new(level.directive()),
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug")),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+    match format {
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Json => builder.json().init(),
+        LogFormat::Compact => builder.compact().init(),
+    }
+}
+
+async fn search(args: SearchArgs, config: &IndexConfig, scoring: &ScoringConfig) -> Result<()> {
+    let search_query = query::parse_query(&args.query.join(" "))?;
+
+    // an empty query would match everything, make the user ask for it explicitly
+    if search_query.is_empty() {
+        eprintln!("Usage: datchani search <QUERY>");
+        eprintln!("Use `*` as the query to list every file");
+        std::process::exit(1);
+    }
+
+    if args.watch {
+        let delay = Duration::from_millis(args.watch_delay_ms);
+        return watch(search_query, &args.output, config, scoring, delay).await;
+    }
+    let stdin = match (args.stdin, args.stdin_null) {
+        (_, true) => Some(b'\0'),
+        (true, false) => Some(b'\n'),
+        (false, false) => None,
+    };
+    run_query(search_query, &args.output, config, scoring, stdin).await
+}
+
+/// Run the query, then run it again every time something in the current directory changes,
+/// until Ctrl-C. Changes are debounced by `delay`, so saving a file only refreshes once
+async fn watch(
+    search_query: Query,
+    output: &OutputArgs,
+    config: &IndexConfig,
+    scoring: &ScoringConfig,
+    delay: Duration,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // indexing reads files too, which mustn't trigger another refresh
+        if matches!(event, Ok(event) if !event.kind.is_access()) {
+            let _ = tx.send(());
+        }
+    })?;
+    // watch before the first run, so nothing changed during it is missed
+    watcher.watch(&env::current_dir()?, RecursiveMode::Recursive)?;
+
+    let refresh = async {
+        run_query(search_query.clone(), output, config, scoring, None).await?;
+        while rx.recv().await.is_some() {
+            // wait for a quiet period, so a burst of events only refreshes once
+            while let Ok(Some(())) = tokio::time::timeout(delay, rx.recv()).await {}
+            print!("\x1B[2J\x1B[H");
+            run_query(search_query.clone(), output, config, scoring, None).await?;
+        }
+        Ok(())
+    };
+    tokio::select! {
+        result = refresh => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+/// Search the current directory, or the paths read from stdin
+/// separated by `stdin` if it's given, see `--stdin`
+async fn run_query(
+    search_query: Query,
+    output: &OutputArgs,
+    config: &IndexConfig,
+    scoring: &ScoringConfig,
+    stdin: Option<u8>,
+) -> Result<()> {
+    // catch bad terms before walking anything
+    if let Err(errors) = search_query.validate() {
+        for e in errors {
+            eprintln!("error: {}", e);
+        }
+        std::process::exit(1);
+    }
 
-                // debug!("Found entry: {:?}", entry);
-                index
-                    .add_file(entry.path().to_path_buf())
-                    .unwrap_or_else(|e| {
-                        warn!("Error adding file: {:?}", e);
-                    });
-                WalkState::Continue
-            })
-        });
-    // WalkDir::new("/home/cappy/Projects")
-    //     .into_iter()
-    //     .par_bridge()
-    //     .for_each(|entry| {
-    //         let entry = entry.unwrap();
-    //         let mut index = index.write().unwrap();
-    //         debug!("Found entry: {:?}", entry);
-    //         index.add_file(entry.path().to_path_buf()).unwrap();
-    //     });
-
-    // info!("Index: {:#?}", index);
-
-    // index
-    //     .read()
-    //     .unwrap()
-    //     .save(env::current_dir().unwrap().join("index.json"))?;
+    let index = match stdin {
+        Some(separator) => index_stdin(separator, config)?,
+        None => index_current_dir(config)?,
+    };
 
     // let f = files::Index::load(env::current_dir().unwrap().join("index.json"))?;
     // debug!("Loaded index: {:#?}", f);
-    let search_query = query::parse_query(&args).unwrap();
     debug!("Parsed query: {:#?}", search_query);
-    let res = query_stream(search_query, index.read().unwrap().to_owned());
+    // `depth:` goes by the root of the index, which the stream takes ownership of
+    let index_root = index.root.clone();
+    let res = query_stream_with_config(search_query.clone(), index, scoring.clone())
+        .take(output.limit.unwrap_or(usize::MAX));
     pin_mut!(res);
 
-    while let Some(r) = res.next().await {
-        println!("{:#?}", r);
+    if output.count {
+        let mut count = 0;
+        while let Some(result) = res.next().await {
+            result.unwrap_or_else(|e| query_failed(e));
+            count += 1;
+        }
+        println!("{}", count);
+        if !output.count_matches(count) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let root = env::current_dir()?;
+    if (output.format != OutputFormat::Plain && !output.json) || output.group_by.is_some() {
+        // columns are aligned and groups sorted, so every result is needed before printing anything
+        let mut results = Vec::new();
+        while let Some(result) = res.next().await {
+            results.push(result.unwrap_or_else(|e| query_failed(e)));
+        }
+        if output.format == OutputFormat::Table
+            && output.fields.fields().contains(&OutputField::Preview)
+        {
+            for (_, file) in &mut results {
+                // a file that can't be read just has no preview
+                let _ = file.load_preview(PREVIEW_BYTES);
+            }
+        }
+        // the formatters only print the results, so show them relative to where we're run
+        for (_, file) in &mut results {
+            file.path = file.display_path(Some(&root)).into_owned();
+        }
+        let formatter: Box<dyn OutputFormatter> = match output.format {
+            OutputFormat::Plain => Box::new(PlainFormatter),
+            OutputFormat::Table => {
+                let table = TableFormatter::new(output.fields.clone());
+                // only cut lines off when a person is reading them
+                match terminal::size() {
+                    Ok((width, _)) if io::stdout().is_terminal() => {
+                        Box::new(table.with_width(width.into()))
+                    }
+                    _ => Box::new(table),
+                }
+            }
+            OutputFormat::Pretty => {
+                let display = DisplayConfig::from_env(output.no_color);
+                Box::new(PrettyFormatter::new(display))
+            }
+        };
+        let formatter = match output.group_by {
+            Some(group_by) => Box::new(GroupedFormatter::new(formatter, group_by)),
+            None => formatter,
+        };
+        print!("{}", formatter.format(&results));
+        return Ok(());
+    }
+
+    // the stream owns the index, so explaining needs its own context
+    let ctx = MatchContext::new(index_root.as_deref(), scoring.clone()).with_query(&search_query);
+    while let Some(result) = res.next().await {
+        let (score, file) = result.unwrap_or_else(|e| query_failed(e));
+        let explanation = if output.explain {
+            match eval_score_explain(&search_query, file.clone(), &ctx) {
+                Ok(Some((_, _, explanation))) => explanation,
+                Ok(None) => Vec::new(),
+                Err(e) => query_failed(e),
+            }
+        } else {
+            Vec::new()
+        };
+        if output.json {
+            let result = QueryResult {
+                file,
+                score,
+                explanation,
+            };
+            println!("{}", serde_json::to_string(&result)?);
+        } else if output.null {
+            // absolute, so the paths can be used from any directory
+            print!("{}\0", file.path.display());
+        } else {
+            println!("{}", file.display_path(Some(&root)).display());
+            for e in explanation {
+                match e.score {
+                    Some(score) => println!("    {} (score {})", e.term, score),
+                    None if e.matched => println!("    {}", e.term),
+                    None => println!("    {} (not matched)", e.term),
+                }
+            }
+        }
     }
     // println!("{:#?}", res);
     Ok(())
 }
+
+/// Write `index` to `path` in `format`, see `datchani index export`
+fn export_index(index: &files::Index, format: ExportFormat, path: &Path) -> Result<()> {
+    match format {
+        ExportFormat::Json => index.save(path.to_path_buf()),
+        ExportFormat::Csv => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            index.write_csv_with_metadata(&mut writer)?;
+            writer.flush()?;
+            Ok(())
+        }
+        ExportFormat::Cbor => index.save_cbor(path),
+        ExportFormat::Msgpack => index.save_msgpack(path),
+    }
+}
+
+/// Read an index written by `export_index`
+fn import_index(format: ExportFormat, path: &Path) -> Result<files::Index> {
+    match format {
+        ExportFormat::Json => files::Index::load(path.to_path_buf()),
+        ExportFormat::Csv => files::Index::read_csv(File::open(path)?),
+        ExportFormat::Cbor => files::Index::load_cbor(path),
+        ExportFormat::Msgpack => files::Index::load_msgpack(path),
+    }
+}
+
+/// Report a query that couldn't be evaluated (e.g. an invalid regex) and exit
+fn query_failed(e: DatchaniError) -> ! {
+    eprintln!("error: {}", e);
+    std::process::exit(1)
+}
+
+/// Build an in-memory index of the paths read from stdin, separated by `separator`.
+/// Paths that can't be indexed, e.g. because they don't exist, are skipped with a warning
+fn index_stdin(separator: u8, config: &IndexConfig) -> Result<files::Index> {
+    let mut files = Vec::new();
+    for path in io::stdin().lock().split(separator) {
+        let mut path = path?;
+        if separator == b'\n' && path.ends_with(b"\r") {
+            path.pop();
+        }
+        if path.is_empty() {
+            continue;
+        }
+        #[cfg(unix)]
+        let path = PathBuf::from(std::ffi::OsString::from_vec(path));
+        #[cfg(not(unix))]
+        let path = PathBuf::from(String::from_utf8_lossy(&path).into_owned());
+        match files::IndexedFile::new_with_config(path.clone(), config) {
+            Ok(file) => files.push(file),
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    // listing a path twice doesn't return it twice
+    Ok(files.into_iter().collect())
+}
+
+/// Walk the current directory and build an in-memory index of it
+fn index_current_dir(config: &IndexConfig) -> Result<files::Index> {
+    let root = env::current_dir()?;
+    let mut index = files::Index::with_root(root.clone());
+    index.files = indexer::walk(&root, config);
+    Ok(index)
+}
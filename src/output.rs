@@ -0,0 +1,711 @@
+//! Output formatting
+//! Renders query results as an aligned table, see `TableFormatter`.
+//! The columns to print are picked with `--fields`, parsed into an `OutputFieldSet`.
+//! Any formatter can be split into sections with `GroupedFormatter`, see `--group-by`.
+//! `--format=pretty` colors results and marks their file type, see `DisplayConfig`
+
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    fmt::Write,
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+
+use chrono::SecondsFormat;
+use clap::ValueEnum;
+use file_type_enum::FileType;
+use owo_colors::{OwoColorize, Style};
+
+use crate::files::{IndexedFile, QueryResult, QueryResultSet};
+
+/// How search results are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// One path per line
+    #[default]
+    Plain,
+    /// Aligned columns, see `--fields`
+    Table,
+    /// Aligned score, path, MIME type and tags, with colors and file type symbols
+    Pretty,
+}
+
+/// What `--group-by` splits the results on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    /// File extension
+    Ext,
+    /// Parent directory
+    Dir,
+    /// Top-level MIME type, e.g. `text` or `image`
+    Mime,
+    /// Tag, files with several tags show up under each of them
+    Tag,
+}
+
+impl GroupBy {
+    /// The groups a file belongs to, every file is in at least one
+    pub fn keys(self, file: &IndexedFile) -> Vec<String> {
+        match self {
+            GroupBy::Ext => vec![match file.path.extension() {
+                Some(ext) => format!(".{}", ext.to_string_lossy()),
+                None => String::from("no extension"),
+            }],
+            GroupBy::Dir => vec![match file.path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.display().to_string(),
+                _ => String::from("."),
+            }],
+            GroupBy::Mime => vec![match &file.data_type {
+                Some(mime) => mime.split('/').next().unwrap_or(mime).to_string(),
+                None => String::from("unknown"),
+            }],
+            GroupBy::Tag if file.tags.is_empty() => vec![String::from("untagged")],
+            GroupBy::Tag => file.tags.clone(),
+        }
+    }
+}
+
+/// Renders a list of results as text
+pub trait OutputFormatter {
+    /// Render every result, each line ends with a newline
+    fn format(&self, results: &[(i64, IndexedFile)]) -> String;
+}
+
+impl<F: OutputFormatter + ?Sized> OutputFormatter for Box<F> {
+    fn format(&self, results: &[(i64, IndexedFile)]) -> String {
+        (**self).format(results)
+    }
+}
+
+/// One path per line
+pub struct PlainFormatter;
+
+impl OutputFormatter for PlainFormatter {
+    fn format(&self, results: &[(i64, IndexedFile)]) -> String {
+        let mut out = String::new();
+        for (_, file) in results {
+            writeln!(out, "{}", file.path.display()).unwrap();
+        }
+        out
+    }
+}
+
+/// Sorts results into groups, printing each under a `── key ──` header.
+/// Groups are sorted by key, results keep their order within a group,
+/// and each group is rendered separately by the wrapped formatter
+pub struct GroupedFormatter<F: OutputFormatter> {
+    inner: F,
+    group_by: GroupBy,
+}
+
+impl<F: OutputFormatter> GroupedFormatter<F> {
+    pub fn new(inner: F, group_by: GroupBy) -> Self {
+        Self { inner, group_by }
+    }
+}
+
+impl<F: OutputFormatter> OutputFormatter for GroupedFormatter<F> {
+    fn format(&self, results: &[(i64, IndexedFile)]) -> String {
+        let mut groups: BTreeMap<String, Vec<(i64, IndexedFile)>> = BTreeMap::new();
+        for result in results {
+            for key in self.group_by.keys(&result.1) {
+                groups.entry(key).or_default().push(result.clone());
+            }
+        }
+
+        let mut out = String::new();
+        for (i, (key, group)) in groups.iter().enumerate() {
+            if i > 0 {
+                out.push('\n');
+            }
+            writeln!(out, "── {} ──", key).unwrap();
+            out.push_str(&self.inner.format(group));
+        }
+        out
+    }
+}
+
+/// A single column of table output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputField {
+    Score,
+    Path,
+    Size,
+    Mime,
+    Tags,
+    Modified,
+    /// First line of the file contents, see `IndexedFile::load_preview`
+    Preview,
+}
+
+impl OutputField {
+    /// Every field printed by default, in order.
+    /// `Preview` reads every result, so it's only printed when asked for
+    pub const ALL: [OutputField; 6] = [
+        OutputField::Score,
+        OutputField::Path,
+        OutputField::Size,
+        OutputField::Mime,
+        OutputField::Tags,
+        OutputField::Modified,
+    ];
+
+    /// Name of the field, as accepted by `--fields`
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputField::Score => "score",
+            OutputField::Path => "path",
+            OutputField::Size => "size",
+            OutputField::Mime => "mime",
+            OutputField::Tags => "tags",
+            OutputField::Modified => "modified",
+            OutputField::Preview => "preview",
+        }
+    }
+
+    /// Render the value of this field for a single result
+    pub fn value(self, score: i64, file: &IndexedFile) -> String {
+        match self {
+            OutputField::Score => score.to_string(),
+            OutputField::Path => file.path.display().to_string(),
+            OutputField::Size => file.file_size.to_string(),
+            OutputField::Mime => file.data_type.clone().unwrap_or_else(|| String::from("-")),
+            OutputField::Tags if file.tags.is_empty() => String::from("-"),
+            OutputField::Tags => file.tags.join(","),
+            OutputField::Modified => file
+                .last_modified
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            // one line per row, so only the first line with something on it
+            OutputField::Preview => file
+                .content_preview
+                .as_deref()
+                .and_then(|preview| preview.lines().map(str::trim).find(|l| !l.is_empty()))
+                .unwrap_or("-")
+                .to_string(),
+        }
+    }
+}
+
+impl FromStr for OutputField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = OutputField::ALL.into_iter().chain([OutputField::Preview]);
+        fields.clone().find(|field| field.name() == s).ok_or_else(|| {
+            let names = fields.map(OutputField::name).collect::<Vec<_>>().join(", ");
+            format!("unknown field `{}`, expected one of: {}, or all", s, names)
+        })
+    }
+}
+
+/// The columns to print, in order.
+/// Parsed from a comma separated list like `path,size,modified`, `all` selects every field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputFieldSet(Vec<OutputField>);
+
+impl OutputFieldSet {
+    pub fn fields(&self) -> &[OutputField] {
+        &self.0
+    }
+}
+
+impl Default for OutputFieldSet {
+    fn default() -> Self {
+        Self(OutputField::ALL.to_vec())
+    }
+}
+
+impl FromStr for OutputFieldSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim() == "all" {
+            return Ok(Self::default());
+        }
+
+        let mut fields = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            let field = name.parse()?;
+            // listing a field twice doesn't print it twice
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        if fields.is_empty() {
+            return Err(String::from("no fields given"));
+        }
+        Ok(Self(fields))
+    }
+}
+
+/// Formats results as a table with one aligned column per field.
+/// A header row is only printed when there's more than one column,
+/// so `--fields=path` gives a plain list of paths, just like `find`
+pub struct TableFormatter {
+    fields: OutputFieldSet,
+    width: Option<usize>,
+}
+
+impl TableFormatter {
+    pub fn new(fields: OutputFieldSet) -> Self {
+        Self {
+            fields,
+            width: None,
+        }
+    }
+
+    /// Cut every line off after `width` characters, e.g. the width of the terminal
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = Some(width);
+        self
+    }
+
+    /// The cells of a single row, one per selected field
+    pub fn row(&self, score: i64, file: &IndexedFile) -> Vec<String> {
+        self.fields
+            .fields()
+            .iter()
+            .map(|field| field.value(score, file))
+            .collect()
+    }
+}
+
+impl OutputFormatter for TableFormatter {
+    fn format(&self, results: &[(i64, IndexedFile)]) -> String {
+        let fields = self.fields.fields();
+        let mut rows = Vec::new();
+        if fields.len() > 1 {
+            rows.push(fields.iter().map(|f| f.name().to_uppercase()).collect());
+        }
+        rows.extend(results.iter().map(|(score, file)| self.row(*score, file)));
+
+        let mut widths = vec![0; fields.len()];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let mut out = String::new();
+        for row in rows {
+            let mut line = String::new();
+            for (i, (cell, width)) in row.iter().zip(&widths).enumerate() {
+                if i > 0 {
+                    line.push_str("  ");
+                }
+                write!(line, "{:<width$}", cell, width = width).unwrap();
+            }
+            let line = line.trim_end();
+            match self.width {
+                Some(width) => out.extend(line.chars().take(width)),
+                None => out.push_str(line),
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// Formats `(value, count)` pairs as two aligned columns under a header,
+/// the counts right-aligned, e.g. for `datchani stats`
+pub fn format_counts(heading: &str, counts: &[(String, usize)]) -> String {
+    let value_width = counts
+        .iter()
+        .map(|(value, _)| value.chars().count())
+        .chain([heading.chars().count()])
+        .max()
+        .unwrap_or(0);
+    let count_width = counts
+        .iter()
+        .map(|(_, count)| count.to_string().len())
+        .chain(["COUNT".len()])
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<value_width$}  {:>count_width$}",
+        heading.to_uppercase(),
+        "COUNT",
+        value_width = value_width,
+        count_width = count_width
+    )
+    .unwrap();
+    for (value, count) in counts {
+        writeln!(
+            out,
+            "{:<value_width$}  {:>count_width$}",
+            value,
+            count,
+            value_width = value_width,
+            count_width = count_width
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Whether `--format=pretty` output is colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayConfig {
+    pub color: bool,
+}
+
+impl DisplayConfig {
+    /// Color only goes to a terminal, and not when `--no-color` or `NO_COLOR` is set
+    pub fn from_env(no_color: bool) -> Self {
+        let no_color_env = env::var_os("NO_COLOR").map_or(false, |v| !v.is_empty());
+        Self {
+            color: !no_color && !no_color_env && io::stdout().is_terminal(),
+        }
+    }
+}
+
+/// Marks the file type in front of the path
+fn file_type_symbol(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Regular => "📄",
+        FileType::Directory => "📁",
+        FileType::Symlink => "🔗",
+        _ => "❔",
+    }
+}
+
+/// The score, path, MIME type and tag cells of a pretty result, with how to color them
+fn pretty_cells(result: &QueryResult) -> [(String, Style); 4] {
+    let file = &result.file;
+    let path_style = match file.file_type {
+        FileType::Directory => Style::new().blue().bold(),
+        FileType::Symlink => Style::new().cyan(),
+        _ => Style::new(),
+    };
+    [
+        (result.score.to_string(), Style::new().yellow()),
+        (
+            format!(
+                "{} {}",
+                file_type_symbol(file.file_type),
+                file.path.display()
+            ),
+            path_style,
+        ),
+        (
+            OutputField::Mime.value(result.score, file),
+            Style::new().dimmed(),
+        ),
+        (
+            OutputField::Tags.value(result.score, file),
+            Style::new().green(),
+        ),
+    ]
+}
+
+impl QueryResult {
+    /// Tab separated score, path, MIME type and tags, colored if `config` says so
+    pub fn display_line(&self, config: &DisplayConfig) -> String {
+        let cells = pretty_cells(self).map(|(cell, style)| {
+            if config.color {
+                cell.style(style).to_string()
+            } else {
+                cell
+            }
+        });
+        cells.join("\t")
+    }
+}
+
+/// Like `QueryResult::display_line`, never colored
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.display_line(&DisplayConfig { color: false }))
+    }
+}
+
+impl QueryResultSet {
+    /// Render the results as aligned columns under a header, like `TableFormatter`
+    pub fn display_table(&self, config: &DisplayConfig) -> String {
+        let rows = self.results.iter().map(pretty_cells).collect::<Vec<_>>();
+        let header = ["SCORE", "PATH", "MIME", "TAGS"].map(|name| (name.to_string(), Style::new()));
+        let mut widths = [0; 4];
+        for row in std::iter::once(&header).chain(&rows) {
+            for (width, (cell, _)) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let mut out = String::new();
+        for row in std::iter::once(&header).chain(&rows) {
+            let mut line = String::new();
+            for (i, ((cell, style), width)) in row.iter().zip(widths).enumerate() {
+                if i > 0 {
+                    line.push_str("  ");
+                }
+                if config.color {
+                    write!(line, "{}", cell.style(*style)).unwrap();
+                } else {
+                    line.push_str(cell);
+                }
+                // escape codes take no space, so pad by the plain text
+                if i + 1 < row.len() {
+                    line.extend(std::iter::repeat(' ').take(width - cell.chars().count()));
+                }
+            }
+            writeln!(out, "{}", line).unwrap();
+        }
+        out
+    }
+}
+
+/// `QueryResultSet::display_table` as an `OutputFormatter`
+pub struct PrettyFormatter {
+    config: DisplayConfig,
+}
+
+impl PrettyFormatter {
+    pub fn new(config: DisplayConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl OutputFormatter for PrettyFormatter {
+    fn format(&self, results: &[(i64, IndexedFile)]) -> String {
+        let set = QueryResultSet {
+            results: results
+                .iter()
+                .map(|(score, file)| QueryResult {
+                    file: file.clone(),
+                    score: *score,
+                    explanation: Vec::new(),
+                })
+                .collect(),
+            total: results.len(),
+            page: 0,
+            per_page: results.len(),
+        };
+        set.display_table(&self.config)
+    }
+}
+
+#[test]
+fn test_parse_fields() {
+    assert_eq!(
+        "path,size,modified"
+            .parse::<OutputFieldSet>()
+            .unwrap()
+            .fields(),
+        [OutputField::Path, OutputField::Size, OutputField::Modified]
+    );
+    assert_eq!(
+        "all".parse::<OutputFieldSet>().unwrap(),
+        OutputFieldSet::default()
+    );
+    assert_eq!(
+        "path,path".parse::<OutputFieldSet>().unwrap().fields(),
+        [OutputField::Path]
+    );
+
+    let err = "path,colour".parse::<OutputFieldSet>().unwrap_err();
+    assert!(err.contains("`colour`"));
+    assert!(err.contains("modified"));
+    assert!("".parse::<OutputFieldSet>().is_err());
+}
+
+#[test]
+fn test_table_fields() {
+    use std::path::PathBuf;
+
+    let results = vec![
+        (
+            42,
+            IndexedFile {
+                path: PathBuf::from("src/main.rs"),
+                file_size: 1024,
+                data_type: Some(String::from("text/x-rust")),
+                tags: vec![String::from("owo"), String::from("uwu")],
+                ..Default::default()
+            },
+        ),
+        (
+            7,
+            IndexedFile {
+                path: PathBuf::from("README.md"),
+                ..Default::default()
+            },
+        ),
+    ];
+
+    // every non-empty subset of the fields, in their default order
+    for mask in 1..(1 << OutputField::ALL.len()) {
+        let fields = OutputField::ALL
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, field)| field.name())
+            .collect::<Vec<_>>();
+        let set = fields.join(",").parse::<OutputFieldSet>().unwrap();
+        let table = TableFormatter::new(set.clone()).format(&results);
+        let lines = table.lines().collect::<Vec<_>>();
+
+        if fields.len() == 1 {
+            assert_eq!(lines.len(), results.len());
+        } else {
+            assert_eq!(lines.len(), results.len() + 1);
+            let header = lines[0].split_whitespace().collect::<Vec<_>>();
+            let expected = fields.iter().map(|f| f.to_uppercase()).collect::<Vec<_>>();
+            assert_eq!(header, expected);
+        }
+
+        // the first result has no spaces in any cell, so columns split cleanly
+        let first = lines[lines.len() - 2]
+            .split_whitespace()
+            .collect::<Vec<_>>();
+        let (score, file) = &results[0];
+        let expected = set
+            .fields()
+            .iter()
+            .map(|f| f.value(*score, file))
+            .collect::<Vec<_>>();
+        assert_eq!(first, expected);
+    }
+
+    let paths = TableFormatter::new("path".parse().unwrap()).format(&results);
+    assert_eq!(paths, "src/main.rs\nREADME.md\n");
+    let paths = TableFormatter::new("path".parse().unwrap())
+        .with_width(6)
+        .format(&results);
+    assert_eq!(paths, "src/ma\nREADME\n");
+}
+
+#[test]
+fn test_preview_field() {
+    let mut file = IndexedFile::default();
+    assert_eq!(OutputField::Preview.value(0, &file), "-");
+    file.content_preview = Some(String::from("\n  fn main() {\n}\n"));
+    assert_eq!(OutputField::Preview.value(0, &file), "fn main() {");
+
+    // reading every result is opt-in
+    let all = "all".parse::<OutputFieldSet>().unwrap();
+    assert!(!all.fields().contains(&OutputField::Preview));
+    let fields = "path,preview".parse::<OutputFieldSet>().unwrap();
+    assert_eq!(fields.fields(), [OutputField::Path, OutputField::Preview]);
+}
+
+#[test]
+fn test_group_by() {
+    use std::path::PathBuf;
+
+    let file = |path: &str, mime: Option<&str>, tags: &[&str]| {
+        (
+            0,
+            IndexedFile {
+                path: PathBuf::from(path),
+                data_type: mime.map(String::from),
+                tags: tags.iter().map(|t| t.to_string()).collect(),
+                ..Default::default()
+            },
+        )
+    };
+    let results = vec![
+        file("src/main.rs", Some("text/x-rust"), &["owo", "uwu"]),
+        file("Cargo.toml", Some("text/x-toml"), &[]),
+        file("src/query.rs", None, &["uwu"]),
+        file("assets/logo.png", Some("image/png"), &["owo"]),
+    ];
+    let grouped = |group_by| GroupedFormatter::new(PlainFormatter, group_by).format(&results);
+
+    assert_eq!(
+        grouped(GroupBy::Ext),
+        "── .png ──\nassets/logo.png\n\n\
+         ── .rs ──\nsrc/main.rs\nsrc/query.rs\n\n\
+         ── .toml ──\nCargo.toml\n"
+    );
+    assert_eq!(
+        grouped(GroupBy::Dir),
+        "── . ──\nCargo.toml\n\n\
+         ── assets ──\nassets/logo.png\n\n\
+         ── src ──\nsrc/main.rs\nsrc/query.rs\n"
+    );
+    assert_eq!(
+        grouped(GroupBy::Mime),
+        "── image ──\nassets/logo.png\n\n\
+         ── text ──\nsrc/main.rs\nCargo.toml\n\n\
+         ── unknown ──\nsrc/query.rs\n"
+    );
+    assert_eq!(
+        grouped(GroupBy::Tag),
+        "── owo ──\nsrc/main.rs\nassets/logo.png\n\n\
+         ── untagged ──\nCargo.toml\n\n\
+         ── uwu ──\nsrc/main.rs\nsrc/query.rs\n"
+    );
+
+    // tables are aligned per group
+    let table = GroupedFormatter::new(TableFormatter::new("path".parse().unwrap()), GroupBy::Ext)
+        .format(&results[..2]);
+    assert_eq!(table, "── .rs ──\nsrc/main.rs\n\n── .toml ──\nCargo.toml\n");
+}
+
+#[test]
+fn test_pretty_output() {
+    use std::path::PathBuf;
+
+    let result = |score, path: &str, file_type, tags: &[&str]| QueryResult {
+        file: IndexedFile {
+            path: PathBuf::from(path),
+            file_type,
+            data_type: Some(String::from("text/plain")).filter(|_| file_type == FileType::Regular),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            ..Default::default()
+        },
+        score,
+        explanation: Vec::new(),
+    };
+    let results = vec![
+        result(120, "src/main.rs", FileType::Regular, &["rust", "todo"]),
+        result(8, "src", FileType::Directory, &[]),
+        result(7, "latest", FileType::Symlink, &["owo"]),
+    ];
+    let set = QueryResultSet {
+        total: results.len(),
+        per_page: results.len(),
+        page: 0,
+        results,
+    };
+
+    insta::assert_snapshot!(set.display_table(&DisplayConfig { color: false }), @r###"
+    SCORE  PATH           MIME        TAGS
+    120    📄 src/main.rs  text/plain  rust,todo
+    8      📁 src          -           -
+    7      🔗 latest       -           owo
+    "###);
+
+    let line = set.results[0].to_string();
+    insta::assert_snapshot!(line, @"120\t📄 src/main.rs\ttext/plain\trust,todo");
+    let colored = set.results[0].display_line(&DisplayConfig { color: true });
+    assert!(colored.contains("\x1b["));
+
+    // colors don't throw off the alignment
+    let colored = set.display_table(&DisplayConfig { color: true });
+    assert!(colored.contains("\x1b["));
+    let strip = |s: &str| {
+        regex::Regex::new("\x1b\\[[0-9;]*m")
+            .unwrap()
+            .replace_all(s, "")
+            .into_owned()
+    };
+    assert_eq!(
+        strip(&colored),
+        set.display_table(&DisplayConfig { color: false })
+    );
+}
+
+#[test]
+fn test_format_counts() {
+    let counts = vec![(String::from("rs"), 120), (String::from("markdown"), 7)];
+    assert_eq!(
+        format_counts("extension", &counts),
+        "EXTENSION  COUNT\nrs           120\nmarkdown       7\n"
+    );
+    assert_eq!(format_counts("tag", &[]), "TAG  COUNT\n");
+}
@@ -16,20 +16,36 @@
 // Path: src/query.rs
 
 use futures_core::stream::Stream;
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use async_stream::stream;
-use fuzzy_matcher::FuzzyMatcher;
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 // let's use nom to parse the query, and skim to do the fuzzy matching
 use nom::{
     branch::alt,
     bytes::complete::{tag, take_while1},
+    combinator::eof,
+    sequence::terminated,
     IResult,
 };
 
 use color_eyre::Result;
+use dashmap::DashMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
 
+use crate::cli::parse_size;
+use crate::errors::{DatchaniError, QueryValidationError};
 use crate::files::{Index, IndexedFile};
+use crate::fuzzy::FuzzyMatcherAbstraction;
+use file_type_enum::FileType;
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
 
 /// A query term
 /// All terms will be parsed as a NormalFuzzy term, unless they start with a reserved keyword, followed by a colon
@@ -39,8 +55,9 @@ use crate::files::{Index, IndexedFile};
 /// `tag:foo` will match all files that have the tag `foo`
 /// `regex:/foo/` will match all files that contain `foo`
 /// and so on
-/// If a term starts with a -, it is treated as an exclusion
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// If a term starts with a -, it is treated as an exclusion.
+/// Excluded plain words are matched exactly, `-~foo` lowers the fuzzy score instead
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Term {
     /// Match by fuzzy search
     NormalFuzzy(String),
@@ -52,112 +69,2240 @@ pub enum Term {
     SuffixName(String),
     /// Match by file extension
     Extension(String),
+    /// Match files with any of these extensions, `ext:rs,toml,md`
+    ExtensionSet(Vec<String>),
     /// Matches by MIME type
     Mime(String),
+    /// Matches by MIME type with `*` wildcards, `mime:image/*` or `mime:*/pdf`
+    MimeGlob(String),
+    /// Matches files whose MIME type was detected with more than this confidence, in percent.
+    /// `mime:image/png mime_confidence:>0.8`, see `IndexedFile::content_type_confidence`
+    MimeConfidenceGt(u8),
+    /// Matches files whose MIME type was detected with less than this confidence, in percent
+    MimeConfidenceLt(u8),
     /// Matches by tag
     Tag(String),
-    /// Match by exact string
+    /// Matches files with a tag fuzzy-similar to this one, written with a trailing `~`
+    /// `tag:tod~` or `#tod~` matches `todo` and `todos`, see `TagMatchConfig`
+    TagFuzzy(String),
+    /// Match by exact substring of the full path
     Exact(String),
-    /// Regex match
-    /// Regex format will be the same as the one used by ripgrep, but in between slashes
+    /// Match by exact substring of the file name only
+    ExactName(String),
+    /// Matches paths with a component named exactly this, at any depth
+    /// `segment:src`, `component:src` or `dir:src` matches everything below any `src` directory
+    PathSegment(String),
+    /// Matches files anywhere below a directory named exactly this
+    /// `ancestor:src`, `under:src` or `beneath:src`. Unlike `PathSegment`,
+    /// the directory itself and files merely named `src` don't match
+    Ancestor(String),
+    /// Regex match against the file name
+    /// Regex format will be the same as the one used by ripgrep, optionally in between slashes
     /// For example, `/foo/` will match all files that contain `foo`
-    /// `/foo/i` will match all files that contain `foo` case-insensitively
+    /// `/foo/i` will match all files that contain `foo` case-insensitively,
+    /// and `/src\/.*\.rs/p` matches against the full path instead, see `PathRegex`
     Regex(String),
+    /// Regex match against the full path, `fullpath-regex:` or the `p` flag of `regex:/.../p`
+    PathRegex(String),
     /// Modified before
     /// Matches all files that were modified before the given date
     Before(String),
     /// Modified after
     /// Matches all files that were modified after the given date
     After(String),
+    /// Match by permission bits, the second field is a mask
+    /// `perm:0o755` matches the exact mode, while `perm:0o002/0o002`
+    /// matches all files that have the world-writable bit set
+    PermMode(u32, u32),
+    /// Matches by the UID of the file owner
+    OwnerUid(u32),
+    /// Matches by the name of the file owner, resolved to a UID
+    OwnerUser(String),
+    /// Matches by the GID of the file owner
+    OwnerGid(u32),
+    /// Matches by the name of the owning group, resolved to a GID
+    OwnerGroup(String),
+    /// Matches files exactly this many path components below the index root
+    /// `depth:1` matches files directly inside the root
+    DepthExact(usize),
+    /// Matches by inode number, e.g. to find every hard link to a file
+    /// `inode:1234` or `ino:1234`, never matches on non-Unix platforms
+    Inode(u64),
+    /// Matches files with more than this many hard links
+    /// `links:>1` matches every file that has been hard linked
+    LinksGt(u32),
+    /// Matches files with fewer than this many hard links
+    LinksLt(u32),
+    /// Matches files with exactly this many hard links, `links:2`
+    LinksExact(u32),
+    /// Matches files between the two sizes in bytes, both inclusive.
+    /// `size:10KB..1MB` or `bytes:10KiB..1MiB`, either end can be left open like `size:1GB..`
+    SizeRange(u64, u64),
+    /// Created before
+    /// Matches all files that were created before the given date.
+    /// Files without a creation time never match, see `IndexedFile::created`
+    CreatedBefore(DateTime<Utc>),
+    /// Created after
+    /// Matches all files that were created after the given date.
+    /// Files without a creation time never match, see `IndexedFile::created`
+    CreatedAfter(DateTime<Utc>),
+    /// Accessed before
+    /// Matches all files last accessed before the given date, `accessed-before:` or `atime:<`.
+    /// Access times are often stale, see `IndexedFile::accessed`
+    AccessedBefore(DateTime<Utc>),
+    /// Accessed after
+    /// Matches all files last accessed after the given date, `accessed-after:` or `atime:>`.
+    /// Access times are often stale, see `IndexedFile::accessed`
+    AccessedAfter(DateTime<Utc>),
+    /// Modified within the given duration before now
+    /// `modified:1h` matches all files modified in the last hour,
+    /// units are `s`, `m`, `h`, `d` and `w`
+    #[serde(with = "duration_seconds")]
+    ModifiedWithin(chrono::Duration),
+    /// Modified since midnight UTC, `modified:today`
+    ModifiedToday,
+    /// Modified since Monday, `modified:this-week`
+    ModifiedThisWeek,
+    /// Modified since the first of the month, `modified:this-month`
+    ModifiedThisMonth,
+    /// Modified since the first of January, `modified:this-year`
+    ModifiedThisYear,
+    /// Created since midnight UTC, `created:today`.
+    /// Like the other `Created*` variants, files without a creation time never match
+    CreatedToday,
+    /// Created since Monday, `created:this-week`
+    CreatedThisWeek,
+    /// Created since the first of the month, `created:this-month`
+    CreatedThisMonth,
+    /// Created since the first of January, `created:this-year`
+    CreatedThisYear,
+    /// Matches files the current user can access, `can:read`, `can:write`, `can:execute`
+    /// or `can:rw`, also written as `readable:true`, `writable:true` and `executable:true`.
+    /// Checked against the indexed permission bits unless `ScoringConfig::live_checks` is set
+    Accessible(AccessMode),
+    /// Matches binary files, `binary:true` or `is:binary`
+    /// Uses `IndexedFile::is_text` when known, otherwise any non-`text/` MIME type counts as binary
+    Binary,
+    /// Matches text files, `text:true`, `binary:false` or `is:text`
+    /// Uses `IndexedFile::is_text` when known, otherwise falls back to a `text/` MIME type
+    Text,
+    /// Matches sparse files, `sparse:true` or `is:sparse`, see `IndexedFile::sparse`
+    Sparse,
+    /// Matches files without holes, `sparse:false`
+    NonSparse,
+    /// Matches files that have the extended attribute, `xattr:user.comment`
+    /// Only indexed with `IndexConfig::index_xattrs`
+    XattrKey(String),
+    /// Matches files where the extended attribute is exactly the UTF-8 value,
+    /// `xattr:user.comment=hello`
+    XattrValue { key: String, value: String },
+    /// Query-level modifier, only returns the N best results
+    /// `top:20`, `limit:20`, `take:20` or `first:20`, the smallest wins if given more than once
+    TopN(usize),
+    /// Query-level modifier, overrides the case sensitivity of the whole query
+    /// `case:insensitive`, `case:sensitive` or `case:smart`
+    Case(CaseSensitivity),
+    /// Matches files the inner term doesn't match, `not:extension:md`.
+    /// Unlike a `-` prefix this is a term itself, so it can be nested
+    Not(Box<Term>),
+}
+
+/// chrono's `Duration` doesn't implement serde's traits, so durations are stored as whole seconds
+mod duration_seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &chrono::Duration,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(duration.num_seconds())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<chrono::Duration, D::Error> {
+        Ok(chrono::Duration::seconds(i64::deserialize(deserializer)?))
+    }
+}
+
+/// How `prefix:`, `suffix:`, `suffix_name:` and `exact:` compare strings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaseSensitivity {
+    #[default]
+    Sensitive,
+    Insensitive,
+    /// Insensitive when the pattern is all lowercase, sensitive otherwise,
+    /// like skim's smart case
+    Smart,
+}
+
+impl CaseSensitivity {
+    fn is_sensitive(&self, pattern: &str) -> bool {
+        match self {
+            CaseSensitivity::Sensitive => true,
+            CaseSensitivity::Insensitive => false,
+            CaseSensitivity::Smart => pattern.chars().any(char::is_uppercase),
+        }
+    }
+
+    /// Like `compare`, for paths: separators are normalized first, see `slash_separators`
+    fn compare_path(&self, haystack: &str, pattern: &str, f: impl Fn(&str, &str) -> bool) -> bool {
+        self.compare(&slash_separators(haystack), &slash_separators(pattern), f)
+    }
+
+    /// Compare `haystack` and `pattern` with `f`, lowercasing both first when insensitive.
+    /// Both are NFC-normalized, see `nfc`
+    fn compare(&self, haystack: &str, pattern: &str, f: impl Fn(&str, &str) -> bool) -> bool {
+        let (haystack, pattern) = (nfc(haystack), nfc(pattern));
+        let (haystack, pattern) = (haystack.as_ref(), pattern.as_ref());
+        if self.is_sensitive(pattern) {
+            f(haystack, pattern)
+        } else {
+            f(&haystack.to_lowercase(), &pattern.to_lowercase())
+        }
+    }
+}
+
+/// `s` with `\\` separators turned into `/` on Windows, so queries can always use `/`.
+/// Elsewhere a backslash is a normal file name character, and `s` is left alone
+fn slash_separators(s: &str) -> Cow<'_, str> {
+    if cfg!(windows) && s.contains('\\') {
+        Cow::Owned(s.replace('\\', "/"))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// Normalize `s` to NFC, so `café` matches whether it's written with a precomposed `é`
+/// or an `e` followed by a combining accent (as macOS tends to store file names).
+/// Only allocates when `s` isn't NFC already
+fn nfc(s: &str) -> Cow<'_, str> {
+    match is_nfc_quick(s.chars()) {
+        IsNormalized::Yes => Cow::Borrowed(s),
+        _ => Cow::Owned(s.nfc().collect()),
+    }
+}
+
+/// The calendar periods of `modified:today`, `created:this-week` and so on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Period {
+    Today,
+    ThisWeek,
+    ThisMonth,
+    ThisYear,
+}
+
+impl Period {
+    fn parse(input: &str) -> Option<Self> {
+        match input {
+            "today" => Some(Period::Today),
+            "this-week" => Some(Period::ThisWeek),
+            "this-month" => Some(Period::ThisMonth),
+            "this-year" => Some(Period::ThisYear),
+            _ => None,
+        }
+    }
+
+    /// Midnight UTC at the start of the period, as of now.
+    /// Weeks start on Monday
+    fn start(self) -> DateTime<Utc> {
+        use chrono::Datelike;
+
+        let today = Utc::now().date_naive();
+        let day = match self {
+            Period::Today => today,
+            Period::ThisWeek => {
+                today - chrono::Duration::days(today.weekday().num_days_from_monday().into())
+            }
+            Period::ThisMonth => today.with_day(1).unwrap_or(today),
+            Period::ThisYear => today.with_ordinal(1).unwrap_or(today),
+        };
+        Utc.from_utc_datetime(&day.and_time(chrono::NaiveTime::MIN))
+    }
+}
+
+/// What `Term::Accessible` checks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccessMode {
+    Read,
+    Write,
+    Execute,
+    ReadWrite,
+}
+
+impl AccessMode {
+    /// The `rwx` bits needed, as in the owner/group/other triplets of a mode
+    fn bits(self) -> u32 {
+        match self {
+            AccessMode::Read => 0o4,
+            AccessMode::Write => 0o2,
+            AccessMode::Execute => 0o1,
+            AccessMode::ReadWrite => 0o6,
+        }
+    }
+
+    /// Ask the OS whether the current user can access `path`, with `access(2)`
+    #[cfg(unix)]
+    pub(crate) fn check_live(self, path: &Path) -> bool {
+        use nix::unistd::{access, AccessFlags};
+
+        let flags = match self {
+            AccessMode::Read => AccessFlags::R_OK,
+            AccessMode::Write => AccessFlags::W_OK,
+            AccessMode::Execute => AccessFlags::X_OK,
+            AccessMode::ReadWrite => AccessFlags::R_OK | AccessFlags::W_OK,
+        };
+        access(path, flags).is_ok()
+    }
+
+    /// Try to open `path` with the requested access.
+    /// There's no cheap way to check for execute access, so that never matches
+    #[cfg(not(unix))]
+    pub(crate) fn check_live(self, path: &Path) -> bool {
+        let (read, write) = match self {
+            AccessMode::Read => (true, false),
+            AccessMode::Write => (false, true),
+            AccessMode::ReadWrite => (true, true),
+            AccessMode::Execute => {
+                tracing::debug!("Execute access can't be checked on this platform");
+                return false;
+            }
+        };
+        std::fs::OpenOptions::new()
+            .read(read)
+            .write(write)
+            .open(path)
+            .is_ok()
+    }
+
+    /// Guess from the indexed permission bits, without touching the filesystem.
+    /// Only the primary group of the current user is taken into account
+    #[cfg(unix)]
+    fn check_cached(self, file: &IndexedFile) -> bool {
+        use nix::unistd::{getegid, geteuid};
+
+        let uid = geteuid().as_raw();
+        if uid == 0 {
+            // root can read and write anything, but only execute what someone else could
+            return self.bits() & 0o1 == 0
+                || file.file_type == FileType::Directory
+                || file.permissions & 0o111 != 0;
+        }
+        let shift = if file.owner_uid == uid {
+            6
+        } else if file.owner_gid == getegid().as_raw() {
+            3
+        } else {
+            0
+        };
+        (file.permissions >> shift) & self.bits() == self.bits()
+    }
+
+    /// Without owners, go by the owner bits of the indexed permissions
+    #[cfg(not(unix))]
+    fn check_cached(self, file: &IndexedFile) -> bool {
+        (file.permissions >> 6) & self.bits() == self.bits()
+    }
+}
+
+/// How tag terms are matched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagMatchConfig {
+    /// Minimum score a tag needs to match a fuzzy tag term (`tag:foo~`).
+    /// The score comes from `ScoringConfig::matcher`, so the scale depends on the matcher
+    pub fuzzy_threshold: i64,
+}
+
+impl Default for TagMatchConfig {
+    fn default() -> Self {
+        Self {
+            fuzzy_threshold: 50,
+        }
+    }
+}
+
+/// Settings that change how queries are matched and scored
+#[derive(Debug, Clone)]
+pub struct ScoringConfig {
+    pub case_sensitivity: CaseSensitivity,
+    /// Matcher used to score fuzzy terms
+    pub matcher: Arc<dyn FuzzyMatcherAbstraction>,
+    pub tags: TagMatchConfig,
+    /// Check terms like `can:write` against the filesystem instead of the index.
+    /// More accurate, but does I/O for every file they're matched against
+    pub live_checks: bool,
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        Self {
+            case_sensitivity: CaseSensitivity::default(),
+            matcher: Arc::from(<dyn FuzzyMatcherAbstraction>::default()),
+            tags: TagMatchConfig::default(),
+            live_checks: false,
+        }
+    }
+}
+
+/// Extra information about where a file comes from, needed by some terms
+#[derive(Debug, Clone, Default)]
+pub struct MatchContext<'a> {
+    /// Root path of the index the file belongs to, if known
+    pub root: Option<&'a Path>,
+    pub config: ScoringConfig,
+    /// Compiled `regex:` patterns, so each is only compiled once per query
+    /// rather than once per file. Shared between clones of the context
+    regex_cache: Arc<DashMap<String, Regex>>,
+}
+
+impl<'a> MatchContext<'a> {
+    pub fn new(root: Option<&'a Path>, config: ScoringConfig) -> Self {
+        Self {
+            root,
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn from_index(index: &'a Index) -> Self {
+        Self {
+            root: index.root.as_deref(),
+            ..Default::default()
+        }
+    }
+
+    /// The compiled regex for `pattern`, from the cache if it was compiled before
+    fn regex(&self, pattern: &str) -> std::result::Result<Regex, DatchaniError> {
+        if let Some(re) = self.regex_cache.get(pattern) {
+            return Ok(re.clone());
+        }
+        let re = Regex::new(pattern).map_err(|e| DatchaniError::InvalidRegex {
+            pattern: pattern.to_string(),
+            message: e.to_string(),
+        })?;
+        self.regex_cache.insert(pattern.to_string(), re.clone());
+        Ok(re)
+    }
+
+    /// Apply query-level modifiers like `case:` on top of the config
+    pub fn with_query(mut self, query: &Query) -> Self {
+        for term in &query.includes {
+            if let Term::Case(case) = term {
+                self.config.case_sensitivity = *case;
+            }
+        }
+        self
+    }
 }
 
 impl Term {
-    pub fn match_rules(&self, file: &IndexedFile) -> bool {
+    /// Like `match_rules`, but fails instead of not matching when the term itself is invalid,
+    /// e.g. a `regex:` term that doesn't compile
+    pub fn try_match_rules(
+        &self,
+        file: &IndexedFile,
+        ctx: &MatchContext,
+    ) -> std::result::Result<bool, DatchaniError> {
+        match self {
+            Term::Regex(s) | Term::PathRegex(s) => {
+                let re = ctx.regex(s)?;
+                let haystack = match self {
+                    Term::PathRegex(_) => file.path.to_string_lossy(),
+                    _ => file.path.file_name().unwrap_or_default().to_string_lossy(),
+                };
+                Ok(re.is_match(&haystack))
+            }
+            Term::Before(date) | Term::After(date) => {
+                let date =
+                    parse_datetime(date).ok_or_else(|| DatchaniError::InvalidDate(date.clone()))?;
+                Ok(match self {
+                    Term::Before(_) => file.last_modified < date,
+                    _ => file.last_modified > date,
+                })
+            }
+            Term::Not(inner) => Ok(inner.is_modifier() || !inner.try_match_rules(file, ctx)?),
+            _ => Ok(self.match_rules(file, ctx)),
+        }
+    }
+
+    /// Whether `file` matches this term. Invalid terms never match, see `try_match_rules`
+    pub fn match_rules(&self, file: &IndexedFile, ctx: &MatchContext) -> bool {
+        let case = ctx.config.case_sensitivity;
         match self {
             Term::NormalFuzzy(_) => {
                 // we have already done the fuzzy matching in the query parser
                 true
             }
-            Term::Regex(s) => {
-                let name = file.path.file_name().unwrap().to_str().unwrap();
-                let re = regex::Regex::new(s).unwrap();
-                re.is_match(name)
+            // modifiers don't filter anything
+            Term::Case(_) | Term::TopN(_) => true,
+            Term::Regex(_) | Term::PathRegex(_) | Term::Before(_) | Term::After(_) => {
+                self.try_match_rules(file, ctx).unwrap_or(false)
             }
             Term::Exact(s) => {
-                let name = file.path.file_name().unwrap().to_str().unwrap();
-                name.contains(s)
+                case.compare_path(file.path.to_str().unwrap_or_default(), s, |h, p| {
+                    h.contains(p)
+                })
             }
+            // `/`, paths ending in `..` and non UTF-8 names have no name to match
+            Term::ExactName(s) => file
+                .path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map_or(false, |name| case.compare(name, s, |h, p| h.contains(p))),
+            Term::PathSegment(s) => file.path.components().any(|component| {
+                component
+                    .as_os_str()
+                    .to_str()
+                    .map_or(false, |component| case.compare(component, s, |h, p| h == p))
+            }),
+            // skip the path itself, so only the directories above the file count
+            Term::Ancestor(s) => file.path.ancestors().skip(1).any(|ancestor| {
+                ancestor
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| case.compare(name, s, |h, p| h == p))
+            }),
             Term::Prefix(s) => file
                 .path
                 .file_name()
                 .unwrap_or_default()
                 .to_str()
-                .unwrap()
-                .starts_with(s),
+                .map_or(false, |name| case.compare_path(name, s, |h, p| h.starts_with(p))),
             Term::Suffix(s) => file
                 .path
                 .file_name()
                 .unwrap_or_default()
                 .to_str()
-                .unwrap()
-                .ends_with(s),
-            Term::SuffixName(s) => {
-                let name = {
-                    if let Some(name) = file.path.file_stem() {
-                        name.to_str().unwrap().split('.').next().unwrap()
-                    } else {
-                        file.path.file_name().unwrap().to_str().unwrap()
-                    }
+                .map_or(false, |name| case.compare_path(name, s, |h, p| h.ends_with(p))),
+            // the name up to its first dot, paths without a file name never match
+            Term::SuffixName(s) => file
+                .path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.split('.').next())
+                .map_or(false, |name| case.compare(name, s, |h, p| h.ends_with(p))),
+            Term::Extension(s) => file
+                .path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map_or(false, |ext| nfc(ext) == nfc(s)),
+            Term::ExtensionSet(exts) => match file.path.extension().and_then(|ext| ext.to_str()) {
+                Some(ext) => {
+                    let ext = nfc(ext);
+                    exts.iter().any(|s| ext == nfc(s))
+                }
+                None => false,
+            },
+            Term::Mime(s) => file.data_type == Some(s.clone()),
+            Term::MimeGlob(pattern) => file.data_type.as_deref().map_or(false, |mime| {
+                match pattern.split_once('*') {
+                    // the common `image/*` doesn't need a regex
+                    Some((prefix, "")) => mime.starts_with(prefix),
+                    _ => ctx
+                        .regex(&mime_glob_regex(pattern))
+                        .map_or(false, |re| re.is_match(mime)),
+                }
+            }),
+            Term::Tag(s) => file.tags.contains(s),
+            Term::TagFuzzy(s) => file.tags.iter().any(|tag| {
+                ctx.config
+                    .matcher
+                    .score(tag, s)
+                    .map_or(false, |score| score >= ctx.config.tags.fuzzy_threshold)
+            }),
+            Term::PermMode(mode, mask) => file.permissions & mask == mode & mask,
+            Term::OwnerUid(_) | Term::OwnerUser(_) | Term::OwnerGid(_) | Term::OwnerGroup(_) => {
+                self.match_owner(file)
+            }
+            Term::DepthExact(depth) => {
+                // without a root, count from the start of the path
+                let relative = match ctx.root {
+                    Some(root) => match file.path.strip_prefix(root) {
+                        Ok(relative) => relative,
+                        Err(_) => return false,
+                    },
+                    None => file.path.as_path(),
                 };
-                name.ends_with(s)
+                relative.components().count() == *depth
+            }
+            // the inode is 0 when it couldn't be read, which no real file has
+            Term::Inode(ino) => file.inode != 0 && file.inode == *ino,
+            Term::LinksGt(n) => file.hardlink_count > *n,
+            Term::LinksLt(n) => file.hardlink_count < *n,
+            Term::LinksExact(n) => file.hardlink_count == *n,
+            Term::SizeRange(lo, hi) => (*lo..=*hi).contains(&file.file_size),
+            Term::MimeConfidenceGt(n) => file.content_type_confidence.map_or(false, |c| c > *n),
+            Term::MimeConfidenceLt(n) => file.content_type_confidence.map_or(false, |c| c < *n),
+            // compared in whole seconds, so `modified:0s` matches anything modified this second
+            Term::ModifiedWithin(duration) => {
+                (Utc::now() - file.last_modified).num_seconds() <= duration.num_seconds()
             }
-            Term::Extension(s) => {
-                if let Some(ext) = file.path.extension() {
-                    ext.to_str().unwrap() == s
-                } else {
-                    false
+            Term::ModifiedToday => file.last_modified >= Period::Today.start(),
+            Term::ModifiedThisWeek => file.last_modified >= Period::ThisWeek.start(),
+            Term::ModifiedThisMonth => file.last_modified >= Period::ThisMonth.start(),
+            Term::ModifiedThisYear => file.last_modified >= Period::ThisYear.start(),
+            Term::CreatedToday => file.created.map_or(false, |c| c >= Period::Today.start()),
+            Term::CreatedThisWeek => file
+                .created
+                .map_or(false, |c| c >= Period::ThisWeek.start()),
+            Term::CreatedThisMonth => file
+                .created
+                .map_or(false, |c| c >= Period::ThisMonth.start()),
+            Term::CreatedThisYear => file
+                .created
+                .map_or(false, |c| c >= Period::ThisYear.start()),
+            Term::CreatedBefore(date) => file.created.map_or(false, |created| created < *date),
+            Term::CreatedAfter(date) => file.created.map_or(false, |created| created > *date),
+            Term::AccessedBefore(date) => file.accessed.map_or(false, |accessed| accessed < *date),
+            Term::AccessedAfter(date) => file.accessed.map_or(false, |accessed| accessed > *date),
+            Term::Accessible(mode) if ctx.config.live_checks => mode.check_live(&file.path),
+            Term::Accessible(mode) => mode.check_cached(file),
+            // files we know nothing about (e.g. directories) are neither
+            Term::Binary => is_text(file) == Some(false),
+            Term::Text => is_text(file) == Some(true),
+            Term::Sparse => file.sparse,
+            Term::NonSparse => !file.sparse,
+            Term::XattrKey(key) => file.xattrs.contains_key(key),
+            Term::XattrValue { key, value } => file
+                .xattrs
+                .get(key)
+                .and_then(|bytes| std::str::from_utf8(bytes).ok())
+                .map_or(false, |bytes| bytes == value),
+            // negating a modifier doesn't make it a filter either
+            Term::Not(inner) => inner.is_modifier() || !inner.match_rules(file, ctx),
+        }
+    }
+}
+
+/// `IndexedFile::is_text`, falling back to whether the MIME type is `text/`
+fn is_text(file: &IndexedFile) -> Option<bool> {
+    file.is_text.or_else(|| {
+        file.data_type
+            .as_ref()
+            .map(|mime| mime.starts_with("text/"))
+    })
+}
+
+impl Term {
+    /// The term inside any number of `Term::Not`s
+    fn innermost(&self) -> &Term {
+        match self {
+            Term::Not(inner) => inner.innermost(),
+            term => term,
+        }
+    }
+
+    /// Whether this term only changes how a query is run, like `case:` and `top:`
+    fn is_modifier(&self) -> bool {
+        matches!(self.innermost(), Term::Case(_) | Term::TopN(_))
+    }
+
+    #[cfg(unix)]
+    fn match_owner(&self, file: &IndexedFile) -> bool {
+        use nix::unistd::{Group, User};
+
+        match self {
+            Term::OwnerUid(uid) => file.owner_uid == *uid,
+            Term::OwnerGid(gid) => file.owner_gid == *gid,
+            Term::OwnerUser(name) => match User::from_name(name) {
+                Ok(Some(user)) => file.owner_uid == user.uid.as_raw(),
+                _ => false,
+            },
+            Term::OwnerGroup(name) => match Group::from_name(name) {
+                Ok(Some(group)) => file.owner_gid == group.gid.as_raw(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn match_owner(&self, _file: &IndexedFile) -> bool {
+        tracing::debug!("Ownership terms are only supported on Unix, ignoring {:?}", self);
+        false
+    }
+}
+
+/// The term in query syntax, using the first spelling `parse_query` accepts for it
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Term::NormalFuzzy(s) => write!(f, "{}", s),
+            Term::Prefix(s) => write!(f, "prefix:{}", s),
+            Term::Suffix(s) => write!(f, "suffix:{}", s),
+            Term::SuffixName(s) => write!(f, "suffix_name:{}", s),
+            Term::Extension(s) => write!(f, "extension:{}", s),
+            Term::ExtensionSet(exts) => write!(f, "extension:{}", exts.join(",")),
+            Term::Mime(s) | Term::MimeGlob(s) => write!(f, "mime:{}", s),
+            Term::MimeConfidenceGt(n) => write!(f, "mime_confidence:>{}", *n as f64 / 100.0),
+            Term::MimeConfidenceLt(n) => write!(f, "mime_confidence:<{}", *n as f64 / 100.0),
+            Term::Tag(s) => write!(f, "tag:{}", s),
+            Term::TagFuzzy(s) => write!(f, "tag:{}~", s),
+            Term::Exact(s) => write!(f, "exact:{}", s),
+            Term::ExactName(s) => write!(f, "exact-name:{}", s),
+            Term::PathSegment(s) => write!(f, "segment:{}", s),
+            Term::Ancestor(s) => write!(f, "ancestor:{}", s),
+            Term::Regex(s) => write!(f, "regex:{}", s),
+            Term::PathRegex(s) => write!(f, "fullpath-regex:{}", s),
+            Term::Before(s) => write!(f, "before:{}", s),
+            Term::After(s) => write!(f, "after:{}", s),
+            Term::PermMode(mode, 0o7777) => write!(f, "perm:0o{:o}", mode),
+            Term::PermMode(mode, mask) => write!(f, "perm:0o{:o}/0o{:o}", mode, mask),
+            Term::OwnerUid(uid) => write!(f, "owner:{}", uid),
+            Term::OwnerUser(name) => write!(f, "owner:{}", name),
+            Term::OwnerGid(gid) => write!(f, "group:{}", gid),
+            Term::OwnerGroup(name) => write!(f, "group:{}", name),
+            Term::DepthExact(depth) => write!(f, "depth:{}", depth),
+            Term::Inode(ino) => write!(f, "inode:{}", ino),
+            Term::LinksGt(n) => write!(f, "links:>{}", n),
+            Term::LinksLt(n) => write!(f, "links:<{}", n),
+            Term::LinksExact(n) => write!(f, "links:{}", n),
+            // an open end is left empty, like it's written
+            Term::SizeRange(lo, hi) => {
+                write!(f, "size:")?;
+                if *lo > 0 {
+                    write!(f, "{}", lo)?;
                 }
+                write!(f, "..")?;
+                if *hi < u64::MAX {
+                    write!(f, "{}", hi)?;
+                }
+                Ok(())
             }
-            Term::Mime(s) => file.data_type == Some(s.clone()),
-            Term::Tag(s) => file.tags.contains(s),
-            _ => todo!(),
+            Term::CreatedBefore(date) => write!(f, "created-before:{}", date.to_rfc3339()),
+            Term::CreatedAfter(date) => write!(f, "created-after:{}", date.to_rfc3339()),
+            Term::AccessedBefore(date) => write!(f, "accessed-before:{}", date.to_rfc3339()),
+            Term::AccessedAfter(date) => write!(f, "accessed-after:{}", date.to_rfc3339()),
+            Term::ModifiedWithin(duration) => {
+                write!(f, "modified:{}", format_duration(*duration))
+            }
+            Term::ModifiedToday => write!(f, "modified:today"),
+            Term::ModifiedThisWeek => write!(f, "modified:this-week"),
+            Term::ModifiedThisMonth => write!(f, "modified:this-month"),
+            Term::ModifiedThisYear => write!(f, "modified:this-year"),
+            Term::CreatedToday => write!(f, "created:today"),
+            Term::CreatedThisWeek => write!(f, "created:this-week"),
+            Term::CreatedThisMonth => write!(f, "created:this-month"),
+            Term::CreatedThisYear => write!(f, "created:this-year"),
+            Term::Accessible(mode) => {
+                let mode = match mode {
+                    AccessMode::Read => "read",
+                    AccessMode::Write => "write",
+                    AccessMode::Execute => "execute",
+                    AccessMode::ReadWrite => "readwrite",
+                };
+                write!(f, "can:{}", mode)
+            }
+            Term::Binary => write!(f, "binary:true"),
+            Term::Text => write!(f, "text:true"),
+            Term::Sparse => write!(f, "sparse:true"),
+            Term::NonSparse => write!(f, "sparse:false"),
+            Term::XattrKey(key) => write!(f, "xattr:{}", key),
+            Term::XattrValue { key, value } => write!(f, "xattr:{}={}", key, value),
+            Term::TopN(n) => write!(f, "top:{}", n),
+            Term::Case(case) => {
+                let case = match case {
+                    CaseSensitivity::Sensitive => "sensitive",
+                    CaseSensitivity::Insensitive => "insensitive",
+                    CaseSensitivity::Smart => "smart",
+                };
+                write!(f, "case:{}", case)
+            }
+            Term::Not(inner) => write!(f, "not:{}", inner),
+        }
+    }
+}
+
+/// `duration` in the largest unit `parse_duration` accepts that it's a whole number of
+fn format_duration(duration: chrono::Duration) -> String {
+    let seconds = duration.num_seconds();
+    let unit = [("w", 7 * 24 * 60 * 60), ("d", 24 * 60 * 60), ("h", 60 * 60), ("m", 60)]
+        .into_iter()
+        .find(|(_, length)| seconds % length == 0 && seconds > 0);
+    match unit {
+        Some((unit, length)) => format!("{}{}", seconds / length, unit),
+        None => format!("{}s", seconds),
+    }
+}
+
+// our control group
+#[test]
+fn test_query() {
+    let query = "prefix:foo suffix:bar baz -qux -\"aaa bbb\" -extension:md #owo -#uwu exact-name:main";
+
+    let query = parse_query(query).unwrap();
+
+    println!("{:#?}", query);
+    assert_eq!(
+        query,
+        Query {
+            includes: vec![
+                Term::Prefix(String::from("foo")),
+                Term::Suffix(String::from("bar")),
+                Term::NormalFuzzy(String::from("baz")),
+                Term::Tag(String::from("owo")),
+                Term::ExactName(String::from("main")),
+            ],
+            excludes: vec![
+                Term::Exact(String::from("qux")),
+                Term::Exact(String::from("aaa bbb")),
+                Term::Extension(String::from("md")),
+                Term::Tag(String::from("uwu")),
+            ],
+        }
+    );
+
+    // quoted tag names can have spaces in them
+    let query = parse_query("#\"my tag\" tag:'other tag' -#'old tag' don't").unwrap();
+    assert_eq!(
+        query.includes,
+        vec![
+            Term::Tag(String::from("my tag")),
+            Term::Tag(String::from("other tag")),
+            Term::NormalFuzzy(String::from("don't")),
+        ]
+    );
+    assert_eq!(query.excludes, vec![Term::Tag(String::from("old tag"))]);
+}
+
+#[test]
+fn test_eval_score_explain() -> Result<()> {
+    let ctx = MatchContext::default();
+    let main = IndexedFile {
+        path: PathBuf::from("src/main.rs"),
+        ..Default::default()
+    };
+    let readme = IndexedFile {
+        path: PathBuf::from("README.md"),
+        ..Default::default()
+    };
+
+    let query = parse_query("ext:rs main")?;
+    let (score, _, explanation) = eval_score_explain(&query, main.clone(), &ctx)?.unwrap();
+    assert_eq!(explanation.len(), 2);
+    assert_eq!(explanation[0].term, "extension:rs");
+    assert_eq!(explanation[0].score, None);
+    assert!(explanation.iter().all(|e| e.matched));
+    // the only fuzzy term makes up the whole score
+    assert_eq!(explanation[1].score, Some(score));
+    assert!(eval_score_explain(&query, readme, &ctx)?.is_none());
+
+    let query = parse_query("main -ext:md")?;
+    let (_, _, explanation) = eval_score_explain(&query, main.clone(), &ctx)?.unwrap();
+    assert_eq!(explanation[1].term, "-extension:md");
+    assert!(!explanation[1].matched);
+
+    // excluded fuzzy terms count against the score
+    let query = parse_query("ext:rs -~src")?;
+    let (score, _, explanation) = eval_score_explain(&query, main, &ctx)?.unwrap();
+    assert_eq!(explanation[1].term, "-~src");
+    assert!(!explanation[1].matched);
+    assert_eq!(explanation[1].score, Some(score));
+    assert!(score < 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_term_display() -> Result<()> {
+    for query in [
+        "main",
+        "prefix:foo",
+        "extension:rs,toml",
+        "mime:image/*",
+        "tag:tod~",
+        "exact-name:main",
+        "fullpath-regex:src/.*",
+        "perm:0o755",
+        "perm:0o2/0o2",
+        "owner:root",
+        "links:>1",
+        "links:2",
+        "mime_confidence:>0.8",
+        "size:1024..",
+        "size:..2048",
+        "created-before:2023-01-01T00:00:00+00:00",
+        "modified:2h",
+        "modified:90s",
+        "modified:this-week",
+        "can:readwrite",
+        "xattr:user.comment=hi",
+        "case:smart",
+        "not:extension:md",
+    ] {
+        let term = &parse_query(query)?.includes[0];
+        assert_eq!(term.to_string(), query);
+        assert_eq!(&parse_query(&term.to_string())?.includes[0], term);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_exclusion_only() {
+    let index = Index {
+        files: vec![
+            IndexedFile {
+                path: PathBuf::from("src/main.rs"),
+                ..Default::default()
+            },
+            IndexedFile {
+                path: PathBuf::from("README.md"),
+                ..Default::default()
+            },
+        ],
+        ..Index::new()
+    };
+
+    let results = query(&parse_query("-extension:md").unwrap(), &index).unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].1.path, PathBuf::from("src/main.rs"));
+}
+
+#[test]
+fn test_not() -> Result<()> {
+    let index = Index {
+        files: ["src/main.rs", "README.md", "docs/guide.md", "Cargo.toml"]
+            .iter()
+            .map(|path| IndexedFile {
+                path: PathBuf::from(path),
+                ..Default::default()
+            })
+            .collect(),
+        ..Index::new()
+    };
+    let paths = |q: &str| -> Result<Vec<PathBuf>> {
+        Ok(query(&parse_query(q)?, &index)?
+            .into_iter()
+            .map(|(_, f)| f.path)
+            .collect())
+    };
+
+    assert_eq!(
+        parse_query("not:extension:md")?.includes,
+        vec![Term::Not(Box::new(Term::Extension(String::from("md"))))]
+    );
+    assert_eq!(paths("not:extension:md")?, paths("-extension:md")?);
+    assert_eq!(paths("not:not:extension:md")?, paths("extension:md")?);
+    assert_eq!(paths("not:not:extension:md")?.len(), 2);
+
+    // fuzzy terms are negated as exact ones, like with `-`
+    assert_eq!(
+        parse_query("not:main")?.includes,
+        vec![Term::Not(Box::new(Term::Exact(String::from("main"))))]
+    );
+    assert_eq!(paths("not:main")?, paths("-main")?);
+
+    // nested terms are validated too
+    assert!(parse_query("not:regex:[a-")?.validate().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_path_segment() -> Result<()> {
+    let file = IndexedFile {
+        path: PathBuf::from("/home/user/projects/datchani/src/main.rs"),
+        ..Default::default()
+    };
+    let ctx = MatchContext::default();
+    for input in [
+        "segment:datchani",
+        "segment:src",
+        "segment:home",
+        "dir:user",
+        "component:main.rs",
+    ] {
+        let term = &parse_query(input)?.includes[0];
+        assert!(term.match_rules(&file, &ctx), "{}", input);
+    }
+    // whole components only
+    for input in ["segment:proj", "segment:datchani/src", "segment:SRC"] {
+        let term = &parse_query(input)?.includes[0];
+        assert!(!term.match_rules(&file, &ctx), "{}", input);
+    }
+
+    let ctx = MatchContext {
+        config: ScoringConfig {
+            case_sensitivity: CaseSensitivity::Insensitive,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    assert!(Term::PathSegment(String::from("SRC")).match_rules(&file, &ctx));
+
+    Ok(())
+}
+
+#[test]
+fn test_ancestor() -> Result<()> {
+    let ctx = MatchContext::default();
+    let file = |path: &str| IndexedFile {
+        path: PathBuf::from(path),
+        ..Default::default()
+    };
+    let deep = file("project/src/query/parse/terms/ancestor.rs");
+    for keyword in ["ancestor", "under", "beneath"] {
+        let query = parse_query(&format!("{}:src", keyword))?;
+        assert_eq!(query.includes, vec![Term::Ancestor(String::from("src"))]);
+        assert!(query.includes[0].match_rules(&deep, &ctx), "{}", keyword);
+    }
+
+    let src = Term::Ancestor(String::from("src"));
+    assert!(src.match_rules(&file("project/src/main.rs"), &ctx));
+    // similarly named siblings, and names that only contain `src`
+    assert!(!src.match_rules(&file("project/src2/main.rs"), &ctx));
+    assert!(!src.match_rules(&file("project/my-src/main.rs"), &ctx));
+    assert!(!src.match_rules(&file("project/srcs/lib/mod.rs"), &ctx));
+    // the directory itself isn't below itself, unlike with `segment:`
+    assert!(!src.match_rules(&file("project/src"), &ctx));
+    assert!(!src.match_rules(&file("project/lib/src"), &ctx));
+    assert!(Term::PathSegment(String::from("src")).match_rules(&file("project/src"), &ctx));
+
+    // with `depth:`, only the files directly inside `src`
+    let query = parse_query("under:src depth:3")?;
+    let matches = |path: &str| {
+        let file = file(path);
+        query.includes.iter().all(|term| term.match_rules(&file, &ctx))
+    };
+    assert!(matches("project/src/main.rs"));
+    assert!(!matches("project/src/query/mod.rs"));
+
+    Ok(())
+}
+
+#[test]
+fn test_extension_set() -> Result<()> {
+    assert_eq!(
+        parse_query("ext:rs,toml extension:md ext:py,")?.includes,
+        vec![
+            Term::ExtensionSet(vec![String::from("rs"), String::from("toml")]),
+            Term::Extension(String::from("md")),
+            Term::Extension(String::from("py")),
+        ]
+    );
+
+    let ctx = MatchContext::default();
+    let term = &parse_query("ext:rs,toml")?.includes[0];
+    for (path, matches) in [
+        ("src/main.rs", true),
+        ("Cargo.toml", true),
+        ("setup.py", false),
+        ("Makefile", false),
+    ] {
+        let file = IndexedFile {
+            path: PathBuf::from(path),
+            ..Default::default()
+        };
+        assert_eq!(term.match_rules(&file, &ctx), matches, "{}", path);
+    }
+    // extensions that aren't UTF-8 never match
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let file = IndexedFile {
+            path: PathBuf::from(std::ffi::OsStr::from_bytes(b"main.r\xffs")),
+            ..Default::default()
+        };
+        assert!(!term.match_rules(&file, &ctx));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_trailing_backslash() -> Result<()> {
+    // nothing left to escape, so it's kept
+    assert_eq!(
+        parse_query("foo\\")?.includes,
+        vec![Term::NormalFuzzy(String::from("foo\\"))]
+    );
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+#[test]
+fn test_windows_paths() -> Result<()> {
+    // backslashes only escape whitespace and quotes
+    assert_eq!(
+        parse_query("exact:src\\main.rs my\\ file")?.includes,
+        vec![
+            Term::Exact(String::from("src\\main.rs")),
+            Term::NormalFuzzy(String::from("my file")),
+        ]
+    );
+
+    let file = IndexedFile {
+        path: PathBuf::from("C:\\Users\\user\\src\\main.rs"),
+        ..Default::default()
+    };
+    let ctx = MatchContext::default();
+    for input in [
+        "exact:src/main.rs",
+        "exact:src\\main.rs",
+        "prefix:main",
+        "suffix:.rs",
+    ] {
+        let term = &parse_query(input)?.includes[0];
+        assert!(term.match_rules(&file, &ctx), "{}", input);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sort_stable() {
+    let files = ["a", "b", "c", "d", "e"]
+        .iter()
+        .map(|name| IndexedFile {
+            path: PathBuf::from(name),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+
+    // nothing fuzzy to score, so every file ties at 0
+    let index = Index {
+        files: files.clone(),
+        ..Index::new()
+    };
+    let results = query(&parse_query("-exact:z").unwrap(), &index).unwrap();
+    let paths = results.iter().map(|(_, f)| f.path.clone()).collect::<Vec<_>>();
+    assert_eq!(paths, files.iter().map(|f| f.path.clone()).collect::<Vec<_>>());
+
+    let mut results = files
+        .into_iter()
+        .zip([3, 1, 4, 5, 2])
+        .map(|(file, score)| (score, file))
+        .collect::<Vec<_>>();
+    Index::sort_by_score_desc(&mut results);
+    assert_eq!(results.iter().map(|(s, _)| *s).collect::<Vec<_>>(), vec![5, 4, 3, 2, 1]);
+    Index::sort_by_score_asc(&mut results);
+    assert_eq!(results.iter().map(|(s, _)| *s).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+}
+
+#[test]
+fn test_case_sensitivity() {
+    let file = IndexedFile {
+        path: PathBuf::from("src/controller.rs"),
+        ..Default::default()
+    };
+    let pattern = Term::SuffixName(String::from("Controller"));
+
+    let query = parse_query("suffix_name:Controller case:insensitive").unwrap();
+    assert_eq!(query.includes[1], Term::Case(CaseSensitivity::Insensitive));
+    for input in ["case:insensitivefoo", "binary:truex", "sparse:false1", "can:readme"] {
+        assert!(
+            !matches!(
+                parse_query(input).unwrap().includes[0],
+                Term::Case(_) | Term::Binary | Term::NonSparse | Term::Accessible(_)
+            ),
+            "{}",
+            input
+        );
+    }
+    let ctx = MatchContext::default().with_query(&query);
+    assert!(query.includes.iter().all(|t| t.match_rules(&file, &ctx)));
+
+    let sensitive = MatchContext::default();
+    assert!(!pattern.match_rules(&file, &sensitive));
+
+    // smart case is only insensitive for all-lowercase patterns
+    let mut smart = MatchContext::default();
+    smart.config.case_sensitivity = CaseSensitivity::Smart;
+    assert!(!pattern.match_rules(&file, &smart));
+    assert!(Term::Prefix(String::from("contr")).match_rules(&file, &smart));
+    let upper = IndexedFile {
+        path: PathBuf::from("src/Controller.rs"),
+        ..Default::default()
+    };
+    assert!(Term::Exact(String::from("src/contr")).match_rules(&upper, &smart));
+    assert!(!Term::Suffix(String::from("RS")).match_rules(&upper, &smart));
+}
+
+#[test]
+fn test_exact_full_path() {
+    let ctx = MatchContext::default();
+    let nested = IndexedFile {
+        path: PathBuf::from("project/src/main.rs"),
+        ..Default::default()
+    };
+    let flat = IndexedFile {
+        path: PathBuf::from("project/main.rs"),
+        ..Default::default()
+    };
+
+    let exact = parse_query("exact:src/main").unwrap();
+    assert!(exact.includes[0].match_rules(&nested, &ctx));
+    assert!(!exact.includes[0].match_rules(&flat, &ctx));
+
+    let name = parse_query("exact-name:main").unwrap();
+    assert!(name.includes[0].match_rules(&nested, &ctx));
+    assert!(name.includes[0].match_rules(&flat, &ctx));
+    // paths without a file name never match
+    let suffix_name = Term::SuffixName(String::from(""));
+    for path in ["/", "project/.."] {
+        let file = IndexedFile {
+            path: PathBuf::from(path),
+            ..Default::default()
+        };
+        assert!(!name.includes[0].match_rules(&file, &ctx));
+        assert!(!suffix_name.match_rules(&file, &ctx));
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_perm_mode() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let file = tempfile::NamedTempFile::new()?;
+    std::fs::set_permissions(file.path(), std::fs::Permissions::from_mode(0o644))?;
+
+    let exact = parse_query("perm:0o644")?;
+    let world_writable = parse_query("mode:0o002/0o002")?;
+    assert_eq!(world_writable.includes, vec![Term::PermMode(0o002, 0o002)]);
+
+    let ixf = IndexedFile::new(file.path().to_path_buf())?;
+    assert!(exact.includes[0].match_rules(&ixf, &MatchContext::default()));
+    assert!(!world_writable.includes[0].match_rules(&ixf, &MatchContext::default()));
+
+    std::fs::set_permissions(file.path(), std::fs::Permissions::from_mode(0o646))?;
+    let ixf = IndexedFile::new(file.path().to_path_buf())?;
+    assert!(!exact.includes[0].match_rules(&ixf, &MatchContext::default()));
+    assert!(world_writable.includes[0].match_rules(&ixf, &MatchContext::default()));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_owner() -> Result<()> {
+    use nix::unistd::{getgid, getuid, Group, User};
+
+    let file = tempfile::NamedTempFile::new()?;
+    let ixf = IndexedFile::new(file.path().to_path_buf())?;
+
+    let uid = getuid();
+    let gid = getgid();
+    assert!(Term::OwnerUid(uid.as_raw()).match_rules(&ixf, &MatchContext::default()));
+    assert!(Term::OwnerGid(gid.as_raw()).match_rules(&ixf, &MatchContext::default()));
+    assert!(!Term::OwnerUid(uid.as_raw() + 1).match_rules(&ixf, &MatchContext::default()));
+
+    if let Some(user) = User::from_uid(uid)? {
+        let query = parse_query(&format!("owner:{}", user.name))?;
+        assert_eq!(query.includes, vec![Term::OwnerUser(user.name)]);
+        assert!(query.includes[0].match_rules(&ixf, &MatchContext::default()));
+    }
+    if let Some(group) = Group::from_gid(gid)? {
+        let query = parse_query(&format!("group:{}", group.name))?;
+        assert!(query.includes[0].match_rules(&ixf, &MatchContext::default()));
+    }
+
+    let query = parse_query(&format!("uid:{} gid:{}", uid, gid))?;
+    assert!(query.includes.iter().all(|term| term.match_rules(&ixf, &MatchContext::default())));
+
+    Ok(())
+}
+
+#[test]
+fn test_depth() {
+    let root = PathBuf::from("/home/user/project");
+    let file = IndexedFile {
+        path: root.join("src/main.rs"),
+        ..Default::default()
+    };
+    let ctx = MatchContext {
+        root: Some(&root),
+        ..Default::default()
+    };
+
+    let query = parse_query("depth:2").unwrap();
+    assert_eq!(query.includes, vec![Term::DepthExact(2)]);
+    assert!(query.includes[0].match_rules(&file, &ctx));
+    assert!(!Term::DepthExact(1).match_rules(&file, &ctx));
+
+    // files outside of the root never match
+    let outside = IndexedFile {
+        path: PathBuf::from("/etc/src/main.rs"),
+        ..Default::default()
+    };
+    assert!(!Term::DepthExact(2).match_rules(&outside, &ctx));
+}
+
+#[test]
+fn test_fuzzy_filename_weight() {
+    let matcher = <dyn FuzzyMatcherAbstraction>::default();
+    let q = parse_query("main").unwrap();
+    let in_name = IndexedFile {
+        path: PathBuf::from("src/main.rs"),
+        ..Default::default()
+    };
+    let in_dir = IndexedFile {
+        path: PathBuf::from("main/src/lib.rs"),
+        ..Default::default()
+    };
+
+    let name_score = fuzzy_score(&q, &in_name, matcher.as_ref());
+    let dir_score = fuzzy_score(&q, &in_dir, matcher.as_ref());
+    assert!(name_score.filename_score > 0);
+    assert_eq!(
+        name_score.total,
+        name_score.filename_score * 2 + name_score.dir_score
+    );
+    assert_eq!(dir_score.filename_score, 0);
+    assert!(name_score.total > dir_score.total);
+
+    let index = Index {
+        files: vec![in_dir, in_name],
+        ..Index::new()
+    };
+    assert_eq!(
+        query(&q, &index).unwrap()[0].1.path,
+        PathBuf::from("src/main.rs")
+    );
+
+    // spans the directory and the file name, so only the full path matches
+    let spanning = fuzzy_score(
+        &parse_query("srcmain").unwrap(),
+        &index[1],
+        matcher.as_ref(),
+    );
+    assert!(spanning.dir_score > 0);
+}
+
+#[test]
+fn test_fuzzy_score_excludes() {
+    let matcher = <dyn FuzzyMatcherAbstraction>::default();
+    let file = IndexedFile {
+        path: PathBuf::from("src/main.rs"),
+        ..Default::default()
+    };
+    let include = fuzzy_score(&parse_query("main").unwrap(), &file, matcher.as_ref());
+
+    let q = parse_query("main -~mn").unwrap();
+    assert_eq!(q.excludes, vec![Term::NormalFuzzy(String::from("mn"))]);
+    // without the `~` it's an exact exclusion
+    assert_eq!(
+        parse_query("-mn").unwrap().excludes,
+        vec![Term::Exact(String::from("mn"))]
+    );
+    let score = fuzzy_score(&q, &file, matcher.as_ref());
+    assert_eq!(score.filename_score, include.filename_score);
+    assert!(score.total < include.total);
+
+    // an exclusion outscoring the includes still matches, with a negative score
+    let q = parse_query("-~main").unwrap();
+    assert_eq!(
+        fuzzy_score(&q, &file, matcher.as_ref()).total,
+        -include.total
+    );
+    let index = Index {
+        files: vec![file],
+        ..Index::new()
+    };
+    let results = query(&q, &index).unwrap();
+    assert_eq!(results.len(), 1);
+    assert!(results[0].0 < 0);
+}
+
+// Run with `cargo test --release -- --ignored bench_` to compare matchers
+#[test]
+#[ignore]
+fn bench_fuzzy_score() {
+    use crate::fuzzy::{NucleoAdapter, SkimAdapter};
+    use std::time::Instant;
+
+    let files = (0..100_000)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!(
+                "home/user/project-{}/src/module_{}/file_{}.rs",
+                i % 97,
+                i % 13,
+                i
+            )),
+            ..Default::default()
+        })
+        .collect::<Vec<_>>();
+    let q = parse_query("mod file rs").unwrap();
+
+    let matchers: [(&str, Box<dyn FuzzyMatcherAbstraction>); 2] = [
+        ("skim", Box::new(SkimAdapter::default())),
+        ("nucleo", Box::new(NucleoAdapter::default())),
+    ];
+    for (name, matcher) in matchers {
+        let start = Instant::now();
+        let total = files
+            .iter()
+            .map(|f| fuzzy_score(&q, f, matcher.as_ref()).total)
+            .sum::<i64>();
+        println!(
+            "{}: {:?} for {} files (total score {})",
+            name,
+            start.elapsed(),
+            files.len(),
+            total
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_inode() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let original = dir.path().join("original");
+    std::fs::File::create(&original)?;
+    std::fs::hard_link(&original, dir.path().join("link"))?;
+    std::fs::File::create(dir.path().join("other"))?;
+
+    let mut index = Index::new();
+    for name in ["original", "link", "other"] {
+        index.add_file(dir.path().join(name))?;
+    }
+    let ino = IndexedFile::new(original)?.inode;
+
+    for keyword in ["inode", "ino"] {
+        let q = parse_query(&format!("{}:{}", keyword, ino))?;
+        assert_eq!(q.includes, vec![Term::Inode(ino)]);
+        assert_eq!(query(&q, &index)?.len(), 2);
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_links() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().join("target");
+    std::fs::File::create(&target)?;
+    std::fs::hard_link(&target, dir.path().join("link"))?;
+    let symlink = dir.path().join("symlink");
+    std::os::unix::fs::symlink(&target, &symlink)?;
+
+    // the symlink itself only has one link, even though its target has two
+    let symlink = IndexedFile::new(symlink)?;
+    assert_eq!(symlink.hardlink_count, 1);
+    let target = IndexedFile::new(target)?;
+    assert_eq!(target.hardlink_count, 2);
+
+    for keyword in ["links", "nlinks", "hardlinks"] {
+        let gt = parse_query(&format!("{}:>1", keyword))?;
+        assert_eq!(gt.includes, vec![Term::LinksGt(1)]);
+        let lt = parse_query(&format!("{}:<2", keyword))?;
+        assert_eq!(lt.includes, vec![Term::LinksLt(2)]);
+        let exact = parse_query(&format!("{}:2", keyword))?;
+        assert_eq!(exact.includes, vec![Term::LinksExact(2)]);
+    }
+    let ctx = MatchContext::default();
+    assert!(Term::LinksGt(1).match_rules(&target, &ctx));
+    assert!(!Term::LinksGt(1).match_rules(&symlink, &ctx));
+    assert!(Term::LinksLt(2).match_rules(&symlink, &ctx));
+    assert!(Term::LinksExact(2).match_rules(&target, &ctx));
+    assert!(!Term::LinksExact(2).match_rules(&symlink, &ctx));
+
+    Ok(())
+}
+
+#[test]
+fn test_size_range() -> Result<()> {
+    assert_eq!(
+        parse_query("size:10KB..1MB")?.includes,
+        vec![Term::SizeRange(10_000, 1_000_000)]
+    );
+    assert_eq!(
+        parse_query("bytes:10KiB..1MiB")?.includes,
+        vec![Term::SizeRange(10_240, 1 << 20)]
+    );
+    // open ends
+    assert_eq!(
+        parse_query("size:1GB..")?.includes,
+        vec![Term::SizeRange(1_000_000_000, u64::MAX)]
+    );
+    assert_eq!(
+        parse_query("size:..512")?.includes,
+        vec![Term::SizeRange(0, 512)]
+    );
+    // not a range, or not a size
+    for input in ["size:10KB", "size:10XB..1MB", "size:ten..eleven"] {
+        assert!(
+            !matches!(parse_query(input)?.includes[0], Term::SizeRange(..)),
+            "{:?} shouldn't parse as a size range",
+            input
+        );
+    }
+
+    let ctx = MatchContext::default();
+    let file = |file_size| IndexedFile {
+        file_size,
+        ..Default::default()
+    };
+    let range = Term::SizeRange(10_000, 1_000_000);
+    // both ends are inclusive
+    assert!(range.match_rules(&file(10_000), &ctx));
+    assert!(range.match_rules(&file(1_000_000), &ctx));
+    assert!(!range.match_rules(&file(9_999), &ctx));
+    assert!(!range.match_rules(&file(1_000_001), &ctx));
+    assert!(Term::SizeRange(0, u64::MAX).match_rules(&file(0), &ctx));
+    assert!(Term::SizeRange(5, 5).match_rules(&file(5), &ctx));
+
+    assert!(parse_query("size:1KB..1KiB")?.validate().is_ok());
+    let errors = parse_query("size:1KiB..1KB")?.validate().unwrap_err();
+    assert_eq!(
+        errors,
+        vec![QueryValidationError::InvalidSizeRange {
+            lo: 1024,
+            hi: 1000
+        }]
+    );
+    assert!(!Term::SizeRange(1024, 1000).match_rules(&file(1000), &ctx));
+
+    Ok(())
+}
+
+#[test]
+fn test_mime_glob() -> Result<()> {
+    assert_eq!(
+        parse_query("mime:image/*")?.includes,
+        vec![Term::MimeGlob("image/*".to_string())]
+    );
+    assert_eq!(
+        parse_query("mime:image/png")?.includes,
+        vec![Term::Mime("image/png".to_string())]
+    );
+
+    let ctx = MatchContext::default();
+    let file = |mime: Option<&str>| IndexedFile {
+        data_type: mime.map(str::to_string),
+        ..Default::default()
+    };
+    let png = file(Some("image/png"));
+    let markdown = file(Some("text/markdown"));
+    let pdf = file(Some("application/pdf"));
+    let unknown = file(None);
+
+    let images = Term::MimeGlob("image/*".to_string());
+    assert!(images.match_rules(&png, &ctx));
+    assert!(!images.match_rules(&markdown, &ctx));
+    assert!(!images.match_rules(&unknown, &ctx));
+
+    let text = Term::MimeGlob("text/*".to_string());
+    assert!(text.match_rules(&markdown, &ctx));
+    assert!(!text.match_rules(&pdf, &ctx));
+
+    let pdfs = Term::MimeGlob("*/pdf".to_string());
+    assert!(pdfs.match_rules(&pdf, &ctx));
+    assert!(!pdfs.match_rules(&file(Some("application/x-pdf")), &ctx));
+    assert!(!pdfs.match_rules(&png, &ctx));
+
+    let anything = Term::MimeGlob("*".to_string());
+    assert!(anything.match_rules(&png, &ctx));
+    assert!(!anything.match_rules(&unknown, &ctx));
+
+    // the exact match is unchanged
+    assert!(Term::Mime("image/png".to_string()).match_rules(&png, &ctx));
+    assert!(!Term::Mime("image/jpeg".to_string()).match_rules(&png, &ctx));
+
+    Ok(())
+}
+
+#[test]
+fn test_mime_confidence() -> Result<()> {
+    let query = parse_query("mime:image/png mime_confidence:>0.8")?;
+    assert_eq!(
+        query.includes,
+        vec![
+            Term::Mime("image/png".to_string()),
+            Term::MimeConfidenceGt(80)
+        ]
+    );
+    assert_eq!(
+        parse_query("mime_confidence:<1")?.includes,
+        vec![Term::MimeConfidenceLt(100)]
+    );
+    assert!(!matches!(
+        parse_query("mime_confidence:>80")?.includes[0],
+        Term::MimeConfidenceGt(_)
+    ));
+
+    let ctx = MatchContext::default();
+    let sure = IndexedFile {
+        data_type: Some("image/png".to_string()),
+        content_type_confidence: Some(100),
+        ..Default::default()
+    };
+    let guessed = IndexedFile {
+        content_type_confidence: Some(50),
+        ..sure.clone()
+    };
+    let unknown = IndexedFile {
+        content_type_confidence: None,
+        ..sure.clone()
+    };
+    assert!(Term::MimeConfidenceGt(80).match_rules(&sure, &ctx));
+    assert!(!Term::MimeConfidenceGt(80).match_rules(&guessed, &ctx));
+    assert!(Term::MimeConfidenceLt(80).match_rules(&guessed, &ctx));
+    // never detected, so neither bound matches
+    assert!(!Term::MimeConfidenceGt(0).match_rules(&unknown, &ctx));
+    assert!(!Term::MimeConfidenceLt(100).match_rules(&unknown, &ctx));
+
+    Ok(())
+}
+
+#[test]
+fn test_unicode_normalization() -> Result<()> {
+    // "café" with a combining acute accent, as macOS stores it
+    let decomposed = IndexedFile {
+        path: PathBuf::from("/tmp/cafe\u{301}.ts\u{30c}t"),
+        ..Default::default()
+    };
+    let ctx = MatchContext::default();
+    for term in [
+        Term::Prefix("caf\u{e9}".to_string()),
+        Term::Suffix("\u{e9}.t\u{161}t".to_string()),
+        Term::SuffixName("f\u{e9}".to_string()),
+        Term::Exact("/tmp/caf\u{e9}".to_string()),
+        Term::ExactName("caf\u{e9}".to_string()),
+        Term::Extension("t\u{161}t".to_string()),
+    ] {
+        assert!(term.match_rules(&decomposed, &ctx), "{:?}", term);
+    }
+    // names that aren't UTF-8 can't be normalized, so they never match
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        let invalid = IndexedFile {
+            path: PathBuf::from(std::ffi::OsStr::from_bytes(b"/tmp/caf\xe9.t\xffst")),
+            ..Default::default()
+        };
+        for term in [
+            Term::Prefix("caf".to_string()),
+            Term::Suffix("st".to_string()),
+            Term::SuffixName("caf".to_string()),
+            Term::Extension("t\u{fffd}st".to_string()),
+        ] {
+            assert!(!term.match_rules(&invalid, &ctx), "{:?}", term);
+        }
+    }
+
+    // the tokenizer steps over whole characters, not bytes
+    assert_eq!(
+        parse_query("\"ดัชนี café\" \\é")?.includes,
+        vec![
+            Term::NormalFuzzy("ดัชนี café".to_string()),
+            Term::NormalFuzzy("é".to_string())
+        ]
+    );
+
+    let matcher = <dyn FuzzyMatcherAbstraction>::default();
+    let query = parse_query("caf\u{e9}")?;
+    assert!(fuzzy_score(&query, &decomposed, matcher.as_ref()).filename_score > 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_path_regex() -> Result<()> {
+    for (input, term) in [
+        ("regex:^main", Term::Regex(String::from("^main"))),
+        ("regex:/^main/", Term::Regex(String::from("^main"))),
+        ("regex:/^MAIN/i", Term::Regex(String::from("(?i)^MAIN"))),
+        (
+            // quoted, or the backslashes would be taken as escapes
+            "\"regex:/src\\/.*\\.rs/p\"",
+            Term::PathRegex(String::from("src/.*\\.rs")),
+        ),
+        (
+            "fullpath-regex:src/.*",
+            Term::PathRegex(String::from("src/.*")),
+        ),
+        // not a flag, so the slashes are part of the pattern
+        ("regex:/a/b", Term::Regex(String::from("/a/b"))),
+        // an escaped backslash followed by a plain slash
+        ("\"regex:/a\\\\/b/\"", Term::Regex(String::from("a\\\\/b"))),
+    ] {
+        assert_eq!(parse_query(input)?.includes, vec![term], "{}", input);
+    }
+
+    let file = IndexedFile {
+        path: PathBuf::from("/home/user/src/main.rs"),
+        ..Default::default()
+    };
+    let ctx = MatchContext::default();
+    // a bare regex only sees the file name
+    assert!(Term::Regex(String::from("^main\\.rs$")).match_rules(&file, &ctx));
+    assert!(!Term::Regex(String::from("src/.*\\.rs")).match_rules(&file, &ctx));
+    assert!(Term::PathRegex(String::from("src/.*\\.rs")).match_rules(&file, &ctx));
+    assert!(!Term::PathRegex(String::from("^main")).match_rules(&file, &ctx));
+
+    // compiled once, then reused by every clone of the context
+    let clone = ctx.clone();
+    assert!(Term::PathRegex(String::from("^/home")).match_rules(&file, &clone));
+    assert_eq!(ctx.regex_cache.len(), 4);
+    assert!(Term::PathRegex(String::from("("))
+        .try_match_rules(&file, &ctx)
+        .is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_binary_text() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let text = dir.path().join("text.txt");
+    std::fs::write(&text, "ดัชนี, a certain magical indexer\n")?;
+    let text = IndexedFile::new(text)?;
+    assert_eq!(text.is_text, Some(true));
+
+    // the test binary itself is a compiled executable
+    let binary = IndexedFile::new(std::env::current_exe()?)?;
+    assert_eq!(binary.is_text, Some(false));
+
+    for (input, term) in [
+        ("binary:true", Term::Binary),
+        ("is:binary", Term::Binary),
+        ("text:false", Term::Binary),
+        ("text:true", Term::Text),
+        ("is:text", Term::Text),
+        ("binary:false", Term::Text),
+    ] {
+        assert_eq!(parse_query(input)?.includes, vec![term]);
+    }
+
+    let ctx = MatchContext::default();
+    assert!(Term::Text.match_rules(&text, &ctx));
+    assert!(!Term::Binary.match_rules(&text, &ctx));
+    assert!(Term::Binary.match_rules(&binary, &ctx));
+    assert!(!Term::Text.match_rules(&binary, &ctx));
+
+    // without `is_text`, fall back to the MIME type
+    let mime = |data_type: Option<&str>| IndexedFile {
+        data_type: data_type.map(String::from),
+        ..Default::default()
+    };
+    assert!(Term::Text.match_rules(&mime(Some("text/plain")), &ctx));
+    assert!(Term::Binary.match_rules(&mime(Some("image/png")), &ctx));
+    assert!(!Term::Text.match_rules(&mime(None), &ctx));
+    assert!(!Term::Binary.match_rules(&mime(None), &ctx));
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_sparse() -> Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("sparse");
+    let mut file = std::fs::File::create(&path)?;
+    // skip over a megabyte without writing it, leaving a hole
+    file.seek(SeekFrom::Start(1024 * 1024))?;
+    file.write_all(b"owo")?;
+    drop(file);
+    let sparse = IndexedFile::new(path)?;
+    assert!(sparse.sparse);
+
+    let dense = dir.path().join("dense");
+    std::fs::write(&dense, vec![1; 1024 * 1024])?;
+    let dense = IndexedFile::new(dense)?;
+    assert!(!dense.sparse);
+
+    for (input, term) in [
+        ("sparse:true", Term::Sparse),
+        ("is:sparse", Term::Sparse),
+        ("sparse:false", Term::NonSparse),
+    ] {
+        assert_eq!(parse_query(input)?.includes, vec![term]);
+    }
+    let ctx = MatchContext::default();
+    assert!(Term::Sparse.match_rules(&sparse, &ctx));
+    assert!(!Term::Sparse.match_rules(&dense, &ctx));
+    assert!(Term::NonSparse.match_rules(&dense, &ctx));
+
+    Ok(())
+}
+
+#[test]
+fn test_xattr() -> Result<()> {
+    assert_eq!(
+        parse_query("xattr:user.comment")?.includes,
+        vec![Term::XattrKey("user.comment".to_string())]
+    );
+    let term = Term::XattrValue {
+        key: "user.comment".to_string(),
+        value: "a=b".to_string(),
+    };
+    assert_eq!(
+        parse_query("xattr:user.comment=a=b")?.includes,
+        vec![term.clone()]
+    );
+
+    let mut file = IndexedFile::default();
+    let ctx = MatchContext::default();
+    assert!(!Term::XattrKey("user.comment".to_string()).match_rules(&file, &ctx));
+    file.xattrs
+        .insert("user.comment".to_string(), b"a=b".to_vec());
+    assert!(Term::XattrKey("user.comment".to_string()).match_rules(&file, &ctx));
+    assert!(term.match_rules(&file, &ctx));
+
+    // values that aren't UTF-8 never match
+    file.xattrs
+        .insert("user.comment".to_string(), vec![0xff, 0xfe]);
+    assert!(Term::XattrKey("user.comment".to_string()).match_rules(&file, &ctx));
+    assert!(!term.match_rules(&file, &ctx));
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn test_accessible() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    for (input, mode) in [
+        ("can:read", AccessMode::Read),
+        ("can:write", AccessMode::Write),
+        ("can:execute", AccessMode::Execute),
+        ("can:rw", AccessMode::ReadWrite),
+        ("readable:true", AccessMode::Read),
+        ("writable:true", AccessMode::Write),
+        ("executable:true", AccessMode::Execute),
+    ] {
+        assert_eq!(parse_query(input)?.includes, vec![Term::Accessible(mode)]);
+    }
+
+    let dir = tempfile::tempdir()?;
+    let script = dir.path().join("script.sh");
+    let locked = dir.path().join("locked");
+    std::fs::write(&script, "#!/bin/sh")?;
+    std::fs::write(&locked, "")?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))?;
+    let script = IndexedFile::new(script)?;
+    let locked = IndexedFile::new(locked)?;
+    // root can read and write anything, no matter the permissions
+    let root = nix::unistd::geteuid().is_root();
+
+    let live = MatchContext {
+        config: ScoringConfig {
+            live_checks: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    for ctx in [MatchContext::default(), live] {
+        for mode in [
+            AccessMode::Read,
+            AccessMode::Write,
+            AccessMode::Execute,
+            AccessMode::ReadWrite,
+        ] {
+            assert!(Term::Accessible(mode).match_rules(&script, &ctx));
+        }
+        assert!(!Term::Accessible(AccessMode::Execute).match_rules(&locked, &ctx));
+        assert_eq!(
+            Term::Accessible(AccessMode::Read).match_rules(&locked, &ctx),
+            root
+        );
+        assert_eq!(
+            Term::Accessible(AccessMode::Write).match_rules(&locked, &ctx),
+            root
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_tag_fuzzy() {
+    let tagged = |tag: &str| IndexedFile {
+        tags: vec![String::from(tag)],
+        ..Default::default()
+    };
+
+    for input in ["tag:tod~", "#tod~", "tags:tod~"] {
+        let query = parse_query(input).unwrap();
+        assert_eq!(query.includes, vec![Term::TagFuzzy(String::from("tod"))]);
+    }
+    assert_eq!(
+        parse_query("#todo").unwrap().includes,
+        vec![Term::Tag(String::from("todo"))]
+    );
+
+    let term = Term::TagFuzzy(String::from("tod"));
+    let ctx = MatchContext::default();
+    for tag in ["todo", "todos", "todone"] {
+        assert!(term.match_rules(&tagged(tag), &ctx), "{} should match", tag);
+    }
+    assert!(!term.match_rules(&tagged("docs"), &ctx));
+    assert!(!term.match_rules(&IndexedFile::default(), &ctx));
+
+    // an impossible threshold rejects everything
+    let mut strict = MatchContext::default();
+    strict.config.tags.fuzzy_threshold = i64::MAX;
+    assert!(!term.match_rules(&tagged("todo"), &strict));
+}
+
+#[test]
+fn test_query_errors() {
+    let index = Index {
+        files: vec![IndexedFile {
+            path: PathBuf::from("src/main.rs"),
+            ..Default::default()
+        }],
+        ..Index::new()
+    };
+
+    let malformed = Query {
+        includes: vec![Term::Regex("(".to_string())],
+        excludes: vec![],
+    };
+    match query(&malformed, &index) {
+        Err(DatchaniError::InvalidRegex { pattern, .. }) => assert_eq!(pattern, "("),
+        other => panic!("expected an invalid regex error, got {:?}", other),
+    }
+    // invalid terms never match when used on their own
+    assert!(!malformed.includes[0].match_rules(&index[0], &MatchContext::default()));
+
+    let bad_date = parse_query("-before:yesterday").unwrap();
+    assert_eq!(
+        query(&bad_date, &index).unwrap_err(),
+        DatchaniError::InvalidDate(String::from("yesterday"))
+    );
+
+    let ok = parse_query("regex:^ma before:2100-01-01").unwrap();
+    assert_eq!(query(&ok, &index).unwrap().len(), 1);
+}
+
+#[test]
+fn test_modified_within() {
+    let cases = [
+        ("30s", 30),
+        ("30m", 30 * 60),
+        ("1h", 60 * 60),
+        ("7d", 7 * 24 * 60 * 60),
+        ("2w", 2 * 7 * 24 * 60 * 60),
+    ];
+    for (input, seconds) in cases {
+        for keyword in ["modified", "changed", "newer"] {
+            let query = parse_query(&format!("{}:{}", keyword, input)).unwrap();
+            assert_eq!(
+                query.includes,
+                vec![Term::ModifiedWithin(chrono::Duration::seconds(seconds))]
+            );
+        }
+
+        let duration = chrono::Duration::seconds(seconds);
+        let inside = IndexedFile {
+            last_modified: Utc::now() - duration + chrono::Duration::seconds(1),
+            ..Default::default()
+        };
+        let outside = IndexedFile {
+            last_modified: Utc::now() - duration - chrono::Duration::seconds(2),
+            ..Default::default()
+        };
+        let term = Term::ModifiedWithin(duration);
+        assert!(term.match_rules(&inside, &MatchContext::default()));
+        assert!(!term.match_rules(&outside, &MatchContext::default()));
+    }
+
+    // anything modified in the current second
+    let now = IndexedFile {
+        last_modified: Utc::now(),
+        ..Default::default()
+    };
+    let query = parse_query("modified:0s").unwrap();
+    assert!(query.includes[0].match_rules(&now, &MatchContext::default()));
+
+    // not a duration, so it's a fuzzy term
+    let query = parse_query("modified:1y").unwrap();
+    assert_eq!(
+        query.includes,
+        vec![Term::NormalFuzzy(String::from("modified:1y"))]
+    );
+}
+
+#[test]
+fn test_modified_periods() -> Result<()> {
+    let cases = [
+        ("today", Term::ModifiedToday, Term::CreatedToday),
+        ("this-week", Term::ModifiedThisWeek, Term::CreatedThisWeek),
+        (
+            "this-month",
+            Term::ModifiedThisMonth,
+            Term::CreatedThisMonth,
+        ),
+        ("this-year", Term::ModifiedThisYear, Term::CreatedThisYear),
+    ];
+    let ctx = MatchContext::default();
+    let now = Utc::now();
+    let recent = IndexedFile {
+        last_modified: now,
+        created: Some(now),
+        ..Default::default()
+    };
+    let old = IndexedFile {
+        last_modified: now - chrono::Duration::days(400),
+        created: Some(now - chrono::Duration::days(400)),
+        ..Default::default()
+    };
+    let unknown = IndexedFile {
+        last_modified: now,
+        created: None,
+        ..Default::default()
+    };
+    for (period, modified, created) in cases {
+        assert_eq!(
+            parse_query(&format!("modified:{}", period))?.includes,
+            vec![modified.clone()]
+        );
+        assert_eq!(
+            parse_query(&format!("created:{}", period))?.includes,
+            vec![created.clone()]
+        );
+        for term in [&modified, &created] {
+            assert!(term.match_rules(&recent, &ctx), "{:?}", term);
+            assert!(!term.match_rules(&old, &ctx), "{:?}", term);
         }
+        assert!(!created.match_rules(&unknown, &ctx));
+    }
+
+    // an hour ago is only today when it's past 1am UTC
+    let hour_ago = IndexedFile {
+        last_modified: now - chrono::Duration::hours(1),
+        ..Default::default()
+    };
+    assert_eq!(
+        Term::ModifiedToday.match_rules(&hour_ago, &ctx),
+        hour_ago.last_modified.date_naive() == Utc::now().date_naive()
+    );
+
+    // not a period, so they're fuzzy terms
+    for input in ["created:yesterday", "modified:this-decade"] {
+        assert_eq!(
+            parse_query(input)?.includes,
+            vec![Term::NormalFuzzy(String::from(input))]
+        );
     }
+
+    Ok(())
 }
 
-// our control group
+// Birth time isn't supported everywhere, so this test does nothing on
+// filesystems that don't record it
 #[test]
-fn test_query() {
-    let query = "prefix:foo suffix:bar baz -qux -\"aaa bbb\" -extension:md #owo -#uwu";
+fn test_created() -> Result<()> {
+    let query = parse_query("created-before:2023-01-15 ca:2023-01-01T12:00:00Z")?;
+    assert_eq!(
+        query.includes,
+        vec![
+            Term::CreatedBefore(Utc.with_ymd_and_hms(2023, 1, 15, 0, 0, 0).unwrap()),
+            Term::CreatedAfter(Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap()),
+        ]
+    );
 
-    let query = parse_query(query).unwrap();
+    let ctx = MatchContext::default();
+    let unknown = IndexedFile::default();
+    assert!(!query.includes[0].match_rules(&unknown, &ctx));
+    assert!(!query.includes[1].match_rules(&unknown, &ctx));
 
-    println!("{:#?}", query);
+    let file = tempfile::NamedTempFile::new()?;
+    let ixf = IndexedFile::new(file.path().to_path_buf())?;
+    if let Some(created) = ixf.created {
+        let hour = chrono::Duration::hours(1);
+        assert!(Term::CreatedAfter(created - hour).match_rules(&ixf, &ctx));
+        assert!(!Term::CreatedBefore(created - hour).match_rules(&ixf, &ctx));
+        assert!(Term::CreatedBefore(created + hour).match_rules(&ixf, &ctx));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_accessed() -> Result<()> {
+    let query = parse_query("accessed-before:2023-01-15 atime:>2023-01-01T12:00:00Z")?;
     assert_eq!(
-        query,
-        Query {
-            includes: vec![
-                Term::Prefix(String::from("foo")),
-                Term::Suffix(String::from("bar")),
-                Term::NormalFuzzy(String::from("baz")),
-                Term::Tag(String::from("owo")),
-            ],
-            excludes: vec![
-                Term::Exact(String::from("qux")),
-                Term::Exact(String::from("aaa bbb")),
-                Term::Extension(String::from("md")),
-                Term::Tag(String::from("uwu")),
-            ],
-        }
+        query.includes,
+        vec![
+            Term::AccessedBefore(Utc.with_ymd_and_hms(2023, 1, 15, 0, 0, 0).unwrap()),
+            Term::AccessedAfter(Utc.with_ymd_and_hms(2023, 1, 1, 12, 0, 0).unwrap()),
+        ]
     );
+    assert_eq!(
+        parse_query("accessed-after:2023-01-01 atime:<2023-01-01")?.includes,
+        parse_query("atime:>2023-01-01 accessed-before:2023-01-01")?.includes,
+    );
+
+    let ctx = MatchContext::default();
+    let unknown = IndexedFile::default();
+    assert!(!query.includes[0].match_rules(&unknown, &ctx));
+    assert!(!query.includes[1].match_rules(&unknown, &ctx));
+
+    let file = tempfile::NamedTempFile::new()?;
+    std::fs::read(file.path())?;
+    let ixf = IndexedFile::new(file.path().to_path_buf())?;
+    let accessed = ixf.accessed.expect("access time should be available");
+    let hour = chrono::Duration::hours(1);
+    assert!(Term::AccessedAfter(accessed - hour).match_rules(&ixf, &ctx));
+    assert!(!Term::AccessedBefore(accessed - hour).match_rules(&ixf, &ctx));
+    assert!(Term::AccessedBefore(accessed + hour).match_rules(&ixf, &ctx));
+
+    Ok(())
+}
+
+#[test]
+fn test_top_n() -> Result<()> {
+    for keyword in ["top", "limit", "take", "first"] {
+        let query = parse_query(&format!("ext:rs {}:3", keyword))?;
+        assert_eq!(query.includes[1], Term::TopN(3));
+        assert_eq!(query.result_limit(), Some(3));
+    }
+    assert_eq!(parse_query("top:5 top:2 top:9")?.result_limit(), Some(2));
+    assert_eq!(parse_query("ext:rs")?.result_limit(), None);
+
+    let mut index = Index::new();
+    index.files = (0..10)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("file_{}.rs", i)),
+            ..Default::default()
+        })
+        .collect();
+    assert_eq!(query(&parse_query("top:3")?, &index)?.len(), 3);
+    assert_eq!(query(&parse_query("ext:rs top:20")?, &index)?.len(), 10);
+    assert!(query(&parse_query("top:0")?, &index)?.is_empty());
+
+    let best = query(&parse_query("file_7 top:1")?, &index)?;
+    assert_eq!(best[0].1.path, PathBuf::from("file_7.rs"));
+    // negating a modifier doesn't filter anything
+    assert_eq!(query(&parse_query("-top:3")?, &index)?.len(), 10);
+    assert_eq!(query(&parse_query("not:top:3")?, &index)?.len(), 10);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_top_n_stream() -> Result<()> {
+    use futures_util::StreamExt;
+
+    let mut index = Index::new();
+    index.files = (0..10)
+        .map(|i| IndexedFile {
+            path: PathBuf::from(format!("file_{}.rs", i)),
+            ..Default::default()
+        })
+        .collect();
+    // the best match is last in the index, so the first match in walk order isn't it
+    let best = query_stream(parse_query("file_9 top:1")?, index)
+        .collect::<Vec<_>>()
+        .await;
+    assert_eq!(best.len(), 1);
+    assert_eq!(best[0].as_ref().unwrap().1.path, PathBuf::from("file_9.rs"));
+
+    Ok(())
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Query {
     pub includes: Vec<Term>,
     pub excludes: Vec<Term>,
 }
 
+impl Query {
+    /// Returns true if the query has no terms at all,
+    /// usually because it was parsed from an empty or whitespace-only string
+    pub fn is_empty(&self) -> bool {
+        self.includes.is_empty() && self.excludes.is_empty()
+    }
+
+    /// Returns true if the query would match every file in the index.
+    /// This is a synonym of `is_empty`
+    pub fn matches_all(&self) -> bool {
+        self.is_empty()
+    }
+
+    /// The most results to return, from `top:` terms
+    pub fn result_limit(&self) -> Option<usize> {
+        self.includes
+            .iter()
+            .filter_map(|term| match term {
+                Term::TopN(n) => Some(*n),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Check every term can be evaluated, without touching any files.
+    /// Returns all the problems found, not just the first one
+    pub fn validate(&self) -> std::result::Result<(), Vec<QueryValidationError>> {
+        let errors = self
+            .includes
+            .iter()
+            .chain(&self.excludes)
+            .map(Term::innermost)
+            .filter_map(|term| match term {
+                Term::Regex(pattern) | Term::PathRegex(pattern) => {
+                    Regex::new(pattern).err().map(|source| QueryValidationError::InvalidRegex {
+                        pattern: pattern.clone(),
+                        source,
+                    })
+                }
+                Term::Before(raw) | Term::After(raw) if parse_datetime(raw).is_none() => {
+                    Some(QueryValidationError::InvalidDate { raw: raw.clone() })
+                }
+                Term::SizeRange(lo, hi) if lo > hi => {
+                    Some(QueryValidationError::InvalidSizeRange { lo: *lo, hi: *hi })
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[test]
+fn test_empty_query() {
+    for input in ["", "  ", "\t", " \t \n"] {
+        let query = parse_query(input).unwrap();
+        assert!(query.is_empty(), "{:?} should parse to an empty query", input);
+        assert!(query.matches_all());
+    }
+
+    assert!(!parse_query(" foo  ").unwrap().is_empty());
+}
+
+#[test]
+fn test_validate() {
+    let query = Query {
+        includes: vec![
+            Term::Regex(String::from("(")),
+            Term::Exact(String::from("owo")),
+        ],
+        excludes: vec![],
+    };
+    let errors = query.validate().unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(matches!(
+        &errors[0],
+        QueryValidationError::InvalidRegex { pattern, .. } if pattern == "("
+    ));
+
+    // excludes are checked too, and every error is reported
+    let query = Query {
+        includes: vec![Term::Before(String::from("yesterday"))],
+        excludes: vec![Term::Regex(String::from("[a-"))],
+    };
+    assert_eq!(query.validate().unwrap_err().len(), 2);
+
+    assert!(parse_query("regex:^main before:2023-01-01 foo")
+        .unwrap()
+        .validate()
+        .is_ok());
+}
+
 // TODO: dedup this please
 
 fn parse_prefix(input: &str) -> IResult<&str, Term> {
@@ -198,21 +2343,45 @@ fn parse_extension(input: &str) -> IResult<&str, Term> {
     let (input, _) = alt((tag("extension:"), tag("ext:"), tag("file:")))(input)?;
     let (input, extension) = take_while1(|_| true)(input)?;
 
-    Ok((input, Term::Extension(String::from(extension))))
+    // `ext:rs,` is still just `ext:rs`
+    let mut extensions = extension
+        .split(',')
+        .filter(|ext| !ext.is_empty())
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let term = match extensions.len() {
+        0 => Term::Extension(String::from(extension)),
+        1 => Term::Extension(extensions.remove(0)),
+        _ => Term::ExtensionSet(extensions),
+    };
+    Ok((input, term))
 }
 
 fn parse_mime(input: &str) -> IResult<&str, Term> {
     let (input, _) = tag("mime:")(input)?;
     let (input, mime) = take_while1(|c: char| c.is_ascii() || c == '_')(input)?;
 
+    if mime.contains('*') {
+        return Ok((input, Term::MimeGlob(String::from(mime))));
+    }
     Ok((input, Term::Mime(String::from(mime))))
 }
 
+/// The anchored regex equivalent of a `mime:` glob, e.g. `*/pdf` becomes `^.*/pdf$`
+fn mime_glob_regex(pattern: &str) -> String {
+    let parts = pattern.split('*').map(regex::escape).collect::<Vec<_>>();
+    format!("^{}$", parts.join(".*"))
+}
+
 fn parse_tag(input: &str) -> IResult<&str, Term> {
     let (input, _) = alt((tag("#"), tag("tag:"), tag("tags:"), tag("tagged:")))(input)?;
     let (input, tag) = take_while1(|_| true)(input)?;
 
-    Ok((input, Term::Tag(String::from(tag))))
+    // a trailing `~` asks for fuzzy matching
+    match tag.strip_suffix('~') {
+        Some(tag) if !tag.is_empty() => Ok((input, Term::TagFuzzy(String::from(tag)))),
+        _ => Ok((input, Term::Tag(String::from(tag)))),
+    }
 }
 
 fn parse_exact(input: &str) -> IResult<&str, Term> {
@@ -222,17 +2391,92 @@ fn parse_exact(input: &str) -> IResult<&str, Term> {
     Ok((input, Term::Exact(String::from(exact))))
 }
 
+fn parse_segment(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("segment:"), tag("component:"), tag("dir:")))(input)?;
+    let (input, segment) = take_while1(|_| true)(input)?;
+
+    Ok((input, Term::PathSegment(String::from(segment))))
+}
+
+fn parse_ancestor(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("ancestor:"), tag("under:"), tag("beneath:")))(input)?;
+    let (input, name) = take_while1(|_| true)(input)?;
+
+    Ok((input, Term::Ancestor(String::from(name))))
+}
+
+fn parse_exact_name(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("exact-name:"), tag("name-exact:")))(input)?;
+    let (input, exact) = take_while1(|_| true)(input)?;
+
+    Ok((input, Term::ExactName(String::from(exact))))
+}
+
 fn parse_regex(input: &str) -> IResult<&str, Term> {
-    let (input, _) = alt((
-        tag("regex:"),
-        tag("re:"),
-        tag("r:"),
-        tag("regexp:"),
-        tag("rgx:"),
+    let (input, full_path) = alt((
+        nom::combinator::value(true, tag("fullpath-regex:")),
+        nom::combinator::value(
+            false,
+            alt((
+                tag("regex:"),
+                tag("re:"),
+                tag("r:"),
+                tag("regexp:"),
+                tag("rgx:"),
+            )),
+        ),
     ))(input)?;
     let (input, regex) = take_while1(|_| true)(input)?;
 
-    Ok((input, Term::Regex(String::from(regex))))
+    let (pattern, path_flag) = split_regex_flags(regex);
+    if full_path || path_flag {
+        Ok((input, Term::PathRegex(pattern)))
+    } else {
+        Ok((input, Term::Regex(pattern)))
+    }
+}
+
+/// Unwrap a `/pattern/flags` regex, returning the pattern and whether the `p` flag was given.
+/// `i` turns into `(?i)`, and `\/` into a plain `/`.
+/// Anything else, e.g. without a closing slash or with unknown flags, is a bare pattern
+fn split_regex_flags(regex: &str) -> (String, bool) {
+    let wrapped = regex
+        .strip_prefix('/')
+        .and_then(|rest| rest.rsplit_once('/'))
+        .filter(|(_, flags)| flags.chars().all(|c| c == 'i' || c == 'p'));
+    match wrapped {
+        Some((pattern, flags)) => {
+            let pattern = unescape_slashes(pattern);
+            let pattern = if flags.contains('i') {
+                format!("(?i){}", pattern)
+            } else {
+                pattern
+            };
+            (pattern, flags.contains('p'))
+        }
+        None => (regex.to_string(), false),
+    }
+}
+
+/// Turn `\/` into `/`, leaving every other escape (including `\\`) to the regex itself
+fn unescape_slashes(pattern: &str) -> String {
+    let mut unescaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('/') => unescaped.push('/'),
+            Some(next) => {
+                unescaped.push('\\');
+                unescaped.push(next);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
 }
 
 fn parse_before(input: &str) -> IResult<&str, Term> {
@@ -249,6 +2493,325 @@ fn parse_after(input: &str) -> IResult<&str, Term> {
     Ok((input, Term::After(String::from(after))))
 }
 
+/// Parse a relative duration like `30m` or `7d`
+fn parse_duration(input: &str) -> Option<chrono::Duration> {
+    let split = input.find(|c: char| !c.is_ascii_digit())?;
+    let (number, unit) = input.split_at(split);
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let seconds = number.parse::<u64>().ok()?.checked_mul(multiplier)?;
+    chrono::Duration::from_std(std::time::Duration::from_secs(seconds)).ok()
+}
+
+fn parse_modified(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("modified:"), tag("changed:"), tag("newer:")))(input)?;
+    let (input, duration) = take_while1(|_| true)(input)?;
+
+    match Period::parse(duration) {
+        Some(Period::Today) => return Ok((input, Term::ModifiedToday)),
+        Some(Period::ThisWeek) => return Ok((input, Term::ModifiedThisWeek)),
+        Some(Period::ThisMonth) => return Ok((input, Term::ModifiedThisMonth)),
+        Some(Period::ThisYear) => return Ok((input, Term::ModifiedThisYear)),
+        None => {}
+    }
+    match parse_duration(duration) {
+        Some(duration) => Ok((input, Term::ModifiedWithin(duration))),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            duration,
+            nom::error::ErrorKind::Verify,
+        ))),
+    }
+}
+
+fn parse_octal(input: &str) -> Option<u32> {
+    u32::from_str_radix(input.strip_prefix("0o").unwrap_or(input), 8).ok()
+}
+
+fn parse_perm(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("perm:"), tag("mode:"), tag("chmod:")))(input)?;
+    let (input, perm) = take_while1(|_| true)(input)?;
+
+    // `mode/mask`, or just `mode` to match the exact permission bits
+    let (mode, mask) = match perm.split_once('/') {
+        Some((mode, mask)) => (parse_octal(mode), parse_octal(mask)),
+        None => (parse_octal(perm), Some(0o7777)),
+    };
+
+    match (mode, mask) {
+        (Some(mode), Some(mask)) => Ok((input, Term::PermMode(mode, mask))),
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            perm,
+            nom::error::ErrorKind::Digit,
+        ))),
+    }
+}
+
+fn parse_owner(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("owner:"), tag("user:"), tag("uid:")))(input)?;
+    let (input, owner) = take_while1(|_| true)(input)?;
+
+    let term = match owner.parse::<u32>() {
+        Ok(uid) => Term::OwnerUid(uid),
+        Err(_) => Term::OwnerUser(String::from(owner)),
+    };
+    Ok((input, term))
+}
+
+fn parse_group(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("group:"), tag("gid:")))(input)?;
+    let (input, group) = take_while1(|_| true)(input)?;
+
+    let term = match group.parse::<u32>() {
+        Ok(gid) => Term::OwnerGid(gid),
+        Err(_) => Term::OwnerGroup(String::from(group)),
+    };
+    Ok((input, term))
+}
+
+fn parse_depth(input: &str) -> IResult<&str, Term> {
+    let (input, _) = tag("depth:")(input)?;
+    let (input, depth) = nom::character::complete::digit1(input)?;
+
+    // digit1 only ever returns digits, so this can only fail on overflow
+    match depth.parse() {
+        Ok(depth) => Ok((input, Term::DepthExact(depth))),
+        Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+            depth,
+            nom::error::ErrorKind::Digit,
+        ))),
+    }
+}
+
+fn parse_inode(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("inode:"), tag("ino:")))(input)?;
+    let (input, ino) = nom::character::complete::digit1(input)?;
+
+    match ino.parse() {
+        Ok(ino) => Ok((input, Term::Inode(ino))),
+        Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+            ino,
+            nom::error::ErrorKind::Digit,
+        ))),
+    }
+}
+
+fn parse_links(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("links:"), tag("nlinks:"), tag("hardlinks:")))(input)?;
+    // without `>` or `<` the count has to match exactly
+    let (input, greater) = nom::combinator::opt(alt((
+        nom::combinator::value(true, tag(">")),
+        nom::combinator::value(false, tag("<")),
+    )))(input)?;
+    let (input, n) = nom::character::complete::digit1(input)?;
+
+    match n.parse() {
+        Ok(n) if greater == Some(true) => Ok((input, Term::LinksGt(n))),
+        Ok(n) if greater == Some(false) => Ok((input, Term::LinksLt(n))),
+        Ok(n) => Ok((input, Term::LinksExact(n))),
+        Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+            n,
+            nom::error::ErrorKind::Digit,
+        ))),
+    }
+}
+
+fn parse_size_range(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("size:"), tag("bytes:")))(input)?;
+    let (lo, hi) = input.split_once("..").ok_or_else(|| {
+        nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag))
+    })?;
+    // an empty end leaves that side of the range open
+    let bound = |raw: &str, open: u64| match raw {
+        "" => Ok(open),
+        raw => parse_size(raw),
+    };
+
+    match (bound(lo, 0), bound(hi, u64::MAX)) {
+        (Ok(lo), Ok(hi)) => Ok(("", Term::SizeRange(lo, hi))),
+        _ => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Digit,
+        ))),
+    }
+}
+
+fn parse_mime_confidence(input: &str) -> IResult<&str, Term> {
+    let (input, _) = tag("mime_confidence:")(input)?;
+    let (input, greater) = alt((
+        nom::combinator::value(true, tag(">")),
+        nom::combinator::value(false, tag("<")),
+    ))(input)?;
+    let (rest, n) = nom::number::complete::double(input)?;
+    // written as a fraction, but stored in percent like `IndexedFile::content_type_confidence`
+    if !(0.0..=1.0).contains(&n) {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let percent = (n * 100.0).round() as u8;
+
+    if greater {
+        Ok((rest, Term::MimeConfidenceGt(percent)))
+    } else {
+        Ok((rest, Term::MimeConfidenceLt(percent)))
+    }
+}
+
+/// Parse either an RFC 3339 timestamp or a plain `YYYY-MM-DD` date (midnight UTC)
+fn parse_datetime(input: &str) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(input) {
+        return Some(date.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(input, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date| Utc.from_utc_datetime(&date))
+}
+
+fn parse_accessed(input: &str) -> IResult<&str, Term> {
+    let (input, before) = alt((
+        nom::combinator::value(true, alt((tag("accessed-before:"), tag("atime:<")))),
+        nom::combinator::value(false, alt((tag("accessed-after:"), tag("atime:>")))),
+    ))(input)?;
+    let (input, date) = take_while1(|_| true)(input)?;
+
+    match parse_datetime(date) {
+        Some(date) if before => Ok((input, Term::AccessedBefore(date))),
+        Some(date) => Ok((input, Term::AccessedAfter(date))),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            date,
+            nom::error::ErrorKind::Verify,
+        ))),
+    }
+}
+
+fn parse_created(input: &str) -> IResult<&str, Term> {
+    let (input, before) = alt((
+        nom::combinator::value(true, alt((tag("created-before:"), tag("cb:")))),
+        nom::combinator::value(false, alt((tag("created-after:"), tag("ca:")))),
+    ))(input)?;
+    let (input, date) = take_while1(|_| true)(input)?;
+
+    match parse_datetime(date) {
+        Some(date) if before => Ok((input, Term::CreatedBefore(date))),
+        Some(date) => Ok((input, Term::CreatedAfter(date))),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            date,
+            nom::error::ErrorKind::Verify,
+        ))),
+    }
+}
+
+fn parse_created_period(input: &str) -> IResult<&str, Term> {
+    let (input, _) = tag("created:")(input)?;
+    let (input, period) = take_while1(|_| true)(input)?;
+
+    match Period::parse(period) {
+        Some(Period::Today) => Ok((input, Term::CreatedToday)),
+        Some(Period::ThisWeek) => Ok((input, Term::CreatedThisWeek)),
+        Some(Period::ThisMonth) => Ok((input, Term::CreatedThisMonth)),
+        Some(Period::ThisYear) => Ok((input, Term::CreatedThisYear)),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            period,
+            nom::error::ErrorKind::Verify,
+        ))),
+    }
+}
+
+fn parse_case(input: &str) -> IResult<&str, Term> {
+    let (input, _) = tag("case:")(input)?;
+    let (input, case) = terminated(
+        alt((
+            nom::combinator::value(CaseSensitivity::Insensitive, tag("insensitive")),
+            nom::combinator::value(CaseSensitivity::Sensitive, tag("sensitive")),
+            nom::combinator::value(CaseSensitivity::Smart, tag("smart")),
+        )),
+        eof,
+    )(input)?;
+
+    Ok((input, Term::Case(case)))
+}
+
+fn parse_access(input: &str) -> IResult<&str, Term> {
+    use nom::combinator::value;
+
+    let (input, mode) = terminated(
+        alt((
+            nom::sequence::preceded(
+                tag("can:"),
+                alt((
+                    value(AccessMode::ReadWrite, alt((tag("readwrite"), tag("rw")))),
+                    value(AccessMode::Read, tag("read")),
+                    value(AccessMode::Write, tag("write")),
+                    value(AccessMode::Execute, alt((tag("execute"), tag("exec")))),
+                )),
+            ),
+            value(AccessMode::Read, tag("readable:true")),
+            value(AccessMode::Write, tag("writable:true")),
+            value(AccessMode::Execute, tag("executable:true")),
+        )),
+        eof,
+    )(input)?;
+
+    Ok((input, Term::Accessible(mode)))
+}
+
+fn parse_binary(input: &str) -> IResult<&str, Term> {
+    terminated(
+        alt((
+            nom::combinator::value(Term::Binary, alt((tag("binary:true"), tag("is:binary")))),
+            nom::combinator::value(Term::Binary, tag("text:false")),
+            nom::combinator::value(Term::Text, alt((tag("text:true"), tag("is:text")))),
+            nom::combinator::value(Term::Text, tag("binary:false")),
+        )),
+        eof,
+    )(input)
+}
+
+fn parse_sparse(input: &str) -> IResult<&str, Term> {
+    terminated(
+        alt((
+            nom::combinator::value(Term::Sparse, alt((tag("sparse:true"), tag("is:sparse")))),
+            nom::combinator::value(Term::NonSparse, tag("sparse:false")),
+        )),
+        eof,
+    )(input)
+}
+
+fn parse_xattr(input: &str) -> IResult<&str, Term> {
+    let (input, _) = tag("xattr:")(input)?;
+    let (input, xattr) = take_while1(|_| true)(input)?;
+
+    let term = match xattr.split_once('=') {
+        Some((key, value)) => Term::XattrValue {
+            key: String::from(key),
+            value: String::from(value),
+        },
+        None => Term::XattrKey(String::from(xattr)),
+    };
+    Ok((input, term))
+}
+
+fn parse_top(input: &str) -> IResult<&str, Term> {
+    let (input, _) = alt((tag("top:"), tag("limit:"), tag("take:"), tag("first:")))(input)?;
+    let (input, n) = nom::character::complete::digit1(input)?;
+
+    match n.parse() {
+        Ok(n) => Ok((input, Term::TopN(n))),
+        Err(_) => Err(nom::Err::Error(nom::error::Error::new(
+            n,
+            nom::error::ErrorKind::Digit,
+        ))),
+    }
+}
+
 fn parse_fuzzy(input: &str) -> IResult<&str, Term> {
     // do nothing and just return the input
     Ok((input, Term::NormalFuzzy(String::from(input))))
@@ -258,6 +2821,7 @@ fn parse_fuzzy(input: &str) -> IResult<&str, Term> {
 /// It will take any string and return a Term enum.
 fn parse_term(input: &str) -> IResult<&str, Term> {
     let (input, term) = alt((
+        parse_not,
         parse_regex,
         parse_prefix,
         parse_extension,
@@ -265,15 +2829,75 @@ fn parse_term(input: &str) -> IResult<&str, Term> {
         parse_suffix,
         parse_before,
         parse_after,
+        parse_modified,
         parse_mime,
         parse_tag,
+        parse_exact_name,
         parse_exact,
+        parse_segment,
+        parse_ancestor,
+        parse_metadata,
+        parse_case,
+        parse_top,
         parse_fuzzy,
     ))(input)?;
 
     Ok((input, term))
 }
 
+fn parse_not(input: &str) -> IResult<&str, Term> {
+    let (input, _) = tag("not:")(input)?;
+    let (input, term) = parse_term(input)?;
+
+    // like with `-`, a fuzzy term can't be negated, so it's matched exactly
+    let term = match term {
+        Term::NormalFuzzy(term) => Term::Exact(term),
+        _ => term,
+    };
+    Ok((input, Term::Not(Box::new(term))))
+}
+
+/// Terms matching on file metadata.
+/// Split out of `parse_term`, since nom's `alt` only takes up to 21 parsers
+fn parse_metadata(input: &str) -> IResult<&str, Term> {
+    alt((
+        parse_perm,
+        parse_owner,
+        parse_group,
+        parse_depth,
+        parse_inode,
+        parse_links,
+        parse_size_range,
+        parse_mime_confidence,
+        parse_created,
+        parse_created_period,
+        parse_accessed,
+        parse_access,
+        parse_binary,
+        parse_sparse,
+        parse_xattr,
+    ))(input)
+}
+
+/// Whether `token` is just the start of a tag term, like `#`, `tag:` or `-#`,
+/// so a quote following it quotes the tag name: `#"my tag"` or `tag:'my tag'`
+fn is_tag_prefix(token: &str) -> bool {
+    matches!(
+        token.strip_prefix('-').unwrap_or(token),
+        "#" | "tag:" | "tags:" | "tagged:"
+    )
+}
+
+/// Whether a `\\` followed by `next` escapes it, otherwise the backslash is kept as is.
+/// Windows paths are full of backslashes, so there it only escapes whitespace and quotes
+fn is_escape(next: Option<char>) -> bool {
+    match next {
+        None => false,
+        Some(next) if cfg!(windows) => next.is_whitespace() || next == '"' || next == '\'',
+        Some(_) => true,
+    }
+}
+
 /// This function breaks down a query into a list of tokens,
 /// then looks for a special negation token `-` and then
 /// turns all the tokens into a Query struct.
@@ -294,23 +2918,31 @@ pub fn parse_query(query: &str) -> Result<Query> {
     while let Some(ch) = query.chars().next() {
         // println!("ch: {}", ch);
         match ch {
-            '\\' => {
+            '\\' if is_escape(query[1..].chars().next()) => {
                 query = &query[1..];
-                token_buf.push(query.chars().next().unwrap());
+                let escaped = query.chars().next().unwrap();
+                token_buf.push(escaped);
+                query = &query[escaped.len_utf8()..];
+                continue;
             }
-            '"' => {
+            // single quotes are only quotes right after a tag prefix, so `don't` still works
+            '"' | '\'' if ch == '"' || is_tag_prefix(&token_buf) => {
+                let quote = ch;
                 query = &query[1..];
                 while let Some(ch) = query.chars().next() {
-                    if ch == '"' {
+                    if ch == quote {
                         // only break this loop, not the outer one
                         break;
                     }
                     token_buf.push(ch);
-                    query = &query[1..];
+                    query = &query[ch.len_utf8()..];
                 }
             }
-            ' ' => {
-                buf.push(token_buf);
+            ch if ch.is_whitespace() => {
+                // consecutive whitespace should not produce empty tokens
+                if !token_buf.is_empty() {
+                    buf.push(token_buf);
+                }
                 // println!("space hit");
                 // println!("buf: {:#?}", buf);
                 token_buf = String::new();
@@ -320,8 +2952,8 @@ pub fn parse_query(query: &str) -> Result<Query> {
                 token_buf.push(ch);
             }
         }
-        // advance the query
-        query = &query[1..];
+        // advance the query, by a whole character
+        query = &query[ch.len_utf8()..];
     }
 
     // if there's anything left in the token buffer, push it
@@ -340,9 +2972,12 @@ pub fn parse_query(query: &str) -> Result<Query> {
                 parse_term(&token).unwrap().1
             };
 
-            // if term is fuzzyterm, turn it into exact
+            // if term is fuzzyterm, turn it into exact, unless it's written `-~term`
             let term = match term {
-                Term::NormalFuzzy(term) => Term::Exact(term),
+                Term::NormalFuzzy(term) => match term.strip_prefix('~') {
+                    Some(fuzzy) if !fuzzy.is_empty() => Term::NormalFuzzy(fuzzy.to_string()),
+                    _ => Term::Exact(term),
+                },
                 _ => term,
             };
             excludes.push(term);
@@ -355,49 +2990,87 @@ pub fn parse_query(query: &str) -> Result<Query> {
     Ok(Query { includes, excludes })
 }
 
-/// Make a fuzzy match score depending on the Query
-
-pub fn fuzzy_score(query: &Query, ixf: IndexedFile) -> Result<(i64, IndexedFile)> {
-    let includes = query
-        .includes
-        .iter()
-        .filter_map(|term| match term {
-            Term::NormalFuzzy(term) => Some(term),
-            _ => None,
-        })
-        .collect::<Vec<_>>();
+/// Fuzzy match score of a single file, see `fuzzy_score`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FuzzyScore {
+    /// The combined score used for ranking, minus the score of excluded fuzzy terms
+    pub total: i64,
+    /// Score of the fuzzy terms against the file name, before weighting
+    pub filename_score: i64,
+    /// Score of the fuzzy terms against the parent directory, before weighting
+    pub dir_score: i64,
+}
 
-    let matcher = fuzzy_matcher::skim::SkimMatcherV2::default()
-        .smart_case()
-        .use_cache(true)
-        .debug(false);
+/// Matches in the file name are worth more than matches in the directories above it
+const FILENAME_WEIGHT: f64 = 2.0;
+const DIR_WEIGHT: f64 = 1.0;
 
-    let mut score: i64 = 0;
+/// Make a fuzzy match score depending on the Query
+/// The file name and its parent directory are scored separately and weighted,
+/// so `main` ranks `src/main.rs` above `main/src/lib.rs`.
+/// A term matching neither on its own (e.g. `src/main`) is scored against the full path instead,
+/// and counted towards the directory score.
+/// Excluded fuzzy terms are subtracted from the total, which can end up negative
+pub fn fuzzy_score(
+    query: &Query,
+    ixf: &IndexedFile,
+    matcher: &dyn FuzzyMatcherAbstraction,
+) -> FuzzyScore {
+    let filename = nfc(ixf
+        .path
+        .file_name()
+        .unwrap_or_default()
+        .to_str()
+        .unwrap_or(""));
+    let dir = nfc(ixf.path.parent().and_then(|p| p.to_str()).unwrap_or(""));
 
-    for term in includes {
-        let mut max_score = 0;
-        let path = ixf.path.clone();
-        if let Some(score) = matcher.fuzzy_match(path.to_str().unwrap(), term) {
-            max_score = score;
+    let score_terms = |terms: &[Term]| {
+        let mut score = FuzzyScore::default();
+        for term in terms {
+            let term = match term {
+                Term::NormalFuzzy(term) => nfc(term),
+                _ => continue,
+            };
+            let filename_score = matcher.score(&filename, &term);
+            let dir_score = matcher.score(&dir, &term);
+            if filename_score.is_none() && dir_score.is_none() {
+                score.dir_score += ixf
+                    .path
+                    .to_str()
+                    .and_then(|path| matcher.score(&nfc(path), &term))
+                    .unwrap_or(0);
+                continue;
+            }
+            score.filename_score += filename_score.unwrap_or(0);
+            score.dir_score += dir_score.unwrap_or(0);
         }
-        score += max_score;
-    }
+        score.total = (score.filename_score as f64 * FILENAME_WEIGHT
+            + score.dir_score as f64 * DIR_WEIGHT) as i64;
+        score
+    };
 
-    Ok((score, ixf))
+    let mut score = score_terms(&query.includes);
+    score.total -= score_terms(&query.excludes).total;
+    score
 }
 
 // New fuzzy match to score each entry individually
 /// Evaluate the score of the file based on the query
-pub fn eval_score(query: &Query, ixf: IndexedFile) -> Result<Option<(i64, IndexedFile)>> {
+pub fn eval_score(
+    query: &Query,
+    file: IndexedFile,
+    ctx: &MatchContext,
+) -> std::result::Result<Option<(i64, IndexedFile)>, DatchaniError> {
     // do fuzzy score first
-    let (score, file) = fuzzy_score(query, ixf.clone())?;
+    let score = fuzzy_score(query, &file, ctx.config.matcher.as_ref()).total;
 
     // then do the filters
-    let mut cond = false;
+    // a query with only exclusions should start from every file
+    let mut cond = query.includes.is_empty();
 
     {
         for term in &query.includes {
-            if term.match_rules(&file) {
+            if term.try_match_rules(&file, ctx)? {
                 cond = true;
             } else {
                 // If it doesn't match the rules once, it should fail
@@ -408,7 +3081,12 @@ pub fn eval_score(query: &Query, ixf: IndexedFile) -> Result<Option<(i64, Indexe
     }
     // if in any case it fails, we should return false
     for term in &query.excludes {
-        if term.match_rules(&file) {
+        // excluded fuzzy terms only lower the score, see `fuzzy_score`,
+        // and excluding a modifier doesn't exclude any files
+        if matches!(term, Term::NormalFuzzy(_)) || term.is_modifier() {
+            continue;
+        }
+        if term.try_match_rules(&file, ctx)? {
             // cond = false;
             return Ok(None);
         }
@@ -421,37 +3099,143 @@ pub fn eval_score(query: &Query, ixf: IndexedFile) -> Result<Option<(i64, Indexe
     }
 }
 
+/// How a single term of a query matched a file, see `eval_score_explain`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MatchExplanation {
+    /// The term as parsed, exclusions start with `-`
+    pub term: String,
+    /// The fuzzy score of the term on its own, `None` for filters
+    pub score: Option<i64>,
+    /// Whether the term matched the file, so `false` for every exclusion of a result
+    pub matched: bool,
+}
+
+/// Like `eval_score`, but also explains every term of the query for a matching file
+pub fn eval_score_explain(
+    query: &Query,
+    file: IndexedFile,
+    ctx: &MatchContext,
+) -> std::result::Result<Option<(i64, IndexedFile, Vec<MatchExplanation>)>, DatchaniError> {
+    let (score, file) = match eval_score(query, file, ctx)? {
+        Some(result) => result,
+        None => return Ok(None),
+    };
+
+    // fuzzy terms always pass the filters, they matched if they scored anything
+    let explain = |term: &Term, excluded: bool| {
+        let (score, matched) = match term {
+            Term::NormalFuzzy(_) => {
+                let single = Query {
+                    includes: vec![term.clone()],
+                    excludes: Vec::new(),
+                };
+                let score = fuzzy_score(&single, &file, ctx.config.matcher.as_ref()).total;
+                // excluded fuzzy terms lower the score instead of filtering, see `fuzzy_score`
+                (Some(if excluded { -score } else { score }), !excluded && score > 0)
+            }
+            _ => (None, term.try_match_rules(&file, ctx)?),
+        };
+        Ok(MatchExplanation {
+            term: match term {
+                Term::NormalFuzzy(_) if excluded => format!("-~{}", term),
+                _ if excluded => format!("-{}", term),
+                _ => term.to_string(),
+            },
+            score,
+            matched,
+        })
+    };
+    let includes = query.includes.iter().map(|term| explain(term, false));
+    let excludes = query.excludes.iter().map(|term| explain(term, true));
+    let explanation = includes
+        .chain(excludes)
+        .collect::<std::result::Result<_, DatchaniError>>()?;
+
+    Ok(Some((score, file, explanation)))
+}
+
 // The actual query function
 /// This is the main entrypoint for querying the index.
 /// It will first try to fuzzy match the query, them finally
 /// filters them by the rules provided in the Term enum.
-pub fn query(query: &Query, index: &Index) -> Vec<(i64, IndexedFile)> {
+/// Fails with the first error encountered, e.g. for an invalid regex
+pub fn query(
+    query: &Query,
+    index: &Index,
+) -> std::result::Result<Vec<(i64, IndexedFile)>, DatchaniError> {
+    query_with_config(query, index, &ScoringConfig::default())
+}
+
+/// Like `query`, but scoring with `config` instead of the defaults
+pub fn query_with_config(
+    query: &Query,
+    index: &Index,
+    config: &ScoringConfig,
+) -> std::result::Result<Vec<(i64, IndexedFile)>, DatchaniError> {
     // first, let's try to match the query with fuzzy matching
+    let ctx = MatchContext {
+        config: config.clone(),
+        ..MatchContext::from_index(index)
+    }
+    .with_query(query);
 
-    let mut scored_index = index
-        .files
-        .iter()
-        .map(|f| eval_score(query, f.to_owned()))
-        .filter(|f| f.is_ok())
-        .filter(|f| f.as_ref().unwrap().is_some())
-        .map(|f| f.unwrap().unwrap())
-        .collect::<Vec<_>>();
+    let mut scored_index = Vec::new();
+    for file in &index.files {
+        if let Some(result) = eval_score(query, file.to_owned(), &ctx)? {
+            scored_index.push(result);
+        }
+    }
 
-    // sort matches by score
-    scored_index.sort_by(|a, b| b.0.cmp(&a.0));
-    // reverse the order
-    scored_index.reverse();
-    scored_index
+    // best matches first, ties keep their order in the index
+    Index::sort_by_score_desc(&mut scored_index);
+    if let Some(limit) = query.result_limit() {
+        scored_index.truncate(limit);
+    }
+    Ok(scored_index)
 }
 
 // Query, but stream the results instead of collecting them
 /// Streaming version of the query function.
-pub fn query_stream(query: Query, index: Index) -> impl Stream<Item = (i64, IndexedFile)> {
+/// Like `query`, the stream ends after the first error
+pub fn query_stream(
+    query: Query,
+    index: Index,
+) -> impl Stream<Item = std::result::Result<(i64, IndexedFile), DatchaniError>> {
+    query_stream_with_config(query, index, ScoringConfig::default())
+}
+
+/// Like `query_stream`, but scoring with `config` instead of the defaults
+pub fn query_stream_with_config(
+    query: Query,
+    index: Index,
+    config: ScoringConfig,
+) -> impl Stream<Item = std::result::Result<(i64, IndexedFile), DatchaniError>> {
     // first, let's try to match the query with fuzzy matching
     let s = stream! {
+        let ctx = MatchContext {
+            config,
+            ..MatchContext::from_index(&index)
+        }
+        .with_query(&query);
+        // the best N can't be known before every file is scored, so `top:` collects them first
+        let limit = query.result_limit();
+        let mut best = Vec::new();
         for file in index.files.iter() {
-            if let Ok(Some((score, file))) = eval_score(&query, file.to_owned()) {
-                yield (score, file);
+            match eval_score(&query, file.to_owned(), &ctx) {
+                Ok(Some(result)) if limit.is_some() => best.push(result),
+                Ok(Some(result)) => yield Ok(result),
+                Ok(None) => {}
+                Err(e) => {
+                    yield Err(e);
+                    return;
+                }
+            }
+        }
+        if let Some(limit) = limit {
+            Index::sort_by_score_desc(&mut best);
+            best.truncate(limit);
+            for result in best {
+                yield Ok(result);
             }
         }
     };
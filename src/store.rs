@@ -0,0 +1,114 @@
+//! Saved queries
+//! Named queries are persisted to `~/.config/datchani/queries.toml`,
+//! one `[queries.NAME]` table per query:
+//!
+//! ```toml
+//! [queries.rust-todo]
+//! query = "ext:rs #todo"
+//! description = "Rust sources tagged todo"
+//! ```
+//!
+//! The raw query string is stored instead of the parsed `Query`,
+//! so saved queries keep working as the query syntax evolves.
+
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::query::{parse_query, Query};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedQuery {
+    /// The raw query string, as typed by the user
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct QueryStore {
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(default)]
+    queries: BTreeMap<String, SavedQuery>,
+}
+
+impl QueryStore {
+    /// Open the store at `path`, a missing file is treated as an empty store
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let mut store: Self = match fs::read_to_string(&path) {
+            Ok(s) => toml::from_str(&s)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+        store.path = path;
+        Ok(store)
+    }
+
+    /// Open the store in the user's config directory
+    pub fn open_default() -> Result<Self> {
+        let dir = dirs::config_dir().ok_or_else(|| eyre!("Could not find the config directory"))?;
+        Self::open(dir.join("datchani").join("queries.toml"))
+    }
+
+    /// Save a query under `name`, replacing any query with the same name.
+    /// The query is parsed first, so invalid queries are never saved
+    pub fn save(&mut self, name: &str, query: &str, description: Option<String>) -> Result<()> {
+        parse_query(query)?;
+        self.queries.insert(
+            name.to_string(),
+            SavedQuery {
+                query: query.to_string(),
+                description,
+            },
+        );
+
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Load and parse the query saved under `name`
+    pub fn load(&self, name: &str) -> Result<Query> {
+        let saved = self
+            .queries
+            .get(name)
+            .ok_or_else(|| eyre!("No saved query named {:?}", name))?;
+        parse_query(&saved.query)
+    }
+
+    /// Get the raw saved query, including its description
+    pub fn get(&self, name: &str) -> Option<&SavedQuery> {
+        self.queries.get(name)
+    }
+
+    /// Names of all saved queries, in alphabetical order
+    pub fn list(&self) -> Vec<String> {
+        self.queries.keys().cloned().collect()
+    }
+}
+
+#[test]
+fn test_query_store() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("datchani").join("queries.toml");
+
+    let mut store = QueryStore::open(path.clone())?;
+    assert!(store.list().is_empty());
+    store.save("rust-todo", "ext:rs #todo", Some(String::from("Rust sources tagged todo")))?;
+    store.save("docs", "ext:md", None)?;
+
+    let store = QueryStore::open(path)?;
+    assert_eq!(store.list(), vec!["docs", "rust-todo"]);
+    assert_eq!(store.load("rust-todo")?, parse_query("ext:rs #todo")?);
+    assert_eq!(
+        store.get("rust-todo").and_then(|q| q.description.as_deref()),
+        Some("Rust sources tagged todo")
+    );
+    assert!(store.load("missing").is_err());
+
+    Ok(())
+}
@@ -2,10 +2,20 @@
 //! Uses xattr to store tags
 //! Tags should be stored as a CSV string,
 //! With the xattr name "user.tags"
+//!
+//! Tags can be namespaced as `namespace/name` (e.g. `user/important`),
+//! so several applications can share the same xattr without stepping on each other.
+//! Namespaced tags are stored and returned as the full `namespace/name` string.
 
-use std::fs::File;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
 
 use color_eyre::Result;
+use tracing::warn;
+use walkdir::WalkDir;
 use xattr::FileExt;
 const TAGS_XATTR: &str = "user.tags";
 
@@ -24,6 +34,217 @@ pub fn get_tags(path: &str) -> Result<Vec<String>> {
     Ok(parse_tags(String::from_utf8(tags)?.as_str()))
 }
 
+/// get tags from a path that belong to the namespace `ns`,
+/// returned with their `ns/` prefix
+pub fn get_tags_by_namespace(path: &str, ns: &str) -> Result<Vec<String>> {
+    let prefix = format!("{}/", ns);
+    Ok(get_tags(path)?
+        .into_iter()
+        .filter(|tag| tag.starts_with(&prefix))
+        .collect())
+}
+
+/// add a tag to a path, does nothing if the tag is already there
+pub fn add_tag(path: &str, tag: &str) -> Result<()> {
+    let mut tags = get_tags(path)?;
+    if tags.iter().any(|t| t == tag) {
+        return Ok(());
+    }
+    tags.push(tag.to_string());
+
+    let file = File::open(path)?;
+    file.set_xattr(TAGS_XATTR, tags.join(",").as_bytes())?;
+    Ok(())
+}
+
+/// add the tag `ns/tag` to a path
+pub fn add_tag_namespaced(path: &str, ns: &str, tag: &str) -> Result<()> {
+    add_tag(path, &format!("{}/{}", ns, tag))
+}
+
+/// open both ends of a tag transfer, failing if either can't be accessed
+fn open_pair(src: &Path, dst: &Path) -> Result<(File, File)> {
+    Ok((File::open(src)?, File::open(dst)?))
+}
+
+fn read_tags(file: &File) -> Result<Vec<String>> {
+    let tags = file.get_xattr(TAGS_XATTR)?.unwrap_or_default();
+    Ok(parse_tags(String::from_utf8(tags)?.as_str()))
+}
+
+/// copy the tags of `src` to `dst`, replacing the tags `dst` had.
+/// If `src` has no tags, `dst` is left unchanged
+pub fn copy_tags(src: &Path, dst: &Path) -> Result<()> {
+    let (src, dst) = open_pair(src, dst)?;
+    let tags = read_tags(&src)?;
+    if !tags.is_empty() {
+        dst.set_xattr(TAGS_XATTR, tags.join(",").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// copy the tags of `src` to `dst` like `copy_tags`, then remove them from `src`
+pub fn move_tags(src: &Path, dst: &Path) -> Result<()> {
+    copy_tags(src, dst)?;
+    let src = File::open(src)?;
+    if src.get_xattr(TAGS_XATTR)?.is_some() {
+        src.remove_xattr(TAGS_XATTR)?;
+    }
+    Ok(())
+}
+
+/// add the tags of `src` to `dst`, keeping the tags `dst` already has
+pub fn merge_tags(src: &Path, dst: &Path) -> Result<()> {
+    let (src, dst) = open_pair(src, dst)?;
+    let mut tags = read_tags(&dst)?;
+    let before = tags.len();
+    for tag in read_tags(&src)? {
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    if tags.len() != before {
+        dst.set_xattr(TAGS_XATTR, tags.join(",").as_bytes())?;
+    }
+    Ok(())
+}
+
+/// get the tags of everything under `dir` (including `dir` itself),
+/// paths without any tags are left out.
+/// Anything that can't be read is logged and skipped, only a missing `dir` is an error
+pub fn get_tags_recursive(dir: &Path) -> Result<BTreeMap<PathBuf, Vec<String>>> {
+    std::fs::metadata(dir)?;
+
+    let mut tagged = BTreeMap::new();
+    for entry in WalkDir::new(dir) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Error walking {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        let path = match entry.path().to_str() {
+            Some(path) => path,
+            None => {
+                warn!("Skipping non UTF-8 path {}", entry.path().display());
+                continue;
+            }
+        };
+        match get_tags(path) {
+            Ok(tags) if tags.is_empty() => {}
+            Ok(tags) => {
+                tagged.insert(entry.into_path(), tags);
+            }
+            Err(e) => warn!("Error reading tags of {}: {}", path, e),
+        }
+    }
+    Ok(tagged)
+}
+
+/// count how many paths under `dir` have each tag, see `get_tags_recursive`
+pub fn summarize_tags(dir: &Path) -> Result<BTreeMap<String, usize>> {
+    let mut counts = BTreeMap::new();
+    for tag in get_tags_recursive(dir)?.into_values().flatten() {
+        *counts.entry(tag).or_insert(0) += 1;
+    }
+    Ok(counts)
+}
+
+#[test]
+fn test_tags_recursive() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let nested = dir.path().join("photos/2023");
+    std::fs::create_dir_all(&nested)?;
+    for name in ["beach.jpg", "city.jpg", "untagged.jpg"] {
+        File::create(nested.join(name))?;
+    }
+    File::create(dir.path().join("notes.txt"))?;
+
+    let path = |p: PathBuf| p.to_str().unwrap().to_owned();
+    add_tag(&path(nested.join("beach.jpg")), "holiday")?;
+    add_tag(&path(nested.join("beach.jpg")), "sea")?;
+    add_tag(&path(nested.join("city.jpg")), "holiday")?;
+    add_tag(&path(dir.path().join("notes.txt")), "work")?;
+    add_tag(&path(dir.path().join("photos")), "media")?;
+
+    let tagged = get_tags_recursive(dir.path())?;
+    assert_eq!(tagged.len(), 4);
+    assert_eq!(tagged[&nested.join("beach.jpg")], vec!["holiday", "sea"]);
+    assert_eq!(tagged[&dir.path().join("photos")], vec!["media"]);
+    assert!(!tagged.contains_key(&nested.join("untagged.jpg")));
+
+    let summary = summarize_tags(dir.path())?;
+    let expected = [("holiday", 2), ("media", 1), ("sea", 1), ("work", 1)]
+        .into_iter()
+        .map(|(tag, count)| (tag.to_owned(), count))
+        .collect::<BTreeMap<_, _>>();
+    assert_eq!(summary, expected);
+
+    assert!(get_tags_recursive(&dir.path().join("missing")).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_namespaced_tags() -> Result<()> {
+    let file = tempfile::NamedTempFile::new()?;
+    let path = file.path().to_str().unwrap();
+
+    add_tag_namespaced(path, "user", "important")?;
+    add_tag_namespaced(path, "project", "my-app")?;
+    add_tag_namespaced(path, "user", "important")?;
+    add_tag(path, "plain")?;
+
+    assert_eq!(
+        get_tags(path)?,
+        vec!["user/important", "project/my-app", "plain"]
+    );
+    assert_eq!(get_tags_by_namespace(path, "user")?, vec!["user/important"]);
+    assert!(get_tags_by_namespace(path, "use")?.is_empty());
+
+    let query = crate::query::parse_query("tag:user/important")?;
+    assert_eq!(
+        query.includes,
+        vec![crate::query::Term::Tag(String::from("user/important"))]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_copy_move_merge_tags() -> Result<()> {
+    let src = tempfile::NamedTempFile::new()?;
+    let dst = tempfile::NamedTempFile::new()?;
+    let tags = |file: &tempfile::NamedTempFile| get_tags(file.path().to_str().unwrap());
+    add_tag(src.path().to_str().unwrap(), "owo")?;
+    add_tag(dst.path().to_str().unwrap(), "uwu")?;
+
+    merge_tags(src.path(), dst.path())?;
+    assert_eq!(tags(&dst)?, vec!["uwu", "owo"]);
+
+    copy_tags(src.path(), dst.path())?;
+    assert_eq!(tags(&src)?, vec!["owo"]);
+    assert_eq!(tags(&dst)?, vec!["owo"]);
+
+    move_tags(src.path(), dst.path())?;
+    assert!(src.as_file().get_xattr(TAGS_XATTR)?.is_none());
+    assert_eq!(tags(&dst)?, vec!["owo"]);
+
+    // an untagged source leaves the destination alone
+    copy_tags(src.path(), dst.path())?;
+    merge_tags(src.path(), dst.path())?;
+    move_tags(src.path(), dst.path())?;
+    assert_eq!(tags(&dst)?, vec!["owo"]);
+
+    let missing = src.path().with_extension("missing");
+    assert!(copy_tags(&missing, dst.path()).is_err());
+    assert!(move_tags(src.path(), &missing).is_err());
+    assert!(merge_tags(src.path(), &missing).is_err());
+
+    Ok(())
+}
+
 #[test]
 fn xattr_test() {
     // read xattr
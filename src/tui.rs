@@ -0,0 +1,236 @@
+//! Interactive search
+//! A small `fzf`-like TUI that re-runs the query on every keystroke.
+//! The TUI is drawn on stderr, so the selected path can be captured from stdout.
+
+use std::{
+    io::{self, Stderr},
+    path::PathBuf,
+};
+
+use color_eyre::Result;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use file_type_enum::FileType;
+use ratatui::{
+    backend::{Backend, CrosstermBackend},
+    layout::{Constraint, Direction, Layout},
+    style::{Modifier, Style},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    Frame, Terminal,
+};
+
+use crate::{
+    files::{Index, IndexedFile, PREVIEW_BYTES},
+    query::{parse_query, ScoringConfig},
+};
+
+/// Only the best matches are listed, there's no point in rendering thousands of them
+const MAX_RESULTS: usize = 1000;
+
+/// Lines of the selected file shown below the results
+const PREVIEW_HEIGHT: u16 = 5;
+
+struct App<'a> {
+    index: &'a Index,
+    scoring: &'a ScoringConfig,
+    input: String,
+    results: Vec<(i64, IndexedFile)>,
+    /// Number of matches, including the ones past `MAX_RESULTS`
+    total: usize,
+    state: ListState,
+}
+
+impl<'a> App<'a> {
+    fn new(index: &'a Index, scoring: &'a ScoringConfig) -> Self {
+        Self {
+            index,
+            scoring,
+            input: String::new(),
+            results: Vec::new(),
+            total: 0,
+            state: ListState::default(),
+        }
+    }
+
+    /// Re-run the query against the index, resetting the selection
+    fn refresh(&mut self) {
+        let page = match parse_query(&self.input) {
+            Ok(q) if !q.is_empty() => self
+                .index
+                .search_ranked(&q, 0, MAX_RESULTS, self.scoring)
+                .ok(),
+            _ => None,
+        };
+        // the query may be half typed, so errors just show no results
+        (self.results, self.total) = match page {
+            Some(page) => (
+                page.results
+                    .into_iter()
+                    .map(|result| (result.score, result.file))
+                    .collect(),
+                page.total,
+            ),
+            None => (Vec::new(), 0),
+        };
+        self.state
+            .select(if self.results.is_empty() { None } else { Some(0) });
+        self.load_preview();
+    }
+
+    fn next(&mut self) {
+        if let Some(i) = self.state.selected() {
+            self.state.select(Some((i + 1).min(self.results.len() - 1)));
+            self.load_preview();
+        }
+    }
+
+    fn previous(&mut self) {
+        if let Some(i) = self.state.selected() {
+            self.state.select(Some(i.saturating_sub(1)));
+            self.load_preview();
+        }
+    }
+
+    /// Read the preview of the selected file, if it hasn't been read yet
+    fn load_preview(&mut self) {
+        let selected = self.state.selected().and_then(|i| self.results.get_mut(i));
+        if let Some((_, file)) = selected {
+            if file.content_preview.is_none() {
+                // unreadable and binary files just show an empty preview
+                let _ = file.load_preview(PREVIEW_BYTES);
+            }
+        }
+    }
+
+    fn selected(&self) -> Option<&IndexedFile> {
+        self.state
+            .selected()
+            .and_then(|i| self.results.get(i))
+            .map(|(_, file)| file)
+    }
+}
+
+/// Nerd Fonts codepoint for the given file type
+fn file_type_icon(file_type: &FileType) -> char {
+    match file_type {
+        FileType::Regular => '\u{f15b}',
+        FileType::Directory => '\u{f07b}',
+        FileType::Symlink => '\u{f0c1}',
+        FileType::BlockDevice | FileType::CharDevice => '\u{f2db}',
+        FileType::Fifo | FileType::Socket => '\u{f1e6}',
+    }
+}
+
+/// Run the interactive search until the user picks a file or quits.
+/// Returns the path of the selected file, if any
+pub fn run(index: &Index, scoring: &ScoringConfig) -> Result<Option<PathBuf>> {
+    enable_raw_mode()?;
+    let mut stderr = io::stderr();
+    execute!(stderr, EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stderr))?;
+
+    let res = event_loop(&mut terminal, index, scoring);
+
+    // always restore the terminal, even if the event loop failed
+    disable_raw_mode()?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    terminal.show_cursor()?;
+
+    res
+}
+
+fn event_loop(
+    terminal: &mut Terminal<CrosstermBackend<Stderr>>,
+    index: &Index,
+    scoring: &ScoringConfig,
+) -> Result<Option<PathBuf>> {
+    let mut app = App::new(index, scoring);
+
+    loop {
+        terminal.draw(|f| draw(f, &mut app))?;
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+
+        match key.code {
+            KeyCode::Esc => return Ok(None),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(None),
+            KeyCode::Enter => return Ok(app.selected().map(|f| f.path.clone())),
+            KeyCode::Up => app.previous(),
+            KeyCode::Down => app.next(),
+            KeyCode::Backspace => {
+                app.input.pop();
+                app.refresh();
+            }
+            KeyCode::Char(c) => {
+                app.input.push(c);
+                app.refresh();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(1),
+            Constraint::Length(PREVIEW_HEIGHT + 2),
+        ])
+        .split(f.size());
+
+    let input = Paragraph::new(app.input.as_str())
+        .block(Block::default().borders(Borders::ALL).title("Query"));
+    f.render_widget(input, chunks[0]);
+
+    let root = app.index.root.as_deref();
+    let items = app
+        .results
+        .iter()
+        .map(|(score, file)| {
+            let path = root
+                .and_then(|root| file.path.strip_prefix(root).ok())
+                .unwrap_or(&file.path);
+            ListItem::new(format!(
+                "{:>5} {} {}  {}",
+                score,
+                file_type_icon(&file.file_type),
+                path.display(),
+                file.last_modified.format("%Y-%m-%d %H:%M"),
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    let list = List::new(items)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(if app.total > app.results.len() {
+                    format!("Results ({} of {})", app.results.len(), app.total)
+                } else {
+                    format!("Results ({})", app.total)
+                }),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+        .highlight_symbol("> ");
+    f.render_stateful_widget(list, chunks[1], &mut app.state);
+
+    // lines aren't wrapped, so long lines are cut off at the edge of the terminal
+    let preview = app
+        .selected()
+        .and_then(|file| file.content_preview.as_deref())
+        .unwrap_or_default();
+    let preview =
+        Paragraph::new(preview).block(Block::default().borders(Borders::ALL).title("Preview"));
+    f.render_widget(preview, chunks[2]);
+
+    // keep the cursor at the end of the input
+    f.set_cursor(chunks[0].x + app.input.chars().count() as u16 + 1, chunks[0].y + 1);
+}
@@ -0,0 +1,381 @@
+use std::{fs::File, path::Path, process::Command};
+
+fn datchani(dir: &Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_datchani"))
+        .args(args)
+        .current_dir(dir)
+        .env("RUST_LOG", "error")
+        .output()
+        .expect("failed to run datchani")
+}
+
+#[test]
+fn count_exit_code() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("main.rs")).unwrap();
+    File::create(dir.path().join("README.md")).unwrap();
+
+    let out = datchani(dir.path(), &["search", "ext:rs", "--count", "--gt", "0"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "1");
+
+    let out = datchani(dir.path(), &["search", "ext:rs", "--count", "--eq", "2"]);
+    assert_eq!(out.status.code(), Some(1));
+
+    let out = datchani(dir.path(), &["search", "ext:py", "--count", "--lt", "1"]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "0");
+}
+
+#[test]
+fn null_separated_output() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("main.rs")).unwrap();
+    File::create(dir.path().join("with\nnewline.rs")).unwrap();
+
+    let out = datchani(dir.path(), &["search", "ext:rs", "-0"]);
+    assert!(out.status.success());
+
+    // what `xargs -0` would do: split on null bytes and hand each path to a command
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let paths = stdout
+        .split('\0')
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>();
+    assert_eq!(paths.len(), 2);
+    for path in paths {
+        assert!(Path::new(path).is_file(), "{:?} should be a file", path);
+    }
+
+    // JSON is already safe, so --null is ignored
+    let out = datchani(dir.path(), &["search", "ext:rs", "-0", "--json"]);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(!stdout.contains('\0'));
+    assert_eq!(stdout.lines().count(), 2);
+}
+
+#[test]
+fn relative_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("src")).unwrap();
+    File::create(dir.path().join("src").join("main.rs")).unwrap();
+
+    let out = datchani(dir.path(), &["search", "ext:rs"]);
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap().trim(),
+        Path::new("src").join("main.rs").to_str().unwrap()
+    );
+    let out = datchani(
+        dir.path(),
+        &["search", "ext:rs", "--format=table", "--fields=path"],
+    );
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap().trim(),
+        Path::new("src").join("main.rs").to_str().unwrap()
+    );
+}
+
+#[test]
+fn invalid_regex() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("main.rs")).unwrap();
+
+    let out = datchani(dir.path(), &["search", "regex:("]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&out.stderr).contains("Invalid regex `(`"));
+}
+
+#[test]
+fn table_fields() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("main.rs")).unwrap();
+    File::create(dir.path().join("README.md")).unwrap();
+
+    // a single path column is the same as plain output
+    let table = datchani(
+        dir.path(),
+        &["search", "*", "--format=table", "--fields=path"],
+    );
+    let plain = datchani(dir.path(), &["search", "*"]);
+    assert!(table.status.success());
+    let mut table = String::from_utf8(table.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let mut plain = String::from_utf8(plain.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    table.sort();
+    plain.sort();
+    assert_eq!(table, plain);
+
+    let out = datchani(
+        dir.path(),
+        &["search", "ext:rs", "--format=table", "--fields=path,size"],
+    );
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let lines = stdout.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[0].split_whitespace().collect::<Vec<_>>(),
+        ["PATH", "SIZE"]
+    );
+    assert!(lines[1].ends_with(" 0"));
+
+    let out = datchani(
+        dir.path(),
+        &["search", "*", "--format=table", "--fields=path,colour"],
+    );
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("unknown field `colour`"));
+}
+
+#[cfg(unix)]
+#[test]
+fn hardlink_count() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("a")).unwrap();
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("b")).unwrap();
+    std::fs::hard_link(dir.path().join("a"), dir.path().join("c")).unwrap();
+    File::create(dir.path().join("unlinked")).unwrap();
+
+    // directories have at least two links, so look for more than that
+    let out = datchani(dir.path(), &["search", "links:>2", "--json"]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let mut names = Vec::new();
+    for line in stdout.lines() {
+        let result: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(result["file"]["hardlink_count"], 3);
+        let path = result["file"]["path"].as_str().unwrap();
+        names.push(Path::new(path).file_name().unwrap().to_owned());
+    }
+    names.sort();
+    assert_eq!(names, ["a", "b", "c"]);
+}
+
+#[test]
+fn stdin_paths() {
+    use std::{io::Write, process::Stdio};
+
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["main.rs", "lib.rs", "README.md", "with\nnewline.rs"] {
+        File::create(dir.path().join(name)).unwrap();
+    }
+    let run = |args: &[&str], input: &[u8]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_datchani"))
+            .args(args)
+            .current_dir(dir.path())
+            .env("RUST_LOG", "error")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("failed to run datchani");
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    // only the listed paths are searched, and missing ones are skipped
+    let out = run(
+        &["search", "ext:rs", "--stdin"],
+        b"main.rs\nREADME.md\nmissing.rs\n\n",
+    );
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), "main.rs\n");
+
+    let out = run(
+        &["search", "ext:rs", "--stdin-null", "-0"],
+        b"lib.rs\0with\nnewline.rs\0README.md\0",
+    );
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let mut paths = stdout
+        .split('\0')
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert_eq!(paths, ["lib.rs", "with\nnewline.rs"]);
+
+    let out = datchani(dir.path(), &["search", "*", "--stdin", "--stdin-null"]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn completions() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let out = datchani(dir.path(), &["completions", "bash"]);
+    assert!(out.status.success());
+    let script = String::from_utf8(out.stdout).unwrap();
+    assert!(!script.is_empty());
+    // subcommands and enum values are completed
+    assert!(script.contains("interactive"));
+    assert!(script.contains("table"));
+
+    let out = datchani(dir.path(), &["completions", "tcsh"]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn watch_refreshes() {
+    use std::{
+        io::{BufRead, BufReader},
+        process::Stdio,
+        sync::mpsc,
+        time::Duration,
+    };
+
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("first.txt")).unwrap();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_datchani"))
+        .args(["search", "ext:txt", "--watch", "--watch-delay-ms", "50"])
+        .current_dir(dir.path())
+        .env("RUST_LOG", "error")
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to run datchani");
+    let (tx, rx) = mpsc::channel();
+    let stdout = child.stdout.take().unwrap();
+    std::thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            if tx.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    let wait_for = |name: &str| loop {
+        let line = rx
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap_or_else(|_| panic!("{} never showed up", name));
+        if line.contains(name) {
+            break;
+        }
+    };
+
+    wait_for("first.txt");
+    File::create(dir.path().join("second.txt")).unwrap();
+    wait_for("second.txt");
+
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
+
+#[test]
+fn index_export() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("main.rs")).unwrap();
+    File::create(dir.path().join("README.md")).unwrap();
+    assert!(datchani(dir.path(), &["rebuild"]).status.success());
+
+    let out = datchani(
+        dir.path(),
+        &[
+            "index", "export", "--format", "csv", "-o", "out.csv", "--filter", "ext:rs",
+        ],
+    );
+    assert!(out.status.success());
+    let csv = std::fs::read_to_string(dir.path().join("out.csv")).unwrap();
+    let lines = csv.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("# metadata: {"));
+    assert!(lines[1].starts_with("path,"));
+    assert_eq!(lines.len(), 3);
+    assert!(lines[2].contains("main.rs"));
+
+    let out = datchani(dir.path(), &["index", "export", "-o", "out.json"]);
+    assert!(out.status.success());
+    let json = std::fs::read_to_string(dir.path().join("out.json")).unwrap();
+    assert!(json.contains("README.md") && json.contains("\"metadata\""));
+}
+
+#[test]
+fn json_logs() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("main.rs")).unwrap();
+
+    // --log-level wins over RUST_LOG=error
+    let args = [
+        "search",
+        "ext:rs",
+        "--log-level",
+        "debug",
+        "--log-format",
+        "json",
+    ];
+    let out = datchani(dir.path(), &args);
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.lines().count() > 0);
+    for line in stderr.lines() {
+        let log: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(log["level"].is_string(), "{:?} should have a level", line);
+    }
+
+    let out = datchani(dir.path(), &["search", "ext:rs", "--log-format", "json"]);
+    assert!(out.stderr.is_empty());
+}
+
+#[test]
+fn explain() {
+    let dir = tempfile::tempdir().unwrap();
+    File::create(dir.path().join("main.rs")).unwrap();
+    File::create(dir.path().join("domain.rs")).unwrap();
+    File::create(dir.path().join("main.py")).unwrap();
+
+    let out = datchani(
+        dir.path(),
+        &["search", "ext:rs", "main", "--explain", "--json"],
+    );
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 2);
+    for line in stdout.lines() {
+        let result: serde_json::Value = serde_json::from_str(line).unwrap();
+        let explanation = result["explanation"].as_array().unwrap();
+        assert_eq!(explanation.len(), 2, "{:?} should explain both terms", line);
+        assert_eq!(explanation[0]["term"], "extension:rs");
+        assert!(explanation[1]["score"].is_i64());
+    }
+
+    // without --explain the JSON is unchanged
+    let out = datchani(dir.path(), &["search", "ext:rs", "main", "--json"]);
+    assert!(!String::from_utf8(out.stdout)
+        .unwrap()
+        .contains("explanation"));
+
+    let out = datchani(dir.path(), &["search", "ext:rs", "main", "--explain"]);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("    extension:rs\n"));
+}
+
+#[test]
+fn stats() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in [
+        "main.rs",
+        "lib.rs",
+        "build.rs",
+        "README.md",
+        "notes.md",
+        "Cargo.toml",
+    ] {
+        File::create(dir.path().join(name)).unwrap();
+    }
+
+    let out = datchani(dir.path(), &["stats", "--top", "2"]);
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let sections = stdout.split("\n\n").collect::<Vec<_>>();
+    assert_eq!(sections.len(), 3);
+    let rows = sections[0]
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        rows,
+        [vec!["EXTENSION", "COUNT"], vec!["rs", "3"], vec!["md", "2"]]
+    );
+    assert!(sections[2].starts_with("TAG"));
+}